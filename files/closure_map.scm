@@ -6,5 +6,6 @@
              (set! next (+ next 1))
              v)))))
 (display (count))
+(newline)
 (map (lambda (n) (count))
      '(0 0 0 0 0))
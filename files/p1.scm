@@ -10,3 +10,4 @@
                 (inc-apply (+ curr 1)))
         ))
 (display (inc-apply 0))
+(newline)
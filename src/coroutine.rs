@@ -0,0 +1,34 @@
+use crate::instructions::Instruction;
+use crate::runtime::PriorityNamespace;
+use crate::types::*;
+
+// The evaluation behind a generator made by make-coroutine-generator.
+// Calling the generator carries on with it from where it left off, with its
+// own stacks, locals and environment, until it yields a value or its procedure returns.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Coroutine {
+    pub state: CoroutineState,
+    pub ins_stack: Vec<Instruction>,
+    pub data_stack: Vec<Ponga>,
+    pub locals: PriorityNamespace,
+    pub env: Id,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CoroutineState {
+    Suspended,
+    Running,
+    Done,
+}
+
+impl Coroutine {
+    pub fn new(ins_stack: Vec<Instruction>, env: Id) -> Coroutine {
+        Coroutine {
+            state: CoroutineState::Suspended,
+            ins_stack,
+            data_stack: Vec::new(),
+            locals: PriorityNamespace::new(),
+            env,
+        }
+    }
+}
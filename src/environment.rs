@@ -0,0 +1,25 @@
+use crate::types::*;
+use std::collections::HashMap;
+
+// A first-class environment. While it's the current one, its globals and
+// imports are swapped into the runtime.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Environment {
+    pub globals: HashMap<String, Ponga>,
+    // Imported names and the global names they refer to, so that they see
+    // the library's binding rather than a copy of its value
+    pub imports: HashMap<String, String>,
+    // Whether every builtin can be seen, rather than only the imported ones
+    pub builtins: bool,
+}
+
+impl Environment {
+    pub fn new(globals: HashMap<String, Ponga>) -> Environment {
+        Environment { globals, imports: HashMap::new(), builtins: true }
+    }
+
+    // An environment with nothing in it until something is imported
+    pub fn empty() -> Environment {
+        Environment { globals: HashMap::new(), imports: HashMap::new(), builtins: false }
+    }
+}
@@ -1,42 +1,78 @@
+use crate::coroutine::Coroutine;
+use crate::environment::Environment;
 use crate::gc_obj::*;
+use crate::instructions::Instruction;
+use crate::parameter::Parameterization;
+use crate::promise::Promise;
+use crate::thread::*;
 use crate::types::*;
-use std::cell::UnsafeCell;
 use std::collections::HashMap;
 use std::collections::HashSet;
 use std::time::{Duration, Instant};
-use std::ptr::NonNull;
+
+pub enum Finalizer {
+    // Scheme procedure, called with the object once it becomes unreachable.
+    // The object is kept alive until the procedure has been run.
+    Scheme(Ponga),
+    // Rust callback, handed the object's data as it is swept. Any refs inside
+    // the data may already have been freed. Send so that the runtime can be
+    // moved to another thread.
+    Native(Box<dyn FnMut(Ponga) + Send>),
+}
 
 pub struct Gc {
-    pub ptrs: HashMap<Id, GcObj>,
+    pub ptrs: Heap,
     pub roots: HashSet<Id>,
-    pub max_id: usize,
+    pub finalizers: HashMap<Id, Vec<Finalizer>>,
+    pub pending_finalizers: Vec<(Ponga, Ponga)>, // func, obj
     pub last_gc: Instant,
     pub gc_duration: Duration,
+    // Roughly how many bytes have been allocated since the last collection,
+    // and how many can be before another one is due
+    allocated: usize,
+    pub gc_allocation: usize,
 }
 
 impl Gc {
     pub fn new() -> Gc {
         Gc {
-            ptrs: HashMap::new(),
+            ptrs: Heap::new(),
             roots: HashSet::new(),
-            max_id: 0,
+            finalizers: HashMap::new(),
+            pending_finalizers: Vec::new(),
             last_gc: Instant::now(),
             gc_duration: Duration::from_secs(5),
+            allocated: 0,
+            gc_allocation: 32 << 20,
         }
     }
 
-    pub fn try_collect_garbage(&mut self) {
-        let now = Instant::now();
-        if now.duration_since(self.last_gc) > self.gc_duration {
-            self.last_gc = now;
-            self.collect_garbage();
-        }
+    // The gc can't see what the runtime is holding, so rather than collecting
+    // by itself it tells the runtime when a collection is due
+    pub fn collection_due(&self) -> bool {
+        self.allocated > self.gc_allocation
+            || Instant::now().duration_since(self.last_gc) > self.gc_duration
     }
 
     pub fn collect_garbage(&mut self) {
-        for root in self.roots.iter() {
-            self.ptrs.get(root).unwrap().trace(self);
+        self.last_gc = Instant::now();
+        self.allocated = 0;
+        let mut worklist: Vec<Id> = self.roots.iter().cloned().collect();
+        for fins in self.finalizers.values() {
+            for fin in fins.iter() {
+                if let Finalizer::Scheme(func) = fin {
+                    func.trace(&mut worklist);
+                }
+            }
+        }
+        for (func, obj) in self.pending_finalizers.iter() {
+            func.trace(&mut worklist);
+            obj.trace(&mut worklist);
         }
+        self.mark(worklist);
+        self.mark_ephemerons();
+        self.clear_weak();
+        self.resurrect_finalizable();
 
         let mut to_delete = vec![];
         for obj in self.ptrs.values() {
@@ -47,47 +83,155 @@ impl Gc {
         }
 
         for id in to_delete {
-            let gco = self.ptrs.get_mut(&id).unwrap();
-            gco.free();
-            self.ptrs.remove(&id);
+            let data = self.ptrs.remove(id).unwrap().into_inner();
+            if let Some(fins) = self.finalizers.remove(&id) {
+                for fin in fins {
+                    if let Finalizer::Native(mut func) = fin {
+                        func(data.clone());
+                    }
+                }
+            }
         }
     }
 
-    pub fn take_id(&mut self, id: Id) -> Option<Ponga> {
-        self.try_collect_garbage();
-        let obj = self.ptrs.get_mut(&id)?;
-        let res = unsafe { *Box::from_raw(obj.data.as_ptr()) };
-        self.ptrs.remove(&id);
-        Some(res)
+    pub fn is_marked(&self, id: Id) -> bool {
+        match self.ptrs.get(id) {
+            Some(obj) => !matches!(obj.get_marker(), MarkerFlag::Unseen),
+            None => false,
+        }
+    }
+
+    // Marks everything reachable from the worklist, returning how many
+    // objects were newly marked
+    fn mark(&self, mut worklist: Vec<Id>) -> usize {
+        let mut marked = 0;
+        while let Some(id) = worklist.pop() {
+            let obj = match self.ptrs.get(id) {
+                Some(obj) => obj,
+                None => continue,
+            };
+            if let MarkerFlag::Seen = obj.get_marker() {
+                continue;
+            }
+            obj.mark_seen();
+            obj.trace(&mut worklist);
+            marked += 1;
+        }
+        marked
+    }
+
+    // A weak table entry keeps its value alive only while its key is alive,
+    // which can in turn make more keys reachable, so iterate to a fixpoint
+    fn mark_ephemerons(&self) {
+        loop {
+            let mut worklist = vec![];
+            for obj in self.ptrs.values() {
+                if !self.is_marked(obj.id) {
+                    continue;
+                }
+                if let Ponga::WeakTable(table) = obj.borrow().unwrap().inner() {
+                    for (key, val) in table.iter() {
+                        if self.is_marked(*key) {
+                            val.trace(&mut worklist);
+                        }
+                    }
+                }
+            }
+            if self.mark(worklist) == 0 {
+                break;
+            }
+        }
+    }
+
+    fn clear_weak(&mut self) {
+        let mut broken_boxes = vec![];
+        let mut dead_keys = vec![];
+        for obj in self.ptrs.values() {
+            if !self.is_marked(obj.id) {
+                continue;
+            }
+            match obj.borrow().unwrap().inner() {
+                Ponga::WeakBox(Some(val))
+                    if val.weak_ids().iter().any(|id| !self.is_marked(*id)) => {
+                    broken_boxes.push(obj.id);
+                }
+                Ponga::WeakTable(table) => {
+                    let dead = table.keys()
+                                    .filter(|key| !self.is_marked(**key))
+                                    .cloned()
+                                    .collect::<Vec<Id>>();
+                    if !dead.is_empty() {
+                        dead_keys.push((obj.id, dead));
+                    }
+                }
+                _ => (),
+            }
+        }
+
+        for id in broken_boxes {
+            let obj = self.ptrs.get_mut(id).unwrap();
+            *obj.borrow_mut().unwrap().inner() = Ponga::WeakBox(None);
+        }
+        for (id, keys) in dead_keys {
+            let obj = self.ptrs.get_mut(id).unwrap();
+            let mut borrowed = obj.borrow_mut().unwrap();
+            if let Ponga::WeakTable(table) = borrowed.inner() {
+                for key in keys {
+                    table.remove(&key);
+                }
+            }
+        }
     }
 
-    pub fn get_new_id(&mut self) -> Id {
-        let id = self.max_id;
-        self.max_id += 1;
-        id
+    // Unreachable objects with Scheme finalizers are kept alive (along with
+    // everything they reference) until their finalizers have been run
+    fn resurrect_finalizable(&mut self) {
+        let dead = self.finalizers
+                       .keys()
+                       .filter(|id| !self.is_marked(**id))
+                       .cloned()
+                       .collect::<Vec<Id>>();
+        let mut worklist = vec![];
+        for id in dead {
+            let fins = self.finalizers.remove(&id).unwrap();
+            let (scheme, native): (Vec<Finalizer>, Vec<Finalizer>) = fins
+                .into_iter()
+                .partition(|fin| matches!(fin, Finalizer::Scheme(_)));
+            if !native.is_empty() {
+                self.finalizers.insert(id, native);
+            }
+            if scheme.is_empty() || !self.ptrs.contains(id) {
+                continue;
+            }
+            for fin in scheme {
+                if let Finalizer::Scheme(func) = fin {
+                    self.pending_finalizers.push((func, Ponga::Ref(id)));
+                }
+            }
+            worklist.push(id);
+        }
+        self.mark(worklist);
+    }
+
+    pub fn add_finalizer(&mut self, id: Id, finalizer: Finalizer) {
+        self.finalizers.entry(id).or_default().push(finalizer);
+    }
+
+    // The id stays reserved for the object until it is put back with
+    // `add_obj_with_id`
+    pub fn take_id(&mut self, id: Id) -> Option<Ponga> {
+        self.ptrs.take(id).map(|obj| obj.into_inner())
     }
 
     pub fn add_obj(&mut self, data: Ponga) -> Id {
-        self.try_collect_garbage();
-        let obj = GcObj::new(self, data);
-        let id = obj.id;
-        self.ptrs.insert(obj.id, obj);
-        id
+        self.allocated += data.approx_size();
+        self.ptrs.insert(data)
     }
 
+    // Puts back an object previously removed with `take_id`
     pub fn add_obj_with_id(&mut self, data: Ponga, id: Id) {
-        self.try_collect_garbage();
-        let obj = GcObj {
-            data: NonNull::new(Box::into_raw(Box::new(data))).unwrap(),
-            flags: UnsafeCell::new(Flags {
-                marker: MarkerFlag::Unseen,
-                taken: TakenFlag::NotTaken,
-                to_free: false,
-            }),
-            id,
-        };
-
-        self.ptrs.insert(obj.id, obj);
+        let restored = self.ptrs.restore(id, data);
+        debug_assert!(restored, "reference {} wasn't taken", id);
     }
 
     pub fn ponga_into_gc_ref(&mut self, data: Ponga) -> Ponga {
@@ -95,69 +239,114 @@ impl Gc {
     }
 }
 
-impl GcObj {
-    pub fn new(state: &mut Gc, data: Ponga) -> GcObj {
-        GcObj {
-            data: NonNull::new(Box::into_raw(Box::new(data))).unwrap(),
-            flags: UnsafeCell::new(Flags {
-                marker: MarkerFlag::Unseen,
-                taken: TakenFlag::NotTaken,
-                to_free: false,
-            }),
-            id: state.get_new_id(),
-        }
-    }
-}
-
 pub trait Trace {
-    fn trace(&self, gc: &Gc);
+    fn trace(&self, worklist: &mut Vec<Id>);
 }
 
-// Marks as seen and calls trace on children
+// Pushes the ids of the children of the object
 impl Trace for GcObj {
-    fn trace(&self, gc: &Gc) {
-        // Probably don't need this variant
-        self.mark_children_not_seen();
-
-        self.borrow().unwrap().trace(gc);
-        self.mark_seen();
+    fn trace(&self, worklist: &mut Vec<Id>) {
+        self.borrow().unwrap().trace(worklist);
     }
 }
 
-// Just calls the true trace on everything that could be a Ref
+// Pushes every id that could be reached from this value
 impl Trace for Ponga {
-    fn trace(&self, gc: &Gc) {
+    fn trace(&self, worklist: &mut Vec<Id>) {
         match self {
-            Ponga::Ref(id) => gc.ptrs.get(id).unwrap().trace(gc),
+            Ponga::Ref(id) => worklist.push(*id),
             Ponga::Array(arr) => {
                 for i in arr.iter() {
-                    i.trace(gc);
+                    i.trace(worklist);
                 }
             }
             Ponga::List(l) => {
                 for i in l.iter() {
-                    i.trace(gc);
+                    i.trace(worklist);
                 }
             }
-            Ponga::Sexpr(arr) => {
+            Ponga::Sexpr(arr) | Ponga::Values(arr) => {
                 for i in arr.iter() {
-                    i.trace(gc);
+                    i.trace(worklist);
                 }
             }
-            Ponga::Object(obj) => {
+            Ponga::Object(obj) | Ponga::Environment(Environment { globals: obj, .. }) => {
                 for i in obj.values() {
-                    i.trace(gc);
+                    i.trace(worklist);
                 }
             }
-            Ponga::CFunc(_, id, stateid) => {
-                gc.ptrs.get(id).unwrap().trace(gc);
-                gc.ptrs.get(stateid).unwrap().trace(gc);
+            Ponga::CFunc(_, id, stateid, envid) => {
+                worklist.push(*id);
+                worklist.push(*stateid);
+                worklist.push(*envid);
             }
             Ponga::MFunc(_, id) => {
-                gc.ptrs.get(id).unwrap().trace(gc);
+                worklist.push(*id);
+            }
+            Ponga::Record(record) => {
+                worklist.push(record.rtype);
+                for i in record.fields.iter() {
+                    i.trace(worklist);
+                }
+            }
+            Ponga::Promise(Promise::Done(val) | Promise::Delayed(val, _)) => val.trace(worklist),
+            Ponga::Promise(Promise::Forward(id)) => worklist.push(*id),
+            Ponga::Parameter(param) => {
+                param.value.trace(worklist);
+                if let Some(converter) = &param.converter {
+                    converter.trace(worklist);
+                }
+            }
+            Ponga::Thread(thread) => {
+                thread.name.trace(worklist);
+                thread.specific.trace(worklist);
+                trace_stacks(&thread.ins_stack, &thread.data_stack, &thread.locals, worklist);
+                worklist.push(thread.env);
+                thread.parameters.trace(worklist);
+                if let ThreadState::Done(Ok(val)) = &thread.state {
+                    val.trace(worklist);
+                }
+            }
+            Ponga::Mutex(mutex) => {
+                mutex.name.trace(worklist);
+                if let MutexState::Locked(Some(owner)) = mutex.state {
+                    worklist.push(owner);
+                }
             }
-            Ponga::Number(_)
+            Ponga::ConditionVariable(cv) => cv.name.trace(worklist),
+            Ponga::Coroutine(Coroutine { ins_stack, data_stack, locals, env, .. }) => {
+                trace_stacks(ins_stack, data_stack, locals, worklist);
+                worklist.push(*env);
+            }
+            Ponga::Channel(channel) => {
+                for val in channel.buffer.iter() {
+                    val.trace(worklist);
+                }
+            }
+            Ponga::HashTable(table) => {
+                for bucket in table.buckets.values() {
+                    for (key, val) in bucket.iter() {
+                        key.trace(worklist);
+                        val.trace(worklist);
+                    }
+                }
+            }
+            // Only held strongly if there is nothing to collect
+            Ponga::WeakBox(Some(val)) => {
+                if val.weak_ids().is_empty() {
+                    val.trace(worklist);
+                }
+            }
+            // Values are traced by the collector once their keys are marked
+            Ponga::WeakTable(_)
+            | Ponga::WeakBox(None)
+            | Ponga::Number(_)
             | Ponga::String(_)
+            | Ponga::MutString(_)
+            | Ponga::RecordType(_)
+            | Ponga::Bytevector(_)
+            | Ponga::Port(_)
+            | Ponga::Eof
             | Ponga::True
             | Ponga::False
             | Ponga::Char(_)
@@ -169,10 +358,86 @@ impl Trace for Ponga {
     }
 }
 
-impl Drop for Gc {
-    fn drop(&mut self) {
-        for obj in self.ptrs.values_mut() {
-            obj.free();
+// For the evaluation a thread or coroutine has suspended
+pub fn trace_stacks(ins_stack: &[Instruction], data_stack: &[Ponga],
+                locals: &HashMap<String, Vec<Ponga>>, worklist: &mut Vec<Id>) {
+    for ins in ins_stack.iter() {
+        ins.trace(worklist);
+    }
+    for val in data_stack.iter().chain(locals.values().flatten()) {
+        val.trace(worklist);
+    }
+}
+
+impl Trace for Parameterization {
+    fn trace(&self, worklist: &mut Vec<Id>) {
+        for (id, val) in self.values.iter() {
+            worklist.push(*id);
+            val.trace(worklist);
+        }
+        for (id, val) in self.saves.iter() {
+            worklist.push(*id);
+            if let Some(val) = val {
+                val.trace(worklist);
+            }
+        }
+    }
+}
+
+// Pushes the ids reachable from the values held by a suspended instruction
+impl Trace for Instruction {
+    fn trace(&self, worklist: &mut Vec<Id>) {
+        match self {
+            Instruction::Eval(val) | Instruction::Data(val) | Instruction::Test(val) => {
+                val.trace(worklist)
+            }
+            Instruction::CallEach(func, arg_lists) | Instruction::Fold(func, arg_lists, _) => {
+                func.trace(worklist);
+                for val in arg_lists.iter().flatten() {
+                    val.trace(worklist);
+                }
+            }
+            Instruction::MapEach(func, arg_lists, results, _) => {
+                func.trace(worklist);
+                for val in arg_lists.iter().flatten().chain(results.iter()) {
+                    val.trace(worklist);
+                }
+            }
+            Instruction::If(consequent, alternative) => {
+                consequent.trace(worklist);
+                alternative.trace(worklist);
+            }
+            Instruction::Cond(body, clauses) => {
+                for val in body.iter().chain(clauses.iter()) {
+                    val.trace(worklist);
+                }
+            }
+            Instruction::Case(clauses) => {
+                for val in clauses.iter() {
+                    val.trace(worklist);
+                }
+            }
+            Instruction::Pop(_, Some(id)) | Instruction::Force(Some(id))
+            | Instruction::RestoreEnvironment(id) => worklist.push(*id),
+            Instruction::PopFrame(frame) => {
+                worklist.extend(frame.iter().filter_map(|(_, id)| *id));
+            }
+            Instruction::LeaveEnvironment(env, locals) => {
+                worklist.push(*env);
+                for val in locals.values().flatten() {
+                    val.trace(worklist);
+                }
+            }
+            Instruction::Do(steps, test, results, commands) => {
+                for (_, step) in steps.iter() {
+                    step.trace(worklist);
+                }
+                test.trace(worklist);
+                for val in results.iter().chain(commands.iter()) {
+                    val.trace(worklist);
+                }
+            }
+            _ => (),
         }
     }
 }
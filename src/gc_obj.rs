@@ -1,78 +1,43 @@
 use crate::types::*;
-use std::cell::UnsafeCell;
+use std::cell::{Cell, Ref, RefCell, RefMut};
 use std::ops::Deref;
 use std::ops::DerefMut;
-use std::ptr::NonNull;
 
 #[derive(Clone, Copy, Debug)]
 pub enum MarkerFlag {
     Unseen,
-    ChildrenNotSeen,
     Seen,
 }
 
-#[derive(Clone, Copy, Debug)]
-pub enum TakenFlag {
-    NotTaken,
-    Shared(usize),
-    Unique,
-}
-
-#[derive(Clone, Copy, Debug)]
-pub struct Flags {
-    pub marker: MarkerFlag,
-    pub taken: TakenFlag,
-    pub to_free: bool,
-}
-
+// A gc managed value. The data is only reachable through `borrow` and
+// `borrow_mut`, which hand out guards that are checked at runtime, so a
+// mutable borrow can never alias another borrow of the same object. Objects
+// are owned by the `Gc` and dropped exactly once, when they are removed from
+// it.
 #[derive(Debug)]
 pub struct GcObj {
-    pub data: NonNull<Ponga>,
+    data: RefCell<Ponga>,
     pub id: Id,
-    pub flags: UnsafeCell<Flags>,
-}
-
-impl Clone for GcObj {
-    fn clone(&self) -> Self {
-        GcObj {
-            data: self.data,
-            id: self.id,
-            flags: UnsafeCell::new(self.get_flags()),
-        }
-    }
+    marker: Cell<MarkerFlag>,
 }
 
 pub struct GcRef<'a> {
-    inner: &'a Ponga,
-    gc_obj: &'a GcObj,
+    inner: Ref<'a, Ponga>,
 }
 
 pub struct GcRefMut<'a> {
-    inner: &'a mut Ponga,
-    gc_obj: &'a mut GcObj,
+    inner: RefMut<'a, Ponga>,
 }
 
 impl<'a> GcRefMut<'a> {
     pub fn inner(&mut self) -> &mut Ponga {
-        self.inner
+        &mut self.inner
     }
 }
 
 impl<'a> GcRef<'a> {
     pub fn inner(&self) -> &Ponga {
-        self.inner
-    }
-}
-
-impl<'a> Drop for GcRef<'a> {
-    fn drop(&mut self) {
-        self.gc_obj.remove_shared();
-    }
-}
-
-impl<'a> Drop for GcRefMut<'a> {
-    fn drop(&mut self) {
-        self.gc_obj.flags.get_mut().taken = TakenFlag::NotTaken;
+        &self.inner
     }
 }
 
@@ -80,7 +45,7 @@ impl Deref for GcRef<'_> {
     type Target = Ponga;
 
     fn deref(&self) -> &Self::Target {
-        self.inner
+        &self.inner
     }
 }
 
@@ -88,121 +53,197 @@ impl Deref for GcRefMut<'_> {
     type Target = Ponga;
 
     fn deref(&self) -> &Self::Target {
-        self.inner
+        &self.inner
     }
 }
 
 impl DerefMut for GcRefMut<'_> {
     fn deref_mut(&mut self) -> &mut Self::Target {
-        self.inner
+        &mut self.inner
     }
 }
 
 impl GcObj {
-    fn add_shared(&self) {
-        unsafe {
-            let flags = &mut *self.flags.get();
-            match flags.taken {
-                TakenFlag::NotTaken => flags.taken = TakenFlag::Shared(1),
-                TakenFlag::Shared(n) => flags.taken = TakenFlag::Shared(n + 1),
-                _ => panic!("Trying to add shared to unique"),
-            }
+    pub fn new(id: Id, data: Ponga) -> GcObj {
+        GcObj {
+            data: RefCell::new(data),
+            id,
+            marker: Cell::new(MarkerFlag::Unseen),
         }
     }
 
-    fn remove_shared(&self) {
-        unsafe {
-            let flags = &mut *self.flags.get();
-            match flags.taken {
-                TakenFlag::Shared(n) => {
-                    if n == 1 {
-                        flags.taken = TakenFlag::NotTaken;
-                    } else {
-                        flags.taken = TakenFlag::Shared(n - 1);
-                    }
-                }
-                _ => panic!("Trying to dec shared when it is not shared"),
+    pub fn mark_seen(&self) {
+        self.marker.set(MarkerFlag::Seen);
+    }
+
+    pub fn mark_unseen(&self) {
+        self.marker.set(MarkerFlag::Unseen);
+    }
+
+    pub fn get_marker(&self) -> MarkerFlag {
+        self.marker.get()
+    }
+
+    // None if the object is currently mutably borrowed
+    pub fn borrow(&self) -> Option<GcRef<'_>> {
+        self.data.try_borrow().ok().map(|inner| GcRef { inner })
+    }
+
+    // None if the object is currently borrowed at all
+    pub fn borrow_mut(&self) -> Option<GcRefMut<'_>> {
+        self.data.try_borrow_mut().ok().map(|inner| GcRefMut { inner })
+    }
+
+    pub fn into_inner(self) -> Ponga {
+        self.data.into_inner()
+    }
+}
+
+// Ids are a slot index in the low bits and the generation of the slot in the
+// high bits. A slot's generation goes up every time its object is freed, so a
+// stale id to a freed object can never resolve to whatever is put in the slot
+// next. Ids are 64 bits wide whatever the width of usize, so the split is the
+// same on every target.
+const SLOT_BITS: u32 = 32;
+const SLOT_MASK: Id = (1 << SLOT_BITS) - 1;
+
+fn make_id(slot: usize, generation: u32) -> Id {
+    ((generation as Id) << SLOT_BITS) | slot as Id
+}
+
+fn split_id(id: Id) -> (usize, u32) {
+    ((id & SLOT_MASK) as usize, (id >> SLOT_BITS) as u32)
+}
+
+#[derive(Debug)]
+enum SlotState {
+    Free,
+    // The object has been taken out with `remove`, but the slot is kept for
+    // it to be put back
+    Taken,
+    Live(GcObj),
+}
+
+#[derive(Debug)]
+struct Slot {
+    generation: u32,
+    state: SlotState,
+}
+
+// The objects owned by the gc, in slots that are reused once freed
+#[derive(Debug, Default)]
+pub struct Heap {
+    slots: Vec<Slot>,
+    free: Vec<usize>,
+    len: usize,
+}
+
+impl Heap {
+    pub fn new() -> Heap {
+        Heap::default()
+    }
+
+    fn slot(&self, id: Id) -> Option<&Slot> {
+        let (slot, generation) = split_id(id);
+        self.slots.get(slot).filter(|slot| slot.generation == generation)
+    }
+
+    fn slot_mut(&mut self, id: Id) -> Option<&mut Slot> {
+        let (slot, generation) = split_id(id);
+        self.slots.get_mut(slot).filter(|slot| slot.generation == generation)
+    }
+
+    pub fn insert(&mut self, data: Ponga) -> Id {
+        let slot = match self.free.pop() {
+            Some(slot) => slot,
+            None => {
+                self.slots.push(Slot { generation: 0, state: SlotState::Free });
+                self.slots.len() - 1
             }
-        }
+        };
+        let id = make_id(slot, self.slots[slot].generation);
+        self.slots[slot].state = SlotState::Live(GcObj::new(id, data));
+        self.len += 1;
+        id
     }
 
-    pub fn mark_seen(&self) {
-        unsafe {
-            let flags = &mut *self.flags.get();
-            flags.marker = MarkerFlag::Seen;
+    pub fn get(&self, id: Id) -> Option<&GcObj> {
+        match self.slot(id) {
+            Some(Slot { state: SlotState::Live(obj), .. }) => Some(obj),
+            _ => None,
         }
     }
 
-    pub fn mark_unseen(&self) {
-        unsafe {
-            let flags = &mut *self.flags.get();
-            flags.marker = MarkerFlag::Unseen;
+    pub fn get_mut(&mut self, id: Id) -> Option<&mut GcObj> {
+        match self.slot_mut(id) {
+            Some(Slot { state: SlotState::Live(obj), .. }) => Some(obj),
+            _ => None,
         }
     }
 
-    pub fn mark_children_not_seen(&self) {
-        unsafe {
-            let flags = &mut *self.flags.get();
-            flags.marker = MarkerFlag::ChildrenNotSeen;
-        }
+    pub fn contains(&self, id: Id) -> bool {
+        self.get(id).is_some()
     }
 
-    pub fn borrow<'a>(&'a self) -> Option<GcRef<'a>> {
-        unsafe {
-            let flags = &mut *self.flags.get();
-            match flags.taken {
-                TakenFlag::Unique => None,
-                _ => {
-                    self.add_shared();
-                    Some(GcRef {
-                        inner: &*self.data.as_ptr(),
-                        gc_obj: self,
-                    })
-                }
+    // Takes the object out but keeps its slot, for `restore` to put it back
+    pub fn take(&mut self, id: Id) -> Option<GcObj> {
+        let slot = self.slot_mut(id)?;
+        match std::mem::replace(&mut slot.state, SlotState::Taken) {
+            SlotState::Live(obj) => {
+                self.len -= 1;
+                Some(obj)
+            }
+            state => {
+                slot.state = state;
+                None
             }
         }
     }
 
-    pub fn borrow_mut<'a>(&'a mut self) -> Option<GcRefMut<'a>> {
-        unsafe {
-            let flags = &mut *self.flags.get();
-            match flags.taken {
-                TakenFlag::NotTaken => {
-                    flags.taken = TakenFlag::Unique;
-                    Some(GcRefMut {
-                        inner: &mut *self.data.as_ptr(),
-                        gc_obj: self,
-                    })
-                }
-                _ => None,
+    // False if the id wasn't taken, in which case nothing is put back
+    pub fn restore(&mut self, id: Id, data: Ponga) -> bool {
+        match self.slot_mut(id) {
+            Some(slot) if matches!(slot.state, SlotState::Taken) => {
+                slot.state = SlotState::Live(GcObj::new(id, data));
+                self.len += 1;
+                true
             }
+            _ => false,
         }
     }
 
-    pub fn get_flags(&self) -> Flags {
-        unsafe { *self.flags.get() }
+    // Frees the slot, so that it can be reused under a new generation
+    pub fn remove(&mut self, id: Id) -> Option<GcObj> {
+        let (index, _) = split_id(id);
+        let slot = self.slot_mut(id)?;
+        let res = match std::mem::replace(&mut slot.state, SlotState::Free) {
+            SlotState::Live(obj) => Some(obj),
+            SlotState::Taken => None,
+            SlotState::Free => return None,
+        };
+        // A slot whose generation would no longer fit in an id is retired
+        if let Some(generation) = slot.generation.checked_add(1) {
+            slot.generation = generation;
+            self.free.push(index);
+        }
+        if res.is_some() {
+            self.len -= 1;
+        }
+        res
     }
 
-    pub fn get_marker(&self) -> MarkerFlag {
-        unsafe {
-            let flags = &*self.flags.get();
-            flags.marker
-        }
+    pub fn len(&self) -> usize {
+        self.len
     }
 
-    pub fn free(&mut self) {
-        unsafe {
-            Box::from_raw(self.data.as_ptr());
-        }
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
     }
-}
 
-impl Drop for GcObj {
-    fn drop(&mut self) {
-        if self.get_flags().to_free {
-            unsafe {
-                let _ = *Box::from_raw(self.data.as_ptr());
-            }
-        }
+    pub fn values(&self) -> impl Iterator<Item = &GcObj> {
+        self.slots.iter().filter_map(|slot| match &slot.state {
+            SlotState::Live(obj) => Some(obj),
+            _ => None,
+        })
     }
 }
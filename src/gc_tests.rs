@@ -0,0 +1,210 @@
+// Aliasing and use-after-free scenarios for the gc handles. These are meant
+// to also be run under Miri:
+//
+//   cargo +nightly miri test gc_tests
+use crate::gc::*;
+use crate::number::*;
+use crate::runtime::*;
+use crate::types::*;
+
+#[test]
+pub fn test_shared_borrows_exclude_mutable() {
+    let mut gc = Gc::new();
+    let id = gc.add_obj(Ponga::Array(vec![Ponga::Null]));
+    let obj = gc.ptrs.get(id).unwrap();
+
+    let first = obj.borrow().unwrap();
+    let second = obj.borrow().unwrap();
+    assert!(obj.borrow_mut().is_none());
+    assert!(*first == *second);
+    drop(first);
+    assert!(obj.borrow_mut().is_none());
+    drop(second);
+
+    let mut unique = obj.borrow_mut().unwrap();
+    assert!(obj.borrow().is_none());
+    assert!(obj.borrow_mut().is_none());
+    *unique.inner() = Ponga::True;
+    drop(unique);
+
+    assert!(*obj.borrow().unwrap() == Ponga::True);
+}
+
+#[test]
+pub fn test_stale_ids_do_not_alias() {
+    let mut gc = Gc::new();
+    let old = gc.add_obj(Ponga::String("old".to_string()));
+    gc.collect_garbage();
+    assert!(!gc.ptrs.contains(old));
+
+    let new = gc.add_obj(Ponga::String("new".to_string()));
+    assert!(new != old);
+    assert!(!gc.ptrs.contains(old));
+    assert!(gc.take_id(old).is_none());
+}
+
+#[test]
+pub fn test_reused_slots_reject_stale_ids() {
+    let mut gc = Gc::new();
+    let old = gc.add_obj(Ponga::String("old".to_string()));
+    gc.collect_garbage();
+
+    // The freed slot is handed out again, under a new generation
+    let new = gc.add_obj(Ponga::String("new".to_string()));
+    assert!(new & 0xffffffff == old & 0xffffffff);
+    assert!(new != old);
+    assert!(!gc.ptrs.contains(old));
+    assert!(gc.take_id(old).is_none());
+    assert!(gc.ptrs.remove(old).is_none());
+    assert!(*gc.ptrs.get(new).unwrap().borrow().unwrap() == Ponga::String("new".to_string()));
+
+    // A taken slot isn't reused until it is freed
+    let taken = gc.take_id(new).unwrap();
+    let other = gc.add_obj(Ponga::Null);
+    assert!(other & 0xffffffff != new & 0xffffffff);
+    gc.add_obj_with_id(taken, new);
+    assert!(gc.ptrs.len() == 2);
+}
+
+#[test]
+pub fn test_take_and_restore_id() {
+    let mut gc = Gc::new();
+    let id = gc.add_obj(Ponga::Array(vec![Ponga::Null]));
+    let taken = gc.take_id(id).unwrap();
+    assert!(!gc.ptrs.contains(id));
+    assert!(gc.take_id(id).is_none());
+
+    gc.add_obj_with_id(taken, id);
+    gc.roots.insert(id);
+    gc.collect_garbage();
+    assert!(*gc.ptrs.get(id).unwrap().borrow().unwrap() == Ponga::Array(vec![Ponga::Null]));
+    // Dropping the gc frees the remaining object exactly once
+    drop(gc);
+}
+
+#[test]
+pub fn test_cycles_are_collected() {
+    let mut gc = Gc::new();
+    let a = gc.add_obj(Ponga::Null);
+    let b = gc.add_obj(Ponga::Array(vec![Ponga::Ref(a)]));
+    *gc.ptrs.get(a).unwrap().borrow_mut().unwrap().inner() = Ponga::Array(vec![Ponga::Ref(b)]);
+
+    gc.roots.insert(a);
+    gc.collect_garbage();
+    assert!(gc.ptrs.len() == 2);
+
+    gc.roots.clear();
+    gc.collect_garbage();
+    assert!(gc.ptrs.is_empty());
+}
+
+#[test]
+pub fn test_native_finalizer_owns_swept_data() {
+    use std::sync::{Arc, Mutex};
+
+    let mut gc = Gc::new();
+    let id = gc.add_obj(Ponga::String("data".to_string()));
+    let swept = Arc::new(Mutex::new(vec![]));
+    let swept_clone = swept.clone();
+    gc.add_finalizer(id, Finalizer::Native(Box::new(move |data| {
+        swept_clone.lock().unwrap().push(data);
+    })));
+
+    gc.collect_garbage();
+    assert!(!gc.ptrs.contains(id));
+    assert!(*swept.lock().unwrap() == vec![Ponga::String("data".to_string())]);
+}
+
+#[test]
+pub fn test_runtime_mutation_through_shared_refs() {
+    let mut runtime = Runtime::new();
+    let list = runtime.gc.add_obj(Ponga::List(vec![Ponga::Number(Number::Int(2))]
+                                                  .into_iter()
+                                                  .collect()));
+    runtime.bind_global("xs".to_string(), Ponga::Ref(list));
+    runtime.bind_global("ys".to_string(), Ponga::Ref(list));
+
+    // cons takes the list out of the heap and puts it back under the same id
+    let parsed = crate::parser::pongascript_parser("(cons 1 xs)").unwrap();
+    let res = runtime.eval(parsed.1.into_iter().next().unwrap()).unwrap();
+    assert!(res == Ponga::Ref(list));
+    runtime.collect_garbage();
+
+    let obj = runtime.get_id_obj_ref(list).unwrap().borrow().unwrap();
+    assert!(obj.get_list_ref().unwrap().len() == 2);
+    drop(obj);
+
+    runtime.unbind_global("xs");
+    runtime.unbind_global("ys");
+    runtime.collect_garbage();
+    assert!(runtime.get_id_obj_ref(list).is_err());
+}
+
+#[test]
+pub fn test_host_arguments_survive_collection() {
+    let mut runtime = Runtime::new();
+    runtime.gc.gc_duration = std::time::Duration::ZERO;
+    let car = runtime.get_identifier_obj_ref("car").unwrap().clone();
+
+    // Nothing but the call holds the list, and a collection is due when the
+    // call starts
+    let list = runtime.gc.add_obj(Ponga::List(vec![Ponga::Number(Number::Int(1))]
+                                                  .into_iter()
+                                                  .collect()));
+    assert!(runtime.call(car, vec![Ponga::Ref(list)]) == Ok(Ponga::Number(Number::Int(1))));
+}
+
+#[test]
+pub fn test_pinned_results_survive_collection() {
+    let mut runtime = Runtime::new();
+    let parsed = crate::parser::pongascript_parser("(list 1 2) (vector 3)").unwrap();
+    let mut forms = parsed.1.into_iter();
+    let list = runtime.eval(forms.next().unwrap()).unwrap();
+    runtime.pin(&list);
+    runtime.pin(&list);
+    let vector = runtime.eval(forms.next().unwrap()).unwrap();
+    let (Ponga::Ref(list_id), Ponga::Ref(vector_id)) = (list.clone(), vector) else {
+        panic!("expected heap objects");
+    };
+    runtime.collect_garbage();
+    assert!(runtime.ponga_to_string(&list) == "'(1 2)");
+    assert!(runtime.get_id_obj_ref(vector_id).is_err());
+
+    // Pinned twice, so it takes two unpins to let it go
+    runtime.unpin(&list);
+    runtime.collect_garbage();
+    assert!(runtime.get_id_obj_ref(list_id).is_ok());
+    runtime.unpin(&list);
+    runtime.collect_garbage();
+    assert!(runtime.get_id_obj_ref(list_id).is_err());
+}
+
+#[test]
+pub fn test_collection_inside_long_evaluations() {
+    let mut runtime = Runtime::new();
+    runtime.gc.gc_allocation = 1 << 16;
+    let parsed = crate::parser::pongascript_parser("
+(define (churn n) (do ((i 0 (+ i 1))) ((= i n)) (make-vector 100 0)))
+(define w (make-weak-box (vector 1)))
+(let ((kept (vector 2))) (churn 20000) (list (weak-box-value w 'gone) (vector-ref kept 0)))
+(define g (make-coroutine-generator (lambda (yield)
+  (let ((v (vector 3))) (churn 5000) (yield (vector-ref v 0)) (churn 5000) (yield v)))))
+(g)
+(vector-ref (g) 0)
+(define (worker n) (lambda () (let ((v (vector n))) (churn 5000) (vector-ref v 0))))
+(define a (thread-start! (make-thread (worker 4))))
+(define b (thread-start! (make-thread (worker 5))))
+(let* ((kept (list 6)) (x (thread-join! a)) (y (thread-join! b))) (list x y (car kept)))
+    ").unwrap();
+
+    // Each result is checked before the next form runs and can collect it
+    let expected = ["", "", "'('gone 2)", "", "3", "3", "", "", "", "'(4 5 6)"];
+    for (form, expected) in parsed.1.into_iter().zip(expected) {
+        let res = runtime.eval(form).unwrap();
+        if !expected.is_empty() {
+            assert_eq!(runtime.ponga_to_string(&res), expected);
+        }
+    }
+    // The garbage made inside each evaluation didn't pile up until the end
+    assert!(runtime.gc.ptrs.len() < 10000);
+}
@@ -0,0 +1,306 @@
+use crate::gc_obj::GcRef;
+use crate::runtime::Runtime;
+use crate::types::*;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+
+// The equivalence predicate a table was made with, which decides both how
+// keys are hashed and how they are compared
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Equiv {
+    Eq,
+    Eqv,
+    Equal,
+    StringEq,
+}
+
+impl Equiv {
+    pub fn from_name(name: &str) -> Option<Equiv> {
+        match name {
+            "eq?" => Some(Equiv::Eq),
+            "eqv?" => Some(Equiv::Eqv),
+            "equal?" => Some(Equiv::Equal),
+            "string=?" => Some(Equiv::StringEq),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Equiv::Eq => "eq?",
+            Equiv::Eqv => "eqv?",
+            Equiv::Equal => "equal?",
+            Equiv::StringEq => "string=?",
+        }
+    }
+
+    pub fn hash(&self, runtime: &Runtime, key: &Ponga) -> RunRes<u64> {
+        match self {
+            Equiv::Eq => Ok(identity_hash(key)),
+            Equiv::Eqv | Equiv::Equal => equal_hash(runtime, key),
+            Equiv::StringEq => match runtime.get_string(key) {
+                Some(s) => Ok(hash_str(&s)),
+                None => Err(RuntimeErr::TypeError(format!(
+                    "string=? hash table keys must be strings (not {:?})", key
+                ))),
+            },
+        }
+    }
+
+    pub fn equal(&self, runtime: &Runtime, a: &Ponga, b: &Ponga) -> RunRes<bool> {
+        match self {
+            Equiv::Eq => Ok(a == b),
+            Equiv::StringEq => Ok(runtime.get_string(a) == runtime.get_string(b)),
+            Equiv::Eqv | Equiv::Equal => equal_structure(runtime, a, b),
+        }
+    }
+
+    // The index of the first of items equal to item
+    pub fn position(&self, runtime: &Runtime, item: &Ponga, items: &[Ponga]) -> RunRes<Option<usize>> {
+        for (i, x) in items.iter().enumerate() {
+            if self.equal(runtime, item, x)? {
+                return Ok(Some(i));
+            }
+        }
+        Ok(None)
+    }
+}
+
+fn hash_str(s: &str) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    s.hash(&mut hasher);
+    hasher.finish()
+}
+
+// Consistent with eq?, which compares refs by id rather than by contents
+pub fn identity_hash(key: &Ponga) -> u64 {
+    hash_str(&format!("{:?}", key))
+}
+
+// Consistent with equal?, which compares structure
+pub fn equal_hash(runtime: &Runtime, key: &Ponga) -> RunRes<u64> {
+    let mut hasher = DefaultHasher::new();
+    hash_structure(runtime, key, &mut hasher)?;
+    Ok(hasher.finish())
+}
+
+fn deref<'a>(runtime: &'a Runtime, id: Id) -> RunRes<GcRef<'a>> {
+    runtime.get_id_obj_ref(id)?.borrow().ok_or_else(|| {
+        RuntimeErr::Other(format!("Reference {} can't be compared while it is being changed", id))
+    })
+}
+
+// Objects that are only equal to themselves, whatever they hold
+fn is_opaque(ponga: &Ponga) -> bool {
+    matches!(
+        ponga,
+        Ponga::WeakTable(_)
+            | Ponga::HashTable(_)
+            | Ponga::RecordType(_)
+            | Ponga::Promise(_)
+            | Ponga::Port(_)
+            | Ponga::Environment(_)
+            | Ponga::Parameter(_)
+            | Ponga::Thread(_)
+            | Ponga::Mutex(_)
+            | Ponga::ConditionVariable(_)
+            | Ponga::Channel(_)
+            | Ponga::Coroutine(_)
+    )
+}
+
+fn hash_all<'a>(runtime: &Runtime, items: impl Iterator<Item = &'a Ponga>,
+                hasher: &mut DefaultHasher) -> RunRes<()> {
+    for item in items {
+        hash_structure(runtime, item, hasher)?;
+    }
+    Ok(())
+}
+
+fn hash_structure(runtime: &Runtime, key: &Ponga, hasher: &mut DefaultHasher) -> RunRes<()> {
+    match key {
+        // Hashed the same as the value held, so that an inline copy matches
+        Ponga::Ref(id) => {
+            let obj = deref(runtime, *id)?;
+            if is_opaque(obj.inner()) {
+                ("opaque", id).hash(hasher);
+                return Ok(());
+            }
+            return hash_structure(runtime, obj.inner(), hasher);
+        }
+        Ponga::Identifier(s) => {
+            if let Ok(val) = runtime.get_identifier_obj_ref(s) {
+                return hash_structure(runtime, val, hasher);
+            }
+        }
+        // Both kinds of string are equal when their contents are
+        Ponga::String(s) | Ponga::MutString(s) => {
+            ("string", s).hash(hasher);
+            return Ok(());
+        }
+        _ => {}
+    }
+    std::mem::discriminant(key).hash(hasher);
+    match key {
+        Ponga::Identifier(s) | Ponga::Symbol(s) => s.hash(hasher),
+        Ponga::Number(_) | Ponga::Char(_) => key.to_string().hash(hasher),
+        Ponga::HFunc(id) => id.hash(hasher),
+        Ponga::CFunc(_, body, state, _) => (body, state).hash(hasher),
+        Ponga::MFunc(args, body) => (args, body).hash(hasher),
+        Ponga::List(l) => hash_all(runtime, l.iter(), hasher)?,
+        Ponga::Array(a) | Ponga::Sexpr(a) | Ponga::Values(a) => hash_all(runtime, a.iter(), hasher)?,
+        Ponga::Object(o) => {
+            // Entries are combined so that their order doesn't matter
+            let mut sum: u64 = 0;
+            for (k, v) in o.iter() {
+                let mut entry = DefaultHasher::new();
+                k.hash(&mut entry);
+                hash_structure(runtime, v, &mut entry)?;
+                sum = sum.wrapping_add(entry.finish());
+            }
+            sum.hash(hasher);
+        }
+        Ponga::Record(r) => {
+            r.rtype.hash(hasher);
+            hash_all(runtime, r.fields.iter(), hasher)?;
+        }
+        Ponga::WeakBox(Some(val)) => hash_structure(runtime, val, hasher)?,
+        Ponga::Bytevector(b) => b.hash(hasher),
+        _ => {}
+    }
+    Ok(())
+}
+
+fn equal_all<'a>(runtime: &Runtime, a: impl ExactSizeIterator<Item = &'a Ponga>,
+                 b: impl ExactSizeIterator<Item = &'a Ponga>) -> RunRes<bool> {
+    if a.len() != b.len() {
+        return Ok(false);
+    }
+    for (x, y) in a.zip(b) {
+        if !equal_structure(runtime, x, y)? {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+// Whether two values are equal?, looking through refs without printing them
+pub fn equal_structure(runtime: &Runtime, a: &Ponga, b: &Ponga) -> RunRes<bool> {
+    match (a, b) {
+        (Ponga::Ref(x), Ponga::Ref(y)) if x == y => Ok(true),
+        (Ponga::Ref(id), other) | (other, Ponga::Ref(id)) => {
+            let obj = deref(runtime, *id)?;
+            if is_opaque(obj.inner()) {
+                return Ok(false);
+            }
+            equal_structure(runtime, obj.inner(), other)
+        }
+        (Ponga::Identifier(s), other) | (other, Ponga::Identifier(s)) => {
+            match runtime.get_identifier_obj_ref(s) {
+                Ok(val) => equal_structure(runtime, val, other),
+                Err(_) => Ok(matches!(other, Ponga::Identifier(t) if t == s)),
+            }
+        }
+        (Ponga::String(x) | Ponga::MutString(x), Ponga::String(y) | Ponga::MutString(y)) => Ok(x == y),
+        (Ponga::Number(_), Ponga::Number(_)) | (Ponga::Char(_), Ponga::Char(_)) => {
+            Ok(a.to_string() == b.to_string())
+        }
+        (Ponga::List(x), Ponga::List(y)) => equal_all(runtime, x.iter(), y.iter()),
+        (Ponga::Array(x), Ponga::Array(y))
+        | (Ponga::Sexpr(x), Ponga::Sexpr(y))
+        | (Ponga::Values(x), Ponga::Values(y)) => equal_all(runtime, x.iter(), y.iter()),
+        (Ponga::Object(x), Ponga::Object(y)) => {
+            if x.len() != y.len() {
+                return Ok(false);
+            }
+            for (k, v) in x.iter() {
+                match y.get(k) {
+                    Some(w) if equal_structure(runtime, v, w)? => {}
+                    _ => return Ok(false),
+                }
+            }
+            Ok(true)
+        }
+        (Ponga::Record(x), Ponga::Record(y)) => {
+            Ok(x.rtype == y.rtype && equal_all(runtime, x.fields.iter(), y.fields.iter())?)
+        }
+        (Ponga::WeakBox(Some(x)), Ponga::WeakBox(Some(y))) => equal_structure(runtime, x, y),
+        (Ponga::CFunc(_, b1, s1, _), Ponga::CFunc(_, b2, s2, _)) => Ok(b1 == b2 && s1 == s2),
+        _ => Ok(a == b),
+    }
+}
+
+// Where a key is, or would go, in a table
+#[derive(Debug, Clone, Copy)]
+pub struct Position {
+    pub hash: u64,
+    pub index: Option<usize>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct HashTable {
+    pub equiv: Equiv,
+    pub buckets: HashMap<u64, Vec<(Ponga, Ponga)>>,
+    pub len: usize,
+}
+
+impl HashTable {
+    pub fn new(equiv: Equiv) -> HashTable {
+        HashTable {
+            equiv,
+            buckets: HashMap::new(),
+            len: 0,
+        }
+    }
+
+    // Keys are hashed and compared while the table isn't borrowed, as they
+    // may look inside it, so this only clones the keys that need comparing
+    pub fn keys_at(&self, hash: u64) -> Vec<Ponga> {
+        match self.buckets.get(&hash) {
+            Some(bucket) => bucket.iter().map(|(k, _)| k.clone()).collect(),
+            None => vec![],
+        }
+    }
+
+    pub fn find(&self, runtime: &Runtime, key: &Ponga) -> RunRes<Position> {
+        let hash = self.equiv.hash(runtime, key)?;
+        let index = self.equiv.position(runtime, key, &self.keys_at(hash))?;
+        Ok(Position { hash, index })
+    }
+
+    pub fn value_at(&self, pos: Position) -> Option<Ponga> {
+        Some(self.buckets.get(&pos.hash)?[pos.index?].1.clone())
+    }
+
+    pub fn insert_at(&mut self, pos: Position, key: Ponga, val: Ponga) {
+        let bucket = self.buckets.entry(pos.hash).or_default();
+        match pos.index {
+            Some(i) => bucket[i].1 = val,
+            None => {
+                bucket.push((key, val));
+                self.len += 1;
+            }
+        }
+    }
+
+    pub fn remove_at(&mut self, pos: Position) -> Option<Ponga> {
+        let bucket = self.buckets.get_mut(&pos.hash)?;
+        let (_, val) = bucket.remove(pos.index?);
+        if bucket.is_empty() {
+            self.buckets.remove(&pos.hash);
+        }
+        self.len -= 1;
+        Some(val)
+    }
+
+    pub fn insert(&mut self, runtime: &Runtime, key: Ponga, val: Ponga) -> RunRes<()> {
+        let pos = self.find(runtime, &key)?;
+        self.insert_at(pos, key, val);
+        Ok(())
+    }
+
+    pub fn entries(&self) -> Vec<(Ponga, Ponga)> {
+        self.buckets.values().flat_map(|bucket| bucket.iter().cloned()).collect()
+    }
+}
@@ -1,10 +1,14 @@
+use crate::runtime::PriorityNamespace;
+use crate::stdlib::Continuation;
 use crate::types::*;
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq)]
 pub enum Instruction {
     Eval(Ponga),
     Call(usize),
-    Pop(String, Option<usize>),
+    Pop(String, Option<Id>),
+    // The pops for the locals of a call or a let body
+    PopFrame(Vec<(String, Option<Id>)>),
     Push(String),
     Define(String),
     Set(String),
@@ -12,6 +16,76 @@ pub enum Instruction {
     CollectObject(Vec<String>),
     CollectList(usize),
     PopStack,
+    // Pushes a value that has already been evaluated
+    Data(Ponga),
+    // Calls the procedure with each argument list in turn, discarding the
+    // results. The lists are stored last first.
+    CallEach(Ponga, Vec<Vec<Ponga>>),
+    // Gives every closure bound to one of the names access to all of them,
+    // once a letrec has evaluated its inits
+    PatchClosures(Vec<String>),
+    // Binds the values on top of the data stack to the names, and any extra
+    // values to the rest name as a list
+    BindValues(Vec<String>, Option<String>),
+    // One iteration of a do loop, once its exit test is on top of the data
+    // stack: the steps, the exit test, the result expressions and the
+    // commands
+    Do(Vec<(String, Ponga)>, Ponga, Vec<Ponga>, Vec<Ponga>),
+    // With no id, forces the value on top of the data stack. With an id,
+    // takes the result of that promise's thunk and either settles it or
+    // keeps forcing.
+    Force(Option<Id>),
+    // Binds the values on top of the data stack to globals, like BindValues
+    DefineValues(Vec<String>, Option<String>),
+    // Calls the procedure under the values on top of the data stack with
+    // those values as its arguments
+    ApplyValues,
+    // Calls the continuation with the values on top of the data stack, the
+    // first argument on top
+    Continue(Continuation, usize),
+    // Takes the result of the last call, then calls the procedure with the
+    // next argument list unless `until` says to stop. Once done, pushes the
+    // results as a vector. The lists are stored last first.
+    MapEach(Ponga, Vec<Vec<Ponga>>, Vec<Ponga>, Until),
+    // Calls the procedure with the next argument list and the accumulator on
+    // top of the data stack, which goes last if the flag is set, until the
+    // lists run out. The lists are stored last first.
+    Fold(Ponga, Vec<Vec<Ponga>>, bool),
+    // Evaluates the test of a conditional form
+    Test(Ponga),
+    // Evaluates the first expression if the value on top of the data stack
+    // is true, and the second if it isn't
+    If(Ponga, Ponga),
+    // The body of a cond clause whose test is on top of the data stack,
+    // followed by the clauses to try if it is false
+    Cond(Vec<Ponga>, Vec<Ponga>),
+    // Runs the case clause that matches the key on top of the data stack
+    Case(Vec<Ponga>),
+    // Evaluates the value on top of the data stack, with code and data
+    // flipped
+    FlipEval,
+    // Passes the values, paired with their parameters on the data stack,
+    // through the parameters' converters
+    ConvertParameters(usize),
+    // Sets the parameters to the values, taken in pairs from the data stack,
+    // until the matching UnbindParameters
+    BindParameters(usize),
+    UnbindParameters(usize),
+    // Goes back to the environment and locals of the code that called eval
+    LeaveEnvironment(Id, PriorityNamespace),
+    // Goes back to the caller's environment after a procedure made in
+    // another one returns
+    RestoreEnvironment(Id),
+    // Stops resolving relative paths against the file that was being loaded
+    LeaveFile,
+}
+
+// When MapEach stops calling the procedure
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Until {
+    End,
+    True,
+    False,
 }
 
 impl std::fmt::Display for Instruction {
@@ -20,6 +94,7 @@ impl std::fmt::Display for Instruction {
             Instruction::Eval(ponga) => write!(f, "eval {}", ponga),
             Instruction::Call(n) => write!(f, "call {}", n),
             Instruction::Pop(name, n) => write!(f, "pop {} {}", name, n.unwrap_or(0)),
+            Instruction::PopFrame(frame) => write!(f, "pop frame of {}", frame.len()),
             Instruction::Push(name) => write!(f, "push {}", name),
             Instruction::Define(name) => write!(f, "define {}", name),
             Instruction::Set(name) => write!(f, "set {}", name),
@@ -27,6 +102,40 @@ impl std::fmt::Display for Instruction {
             Instruction::CollectObject(names) => write!(f, "collect object {}", names.join(", ")),
             Instruction::CollectList(n) => write!(f, "collect list {}", n),
             Instruction::PopStack => write!(f, "pop stack"),
+            Instruction::Data(ponga) => write!(f, "data {}", ponga),
+            Instruction::CallEach(func, args) => write!(f, "call each {} {}", func, args.len()),
+            Instruction::Do(steps, test, _, _) => {
+                let names: Vec<&str> = steps.iter().map(|(name, _)| name.as_str()).collect();
+                write!(f, "do {} until {}", names.join(", "), test)
+            }
+            Instruction::Force(Some(id)) => write!(f, "force {}", id),
+            Instruction::Force(None) => write!(f, "force"),
+            Instruction::DefineValues(names, _) => write!(f, "define values {}", names.join(", ")),
+            Instruction::ApplyValues => write!(f, "apply values"),
+            Instruction::Continue(k, n) => write!(f, "continue {:?} {}", k, n),
+            Instruction::MapEach(func, args, _, until) => {
+                write!(f, "map each {} {} until {:?}", func, args.len(), until)
+            }
+            Instruction::Fold(func, args, _) => write!(f, "fold {} {}", func, args.len()),
+            Instruction::Test(test) => write!(f, "test {}", test),
+            Instruction::If(_, _) => write!(f, "if"),
+            Instruction::Cond(_, clauses) => write!(f, "cond {}", clauses.len()),
+            Instruction::Case(clauses) => write!(f, "case {}", clauses.len()),
+            Instruction::FlipEval => write!(f, "flip eval"),
+            Instruction::ConvertParameters(n) => write!(f, "convert parameters {}", n),
+            Instruction::BindParameters(n) => write!(f, "bind parameters {}", n),
+            Instruction::UnbindParameters(n) => write!(f, "unbind parameters {}", n),
+            Instruction::LeaveEnvironment(id, _) => write!(f, "leave environment {}", id),
+            Instruction::RestoreEnvironment(id) => write!(f, "restore environment {}", id),
+            Instruction::LeaveFile => write!(f, "leave file"),
+            Instruction::PatchClosures(names) => write!(f, "patch closures {}", names.join(", ")),
+            Instruction::BindValues(names, rest) => {
+                write!(f, "bind values {}", names.join(", "))?;
+                match rest {
+                    Some(rest) => write!(f, " . {}", rest),
+                    None => Ok(()),
+                }
+            }
         }
     }
 }
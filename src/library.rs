@@ -0,0 +1,128 @@
+use crate::types::*;
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+// A library made by define-library, or one of the builtin ones. A library's
+// own definitions are bound under names prefixed with the library name, which
+// can't clash with anything else since names can't contain parentheses, in a
+// namespace shared by every environment.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Library {
+    pub name: String,
+    // Exported name to the global name it refers to
+    pub exports: HashMap<String, String>,
+}
+
+impl Library {
+    pub fn new(name: String, exports: HashMap<String, String>) -> Library {
+        Library { name, exports }
+    }
+
+    pub fn builtin(name: &str, names: &[&str]) -> Library {
+        let exports = names.iter().map(|n| (n.to_string(), n.to_string())).collect();
+        Library::new(name.to_string(), exports)
+    }
+}
+
+// The parts of a library name like (srfi 1), which are identifiers or
+// exact integers
+pub fn library_name_parts(name: &Ponga) -> RunRes<Vec<String>> {
+    let parts = match name {
+        Ponga::Sexpr(parts) if !parts.is_empty() => parts,
+        _ => return Err(RuntimeErr::Other(format!("Invalid library name {:?}", name))),
+    };
+    parts.iter()
+         .map(|part| match part {
+             Ponga::Identifier(s) => Ok(s.clone()),
+             Ponga::Number(n) => Ok(n.to_string()),
+             _ => Err(RuntimeErr::Other(format!("Invalid library name {:?}", name))),
+         })
+         .collect()
+}
+
+pub fn library_name(name: &Ponga) -> RunRes<String> {
+    Ok(format!("({})", library_name_parts(name)?.join(" ")))
+}
+
+// Where a library is looked for under each directory of the search path,
+// so (my util) is my/util.sld or my/util.scm
+pub fn library_files(parts: &[String]) -> Vec<PathBuf> {
+    ["sld", "scm"].iter()
+                  .map(|ext| PathBuf::from(format!("{}.{}", parts.join("/"), ext)))
+                  .collect()
+}
+
+pub fn mangle(library: &str, name: &str) -> String {
+    format!("{}:{}", library, name)
+}
+
+// Whether the name is one a library's definition was given by mangle
+pub fn is_mangled(name: &str) -> bool {
+    name.starts_with('(') && name.contains("):")
+}
+
+// The name as it was written in the library that defined it
+pub fn local_name(name: &str) -> &str {
+    match name.strip_prefix('(').and_then(|_| name.split_once("):")) {
+        Some((_, local)) => local,
+        None => name,
+    }
+}
+
+// The names a top level library form defines
+pub fn defined_names(form: &Ponga, res: &mut Vec<String>) {
+    let arr = match form {
+        Ponga::Sexpr(arr) => arr,
+        _ => return,
+    };
+    let head = match arr.first() {
+        Some(Ponga::Identifier(s)) => s.as_str(),
+        _ => return,
+    };
+    let name = |p: &Ponga| match p {
+        Ponga::Identifier(s) => Some(s.clone()),
+        _ => None,
+    };
+    match (head, arr.get(1)) {
+        ("begin", _) => arr[1..].iter().for_each(|f| defined_names(f, res)),
+        ("define", Some(Ponga::Sexpr(sig))) => res.extend(sig.first().and_then(name)),
+        ("define", Some(p)) => res.extend(name(p)),
+        ("define-values", Some(Ponga::Sexpr(formals))) => {
+            res.extend(formals.iter().filter_map(name).filter(|n| n != "."))
+        }
+        ("define-values", Some(p)) => res.extend(name(p)),
+        ("define-record-type", Some(p)) => {
+            res.extend(name(p));
+            match arr.get(2) {
+                Some(Ponga::Sexpr(ctor)) => res.extend(ctor.first().and_then(name)),
+                Some(p) => res.extend(name(p)),
+                None => (),
+            }
+            res.extend(arr.get(3).and_then(name));
+            for spec in arr.iter().skip(4) {
+                if let Ponga::Sexpr(spec) = spec {
+                    res.extend(spec.iter().skip(1).filter_map(name));
+                }
+            }
+        }
+        _ => (),
+    }
+}
+
+// Renames identifiers throughout a form, leaving quoted data alone. Binders
+// are renamed along with their uses, so shadowing still works out.
+pub fn rename_identifiers(form: Ponga, names: &HashMap<String, String>) -> Ponga {
+    match form {
+        Ponga::Identifier(s) => match names.get(&s) {
+            Some(renamed) => Ponga::Identifier(renamed.clone()),
+            None => Ponga::Identifier(s),
+        },
+        Ponga::Sexpr(arr) => {
+            if matches!(arr.first(), Some(Ponga::Identifier(s)) if s == "quote") {
+                return Ponga::Sexpr(arr);
+            }
+            Ponga::Sexpr(arr.into_iter().map(|f| rename_identifiers(f, names)).collect())
+        }
+        form => form,
+    }
+}
@@ -1,15 +1,28 @@
 #![allow(dead_code)]
 
+mod environment;
 mod gc;
 mod gc_obj;
+mod hash_table;
+mod library;
+mod parameter;
 mod parser;
+mod pool;
+mod port;
+mod promise;
 mod ratio;
+mod record;
 mod runtime;
 mod stdlib;
+mod coroutine;
+mod thread;
 mod tests;
+#[cfg(test)]
+mod gc_tests;
 mod types;
 mod number;
 mod instructions;
+mod snapshot;
 
 use types::*;
 use runtime::*;
@@ -17,15 +30,34 @@ use std::io::{self, BufRead, Write};
 
 fn run_main() -> RunRes<()> {
     let mut args = std::env::args();
-    if args.len() > 2 {
-        println!("Usage: {} <optional file>", args.nth(0).unwrap());
+    let prog_name = args.next().unwrap();
+    let mut file_name = None;
+    let mut heap_dump = None;
+    let mut runtime = Runtime::new();
+    while let Some(arg) = args.next() {
+        if arg == "--heap-dump" {
+            heap_dump = Some(args.next().ok_or(RuntimeErr::Other(
+                "--heap-dump requires a file name".to_string()
+            ))?);
+        } else if arg == "--lib-path" {
+            runtime.add_library_path(args.next().ok_or(RuntimeErr::Other(
+                "--lib-path requires a directory".to_string()
+            ))?);
+        } else if file_name.is_none() {
+            file_name = Some(arg);
+        } else {
+            println!("Usage: {} [--heap-dump <file>] [--lib-path <dir>]... <optional file>", prog_name);
+            return Ok(());
+        }
+    }
+    if let (Some(file_name), None) = (&file_name, &heap_dump) {
+        let _res = run_file_in(runtime, file_name)?;
         return Ok(());
     }
-    if args.len() == 2 {
-        let file_name = args.nth(1).unwrap();
-        let _res = run_file(&file_name)?;
+
+    if let Some(file_name) = file_name {
+        runtime.run_file(&file_name)?;
     } else {
-        let mut runtime = Runtime::new();
         let stdin = io::stdin();
         let mut stdout = io::stdout();
         loop {
@@ -39,6 +71,9 @@ fn run_main() -> RunRes<()> {
             runtime.run_str(&line)?;
         }
     }
+    if let Some(dump) = heap_dump {
+        runtime.write_heap_dump(&dump)?;
+    }
     Ok(())
 }
 
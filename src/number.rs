@@ -164,6 +164,14 @@ impl Number {
         }
     }
 
+    pub fn to_f64(self) -> f64 {
+        match self {
+            Number::Int(i) => i as f64,
+            Number::Float(i) => i,
+            Number::Rational(r) => r.to_f64(),
+        }
+    }
+
     pub fn ceiling(self) -> Number {
         match self {
             Number::Int(i) => Number::Int(i),
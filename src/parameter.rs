@@ -0,0 +1,32 @@
+use crate::types::*;
+use std::collections::HashMap;
+
+// A parameter object made by make-parameter. Calling it gives the value,
+// which parameterize replaces for the extent of its body.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Parameter {
+    pub value: Box<Ponga>,
+    // Applied to the initial value and to each value given by parameterize
+    pub converter: Option<Box<Ponga>>,
+}
+
+impl Parameter {
+    pub fn new(value: Ponga, converter: Option<Ponga>) -> Parameter {
+        Parameter { value: Box::new(value), converter: converter.map(Box::new) }
+    }
+}
+
+// The values parameterize has given parameters in one thread, by parameter,
+// and the bindings they replaced, innermost last
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Parameterization {
+    pub values: HashMap<Id, Ponga>,
+    pub saves: Vec<(Id, Option<Ponga>)>,
+}
+
+impl Parameterization {
+    // What a new thread starts out with
+    pub fn inherit(&self) -> Parameterization {
+        Parameterization { values: self.values.clone(), saves: Vec::new() }
+    }
+}
@@ -275,6 +275,17 @@ pub fn array_parser(input: &str) -> IResult<&str, Ponga> {
     )(input)
 }
 
+pub fn bytevector_parser(input: &str) -> IResult<&str, Ponga> {
+    map(
+        delimited(
+            tag("#u8("),
+            separated_list0(multispace1, map_res(int_parser, u8::try_from)),
+            preceded(multispace0, tag(")")),
+        ),
+        Ponga::Bytevector,
+    )(input)
+}
+
 pub fn list_parser(input: &str) -> IResult<&str, Ponga> {
     map(
         delimited(
@@ -338,12 +349,42 @@ pub fn bool_parser(input: &str) -> IResult<&str, Ponga> {
     alt((false_parser, true_parser))(input)
 }
 
-pub fn char_parser(input: &str) -> IResult<&str, Ponga> {
-    map(preceded(tag("#\\"), take(1usize)), |x: &str| {
-        Ponga::Char(x.chars().nth(0).unwrap())
+// The names of characters that would be hard to see written as they are
+pub const CHAR_NAMES: &[(&str, char)] = &[
+    ("alarm", '\u{07}'),
+    ("backspace", '\u{08}'),
+    ("delete", '\u{7F}'),
+    ("escape", '\u{1B}'),
+    ("newline", '\n'),
+    ("null", '\0'),
+    ("return", '\r'),
+    ("space", ' '),
+    ("tab", '\t'),
+];
+
+fn char_name_parser(input: &str) -> IResult<&str, char> {
+    for (name, c) in CHAR_NAMES.iter() {
+        if let Ok((rest, _)) = tag::<_, _, nom::error::Error<&str>>(*name)(input) {
+            return Ok((rest, *c));
+        }
+    }
+    Err(nom::Err::Error(nom::error::Error::new(input, nom::error::ErrorKind::Tag)))
+}
+
+// #\x41 is A
+fn char_hex_parser(input: &str) -> IResult<&str, char> {
+    let hex = take_while_m_n(1, 6, |c: char| c.is_ascii_hexdigit());
+    map_opt(preceded(char('x'), hex), |hex| {
+        u32::from_str_radix(hex, 16).ok().and_then(std::char::from_u32)
     })(input)
 }
 
+pub fn char_parser(input: &str) -> IResult<&str, Ponga> {
+    let single = map(take(1usize), |x: &str| x.chars().next().unwrap());
+    map(preceded(tag("#\\"), alt((char_name_parser, char_hex_parser, single))),
+        Ponga::Char)(input)
+}
+
 pub fn ponga_parser(input: &str) -> IResult<&str, Ponga> {
     preceded(
         multispace0,
@@ -351,6 +392,7 @@ pub fn ponga_parser(input: &str) -> IResult<&str, Ponga> {
             string_parser,
             char_parser,
             num_parser,
+            bytevector_parser,
             array_parser,
             list_parser,
             sexpr_parser,
@@ -0,0 +1,314 @@
+use crate::runtime::Runtime;
+use crate::types::*;
+use std::collections::VecDeque;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::thread::JoinHandle;
+use std::time::SystemTime;
+
+// A pool of runtimes, each on an OS thread of its own. The runtimes share
+// nothing: every gc heap is only ever touched by the thread that owns it, so
+// values go from one runtime to another as messages, which are deep copies
+// made outside of any heap.
+pub struct Pool {
+    workers: Vec<Worker>,
+}
+
+struct Worker {
+    // None once the pool is being dropped, which lets the thread finish
+    jobs: Option<Sender<Job>>,
+    inbox: Sender<Mail>,
+    handle: Option<JoinHandle<()>>,
+}
+
+type Job = Box<dyn FnOnce(&mut Runtime) + Send>;
+
+// The result of a job given to a worker, once it has run
+pub struct Task<T> {
+    worker: usize,
+    res: Receiver<T>,
+}
+
+// A value copied out of a runtime. Only data can be copied: numbers, strings,
+// characters, symbols, booleans, lists, vectors and objects made of them.
+// Shared structure is copied once for every reference to it, and cycles
+// can't be copied at all.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Message(Ponga);
+
+// What arrives in a worker's inbox. The workers can all send to each other,
+// so an inbox never disconnects, and the pool closes them instead when it's
+// dropped.
+enum Mail {
+    Message(Message),
+    Close,
+}
+
+// How a runtime in a pool reaches the others. Messages that have arrived are
+// kept in pending until receive-message takes them.
+pub struct Mailbox {
+    pub worker: usize,
+    outboxes: Vec<Sender<Mail>>,
+    inbox: Receiver<Mail>,
+    pending: VecDeque<Message>,
+    // Whether the pool has closed the inbox, after which only the pending
+    // messages are left
+    closed: bool,
+}
+
+impl Message {
+    pub fn new(runtime: &Runtime, val: &Ponga) -> RunRes<Message> {
+        Ok(Message(copy_out(runtime, val, &mut vec![])?))
+    }
+
+    // Puts the copy into the runtime's heap
+    pub fn into_ponga(self, runtime: &mut Runtime) -> Ponga {
+        copy_in(runtime, self.0)
+    }
+
+    pub fn value(&self) -> &Ponga {
+        &self.0
+    }
+}
+
+// Follows refs into the heap, with the ids of the objects being copied in
+// visiting to catch cycles
+fn copy_out(runtime: &Runtime, val: &Ponga, visiting: &mut Vec<Id>) -> RunRes<Ponga> {
+    match val {
+        Ponga::Ref(id) => {
+            if visiting.contains(id) {
+                return Err(RuntimeErr::TypeError(
+                    "Can't send a value that contains itself to another runtime".to_string()
+                ));
+            }
+            visiting.push(*id);
+            let obj = runtime.get_id_obj_ref(*id)?.borrow().unwrap();
+            let res = copy_out(runtime, obj.inner(), visiting);
+            visiting.pop();
+            res
+        }
+        Ponga::List(list) => Ok(Ponga::List(
+            list.iter().map(|x| copy_out(runtime, x, visiting)).collect::<RunRes<_>>()?
+        )),
+        Ponga::Array(arr) => Ok(Ponga::Array(
+            arr.iter().map(|x| copy_out(runtime, x, visiting)).collect::<RunRes<_>>()?
+        )),
+        Ponga::Object(obj) => Ok(Ponga::Object(
+            obj.iter()
+               .map(|(k, v)| Ok((k.clone(), copy_out(runtime, v, visiting)?)))
+               .collect::<RunRes<_>>()?
+        )),
+        Ponga::Null
+        | Ponga::Number(_)
+        | Ponga::String(_)
+        | Ponga::MutString(_)
+        | Ponga::Char(_)
+        | Ponga::Symbol(_)
+        | Ponga::True
+        | Ponga::False
+        | Ponga::Eof => Ok(val.clone()),
+        other => Err(RuntimeErr::TypeError(format!(
+            "Can't send {} to another runtime", runtime.ponga_to_string(other)
+        ))),
+    }
+}
+
+// Everything that lived in the gc where it came from goes in the gc here too
+fn copy_in(runtime: &mut Runtime, val: Ponga) -> Ponga {
+    match val {
+        Ponga::List(list) => {
+            let list = list.into_iter().map(|x| copy_in(runtime, x)).collect();
+            runtime.gc.ponga_into_gc_ref(Ponga::List(list))
+        }
+        Ponga::Array(arr) => {
+            let arr = arr.into_iter().map(|x| copy_in(runtime, x)).collect();
+            runtime.gc.ponga_into_gc_ref(Ponga::Array(arr))
+        }
+        Ponga::Object(obj) => {
+            let obj = obj.into_iter().map(|(k, v)| (k, copy_in(runtime, v))).collect();
+            runtime.gc.ponga_into_gc_ref(Ponga::Object(obj))
+        }
+        Ponga::MutString(s) => runtime.gc.ponga_into_gc_ref(Ponga::MutString(s)),
+        val => val,
+    }
+}
+
+impl Mailbox {
+    pub fn worker_count(&self) -> usize {
+        self.outboxes.len()
+    }
+
+    pub fn send(&self, worker: usize, msg: Message) -> RunRes<()> {
+        let outbox = self.outboxes.get(worker).ok_or_else(|| RuntimeErr::Other(format!(
+            "There is no worker {} in a pool of {}", worker, self.outboxes.len()
+        )))?;
+        outbox.send(Mail::Message(msg)).map_err(|_| RuntimeErr::Other(format!(
+            "Worker {} has stopped", worker
+        )))
+    }
+
+    fn deliver(&mut self, mail: Mail) {
+        match mail {
+            Mail::Message(msg) => self.pending.push_back(msg),
+            Mail::Close => self.closed = true,
+        }
+    }
+
+    // Moves whatever has arrived into pending
+    pub fn fetch(&mut self) {
+        while let Ok(mail) = self.inbox.try_recv() {
+            self.deliver(mail);
+        }
+    }
+
+    pub fn has_pending(&self) -> bool {
+        !self.pending.is_empty()
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.closed
+    }
+
+    pub fn take(&mut self) -> Option<Message> {
+        self.fetch();
+        self.pending.pop_front()
+    }
+
+    // Waits for a message, or for the inbox to be closed, until the deadline
+    // if there is one. False if nothing can arrive any more.
+    pub fn wait(&mut self, deadline: Option<SystemTime>) -> bool {
+        if self.closed {
+            return false;
+        }
+        let res = match deadline {
+            Some(deadline) => {
+                let timeout = deadline.duration_since(SystemTime::now()).unwrap_or_default();
+                self.inbox.recv_timeout(timeout)
+            }
+            None => self.inbox.recv().map_err(|_| RecvTimeoutError::Disconnected),
+        };
+        match res {
+            Ok(mail) => {
+                self.deliver(mail);
+                true
+            }
+            Err(RecvTimeoutError::Timeout) => true,
+            Err(RecvTimeoutError::Disconnected) => false,
+        }
+    }
+}
+
+impl Pool {
+    // Each runtime is made on its own thread
+    pub fn new(workers: usize) -> Pool {
+        Pool::start((0..workers).map(|_| Runtime::new as fn() -> Runtime).collect())
+    }
+
+    // Moves runtimes that have already been set up onto the threads
+    pub fn from_runtimes(runtimes: Vec<Runtime>) -> Pool {
+        Pool::start(runtimes.into_iter().map(|runtime| move || runtime).collect())
+    }
+
+    fn start<F>(makers: Vec<F>) -> Pool
+    where
+        F: FnOnce() -> Runtime + Send + 'static,
+    {
+        let (outboxes, inboxes): (Vec<_>, Vec<_>) =
+            makers.iter().map(|_| mpsc::channel::<Mail>()).unzip();
+        let mut workers = vec![];
+        for (worker, (make, inbox)) in makers.into_iter().zip(inboxes).enumerate() {
+            let mailbox = Mailbox {
+                worker,
+                outboxes: outboxes.clone(),
+                inbox,
+                pending: VecDeque::new(),
+                closed: false,
+            };
+            let (jobs, job_receiver) = mpsc::channel::<Job>();
+            let handle = std::thread::spawn(move || {
+                let mut runtime = make();
+                runtime.mailbox = Some(mailbox);
+                for job in job_receiver {
+                    job(&mut runtime);
+                }
+            });
+            workers.push(Worker {
+                jobs: Some(jobs),
+                inbox: outboxes[worker].clone(),
+                handle: Some(handle),
+            });
+        }
+        Pool { workers }
+    }
+
+    pub fn len(&self) -> usize {
+        self.workers.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.workers.is_empty()
+    }
+
+    fn worker(&self, worker: usize) -> RunRes<&Worker> {
+        self.workers.get(worker).ok_or_else(|| RuntimeErr::Other(format!(
+            "There is no worker {} in a pool of {}", worker, self.workers.len()
+        )))
+    }
+
+    // Runs f on the worker's runtime once the jobs given to it before are done
+    pub fn execute<T, F>(&self, worker: usize, f: F) -> RunRes<Task<T>>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut Runtime) -> T + Send + 'static,
+    {
+        let (send, res) = mpsc::channel();
+        let job: Job = Box::new(move |runtime| {
+            let _ = send.send(f(runtime));
+        });
+        let stopped = || RuntimeErr::Other(format!("Worker {} has stopped", worker));
+        self.worker(worker)?.jobs.as_ref().ok_or_else(stopped)?.send(job).map_err(|_| stopped())?;
+        Ok(Task { worker, res })
+    }
+
+    // Evaluates each form in the string on the worker, giving back a copy of
+    // the value of the last
+    pub fn eval(&self, worker: usize, s: &str) -> RunRes<Task<RunRes<Message>>> {
+        let s = s.to_string();
+        self.execute(worker, move |runtime| {
+            let env = runtime.current_environment();
+            let val = runtime.eval_str_in(&env, &s)?;
+            Message::new(runtime, &val)
+        })
+    }
+
+    // Leaves a message for the worker's receive-message
+    pub fn send(&self, worker: usize, msg: Message) -> RunRes<()> {
+        self.worker(worker)?.inbox.send(Mail::Message(msg)).map_err(|_| RuntimeErr::Other(format!(
+            "Worker {} has stopped", worker
+        )))
+    }
+}
+
+// Waits for every worker to finish the jobs it has been given. Any of them
+// waiting for a message, or that go on to wait for one, get an error instead.
+impl Drop for Pool {
+    fn drop(&mut self) {
+        for worker in self.workers.iter_mut() {
+            worker.jobs = None;
+            let _ = worker.inbox.send(Mail::Close);
+        }
+        for worker in self.workers.iter_mut() {
+            if let Some(handle) = worker.handle.take() {
+                let _ = handle.join();
+            }
+        }
+    }
+}
+
+impl<T> Task<T> {
+    // Waits for the job to finish
+    pub fn join(self) -> RunRes<T> {
+        self.res.recv().map_err(|_| RuntimeErr::Other(format!(
+            "Worker {} stopped before finishing its job", self.worker
+        )))
+    }
+}
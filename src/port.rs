@@ -0,0 +1,376 @@
+use crate::parser::ponga_parser;
+use crate::types::*;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::sync::{Arc, Mutex};
+
+// How many bytes of a file are read at a time
+const FILE_CHUNK: usize = 8192;
+
+// Where the rest of an input port's data comes from once what has been read
+// so far runs out
+#[derive(Debug, Clone)]
+pub enum Feed {
+    // Strings are all there from the start
+    Nothing,
+    // A line at a time
+    Stdin,
+    // A chunk at a time
+    File(Arc<Mutex<BufReader<File>>>),
+}
+
+// Where the characters or bytes read from an input port come from, read as
+// they are needed
+#[derive(Debug, Clone)]
+pub struct Source {
+    pub data: Vec<u8>,
+    pub pos: usize,
+    pub feed: Feed,
+}
+
+impl Source {
+    // Reads more from the feed, returning whether there was any
+    fn read_more(&mut self) -> RunRes<bool> {
+        let mut more = vec![];
+        let res = match &self.feed {
+            Feed::Nothing => return Ok(false),
+            Feed::Stdin => std::io::stdin().lock().read_until(b'\n', &mut more),
+            Feed::File(file) => {
+                let mut file = file.lock().unwrap();
+                file.by_ref().take(FILE_CHUNK as u64).read_to_end(&mut more)
+            }
+        };
+        match res {
+            Ok(0) => Ok(false),
+            Ok(_) => {
+                self.data.drain(..self.pos);
+                self.pos = 0;
+                self.data.extend(more);
+                Ok(true)
+            }
+            Err(e) => Err(RuntimeErr::Other(format!("Error reading from port: {}", e))),
+        }
+    }
+}
+
+// Where read_datum has got to in looking for the end of the next datum, so
+// that reading more of the source carries on from there rather than starting
+// over
+#[derive(Default)]
+struct Scan {
+    // Past the port's position
+    len: usize,
+    depth: usize,
+    in_string: bool,
+    escaped: bool,
+    // Where the atom being scanned started, if there is one
+    atom: Option<usize>,
+}
+
+impl Scan {
+    // Looks over the unread bytes, giving the length of the first datum
+    // once its end has been seen
+    fn advance(&mut self, unread: &[u8]) -> Option<usize> {
+        while self.len < unread.len() {
+            let i = self.len;
+            let b = unread[i];
+            if self.in_string {
+                self.len += 1;
+                match b {
+                    _ if self.escaped => self.escaped = false,
+                    b'\\' => self.escaped = true,
+                    b'"' => {
+                        self.in_string = false;
+                        if self.depth == 0 {
+                            return Some(self.len);
+                        }
+                    }
+                    _ => (),
+                }
+                continue;
+            }
+            // The character of #\( or #\" is part of the atom, so it's taken
+            // whole once all of it has been read
+            if b == b'#' && unread.get(i + 1) == Some(&b'\\') {
+                let end = i + 2 + unread.get(i + 2).map_or(1, |first| utf8_len(*first));
+                if end > unread.len() {
+                    return None;
+                }
+                self.atom.get_or_insert(i);
+                self.len = end;
+                continue;
+            }
+            let ends_atom = b.is_ascii_whitespace() || b == b'"' || b == b')' || (
+                // '(, #( and #u8( start a list rather than ending an atom
+                b == b'(' && !matches!(self.atom.map(|start| &unread[start..i]),
+                                       None | Some(b"'") | Some(b"#") | Some(b"#u8"))
+            );
+            if ends_atom && self.depth == 0 && self.atom.is_some() {
+                return Some(i);
+            }
+            self.len += 1;
+            match b {
+                b'"' => self.in_string = true,
+                b'(' => self.depth += 1,
+                b')' => {
+                    self.depth = self.depth.saturating_sub(1);
+                    if self.depth == 0 {
+                        return Some(self.len);
+                    }
+                }
+                _ if b.is_ascii_whitespace() => (),
+                _ => {
+                    self.atom.get_or_insert(i);
+                    continue;
+                }
+            }
+            self.atom = None;
+        }
+        None
+    }
+}
+
+// Where the writes to an output port go
+#[derive(Debug, Clone)]
+pub enum Sink {
+    Stdout,
+    Stderr,
+    // Kept for get-output-string
+    Buffer(Vec<u8>),
+    File(Arc<Mutex<File>>),
+}
+
+#[derive(Debug, Clone)]
+pub enum Direction {
+    Input(Source),
+    Output(Sink),
+}
+
+#[derive(Debug, Clone)]
+pub struct Port {
+    pub direction: Direction,
+    pub binary: bool,
+    pub open: bool,
+}
+
+// Ports are only ever compared through the refs that point to them
+impl PartialEq for Port {
+    fn eq(&self, other: &Port) -> bool {
+        std::ptr::eq(self, other)
+    }
+}
+
+// The number of bytes in the UTF-8 character starting with this byte
+fn utf8_len(first: u8) -> usize {
+    match first {
+        0xF0..=0xFF => 4,
+        0xE0..=0xEF => 3,
+        0xC0..=0xDF => 2,
+        _ => 1,
+    }
+}
+
+impl Port {
+    pub fn input(data: Vec<u8>, binary: bool) -> Port {
+        let source = Source { data, pos: 0, feed: Feed::Nothing };
+        Port { direction: Direction::Input(source), binary, open: true }
+    }
+
+    pub fn file(file: File, binary: bool) -> Port {
+        let feed = Feed::File(Arc::new(Mutex::new(BufReader::new(file))));
+        let source = Source { data: vec![], pos: 0, feed };
+        Port { direction: Direction::Input(source), binary, open: true }
+    }
+
+    pub fn stdin() -> Port {
+        let source = Source { data: vec![], pos: 0, feed: Feed::Stdin };
+        Port { direction: Direction::Input(source), binary: false, open: true }
+    }
+
+    pub fn output(sink: Sink, binary: bool) -> Port {
+        Port { direction: Direction::Output(sink), binary, open: true }
+    }
+
+    pub fn is_input(&self) -> bool {
+        matches!(self.direction, Direction::Input(_))
+    }
+
+    fn source(&mut self) -> RunRes<&mut Source> {
+        if !self.open {
+            return Err(RuntimeErr::Other("Can't read from a closed port".to_string()));
+        }
+        match &mut self.direction {
+            Direction::Input(source) => Ok(source),
+            Direction::Output(_) => Err(RuntimeErr::TypeError("Expected input port".to_string())),
+        }
+    }
+
+    // Makes at least n unread bytes available if the source has them
+    fn fill(&mut self, n: usize) -> RunRes<&mut Source> {
+        let source = self.source()?;
+        while source.data.len() - source.pos < n && source.read_more()? {}
+        Ok(source)
+    }
+
+    pub fn peek_u8(&mut self) -> RunRes<Option<u8>> {
+        let source = self.fill(1)?;
+        Ok(source.data.get(source.pos).copied())
+    }
+
+    pub fn read_u8(&mut self) -> RunRes<Option<u8>> {
+        let res = self.peek_u8()?;
+        if res.is_some() {
+            self.source()?.pos += 1;
+        }
+        Ok(res)
+    }
+
+    // The next character along with its length in bytes
+    fn next_char(&mut self) -> RunRes<Option<(char, usize)>> {
+        let first = match self.peek_u8()? {
+            Some(first) => first,
+            None => return Ok(None),
+        };
+        let len = utf8_len(first);
+        let source = self.fill(len)?;
+        let end = (source.pos + len).min(source.data.len());
+        match std::str::from_utf8(&source.data[source.pos..end]) {
+            Ok(s) => Ok(s.chars().next().map(|c| (c, len))),
+            Err(_) => Err(RuntimeErr::Other("Port contains invalid UTF-8".to_string())),
+        }
+    }
+
+    pub fn peek_char(&mut self) -> RunRes<Option<char>> {
+        Ok(self.next_char()?.map(|(c, _)| c))
+    }
+
+    pub fn read_char(&mut self) -> RunRes<Option<char>> {
+        let res = self.next_char()?;
+        if let Some((_, len)) = res {
+            self.source()?.pos += len;
+        }
+        Ok(res.map(|(c, _)| c))
+    }
+
+    // Reads up to n characters, or None if there were none left
+    pub fn read_string(&mut self, n: usize) -> RunRes<Option<String>> {
+        let mut res = String::new();
+        while res.chars().count() < n {
+            match self.read_char()? {
+                Some(c) => res.push(c),
+                None => break,
+            }
+        }
+        Ok(if res.is_empty() && n > 0 { None } else { Some(res) })
+    }
+
+    // Reads up to n bytes, or None if there were none left
+    pub fn read_bytes(&mut self, n: usize) -> RunRes<Option<Vec<u8>>> {
+        let source = self.fill(n)?;
+        let end = (source.pos + n).min(source.data.len());
+        let res = source.data[source.pos..end].to_vec();
+        source.pos = end;
+        Ok(if res.is_empty() && n > 0 { None } else { Some(res) })
+    }
+
+    // Reads up to the end of the line, which isn't included
+    pub fn read_line(&mut self) -> RunRes<Option<String>> {
+        let mut res = String::new();
+        loop {
+            match self.read_char()? {
+                Some('\n') => return Ok(Some(res)),
+                Some(c) => res.push(c),
+                None if res.is_empty() => return Ok(None),
+                None => return Ok(Some(res)),
+            }
+        }
+    }
+
+    // Whether reading wouldn't have to wait for stdin
+    pub fn ready(&mut self) -> RunRes<bool> {
+        let source = self.source()?;
+        Ok(!matches!(source.feed, Feed::Stdin) || source.pos < source.data.len())
+    }
+
+    // Parses the next datum, reading more of the source for as long as what
+    // has been read so far doesn't hold a complete one. Only the datum itself
+    // is parsed, once its end has been found.
+    pub fn read_datum(&mut self) -> RunRes<Option<Ponga>> {
+        let source = self.source()?;
+        let mut scan = Scan::default();
+        loop {
+            // Whitespace before the datum is skipped over for good
+            if scan.len == 0 {
+                let unread = &source.data[source.pos..];
+                source.pos += unread.iter().take_while(|b| b.is_ascii_whitespace()).count();
+            }
+            let found = scan.advance(&source.data[source.pos..]);
+            if found.is_none() && source.read_more()? {
+                continue;
+            }
+            let unread = &source.data[source.pos..];
+            let end = match found {
+                Some(end) => Some(end),
+                // An atom can end the source
+                None if scan.atom.is_some() && scan.depth == 0 => Some(unread.len()),
+                None if unread.is_empty() => return Ok(None),
+                None => None,
+            };
+            let text = std::str::from_utf8(&unread[..end.unwrap_or(unread.len())])
+                .map_err(|_| RuntimeErr::Other("Port contains invalid UTF-8".to_string()))?;
+            if let Some(Ok((rest, val))) = end.map(|_| ponga_parser(text)) {
+                source.pos += text.len() - rest.len();
+                return Ok(Some(val));
+            }
+            let err = RuntimeErr::ParseError(format!("Couldn't read a datum from {}", text));
+            source.pos += text.len();
+            return Err(err);
+        }
+    }
+
+    pub fn write_bytes(&mut self, bytes: &[u8]) -> RunRes<()> {
+        if !self.open {
+            return Err(RuntimeErr::Other("Can't write to a closed port".to_string()));
+        }
+        let res = match &mut self.direction {
+            Direction::Output(Sink::Stdout) => {
+                let mut stdout = std::io::stdout();
+                stdout.write_all(bytes).and_then(|_| stdout.flush())
+            }
+            Direction::Output(Sink::Stderr) => std::io::stderr().write_all(bytes),
+            Direction::Output(Sink::Buffer(buf)) => {
+                buf.extend_from_slice(bytes);
+                Ok(())
+            }
+            Direction::Output(Sink::File(file)) => file.lock().unwrap().write_all(bytes),
+            Direction::Input(_) => {
+                return Err(RuntimeErr::TypeError("Expected output port".to_string()))
+            }
+        };
+        res.map_err(|e| RuntimeErr::Other(format!("Error writing to port: {}", e)))
+    }
+
+    pub fn write_str(&mut self, s: &str) -> RunRes<()> {
+        self.write_bytes(s.as_bytes())
+    }
+
+    // What has been written to a string port so far
+    pub fn output_string(&self) -> Option<String> {
+        self.output_bytes().map(|buf| String::from_utf8_lossy(&buf).into_owned())
+    }
+
+    // What has been written to a string or bytevector port so far
+    pub fn output_bytes(&self) -> Option<Vec<u8>> {
+        match &self.direction {
+            Direction::Output(Sink::Buffer(buf)) => Some(buf.clone()),
+            _ => None,
+        }
+    }
+
+    pub fn close(&mut self) {
+        if let Direction::Output(Sink::File(file)) = &self.direction {
+            let _ = file.lock().unwrap().flush();
+        }
+        self.open = false;
+    }
+}
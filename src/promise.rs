@@ -0,0 +1,13 @@
+use crate::types::*;
+
+// The state of a promise made by delay, delay-force or make-promise
+#[derive(Debug, Clone, PartialEq)]
+pub enum Promise {
+    Done(Box<Ponga>),
+    // A thunk, and whether it produces another promise (from delay-force)
+    // rather than the value itself
+    Delayed(Box<Ponga>, bool),
+    // Shares the state of the promise with this id, once delay-force has
+    // handed its thunk over to it
+    Forward(Id),
+}
@@ -0,0 +1,31 @@
+use crate::types::*;
+
+// A type made by define-record-type. Records refer to their type by its gc
+// id, which is what tells apart two types with the same name.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RecordType {
+    pub name: String,
+    pub fields: Vec<String>,
+}
+
+impl RecordType {
+    pub fn new(name: String, fields: Vec<String>) -> RecordType {
+        RecordType { name, fields }
+    }
+
+    pub fn field_index(&self, field: &str) -> Option<usize> {
+        self.fields.iter().position(|f| f == field)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Record {
+    pub rtype: Id,
+    pub fields: Vec<Ponga>,
+}
+
+impl Record {
+    pub fn new(rtype: Id, fields: Vec<Ponga>) -> Record {
+        Record { rtype, fields }
+    }
+}
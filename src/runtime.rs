@@ -1,26 +1,124 @@
+use crate::coroutine::*;
+use crate::environment::Environment;
 use crate::gc::*;
 use crate::gc_obj::*;
 use crate::parser::*;
 use crate::stdlib::*;
 use crate::types::*;
 use crate::instructions::*;
+use crate::snapshot::*;
+use crate::hash_table::Equiv;
+use crate::library::*;
+use crate::parameter::{Parameter, Parameterization};
+use crate::pool::Mailbox;
+use crate::port::*;
+use crate::promise::Promise;
+use crate::record::*;
+use crate::thread::*;
 use itertools::Itertools;
 use std::collections::HashMap;
 use std::collections::LinkedList;
 use std::fs::File;
 use std::io::prelude::*;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
 
 pub const MAX_STACK_SIZE: usize = 100_000;
 
+// How many instructions a thread runs before the others get a turn
+pub const TIME_SLICE: usize = 1000;
+
 pub type Namespace = HashMap<String, Ponga>;
 
 pub type PriorityNamespace = HashMap<String, Vec<Ponga>>;
 
 pub struct Runtime {
     pub globals: Namespace,
+    // The current environment's imported names and what they refer to
+    imports: HashMap<String, String>,
+    // Whether the current environment sees the builtins it hasn't imported
+    builtins: bool,
+    // The definitions of every library, under their mangled names
+    library_globals: Namespace,
     pub global_funcs: Namespace,
     pub locals: PriorityNamespace,
     pub gc: Gc,
+    // Instructions queued by the builtin currently being called, which run
+    // in the caller's loop once the builtin returns
+    scheduled: Vec<Instruction>,
+    // How many running instruction loops have stacks the gc can't see. Only
+    // with one, its own, can the loop stop to collect.
+    unrooted: usize,
+    // What loops and calls waiting on another one to finish are holding
+    parked: Vec<Parked>,
+    // The environment the globals belong to, and the one top level code
+    // starts in
+    current_env: Id,
+    pub interaction_env: Ponga,
+    // Values held from Rust, which are kept alive until they're unpinned
+    pinned: Vec<Ponga>,
+    // What new environments start with, besides the builtin functions
+    builtin_globals: Namespace,
+    // Parameters holding the ports used by reading and writing procedures
+    // when none is given
+    pub current_input: Ponga,
+    pub current_output: Ponga,
+    pub current_error: Ponga,
+    // Libraries that have been defined or loaded, by name
+    pub libraries: HashMap<String, Library>,
+    // The directories searched for libraries that haven't been loaded yet
+    pub library_path: Vec<PathBuf>,
+    // The files being loaded or included, innermost last, which relative
+    // paths are resolved against
+    loading: Vec<PathBuf>,
+    // The thread running now, and every thread that has been started and
+    // hasn't finished, the primordial one included, in the order they take
+    // turns
+    pub current_thread: Ponga,
+    threads: Vec<Id>,
+    // Set by a builtin that can't go on yet, which has scheduled itself to
+    // be called again once the other threads have had a turn
+    blocked: Option<Block>,
+    // Set by yield, for the coroutine running now to give back
+    yielded: Option<Ponga>,
+    // The parameterize bindings of the thread running now, which other
+    // threads don't see
+    parameters: Parameterization,
+    // How to reach the other runtimes, when this one is in a pool
+    pub mailbox: Option<Mailbox>,
+    // Errors from finalizers, which run where nothing can catch them, kept
+    // until they're taken
+    finalizer_errors: Vec<RuntimeErr>,
+    // How many instructions may be waiting before evaluation gives up
+    pub max_stack_size: usize,
+}
+
+// What the gc traces for a loop or call that is waiting on another one
+#[derive(Default)]
+struct Parked {
+    ins_stack: Vec<Instruction>,
+    data_stack: Vec<Ponga>,
+    locals: PriorityNamespace,
+    parameters: Parameterization,
+}
+
+// What an instruction loop is running
+#[derive(Clone, Copy, PartialEq)]
+enum LoopMode {
+    // Whatever its caller wants evaluated
+    Nested,
+    // A green thread's turn
+    Thread,
+    // A coroutine until it yields
+    Coroutine,
+}
+
+// How an instruction loop stopped
+enum Exit {
+    Done(Ponga),
+    // A green thread's turn ended or a coroutine yielded, leaving its stacks
+    // like this
+    Suspended(Vec<Instruction>, Vec<Ponga>),
 }
 
 pub enum WhereVar {
@@ -32,7 +130,19 @@ pub enum WhereVar {
 impl Runtime {
     pub fn new() -> Self {
         let mut global_funcs = Namespace::new();
-        let gc = Gc::new();
+        let mut gc = Gc::new();
+        let mut port_parameter = |port| {
+            let port = gc.ponga_into_gc_ref(Ponga::Port(port));
+            gc.ponga_into_gc_ref(Ponga::Parameter(Parameter::new(port, None)))
+        };
+        let current_input = port_parameter(Port::stdin());
+        let current_output = port_parameter(Port::output(Sink::Stdout, false));
+        let current_error = port_parameter(Port::output(Sink::Stderr, false));
+        let current_env = gc.add_obj(Ponga::Environment(Environment::new(Namespace::new())));
+        let mut primordial = Thread::new(Ponga::Symbol("primordial".to_string()), vec![], current_env,
+                                         Parameterization::default());
+        primordial.state = ThreadState::Running;
+        let primordial = gc.add_obj(Ponga::Thread(primordial));
 
         for (i, val) in FUNCS.iter().enumerate() {
             global_funcs.insert(val.0.to_string(), Ponga::HFunc(i));
@@ -40,13 +150,53 @@ impl Runtime {
 
         let mut res = Self {
             globals: Namespace::new(),
+            imports: HashMap::new(),
+            builtins: true,
+            library_globals: Namespace::new(),
             global_funcs,
             locals: PriorityNamespace::new(),
             gc,
+            scheduled: Vec::new(),
+            unrooted: 0,
+            parked: Vec::new(),
+            current_env,
+            interaction_env: Ponga::Ref(current_env),
+            pinned: Vec::new(),
+            builtin_globals: Namespace::new(),
+            current_input,
+            current_output,
+            current_error,
+            libraries: HashMap::new(),
+            library_path: vec![PathBuf::from(".")],
+            loading: Vec::new(),
+            current_thread: Ponga::Ref(primordial),
+            threads: vec![primordial],
+            blocked: None,
+            yielded: None,
+            parameters: Parameterization::default(),
+            mailbox: None,
+            finalizer_errors: Vec::new(),
+            max_stack_size: MAX_STACK_SIZE,
         };
 
+        res.bind_global("current-input-port".to_string(), res.current_input.clone());
+        res.bind_global("current-output-port".to_string(), res.current_output.clone());
+        res.bind_global("current-error-port".to_string(), res.current_error.clone());
+
         let stdlib_scm = include_str!("stdlib.scm");
         res.run_str(stdlib_scm).unwrap();
+        res.builtin_globals = res.globals.clone();
+
+        for (name, names) in LIBRARIES.iter() {
+            res.libraries.insert(name.to_string(), Library::builtin(name, names));
+        }
+        // Everything built in, including what isn't part of any standard
+        let all: Vec<&str> = res.global_funcs.keys().chain(res.globals.keys())
+                                .map(|name| name.as_str())
+                                .filter(|name| !name.starts_with('$'))
+                                .collect();
+        let all = Library::builtin("(pongascript)", &all);
+        res.libraries.insert(all.name.clone(), all);
 
         res
     }
@@ -60,7 +210,11 @@ impl Runtime {
     }
 
     pub fn bind_global(&mut self, s: String, pong: Ponga) {
-        self.globals.insert(s, pong);
+        if is_mangled(&s) {
+            self.library_globals.insert(s, pong);
+        } else {
+            self.globals.insert(s, pong);
+        }
     }
 
     pub fn unbind_global(&mut self, s: &str) {
@@ -68,33 +222,130 @@ impl Runtime {
     }
 
     pub fn add_roots_to_gc(&mut self) {
-        self.gc.roots = std::collections::HashSet::new();
+        let mut roots = vec![];
         for v in self.globals.values() {
-            match v {
-                Ponga::Ref(id) => { self.gc.roots.insert(*id); },
-                _ => (),
-            }
+            v.trace(&mut roots);
         }
         for vec in self.locals.values() {
             for i in vec.iter() {
-                match i {
-                    Ponga::Ref(id) => { self.gc.roots.insert(*id); },
-                    _ => (),
-                }
+                i.trace(&mut roots);
+            }
+        }
+        for (_, v) in self.other_roots() {
+            v.trace(&mut roots);
+        }
+        for parked in self.parked.iter() {
+            trace_stacks(&parked.ins_stack, &parked.data_stack, &parked.locals, &mut roots);
+            parked.parameters.trace(&mut roots);
+        }
+        self.gc.roots = roots.into_iter().collect();
+    }
+
+    // What the runtime holds on to besides the globals and locals
+    pub fn other_roots(&self) -> Vec<(String, Ponga)> {
+        let mut res = vec![
+            ("current input port".to_string(), self.current_input.clone()),
+            ("current output port".to_string(), self.current_output.clone()),
+            ("current error port".to_string(), self.current_error.clone()),
+            ("current environment".to_string(), Ponga::Ref(self.current_env)),
+            ("interaction environment".to_string(), self.interaction_env.clone()),
+        ];
+        for val in self.pinned.iter() {
+            res.push(("pinned value".to_string(), val.clone()));
+        }
+        res.push(("current thread".to_string(), self.current_thread.clone()));
+        for id in self.threads.iter() {
+            res.push(("thread".to_string(), Ponga::Ref(*id)));
+        }
+        for (id, val) in self.parameters.values.iter().sorted_by_key(|(id, _)| **id) {
+            res.push(("parameterized parameter".to_string(), Ponga::Ref(*id)));
+            res.push(("parameter value".to_string(), val.clone()));
+        }
+        for (id, val) in self.parameters.saves.iter() {
+            res.push(("parameterized parameter".to_string(), Ponga::Ref(*id)));
+            if let Some(val) = val {
+                res.push(("saved parameter value".to_string(), val.clone()));
             }
         }
+        for (name, val) in self.builtin_globals.iter().sorted_by(|a, b| a.0.cmp(b.0)) {
+            res.push((format!("builtin {}", name), val.clone()));
+        }
+        for (name, val) in self.library_globals.iter().sorted_by(|a, b| a.0.cmp(b.0)) {
+            res.push((name.clone(), val.clone()));
+        }
+        res
     }
 
     pub fn collect_garbage(&mut self) {
+        self.collect_garbage_holding(&[], &[]);
+    }
+
+    // Also keeps what the stacks of the loop that is collecting hold
+    fn collect_garbage_holding(&mut self, ins_stack: &[Instruction], data_stack: &[Ponga]) {
         self.add_roots_to_gc();
+        let mut roots = vec![];
+        for ins in ins_stack.iter() {
+            ins.trace(&mut roots);
+        }
+        for val in data_stack.iter() {
+            val.trace(&mut roots);
+        }
+        self.gc.roots.extend(roots);
         self.gc.collect_garbage();
         self.gc.roots.clear();
+        self.run_finalizers();
+    }
+
+    // A finalizer that fails doesn't stop the others from running. The ones
+    // still waiting stay with the gc, which keeps them alive.
+    pub fn run_finalizers(&mut self) {
+        while !self.gc.pending_finalizers.is_empty() {
+            let (func, obj) = self.gc.pending_finalizers.remove(0);
+            if let Err(e) = self.call(func, vec![obj]) {
+                self.finalizer_errors.push(e);
+            }
+        }
+    }
+
+    pub fn take_finalizer_errors(&mut self) -> Vec<RuntimeErr> {
+        std::mem::take(&mut self.finalizer_errors)
+    }
+
+    pub fn set_native_finalizer(&mut self, pong: &Ponga,
+                                func: Box<dyn FnMut(Ponga) + Send>) -> RunRes<()> {
+        match pong {
+            Ponga::Ref(id) => {
+                self.gc.add_finalizer(*id, Finalizer::Native(func));
+                Ok(())
+            }
+            _ => Err(RuntimeErr::TypeError(format!(
+                "finalizers can only be set on heap objects (not {:?})", pong
+            ))),
+        }
+    }
+
+    pub fn heap_snapshot(&self) -> HeapSnapshot {
+        HeapSnapshot::new(self)
+    }
+
+    // Writes a DOT graph if the file ends in .dot, and JSON otherwise
+    pub fn write_heap_dump(&self, filename: &str) -> RunRes<()> {
+        let snapshot = self.heap_snapshot();
+        let contents = if filename.ends_with(".dot") {
+            snapshot.to_dot()
+        } else {
+            snapshot.to_json()
+        };
+        let mut file = File::create(filename)?;
+        file.write_all(contents.as_bytes())?;
+        eprint!("{}", snapshot.summary(10));
+        Ok(())
     }
 
     pub fn get_id_obj_ref(&self, id: Id) -> RunRes<&GcObj> {
         self.gc
             .ptrs
-            .get(&id)
+            .get(id)
             .ok_or(RuntimeErr::ReferenceError(format!(
                 "Reference {} not found",
                 id
@@ -104,7 +355,7 @@ impl Runtime {
     pub fn get_id_obj(&mut self, id: Id) -> RunRes<&mut GcObj> {
         self.gc
             .ptrs
-            .get_mut(&id)
+            .get_mut(id)
             .ok_or(RuntimeErr::ReferenceError(format!(
                 "Reference {} not found",
                 id
@@ -122,7 +373,15 @@ impl Runtime {
             Some(v) => return Ok(v),
             None => (),
         }
-        match self.global_funcs.get(identifier) {
+        if let Some(v) = self.library_globals.get(identifier) {
+            return Ok(v);
+        }
+        if let Some(global) = self.imports.get(identifier) {
+            return self.imported(global).ok_or_else(|| RuntimeErr::ReferenceError(format!(
+                "Identifier {} is imported from {}, which isn't bound", identifier, global
+            )));
+        }
+        match self.global_funcs.get(identifier).filter(|_| self.builtins) {
             Some(v) => Ok(v),
             None => Err(RuntimeErr::ReferenceError(format!(
                 "Identifier {} not found",
@@ -148,11 +407,27 @@ impl Runtime {
             }
             None => (),
         }
+        if let Some(v) = self.library_globals.get_mut(identifier) {
+            *v = pong;
+            return Ok(());
+        }
+        if self.imports.contains_key(identifier) {
+            return Err(RuntimeErr::Other(format!(
+                "{} is imported, so it can't be set", identifier
+            )));
+        }
         Err(RuntimeErr::ReferenceError(format!(
                     "Identifier {} used in set is unknown", identifier
         )))
     }
 
+    // What an import refers to: a library's definition or a builtin
+    fn imported(&self, global: &str) -> Option<&Ponga> {
+        self.library_globals.get(global)
+            .or_else(|| self.builtin_globals.get(global))
+            .or_else(|| self.global_funcs.get(global))
+    }
+
     pub fn pop_local(&mut self, identifier: &str) -> Ponga {
         let vec = self.locals.get_mut(identifier).unwrap();
         let res = vec.pop().unwrap();
@@ -188,6 +463,82 @@ impl Runtime {
         }
     }
 
+    // Pops a local once its scope is done, writing it back to the state of
+    // the closure it came from if there is one
+    fn pop_scope_local(&mut self, s: String, opt_id: Option<Id>) -> RunRes<()> {
+        let val = self.pop_local(&s);
+        if let Some(id) = opt_id {
+            let _ = self.set_identifier(&s, val.clone());
+            let state_obj = self.get_id_obj(id)?;
+            let mut borrowed = state_obj.borrow_mut().unwrap();
+            borrowed.inner()
+                    .extract_map_ref_mut()
+                    .unwrap()
+                    .insert(s, val);
+        }
+        Ok(())
+    }
+
+    fn pop_frame(&mut self, frame: Vec<(String, Option<Id>)>) -> RunRes<()> {
+        for (s, opt_id) in frame.into_iter() {
+            self.pop_scope_local(s, opt_id)?;
+        }
+        Ok(())
+    }
+
+    // A call in tail position lands on top of the frames it returns through,
+    // which are popped one after another with nothing run in between. A local
+    // in them that is hidden by a newer one of the same name, written back to
+    // the same place, can't be seen again: the newer one is popped first and
+    // writes over it. Those are dropped along with frames left empty, so that
+    // a loop through tail calls and let bodies doesn't grow the stack.
+    // Frames list their locals newest first.
+    fn drop_hidden_locals(&mut self, ins_stack: &mut Vec<Instruction>,
+                          frame: &[(String, Option<Id>)]) {
+        let start = ins_stack.iter()
+                             .rposition(|ins| !matches!(ins, Instruction::PopFrame(_)))
+                             .map_or(0, |i| i + 1);
+        if start == ins_stack.len() {
+            return;
+        }
+        // Each name's locals in the frames, oldest first, by frame and index
+        let mut chains: HashMap<String, Vec<(usize, usize, Option<Id>)>> = HashMap::new();
+        for (i, ins) in ins_stack[start..].iter().enumerate() {
+            if let Instruction::PopFrame(locals) = ins {
+                for (j, (s, opt_id)) in locals.iter().enumerate().rev() {
+                    chains.entry(s.clone()).or_default().push((start + i, j, *opt_id));
+                }
+            }
+        }
+        let mut hidden = vec![];
+        for (s, chain) in chains.iter() {
+            let newest = frame.iter().rev().find(|(name, _)| name == s).map(|(_, opt_id)| *opt_id);
+            let vals = self.locals.get_mut(s).unwrap();
+            let base = vals.len() - chain.len();
+            for (k, &(i, j, opt_id)) in chain.iter().enumerate().rev() {
+                let newer = chain.get(k + 1).map(|(_, _, opt_id)| *opt_id).or(newest);
+                if newer == Some(opt_id) {
+                    vals.remove(base + k);
+                    hidden.push((i, j));
+                }
+            }
+        }
+        // Indices within a frame are removed from the back so the rest stay put
+        hidden.sort_unstable();
+        for (i, j) in hidden.into_iter().rev() {
+            if let Instruction::PopFrame(locals) = &mut ins_stack[i] {
+                locals.remove(j);
+            }
+        }
+        let mut i = start;
+        while i < ins_stack.len() {
+            match &ins_stack[i] {
+                Instruction::PopFrame(locals) if locals.is_empty() => { ins_stack.remove(i); }
+                _ => i += 1,
+            }
+        }
+    }
+
     pub fn push_local(&mut self, identifier: &str, pong: Ponga) {
         self.locals
             .entry(identifier.to_string())
@@ -250,9 +601,401 @@ impl Runtime {
     }
 
     pub fn eval(&mut self, pong: Ponga) -> RunRes<Ponga> {
+        self.run(vec![Instruction::Eval(pong)])
+    }
+
+    // Calls a procedure on arguments that have already been evaluated, and
+    // so are passed through as they are
+    pub fn call(&mut self, func: Ponga, args: Vec<Ponga>) -> RunRes<Ponga> {
+        let mut ins_stack = self.call_instructions(func, args)?;
+        ins_stack.reverse();
+        self.run(ins_stack)
+    }
+
+    // Makes the builtin currently running return the result of calling the
+    // procedure instead of its own, without nesting another loop
+    pub fn tail_call(&mut self, func: Ponga, args: Vec<Ponga>) -> RunRes<()> {
+        let ins = self.call_instructions(func, args)?;
+        self.scheduled.push(Instruction::PopStack);
+        self.scheduled.extend(ins);
+        Ok(())
+    }
+
+    // Calls the procedure on each argument list in order once the builtin
+    // currently running returns
+    pub fn call_each(&mut self, func: Ponga, mut arg_lists: Vec<Vec<Ponga>>) -> RunRes<()> {
+        let func = self.check_callable(func)?;
+        arg_lists.reverse();
+        self.scheduled.push(Instruction::CallEach(func, arg_lists));
+        Ok(())
+    }
+
+    // Makes the builtin currently running return the results of calling the
+    // procedure on each argument list, as an inline vector, stopping early
+    // after the first result `until` picks out
+    pub fn tail_map(&mut self, func: Ponga, mut arg_lists: Vec<Vec<Ponga>>,
+                    until: Until) -> RunRes<()> {
+        let func = self.check_callable(func)?;
+        self.scheduled.push(Instruction::PopStack);
+        if arg_lists.is_empty() {
+            self.scheduled.push(Instruction::Data(Ponga::Array(vec![])));
+            return Ok(());
+        }
+        arg_lists.reverse();
+        let first = arg_lists.pop().unwrap();
+        let call = self.call_instructions(func.clone(), first)?;
+        self.scheduled.extend(call);
+        self.scheduled.push(Instruction::MapEach(func, arg_lists, vec![], until));
+        Ok(())
+    }
+
+    // Makes the builtin currently running return the accumulator after
+    // passing it through the procedure with each argument list in turn
+    pub fn tail_fold(&mut self, func: Ponga, init: Ponga, mut arg_lists: Vec<Vec<Ponga>>,
+                     acc_last: bool) -> RunRes<()> {
+        let func = self.check_callable(func)?;
+        arg_lists.reverse();
+        self.scheduled.push(Instruction::PopStack);
+        self.scheduled.push(Instruction::Data(init));
+        self.scheduled.push(Instruction::Fold(func, arg_lists, acc_last));
+        Ok(())
+    }
+
+    // Makes the builtin currently running return what the continuation
+    // returns for the arguments
+    pub fn tail_continue(&mut self, k: Continuation, args: Vec<Ponga>) {
+        self.scheduled.push(Instruction::PopStack);
+        let n_args = args.len();
+        self.scheduled.extend(args.into_iter().rev().map(Instruction::Data));
+        self.scheduled.push(Instruction::Continue(k, n_args));
+    }
+
+    // Passes what the builtin currently running returns, including anything
+    // it has already scheduled, on to the continuation as its last argument
+    pub fn then(&mut self, k: Continuation, args: Vec<Ponga>) {
+        let n_args = args.len() + 1;
+        self.scheduled.extend(args.into_iter().rev().map(Instruction::Data));
+        self.scheduled.push(Instruction::Continue(k, n_args));
+    }
+
+    // Forces the promise once the builtin currently running returns
+    pub fn tail_force(&mut self, promise: Ponga) {
+        self.scheduled.push(Instruction::PopStack);
+        self.scheduled.push(Instruction::Data(promise));
+        self.scheduled.push(Instruction::Force(None));
+    }
+
+    // The value of a promise, or the value itself if it isn't one
+    pub fn force(&mut self, promise: Ponga) -> RunRes<Ponga> {
+        self.run(vec![Instruction::Force(None), Instruction::Data(promise)])
+    }
+
+    pub fn make_promise(&mut self, state: Promise) -> Ponga {
+        self.gc.ponga_into_gc_ref(Ponga::Promise(state))
+    }
+
+    // The id and state of the promise that holds the state of this one, or
+    // None if it isn't a promise
+    pub fn promise_state(&self, promise: &Ponga) -> RunRes<Option<(Id, Promise)>> {
+        let mut id = match promise {
+            Ponga::Ref(id) => *id,
+            _ => return Ok(None),
+        };
+        loop {
+            let obj = self.get_id_obj_ref(id)?.borrow().unwrap();
+            match obj.inner() {
+                Ponga::Promise(Promise::Forward(next)) => id = *next,
+                Ponga::Promise(state) => return Ok(Some((id, state.clone()))),
+                _ => return Ok(None),
+            }
+        }
+    }
+
+    fn set_promise_state(&self, id: Id, state: Promise) -> RunRes<()> {
+        let mut obj = self.get_id_obj_ref(id)?.borrow_mut().unwrap();
+        *obj.inner() = Ponga::Promise(state);
+        Ok(())
+    }
+
+    // In execution order, for handling the result of the thunk of the
+    // promise with this id
+    fn force_promise(&mut self, id: Id, result: Ponga) -> RunRes<Vec<Instruction>> {
+        let (id, state) = self.promise_state(&Ponga::Ref(id))?.unwrap();
+        let delay_force = match state {
+            // Forcing the thunk forced this promise too
+            Promise::Done(val) => return Ok(vec![Instruction::Data(*val)]),
+            Promise::Delayed(_, delay_force) => delay_force,
+            Promise::Forward(_) => unreachable!(),
+        };
+        if !delay_force {
+            self.set_promise_state(id, Promise::Done(Box::new(result.clone())))?;
+            return Ok(vec![Instruction::Data(result)]);
+        }
+        match self.promise_state(&result)? {
+            Some((_, Promise::Done(val))) => {
+                self.set_promise_state(id, Promise::Done(val.clone()))?;
+                Ok(vec![Instruction::Data(*val)])
+            }
+            // Takes over the other promise's thunk rather than forcing it
+            // inside this one, so chains of delay-force run in constant space
+            Some((other, Promise::Delayed(thunk, delay_force))) if other != id => {
+                self.set_promise_state(id, Promise::Delayed(thunk.clone(), delay_force))?;
+                self.set_promise_state(other, Promise::Forward(id))?;
+                let mut res = self.call_instructions(*thunk, vec![])?;
+                res.push(Instruction::Force(Some(id)));
+                Ok(res)
+            }
+            _ => Err(RuntimeErr::TypeError(format!(
+                "delay-force expression must produce another promise, received {}",
+                self.ponga_to_string(&result)
+            ))),
+        }
+    }
+
+    // Calls consumer with the values produced by producer once the builtin
+    // currently running returns
+    pub fn call_with_values(&mut self, producer: Ponga, consumer: Ponga) -> RunRes<()> {
+        let consumer = self.check_callable(consumer)?;
+        let call = self.call_instructions(producer, vec![])?;
+        self.scheduled.push(Instruction::PopStack);
+        self.scheduled.push(Instruction::Data(consumer));
+        self.scheduled.extend(call);
+        self.scheduled.push(Instruction::ApplyValues);
+        Ok(())
+    }
+
+    fn check_callable(&mut self, func: Ponga) -> RunRes<Ponga> {
+        match self.id_or_ref_peval(func)? {
+            func@(Ponga::HFunc(_) | Ponga::CFunc(_, _, _, _)) => Ok(func),
+            func if self.is_applicable_object(&func) => Ok(func),
+            Ponga::MFunc(_, _) => Err(RuntimeErr::TypeError(
+                "Macros can't be called as procedures".to_string()
+            )),
+            other => Err(RuntimeErr::TypeError(format!(
+                "Expected procedure, received {}", self.ponga_to_string(&other)
+            ))),
+        }
+    }
+
+    // In execution order. Call takes its first argument from the top of the
+    // data stack, so the arguments are pushed last first.
+    fn call_instructions(&mut self, func: Ponga, args: Vec<Ponga>) -> RunRes<Vec<Instruction>> {
+        let func = self.check_callable(func)?;
+        let n_args = args.len();
+        let mut res = vec![Instruction::Data(func)];
+        res.extend(args.into_iter().rev().map(Instruction::Data));
+        res.push(Instruction::Call(n_args));
+        Ok(res)
+    }
+
+    // In execution order, for a cond or case clause whose test produced val.
+    // `=>` passes val to a procedure and an empty body produces val itself.
+    fn clause_instructions(&mut self, val: Ponga, mut body: Vec<Ponga>) -> RunRes<Vec<Instruction>> {
+        match body.first() {
+            None => Ok(vec![Instruction::Data(val)]),
+            Some(Ponga::Identifier(s)) if s == "=>" => {
+                if body.len() != 2 {
+                    return Err(RuntimeErr::Other(
+                        "=> must be followed by one expression".to_string()
+                    ));
+                }
+                let func = body.pop().unwrap();
+                Ok(vec![Instruction::Eval(func), Instruction::Data(val), Instruction::Call(1)])
+            }
+            Some(_) => Ok(vec![Instruction::Eval(sequence(body))]),
+        }
+    }
+
+    // In execution order, for trying the cond clauses from the first
+    fn cond_instructions(&mut self, clauses: Vec<Ponga>) -> RunRes<Vec<Instruction>> {
+        let mut clauses = clauses.into_iter();
+        let mut clause = match clauses.next() {
+            Some(Ponga::Sexpr(clause)) if !clause.is_empty() => clause.into_iter(),
+            Some(_) => return Err(RuntimeErr::TypeError(
+                "cond requires S-Expr clauses".to_string()
+            )),
+            None => return Ok(vec![Instruction::Data(Ponga::Null)]),
+        };
+        match clause.next().unwrap() {
+            Ponga::Identifier(s) if s == "else" => {
+                self.clause_instructions(Ponga::True, clause.collect())
+            }
+            test => Ok(vec![
+                Instruction::Test(test),
+                Instruction::Cond(clause.collect(), clauses.collect()),
+            ]),
+        }
+    }
+
+    // In execution order, for the first case clause that matches the key
+    fn case_instructions(&mut self, key: Ponga, clauses: Vec<Ponga>) -> RunRes<Vec<Instruction>> {
+        for clause in clauses.into_iter() {
+            let mut clause = match clause {
+                Ponga::Sexpr(clause) if !clause.is_empty() => clause.into_iter(),
+                _ => return Err(RuntimeErr::TypeError(
+                    "case requires S-Expr clauses".to_string()
+                )),
+            };
+            let matched = match clause.next().unwrap() {
+                Ponga::Identifier(s) if s == "else" => true,
+                Ponga::Sexpr(data) => {
+                    let data: Vec<Ponga> = data.into_iter().map(|datum| self.quoted(datum)).collect();
+                    Equiv::Eqv.position(self, &key, &data)?.is_some()
+                }
+                _ => return Err(RuntimeErr::TypeError(
+                    "case clauses must start with a list of data or else".to_string()
+                )),
+            };
+            if matched {
+                return self.clause_instructions(key, clause.collect());
+            }
+        }
+        Ok(vec![Instruction::Data(Ponga::Null)])
+    }
+
+    // A procedure that sees the locals bound where it's made, and the globals
+    // of the environment it's made in
+    fn make_closure(&mut self, params: Vec<String>, body: Ponga) -> Ponga {
+        let state = Ponga::Object(self.condense_locals());
+        let state_id = self.gc.add_obj(state);
+        let body_id = self.gc.add_obj(body);
+        Ponga::CFunc(params, body_id, state_id, self.current_env)
+    }
+
+    // Source code as the data it quotes
+    fn quoted(&self, val: Ponga) -> Ponga {
+        match val.deep_copy(self) {
+            Ponga::Sexpr(arr) => Ponga::List(arr.into_iter().collect()),
+            Ponga::Identifier(s) => Ponga::Symbol(s),
+            val => val,
+        }
+    }
+
+    // Turns data back into code, undoing what datum does
+    pub fn code(&self, val: Ponga) -> RunRes<Ponga> {
+        match val {
+            Ponga::Symbol(s) => Ok(Ponga::Identifier(s)),
+            Ponga::List(list) if list.is_empty() => Ok(Ponga::List(list)),
+            Ponga::List(list) => {
+                Ok(Ponga::Sexpr(list.into_iter().map(|x| self.code(x)).collect::<RunRes<_>>()?))
+            }
+            Ponga::Sexpr(arr) => {
+                Ok(Ponga::Sexpr(arr.into_iter().map(|x| self.code(x)).collect::<RunRes<_>>()?))
+            }
+            Ponga::Ref(id) => {
+                let inner = self.get_id_obj_ref(id)?.borrow().unwrap().clone();
+                match inner {
+                    Ponga::List(_) => self.code(inner),
+                    _ => Ok(Ponga::Ref(id)),
+                }
+            }
+            val => Ok(val),
+        }
+    }
+
+    // Turns parsed code into the data read gives back, with quote
+    // abbreviations spelled out as (quote x)
+    pub fn datum(&mut self, val: Ponga) -> Ponga {
+        let quote = |runtime: &mut Runtime, val| {
+            let items = [Ponga::Symbol("quote".to_string()), val];
+            runtime.gc.ponga_into_gc_ref(Ponga::List(items.into_iter().collect()))
+        };
+        match val {
+            Ponga::Identifier(s) => Ponga::Symbol(s),
+            Ponga::Symbol(s) => quote(self, Ponga::Symbol(s)),
+            Ponga::Sexpr(arr) => {
+                let list = arr.into_iter().map(|x| self.datum(x)).collect();
+                self.gc.ponga_into_gc_ref(Ponga::List(list))
+            }
+            Ponga::List(list) => {
+                let list = list.into_iter().map(|x| self.datum(x)).collect();
+                let list = self.gc.ponga_into_gc_ref(Ponga::List(list));
+                quote(self, list)
+            }
+            Ponga::Array(arr) => {
+                let arr = arr.into_iter().map(|x| self.datum(x)).collect();
+                self.gc.ponga_into_gc_ref(Ponga::Array(arr))
+            }
+            Ponga::Bytevector(bytes) => self.gc.ponga_into_gc_ref(Ponga::Bytevector(bytes)),
+            val => val,
+        }
+    }
+
+    // Calls a builtin or continuation, then runs whatever it scheduled
+    fn call_builtin(&mut self, func: impl FnOnce(&mut Runtime) -> RunRes<Ponga>,
+                    ins_stack: &mut Vec<Instruction>, data_stack: &mut Vec<Ponga>) -> RunRes<()> {
+        let outer = std::mem::take(&mut self.scheduled);
+        let res = func(self);
+        let scheduled = std::mem::replace(&mut self.scheduled, outer);
+        data_stack.push(res?);
+        ins_stack.extend(scheduled.into_iter().rev());
+        Ok(())
+    }
+
+    fn run(&mut self, ins_stack: Vec<Instruction>) -> RunRes<Ponga> {
+        // What the evaluation could leave half done if it fails, parked so
+        // that a collection during it doesn't free what the locals held
+        let env = self.current_env;
+        self.parked.push(Parked {
+            data_stack: vec![Ponga::Ref(env)],
+            locals: self.locals.clone(),
+            ..Parked::default()
+        });
+        let parameters = self.parameters.saves.len();
+        let loading = self.loading.len();
+        let res = self.run_instructions(ins_stack);
+        let parked = self.parked.pop().unwrap();
+        if res.is_err() {
+            self.locals = parked.locals;
+            self.switch_environment(env)?;
+            self.unbind_parameters(self.parameters.saves.len() - parameters)?;
+            self.loading.truncate(loading);
+        }
+        res
+    }
+
+    fn run_instructions(&mut self, ins_stack: Vec<Instruction>) -> RunRes<Ponga> {
+        match self.run_loop(ins_stack, vec![], LoopMode::Nested)? {
+            Exit::Done(val) => Ok(val),
+            Exit::Suspended(_, _) => unreachable!(),
+        }
+    }
+
+    fn run_loop(&mut self, ins_stack: Vec<Instruction>, data_stack: Vec<Ponga>,
+                mode: LoopMode) -> RunRes<Exit> {
+        self.unrooted += 1;
+        let res = self.run_steps(ins_stack, data_stack, mode);
+        self.unrooted -= 1;
+        res
+    }
+
+    // Lets another loop run on top of this one with this one's stacks where
+    // the gc can see them
+    fn while_parked<T>(&mut self, ins_stack: &mut Vec<Instruction>, data_stack: &mut Vec<Ponga>,
+                       func: impl FnOnce(&mut Runtime) -> T) -> T {
+        self.parked.push(Parked {
+            ins_stack: std::mem::take(ins_stack),
+            data_stack: std::mem::take(data_stack),
+            ..Parked::default()
+        });
+        self.unrooted -= 1;
+        let res = func(self);
+        self.unrooted += 1;
+        let parked = self.parked.pop().unwrap();
+        *ins_stack = parked.ins_stack;
+        *data_stack = parked.data_stack;
+        res
+    }
+
+    // Only the loop a green thread was given its turn in gives the turn back
+    // when it's used up or the thread blocks. Any other loop lets the other
+    // threads run on top of it instead. Every time slice, the loop collects
+    // garbage if it's due and nothing but this loop is holding values the gc
+    // can't see.
+    fn run_steps(&mut self, mut ins_stack: Vec<Instruction>, mut data_stack: Vec<Ponga>,
+                 mode: LoopMode) -> RunRes<Exit> {
         use Ponga::*;
-        let mut data_stack = vec![];
-        let mut ins_stack = vec![Instruction::Eval(pong)];
+        let mut steps = 0;
         loop {
             //println!("{}", ins_stack[ins_stack.len() - 1]);
             // if data_stack.len() != 0 {
@@ -260,35 +1003,239 @@ impl Runtime {
             //         &data_stack[data_stack.len() - 1]
             //     ));
             // }
-            if ins_stack.len() > MAX_STACK_SIZE {
+            if ins_stack.len() > self.max_stack_size {
                 return Err(RuntimeErr::Other(format!(
-                    "Stack size exceeded max of {}", MAX_STACK_SIZE
+                    "Stack size exceeded max of {}", self.max_stack_size
                 )));
             }
-            match ins_stack.pop().unwrap() {
+            if self.yielded.is_some() {
+                if mode == LoopMode::Coroutine {
+                    return Ok(Exit::Suspended(ins_stack, data_stack));
+                }
+                self.yielded = None;
+                return Err(RuntimeErr::Other(
+                    "yield must be called from the body of a coroutine generator".to_string()
+                ));
+            }
+            if steps % TIME_SLICE == 0 && self.unrooted == 1 && self.gc.collection_due() {
+                self.collect_garbage_holding(&ins_stack, &data_stack);
+            }
+            if self.blocked.is_some() || steps == TIME_SLICE {
+                steps = 0;
+                if mode == LoopMode::Thread {
+                    return Ok(Exit::Suspended(ins_stack, data_stack));
+                }
+                self.while_parked(&mut ins_stack, &mut data_stack,
+                                  |runtime| runtime.run_other_threads())?;
+            }
+            steps += 1;
+            let ins = match ins_stack.pop() {
+                Some(ins) => ins,
+                None => break,
+            };
+            match ins {
                 Instruction::PopStack => {
                     data_stack.pop();
                 }
+                Instruction::Data(val) => data_stack.push(val),
+                Instruction::Force(None) => {
+                    let val = pop_or(&mut data_stack)?;
+                    match self.promise_state(&val)? {
+                        Some((_, crate::promise::Promise::Done(val))) => data_stack.push(*val),
+                        Some((id, crate::promise::Promise::Delayed(thunk, _))) => {
+                            ins_stack.push(Instruction::Force(Some(id)));
+                            let call = self.call_instructions(*thunk, vec![])?;
+                            ins_stack.extend(call.into_iter().rev());
+                        }
+                        _ => data_stack.push(val),
+                    }
+                }
+                Instruction::Force(Some(id)) => {
+                    let result = pop_or(&mut data_stack)?;
+                    let res = self.force_promise(id, result)?;
+                    ins_stack.extend(res.into_iter().rev());
+                }
+                Instruction::Do(steps, test, results, commands) => {
+                    if pop_or(&mut data_stack)? != Ponga::False {
+                        ins_stack.push(Instruction::Eval(sequence(results)));
+                    } else {
+                        // The steps are all evaluated before any variable is
+                        // updated
+                        ins_stack.push(Instruction::Do(steps.clone(), test.clone(), results, commands.clone()));
+                        ins_stack.push(Instruction::Test(test));
+                        for (name, _) in steps.iter() {
+                            ins_stack.push(Instruction::PopStack);
+                            ins_stack.push(Instruction::Set(name.clone()));
+                        }
+                        for (_, step) in steps.into_iter().rev() {
+                            ins_stack.push(Instruction::Eval(step));
+                        }
+                        for command in commands.into_iter().rev() {
+                            ins_stack.push(Instruction::PopStack);
+                            ins_stack.push(Instruction::Eval(command));
+                        }
+                    }
+                }
+                Instruction::PatchClosures(names) => {
+                    let mut bound = vec![];
+                    for name in names.into_iter() {
+                        let val = self.get_identifier_obj_ref(&name)?.clone();
+                        bound.push((name, val));
+                    }
+                    for (_, val) in bound.iter() {
+                        if let CFunc(_, _, state_id, _) = val {
+                            let state_obj = self.get_id_obj_ref(*state_id)?;
+                            let mut borrowed = state_obj.borrow_mut().unwrap();
+                            let state = borrowed.inner().extract_map_ref_mut().unwrap();
+                            for (name, val) in bound.iter() {
+                                state.insert(name.clone(), val.clone());
+                            }
+                        }
+                    }
+                }
+                Instruction::BindValues(names, rest) => {
+                    let val = pop_or(&mut data_stack)?;
+                    for (name, val) in self.match_values(val, &names, rest)? {
+                        self.push_local(&name, val);
+                    }
+                }
+                Instruction::DefineValues(names, rest) => {
+                    let val = pop_or(&mut data_stack)?;
+                    for (name, val) in self.match_values(val, &names, rest)? {
+                        self.bind_global(name, val);
+                    }
+                    data_stack.push(Ponga::Null);
+                }
+                Instruction::ApplyValues => {
+                    let vals = self.spread_values(pop_or(&mut data_stack)?);
+                    ins_stack.push(Instruction::Call(vals.len()));
+                    data_stack.extend(vals.into_iter().rev());
+                }
+                Instruction::Continue(k, n_args) => {
+                    let mut args = Vec::new();
+                    for _ in 0..n_args {
+                        args.push(pop_or(&mut data_stack)?);
+                    }
+                    self.call_builtin(|runtime| k.call(runtime, args),
+                                      &mut ins_stack, &mut data_stack)?;
+                }
+                Instruction::MapEach(func, mut arg_lists, mut results, until) => {
+                    let res = pop_or(&mut data_stack)?;
+                    let stop = match until {
+                        Until::End => false,
+                        Until::True => res != False,
+                        Until::False => res == False,
+                    };
+                    results.push(res);
+                    match arg_lists.pop() {
+                        Some(args) if !stop => {
+                            let n_args = args.len();
+                            ins_stack.push(Instruction::MapEach(func.clone(), arg_lists, results, until));
+                            ins_stack.push(Instruction::Call(n_args));
+                            data_stack.push(func);
+                            data_stack.extend(args.into_iter().rev());
+                        }
+                        _ => data_stack.push(Array(results)),
+                    }
+                }
+                Instruction::Fold(func, mut arg_lists, acc_last) => {
+                    let acc = pop_or(&mut data_stack)?;
+                    match arg_lists.pop() {
+                        Some(mut args) => {
+                            if acc_last {
+                                args.push(acc);
+                            } else {
+                                args.insert(0, acc);
+                            }
+                            let n_args = args.len();
+                            ins_stack.push(Instruction::Fold(func.clone(), arg_lists, acc_last));
+                            ins_stack.push(Instruction::Call(n_args));
+                            data_stack.push(func);
+                            data_stack.extend(args.into_iter().rev());
+                        }
+                        None => data_stack.push(acc),
+                    }
+                }
+                Instruction::Test(test) => {
+                    ins_stack.push(Instruction::Eval(self.id_or_ref_peval(test)?));
+                }
+                Instruction::If(consequent, alternative) => {
+                    let val = match pop_or(&mut data_stack)? {
+                        False => alternative,
+                        _ => consequent,
+                    };
+                    ins_stack.push(Instruction::Eval(val));
+                }
+                Instruction::Cond(body, clauses) => {
+                    let res = match pop_or(&mut data_stack)? {
+                        False => self.cond_instructions(clauses)?,
+                        val => self.clause_instructions(val, body)?,
+                    };
+                    ins_stack.extend(res.into_iter().rev());
+                }
+                Instruction::Case(clauses) => {
+                    let key = pop_or(&mut data_stack)?;
+                    let res = self.case_instructions(key, clauses)?;
+                    ins_stack.extend(res.into_iter().rev());
+                }
+                Instruction::FlipEval => {
+                    let val = pop_or(&mut data_stack)?;
+                    ins_stack.push(Instruction::Eval(val.flip_code_vals(self)));
+                }
+                Instruction::ConvertParameters(n) => {
+                    let mut res = vec![];
+                    for _ in 0..n {
+                        let val = pop_or(&mut data_stack)?;
+                        let param = pop_or(&mut data_stack)?;
+                        let converter = self.parameter(&param)?.1.converter;
+                        let mut binding = vec![Instruction::Data(param)];
+                        match converter {
+                            Some(converter) => {
+                                binding.extend(self.call_instructions(*converter, vec![val])?);
+                            }
+                            None => binding.push(Instruction::Data(val)),
+                        }
+                        res.push(binding);
+                    }
+                    res.reverse();
+                    ins_stack.push(Instruction::BindParameters(n));
+                    ins_stack.extend(res.into_iter().flatten().rev());
+                }
+                Instruction::BindParameters(n) => {
+                    let mut bindings = vec![];
+                    for _ in 0..n {
+                        let val = pop_or(&mut data_stack)?;
+                        bindings.push((pop_or(&mut data_stack)?, val));
+                    }
+                    bindings.reverse();
+                    self.bind_parameters(bindings)?;
+                }
+                Instruction::UnbindParameters(n) => self.unbind_parameters(n)?,
+                Instruction::LeaveFile => self.leave_file(),
+                Instruction::RestoreEnvironment(env) => {
+                    self.switch_environment(env)?;
+                }
+                Instruction::LeaveEnvironment(env, locals) => {
+                    self.switch_environment(env)?;
+                    self.locals = locals;
+                }
+                Instruction::CallEach(func, mut arg_lists) => {
+                    if let Some(args) = arg_lists.pop() {
+                        let n_args = args.len();
+                        ins_stack.push(Instruction::CallEach(func.clone(), arg_lists));
+                        ins_stack.push(Instruction::PopStack);
+                        ins_stack.push(Instruction::Call(n_args));
+                        data_stack.push(func);
+                        data_stack.extend(args.into_iter().rev());
+                    }
+                }
                 Instruction::Define(s) => {
                     self.bind_global(s, pop_or(&mut data_stack)?);
                     data_stack.push(Ponga::Null);
                 }
                 Instruction::Push(s) => self.push_local(&s, pop_or(&mut data_stack)?),
-                Instruction::Pop(s, opt_id) => {
-                    let val = self.pop_local(&s);
-                    match opt_id {
-                        Some(id) => {
-                            match self.set_identifier(&s, val.clone()) {_=>()}; 
-                            let state_obj = self.get_id_obj(id)?;
-                            let mut borrowed = state_obj.borrow_mut().unwrap();
-                            borrowed.inner()
-                                    .extract_map_ref_mut()
-                                    .unwrap()
-                                    .insert(s, val);
-                        }
-                        _ => (),
-                    }
-                },
+                Instruction::Pop(s, opt_id) => self.pop_scope_local(s, opt_id)?,
+                Instruction::PopFrame(frame) => self.pop_frame(frame)?,
                 Instruction::Set(s) => { 
                     let data = pop_or(&mut data_stack)?;
                     self.set_identifier(&s, data)?;
@@ -331,36 +1278,44 @@ impl Runtime {
                     )?;
                     match func {
                         HFunc(id) => {
-                            data_stack.push(FUNCS[id].1(self, args)?);
+                            self.call_builtin(|runtime| FUNCS[id].1(runtime, args),
+                                              &mut ins_stack, &mut data_stack)?;
                         }
-                        CFunc(args_names, sexpr_id, state_id) => {
-                            let state_obj = self.get_id_obj_ref(state_id)?.clone();
-
-                            // Pop the entire state after we're done evaluating
-                            let ref_gc_obj = state_obj.borrow().unwrap();
-                            let state_map_ref = ref_gc_obj.extract_map_ref().unwrap();
-                            for (name, _) in state_map_ref.into_iter() {
-                                ins_stack.push(Instruction::Pop(name.clone(),
-                                                                Some(state_id)));
-                            } 
-
-                            // Pop all of the args before popping state
-                            for name in args_names.iter() {
-                                ins_stack.push(Instruction::Pop(name.clone(),
-                                                                None));
-                            } 
+                        CFunc(args_names, sexpr_id, state_id, env_id) => {
+                            if args.len() != args_names.len() {
+                                return Err(RuntimeErr::TypeError(format!(
+                                    "Expected {} arguments, received {}", args_names.len(), args.len()
+                                )));
+                            }
+                            let state_map = self.get_id_obj_ref(state_id)?
+                                                .borrow()
+                                                .unwrap()
+                                                .clone()
+                                                .extract_map()
+                                                .unwrap();
+
+                            // Pop all of the args, then the entire state, after
+                            // we're done evaluating
+                            let mut frame: Vec<(std::string::String, Option<Id>)> = args_names.iter()
+                                .map(|name| (name.clone(), None))
+                                .collect();
+                            frame.extend(state_map.keys().map(|name| (name.clone(), Some(state_id))));
+                            // The body runs in the environment the procedure
+                            // was made in
+                            if env_id != self.current_env {
+                                let prev = self.switch_environment(env_id)?;
+                                ins_stack.push(Instruction::RestoreEnvironment(prev));
+                            }
+                            self.drop_hidden_locals(&mut ins_stack, &frame);
+                            if !frame.is_empty() {
+                                ins_stack.push(Instruction::PopFrame(frame));
+                            }
 
                             // Push S-Expr to be evaluated
                             let sexpr_obj = self.get_id_obj_ref(sexpr_id)?;
                             let sexpr = sexpr_obj.borrow().unwrap().clone();
                             ins_stack.push(Instruction::Eval(sexpr));
 
-                            let state_map = state_obj.borrow()
-                                                     .unwrap()
-                                                     .clone()
-                                                     .extract_map()
-                                                     .unwrap();
-
                             // Push the entire state, can just do now, don't need to delay
                             for (name, val) in state_map.into_iter() {
                                 self.push_local(&name, val);
@@ -389,8 +1344,26 @@ impl Runtime {
 
                             ins_stack.push(Instruction::Eval(sexpr));
                         }
-                        o => return Err(RuntimeErr::TypeError(
-                            format!("Expected function, received {:?}!", o)
+                        Ref(id) if self.parameter_id(&Ref(id)).is_some() => {
+                            if !args.is_empty() {
+                                return Err(RuntimeErr::TypeError(format!(
+                                    "Parameters take no arguments, received {}", args.len()
+                                )));
+                            }
+                            data_stack.push(self.parameter_value(&Ref(id))?);
+                        }
+                        Ref(id) if self.coroutine_id(&Ref(id)).is_some() => {
+                            if !args.is_empty() {
+                                return Err(RuntimeErr::TypeError(format!(
+                                    "Generators take no arguments, received {}", args.len()
+                                )));
+                            }
+                            let res = self.while_parked(&mut ins_stack, &mut data_stack,
+                                                        |runtime| runtime.resume_coroutine(id));
+                            data_stack.push(res?);
+                        }
+                        o => return Err(RuntimeErr::TypeError(
+                            format!("Expected function, received {:?}!", o)
                         )),
                     }
                 }
@@ -402,6 +1375,11 @@ impl Runtime {
                                 ins_stack.push(Instruction::Eval(i));
                             }
                         }
+                        // A literal gives a new bytevector each time, like
+                        // a vector literal
+                        Ponga::Bytevector(bytes) => {
+                            data_stack.push(self.gc.ponga_into_gc_ref(Ponga::Bytevector(bytes)));
+                        }
                         Ponga::List(v) => {
                             ins_stack.push(Instruction::CollectList(v.len()));
                             for i in v.into_iter() {
@@ -428,17 +1406,96 @@ match func {
                     "if must have three arguments".to_string()
                 ));
             }
-            // Can make this better if we push it later but should be fine for now
-            //println!("iter: {:?}", iter);
-            let cond = self.id_or_ref_peval(iter.next().unwrap())?;
-            let cond = self.eval(cond)?;
-            //println!("cond: {}", cond);
-            let val = if cond != Ponga::False {
-                iter.nth(0).unwrap()
+            let test = iter.next().unwrap();
+            let (consequent, alternative) = iter.collect_tuple().unwrap();
+            ins_stack.push(Instruction::If(consequent, alternative));
+            ins_stack.push(Instruction::Test(test));
+            continue;
+        }
+        "cond" => {
+            let res = self.cond_instructions(iter.collect())?;
+            ins_stack.extend(res.into_iter().rev());
+            continue;
+        }
+        "case" => {
+            if iter.len() < 1 {
+                return Err(RuntimeErr::Other(
+                    "case must have at least one argument".to_string()
+                ));
+            }
+            let key = iter.next().unwrap();
+            ins_stack.push(Instruction::Case(iter.collect()));
+            ins_stack.push(Instruction::Test(key));
+            continue;
+        }
+        "when" | "unless" => {
+            if iter.len() < 2 {
+                return Err(RuntimeErr::Other(format!(
+                    "{} must have at least two arguments", s
+                )));
+            }
+            let test = iter.next().unwrap();
+            let body = sequence(iter.collect());
+            if s == "when" {
+                ins_stack.push(Instruction::If(body, Null));
             } else {
-                iter.nth(1).unwrap()
+                ins_stack.push(Instruction::If(Null, body));
+            }
+            ins_stack.push(Instruction::Test(test));
+            continue;
+        }
+        "do" => {
+            if iter.len() < 2 {
+                return Err(RuntimeErr::Other(
+                    "do must have at least two arguments".to_string()
+                ));
+            }
+            let specs = iter.next().unwrap();
+            let mut exit = match iter.next().unwrap() {
+                Sexpr(exit) if !exit.is_empty() => exit.into_iter(),
+                _ => return Err(RuntimeErr::Other(
+                    "do requires a test and result expressions".to_string()
+                )),
             };
-            ins_stack.push(Instruction::Eval(val));
+            let test = exit.next().unwrap();
+            let results = exit.collect();
+            let commands = iter.collect();
+            if !specs.is_sexpr() {
+                return Err(RuntimeErr::Other(
+                    "first argument to do must be an s-expr of variables".to_string()
+                ));
+            }
+            let mut vars = vec![];
+            let mut inits = vec![];
+            let mut steps = vec![];
+            for spec in specs.get_array()?.into_iter() {
+                let spec = match spec {
+                    Sexpr(spec) if spec.len() == 2 || spec.len() == 3 => spec,
+                    _ => return Err(RuntimeErr::Other(
+                        "do variables must be (variable init [step])".to_string()
+                    )),
+                };
+                let mut spec = spec.into_iter();
+                let var = spec.next().unwrap().extract_name()?;
+                inits.push(spec.next().unwrap());
+                if let Some(step) = spec.next() {
+                    steps.push((var.clone(), step));
+                }
+                vars.push(var);
+            }
+
+            // The inits are all evaluated before any variable is bound
+            for var in vars.iter().rev() {
+                ins_stack.push(Instruction::Pop(var.clone(), None));
+            }
+            ins_stack.push(Instruction::Do(steps, test.clone(), results, commands));
+            ins_stack.push(Instruction::Test(test));
+            for var in vars.into_iter() {
+                ins_stack.push(Instruction::Push(var));
+            }
+            for init in inits.into_iter().rev() {
+                ins_stack.push(Instruction::Eval(init));
+            }
             continue;
         }
         "$PRINT_RAW" => {
@@ -461,7 +1518,7 @@ match func {
             data_stack.push(val);
             continue;
         }
-        "$EVAL" | "eval" => {
+        "$EVAL" => {
             if iter.len() != 1 {
                 return Err(RuntimeErr::Other(
                     "$EVAL must have one argument".to_string()
@@ -511,24 +1568,98 @@ match func {
                     "eval.code<->list.eval must have one argument".to_string()
                 ));
             }
-            let val = self.eval(iter.next().unwrap())?;
-            ins_stack.push(Instruction::Eval(val.flip_code_vals(&self)));
+            ins_stack.push(Instruction::FlipEval);
+            ins_stack.push(Instruction::Eval(iter.next().unwrap()));
+            continue;
+        }
+        "include" | "include-ci" => {
+            let forms = self.include_files(iter.collect(), s == "include-ci")?;
+            if forms.is_empty() {
+                data_stack.push(Null);
+                continue;
+            }
+            let mut begin = vec![Identifier("begin".to_string())];
+            begin.extend(forms);
+            ins_stack.push(Instruction::Eval(Sexpr(begin)));
+            continue;
+        }
+        "parameterize" => {
+            if iter.len() < 2 {
+                return Err(RuntimeErr::Other(
+                    "parameterize must have at least two arguments".to_string()
+                ));
+            }
+            let bindings = iter.next().unwrap().get_array()?;
+            let n = bindings.len();
+            ins_stack.push(Instruction::UnbindParameters(n));
+            ins_stack.push(Instruction::Eval(make_body(iter.collect())?));
+            ins_stack.push(Instruction::ConvertParameters(n));
+            for binding in bindings.into_iter().rev() {
+                let (param, val) = match binding {
+                    Sexpr(binding) if binding.len() == 2 => binding.into_iter().collect_tuple().unwrap(),
+                    _ => return Err(RuntimeErr::Other(
+                        "parameterize bindings must be (parameter value)".to_string()
+                    )),
+                };
+                ins_stack.push(Instruction::Eval(val));
+                ins_stack.push(Instruction::Eval(param));
+            }
+            continue;
+        }
+        "select" => {
+            ins_stack.push(Instruction::Eval(select_call(iter.collect())?));
             continue;
         }
+        "define-library" => {
+            if iter.len() == 0 {
+                return Err(RuntimeErr::Other(
+                    "define-library must have a name".to_string()
+                ));
+            }
+            self.define_library(iter.next().unwrap(), iter.collect())?;
+            data_stack.push(Null);
+        }
+        "import" => {
+            let env = self.current_environment();
+            for set in iter {
+                self.import_into_environment(&env, set)?;
+            }
+            data_stack.push(Null);
+        }
         "quote" => {
             if iter.len() != 1 {
                 return Err(RuntimeErr::Other(
                     "quote must have one argument".to_string()
                 ));
             }
-            let val = iter.next().unwrap().deep_copy(&self);
-            let val = match val {
-                Ponga::Sexpr(arr) => Ponga::List(arr.into_iter().collect()),
-                Ponga::Identifier(s) => Ponga::Symbol(s),
-                val => val,
-            };
+            let val = self.quoted(iter.next().unwrap());
             data_stack.push(val);
         }
+        "delay" | "delay-force" => {
+            if iter.len() != 1 {
+                return Err(RuntimeErr::Other(format!(
+                    "{} must have one argument", s
+                )));
+            }
+            let thunk = self.make_closure(vec![], make_body(iter.collect())?);
+            let promise = self.make_promise(crate::promise::Promise::Delayed(Box::new(thunk), s == "delay-force"));
+            data_stack.push(promise);
+            continue;
+        }
+        // A stream is a promise of either '() or a pair of a promise of its
+        // first element and the rest of the stream
+        "stream-cons" => {
+            if iter.len() != 2 {
+                return Err(RuntimeErr::Other(
+                    "stream-cons must have two arguments".to_string()
+                ));
+            }
+            let first = Sexpr(vec![Identifier("delay".to_string()), iter.next().unwrap()]);
+            let rest = Sexpr(vec![Identifier("delay-force".to_string()), iter.next().unwrap()]);
+            let pair = Sexpr(vec![Identifier("make-stream-pair".to_string()), first, rest]);
+            ins_stack.push(Instruction::Eval(Sexpr(vec![Identifier("delay".to_string()), pair])));
+            continue;
+        }
         "$DELAY" => {
             for i in iter {
                 data_stack.push(i);
@@ -546,13 +1677,13 @@ match func {
             );
         }
         "lambda" => {
-            if iter.len() != 2 {
+            if iter.len() < 2 {
                 return Err(RuntimeErr::Other(
-                    "lambda must have two arguments".to_string()
+                    "lambda must have at least two arguments".to_string()
                 ));
             }
             let first = iter.next().unwrap();
-            let body = iter.next().unwrap();
+            let body = make_body(iter.collect())?;
 
             let mut cargs = Vec::new();
             if !first.is_sexpr() {
@@ -573,22 +1704,17 @@ match func {
                 cargs.push(i.extract_name()?);
             }
 
-            let new_state = Ponga::Object(self.condense_locals());
-            let state_id = self.gc.add_obj(new_state);
-            let body_id = self.gc.add_obj(body);
-
-
-            data_stack.push(CFunc(cargs, body_id, state_id));
+            data_stack.push(self.make_closure(cargs, body));
             continue;
         }
         "open-lambda" => {
-            if iter.len() != 2 {
+            if iter.len() < 2 {
                 return Err(RuntimeErr::Other(
-                    "lambda must have two arguments".to_string()
+                    "lambda must have at least two arguments".to_string()
                 ));
             }
             let first = iter.next().unwrap();
-            let body = iter.next().unwrap();
+            let body = make_body(iter.collect())?;
 
             let mut cargs = Vec::new();
             if !first.is_sexpr() {
@@ -614,7 +1740,7 @@ match func {
             let body_id = self.gc.add_obj(body);
 
 
-            data_stack.push(CFunc(cargs, body_id, state_id));
+            data_stack.push(CFunc(cargs, body_id, state_id, self.current_env));
             continue;
         }
         "mac" => {
@@ -754,18 +1880,18 @@ match func {
             }
 
         }
-        "let" => {
+        "let" | "let*" => {
             if iter.len() < 2 {
                 return Err(RuntimeErr::Other(
                     "let must have at least two arguments".to_string()
                 ));
             }
             let first = iter.next().unwrap();
-            let mut body = vec![Ponga::Identifier("begin".to_string())];
-            for arg in iter {
-                body.push(arg);
+            if let Identifier(name) = first {
+                ins_stack.push(Instruction::Eval(named_let(name, iter.collect())?));
+                continue;
             }
-            let body = Ponga::Sexpr(body);
+            let body = make_body(iter.collect())?;
 
             if !first.is_sexpr() {
                 return Err(RuntimeErr::Other(
@@ -792,30 +1918,45 @@ match func {
                 let id = inner_iter.next().unwrap().extract_name()?;
                 let val = inner_iter.next().unwrap();
 
-                names.push((id.clone(), val));
-                ins_stack.push(Instruction::Pop(id, None));
-
+                names.push((id, val));
             }
+            // Popped as a frame so that a tail call in the body can pop it
+            ins_stack.push(Instruction::PopFrame(
+                names.iter().map(|(id, _)| (id.clone(), None)).collect()
+            ));
             ins_stack.push(Instruction::Eval(body));
 
-            for (name, val) in names {
-                ins_stack.push(Instruction::Push(name));
-                ins_stack.push(Instruction::Eval(val));
+            if s == "let*" {
+                for (name, val) in names {
+                    ins_stack.push(Instruction::Push(name));
+                    ins_stack.push(Instruction::Eval(val));
+                }
+            } else {
+                // Every init is evaluated before any of the names are bound
+                for (name, _) in names.iter().rev() {
+                    ins_stack.push(Instruction::Push(name.clone()));
+                }
+                for (_, val) in names {
+                    ins_stack.push(Instruction::Eval(val));
+                }
             }
-
         }
         "define" => {
-            if iter.len() != 2 {
+            if iter.len() < 2 {
                 return Err(RuntimeErr::Other(
-                    "define must have two arguments".to_string()
+                    "define must have at least two arguments".to_string()
                 ));
             }
             let name = iter.next().unwrap();
-            let val = iter.next().unwrap();
 
             if name.is_identifier() {
+                if iter.len() != 1 {
+                    return Err(RuntimeErr::Other(
+                        "define of an identifier must have two arguments".to_string()
+                    ));
+                }
                 ins_stack.push(Instruction::Define(name.extract_name()?));
-                ins_stack.push(Instruction::Eval(val));
+                ins_stack.push(Instruction::Eval(iter.next().unwrap()));
                 continue;
             } else if name.is_sexpr() {
                 let v = name.get_array()?;
@@ -833,10 +1974,9 @@ match func {
                 ins_stack.push(Instruction::Define(new_name.extract_name()?));
                 
                 
-                let new_sexpr = Sexpr(vec![Identifier("lambda".to_string()),
-                                           Sexpr(other_args),
-                                           val]);
-                ins_stack.push(Instruction::Eval(new_sexpr));
+                let mut new_sexpr = vec![Identifier("lambda".to_string()), Sexpr(other_args)];
+                new_sexpr.extend(iter);
+                ins_stack.push(Instruction::Eval(Sexpr(new_sexpr)));
                 continue;
             } else {
                 return Err(RuntimeErr::Other(
@@ -846,6 +1986,196 @@ match func {
 
             }
         }
+        "letrec" | "letrec*" => {
+            if iter.len() < 2 {
+                return Err(RuntimeErr::Other(
+                    "letrec must have at least two arguments".to_string()
+                ));
+            }
+            let bindings = parse_bindings(iter.next().unwrap(), "letrec")?;
+            let body = make_body(iter.collect())?;
+            let names: Vec<std::string::String> = bindings.iter().map(|(name, _)| name.clone()).collect();
+
+            // Bind every name first so that the inits can refer to each
+            // other, then evaluate and assign them in order. Closures are
+            // patched after each one, as calling them writes their captured
+            // state back.
+            ins_stack.push(Instruction::PopFrame(
+                names.iter().map(|name| (name.clone(), None)).collect()
+            ));
+            ins_stack.push(Instruction::Eval(body));
+            for (name, val) in bindings.into_iter().rev() {
+                ins_stack.push(Instruction::PatchClosures(names.clone()));
+                ins_stack.push(Instruction::PopStack);
+                ins_stack.push(Instruction::Set(name));
+                ins_stack.push(Instruction::Eval(val));
+            }
+            for name in names.into_iter().rev() {
+                ins_stack.push(Instruction::Push(name));
+                ins_stack.push(Instruction::Data(Ponga::Null));
+            }
+            continue;
+        }
+        "let-values" | "let*-values" => {
+            if iter.len() < 2 {
+                return Err(RuntimeErr::Other(
+                    "let-values must have at least two arguments".to_string()
+                ));
+            }
+            let first = iter.next().unwrap();
+            let body = make_body(iter.collect())?;
+            if !first.is_sexpr() {
+                return Err(RuntimeErr::Other(
+                    "first argument to let-values must be an s-expr of bindings".to_string()
+                ));
+            }
+            let mut clauses = vec![];
+            for clause in first.get_array()?.into_iter() {
+                let clause = match clause {
+                    Sexpr(clause) if clause.len() == 2 => clause,
+                    _ => return Err(RuntimeErr::Other(
+                        "let-values requires pairs of formals and expressions".to_string()
+                    )),
+                };
+                let mut clause = clause.into_iter();
+                let (names, rest) = parse_formals(clause.next().unwrap())?;
+                clauses.push((names, rest, clause.next().unwrap()));
+            }
+
+            let frame = clauses.iter()
+                               .flat_map(|(names, rest, _)| names.iter().chain(rest.iter()))
+                               .map(|name| (name.clone(), None))
+                               .collect();
+            ins_stack.push(Instruction::PopFrame(frame));
+            ins_stack.push(Instruction::Eval(body));
+            if s == "let*-values" {
+                for (names, rest, expr) in clauses.into_iter().rev() {
+                    ins_stack.push(Instruction::BindValues(names, rest));
+                    ins_stack.push(Instruction::Eval(expr));
+                }
+            } else {
+                // Every expression is evaluated before any of the names are bound
+                let mut exprs = vec![];
+                for (names, rest, expr) in clauses.into_iter() {
+                    ins_stack.push(Instruction::BindValues(names, rest));
+                    exprs.push(expr);
+                }
+                for expr in exprs.into_iter().rev() {
+                    ins_stack.push(Instruction::Eval(expr));
+                }
+            }
+            continue;
+        }
+        "define-record-type" => {
+            if iter.len() < 3 {
+                return Err(RuntimeErr::Other(
+                    "define-record-type must have at least three arguments".to_string()
+                ));
+            }
+            let type_name = iter.next().unwrap().extract_name()?;
+            let ctor = iter.next().unwrap();
+            let pred = iter.next().unwrap().extract_name()?;
+            let mut fields = vec![];
+            let mut procs = vec![];
+            for spec in iter {
+                let mut spec = match spec {
+                    Sexpr(spec) if !spec.is_empty() && spec.len() <= 3 => spec.into_iter(),
+                    _ => return Err(RuntimeErr::Other(
+                        "define-record-type fields must be (field [accessor [modifier]])".to_string()
+                    )),
+                };
+                fields.push(spec.next().unwrap().extract_name()?);
+                let accessor = spec.next().map(|p| p.extract_name()).transpose()?;
+                let modifier = spec.next().map(|p| p.extract_name()).transpose()?;
+                procs.push((accessor, modifier));
+            }
+            let printed_name = local_name(&type_name).trim_start_matches('<')
+                                                     .trim_end_matches('>')
+                                                     .to_string();
+            let field_names: Vec<&str> = fields.iter().map(|f| f.as_str()).collect();
+            let rtype = self.make_record_type(&printed_name, &field_names);
+
+            // The procedures are lambdas around the record builtins, with the
+            // type itself in their bodies
+            let define = |name: std::string::String, val: Ponga| {
+                Sexpr(vec![Identifier("define".to_string()), Identifier(name), val])
+            };
+            let lambda = |args: &[&str], body: Vec<Ponga>| {
+                let args = args.iter().map(|a| Identifier(a.to_string())).collect();
+                Sexpr(vec![Identifier("lambda".to_string()), Sexpr(args), Sexpr(body)])
+            };
+            let builtin = |name: &str| Identifier(name.to_string());
+            let index = |i: usize| Ponga::Number(crate::number::Number::Int(i as isize));
+            let mut defines = vec![Identifier("begin".to_string()), define(type_name, rtype.clone())];
+
+            let ctor = match ctor {
+                Sexpr(spec) if !spec.is_empty() => {
+                    let mut spec = spec.into_iter();
+                    let name = spec.next().unwrap().extract_name()?;
+                    let args = spec.map(|a| a.extract_name()).collect::<RunRes<Vec<_>>>()?;
+                    Some((name, args))
+                }
+                Identifier(name) => Some((name, fields.clone())),
+                False => None,
+                _ => return Err(RuntimeErr::Other(
+                    "define-record-type constructor must be (name field ...), a name or #f".to_string()
+                )),
+            };
+            if let Some((name, args)) = ctor {
+                let mut body = vec![builtin("$MAKE-RECORD"), rtype.clone()];
+                for arg in args.iter() {
+                    let i = fields.iter().position(|f| f == arg).ok_or_else(|| {
+                        RuntimeErr::Other(format!("{} is not a field of {}", arg, printed_name))
+                    })?;
+                    body.push(index(i));
+                    body.push(Identifier(arg.clone()));
+                }
+                let args: Vec<&str> = args.iter().map(|a| a.as_str()).collect();
+                defines.push(define(name, lambda(&args, body)));
+            }
+            let body = vec![builtin("$RECORD?"), rtype.clone(), builtin("obj")];
+            defines.push(define(pred, lambda(&["obj"], body)));
+            for (i, (accessor, modifier)) in procs.into_iter().enumerate() {
+                if let Some(name) = accessor {
+                    let body = vec![builtin("$RECORD-REF"), rtype.clone(), index(i),
+                                    builtin("record"), Ponga::Symbol(name.clone())];
+                    defines.push(define(name, lambda(&["record"], body)));
+                }
+                if let Some(name) = modifier {
+                    let body = vec![builtin("$RECORD-SET!"), rtype.clone(), index(i),
+                                    builtin("record"), builtin("value"), Ponga::Symbol(name.clone())];
+                    defines.push(define(name, lambda(&["record", "value"], body)));
+                }
+            }
+            ins_stack.push(Instruction::Eval(Sexpr(defines)));
+            continue;
+        }
+        "define-values" => {
+            if iter.len() != 2 {
+                return Err(RuntimeErr::Other(
+                    "define-values must have two arguments".to_string()
+                ));
+            }
+            let (names, rest) = parse_formals(iter.next().unwrap())?;
+            ins_stack.push(Instruction::DefineValues(names, rest));
+            ins_stack.push(Instruction::Eval(iter.next().unwrap()));
+            continue;
+        }
+        // (receive formals expr body ...) is (let-values ((formals expr)) body ...)
+        "receive" => {
+            if iter.len() < 3 {
+                return Err(RuntimeErr::Other(
+                    "receive must have at least three arguments".to_string()
+                ));
+            }
+            let formals = iter.next().unwrap();
+            let expr = iter.next().unwrap();
+            let mut let_values = vec![Identifier("let-values".to_string()),
+                                      Sexpr(vec![Sexpr(vec![formals, expr])])];
+            let_values.extend(iter);
+            ins_stack.push(Instruction::Eval(Sexpr(let_values)));
+            continue;
+        }
         "defmacro" => {
             if iter.len() != 2 {
                 return Err(RuntimeErr::Other(
@@ -930,7 +2260,7 @@ match func {
         }
         other => {
             let ref_obj = self.get_identifier_obj_ref(other)?;
-            if ref_obj.is_func() {
+            if ref_obj.is_func() || self.is_applicable_object(ref_obj) {
                 let n_args = iter.len();
                 ins_stack.push(Instruction::Call(n_args));
                 data_stack.push(ref_obj.clone());
@@ -953,7 +2283,7 @@ match func {
             }
         }
     }
-    cfunc@CFunc(_, _, _,) => {
+    cfunc@CFunc(_, _, _, _) => {
         let n_args = iter.len();
         ins_stack.push(Instruction::Call(n_args));
         for arg in iter {
@@ -1010,17 +2340,42 @@ match func {
                     }
                 }
             }
-            if ins_stack.len() == 0 {
+            if ins_stack.is_empty() && self.yielded.is_none() {
                 break;
             }
         }
-        pop_or(&mut data_stack)
+        Ok(Exit::Done(pop_or(&mut data_stack)?))
+    }
+
+    // The values produced by an expression
+    pub fn spread_values(&self, val: Ponga) -> Vec<Ponga> {
+        match val {
+            Ponga::Values(vals) => vals,
+            val => vec![val],
+        }
+    }
+
+    // Binds names to the values, with any extras as a list bound to rest
+    fn match_values(&mut self, val: Ponga, names: &[String],
+                    rest: Option<String>) -> RunRes<Vec<(String, Ponga)>> {
+        let vals = self.spread_values(val);
+        if vals.len() < names.len() || (rest.is_none() && vals.len() > names.len()) {
+            return Err(RuntimeErr::Other(format!(
+                "Expected {} values, received {}", names.len(), vals.len()
+            )));
+        }
+        let mut vals = vals.into_iter();
+        let mut res: Vec<(String, Ponga)> = names.iter().cloned().zip(vals.by_ref()).collect();
+        if let Some(rest) = rest {
+            res.push((rest, self.gc.ponga_into_gc_ref(Ponga::List(vals.collect()))));
+        }
+        Ok(res)
     }
 
     pub fn is_func(&self, pong: &Ponga) -> bool {
         match pong {
             Ponga::HFunc(_) => true,
-            Ponga::CFunc(_, _, _) => true,
+            Ponga::CFunc(_, _, _, _) => true,
             Ponga::MFunc(_, _) => true,
             Ponga::Identifier(s) => {
                 let f = self.get_identifier_obj_ref(s).unwrap();
@@ -1109,6 +2464,78 @@ match func {
         }
     }
 
+    // The contents of a string literal or of a mutable string
+    pub fn get_string(&self, pong: &Ponga) -> Option<String> {
+        match pong {
+            Ponga::String(s) => Some(s.clone()),
+            Ponga::Ref(id) => {
+                let obj = self.get_id_obj_ref(*id).ok()?.borrow()?;
+                match obj.inner() {
+                    Ponga::MutString(s) => Some(s.clone()),
+                    _ => None,
+                }
+            }
+            _ => None,
+        }
+    }
+
+    // Record types and records, as made by define-record-type
+    pub fn make_record_type(&mut self, name: &str, fields: &[&str]) -> Ponga {
+        let fields = fields.iter().map(|f| f.to_string()).collect();
+        let rtype = RecordType::new(name.to_string(), fields);
+        self.gc.ponga_into_gc_ref(Ponga::RecordType(rtype))
+    }
+
+    // The gc id of a record type along with the type itself
+    pub fn record_type(&self, rtype: &Ponga) -> RunRes<(Id, RecordType)> {
+        if let Ponga::Ref(id) = rtype {
+            let obj = self.get_id_obj_ref(*id)?.borrow().unwrap();
+            if let Ponga::RecordType(t) = obj.inner() {
+                return Ok((*id, t.clone()));
+            }
+        }
+        Err(RuntimeErr::TypeError(format!(
+            "Expected record type, received {}", self.ponga_to_string(rtype)
+        )))
+    }
+
+    // A record of the type with every field given in order
+    pub fn make_record(&mut self, rtype: &Ponga, fields: Vec<Ponga>) -> RunRes<Ponga> {
+        let (id, t) = self.record_type(rtype)?;
+        if fields.len() != t.fields.len() {
+            return Err(RuntimeErr::TypeError(format!(
+                "Record {} has {} fields, received {}", t.name, t.fields.len(), fields.len()
+            )));
+        }
+        Ok(self.gc.ponga_into_gc_ref(Ponga::Record(Record::new(id, fields))))
+    }
+
+    // The type of a record, or None if it isn't one
+    pub fn record_type_of(&self, record: &Ponga) -> Option<Ponga> {
+        if let Ponga::Ref(id) = record {
+            if let Ponga::Record(r) = self.get_id_obj_ref(*id).ok()?.borrow()?.inner() {
+                return Some(Ponga::Ref(r.rtype));
+            }
+        }
+        None
+    }
+
+    pub fn record_field(&self, record: &Ponga, field: &str) -> RunRes<Ponga> {
+        if let Ponga::Ref(id) = record {
+            let obj = self.get_id_obj_ref(*id)?.borrow().unwrap();
+            if let Ponga::Record(r) = obj.inner() {
+                let (_, t) = self.record_type(&Ponga::Ref(r.rtype))?;
+                let index = t.field_index(field).ok_or_else(|| RuntimeErr::Other(format!(
+                    "Record {} has no field {}", t.name, field
+                )))?;
+                return Ok(r.fields[index].clone());
+            }
+        }
+        Err(RuntimeErr::TypeError(format!(
+            "Expected record, received {}", self.ponga_to_string(record)
+        )))
+    }
+
     pub fn is_symbol(&self, pong: &Ponga) -> bool {
         match pong {
             Ponga::Symbol(_) => true,
@@ -1127,14 +2554,14 @@ match func {
     pub fn ponga_to_string(&self, ponga: &Ponga) -> String {
         match ponga {
             Ponga::Number(_) => format!("{}", ponga),
-            Ponga::String(_) => format!("{}", ponga),
+            Ponga::String(_) | Ponga::MutString(_) => format!("{}", ponga),
             Ponga::False => format!("{}", ponga),
             Ponga::True => format!("{}", ponga),
             Ponga::Char(_) => format!("{}", ponga),
             Ponga::Null => format!("{}", ponga),
             Ponga::Symbol(_) => format!("'{}", ponga),
             Ponga::HFunc(id) => format!("{}", FUNCS[*id].0),
-            Ponga::CFunc(args, _, stateid) => {
+            Ponga::CFunc(args, _, stateid, _) => {
                 let obj = self.get_id_obj_ref(*stateid).unwrap();
                 let obj_ref = obj.borrow().unwrap();
                 let state = obj_ref.inner();
@@ -1172,20 +2599,43 @@ match func {
             Ponga::List(l) => {
                 format!("'({})", l.iter().map(|p| self.ponga_to_string(p)).format(" "))
             }
+            Ponga::WeakBox(Some(val)) => {
+                format!("#<weak-box {}>", self.ponga_to_string(val))
+            }
+            Ponga::WeakBox(None) => format!("{}", ponga),
+            Ponga::WeakTable(_) => format!("{}", ponga),
+            Ponga::HashTable(_) => format!("{}", ponga),
+            Ponga::RecordType(_)
+            | Ponga::Promise(_)
+            | Ponga::Bytevector(_)
+            | Ponga::Port(_)
+            | Ponga::Environment(_)
+            | Ponga::Parameter(_)
+            | Ponga::Thread(_)
+            | Ponga::Mutex(_)
+            | Ponga::ConditionVariable(_)
+            | Ponga::Channel(_)
+            | Ponga::Coroutine(_)
+            | Ponga::Eof => format!("{}", ponga),
+            Ponga::Record(r) => {
+                let fields = r.fields.iter().map(|p| self.ponga_to_string(p));
+                self.record_to_string(r, fields)
+            }
+            Ponga::Values(vals) => format!("{}", vals.iter().map(|p| self.ponga_to_string(p)).format(" ")),
         }
     }
 
     pub fn ponga_to_string_no_id(&self, ponga: &Ponga) -> String {
         match ponga {
             Ponga::Number(_) => format!("{}", ponga),
-            Ponga::String(_) => format!("{}", ponga),
+            Ponga::String(_) | Ponga::MutString(_) => format!("{}", ponga),
             Ponga::False => format!("{}", ponga),
             Ponga::True => format!("{}", ponga),
             Ponga::Char(_) => format!("{}", ponga),
             Ponga::Null => format!("{}", ponga),
             Ponga::Symbol(_) => format!("'{}", ponga),
             Ponga::HFunc(id) => format!("{}", FUNCS[*id].0),
-            Ponga::CFunc(args, _, stateid) => {
+            Ponga::CFunc(args, _, stateid, _) => {
                 let obj = self.get_id_obj_ref(*stateid).unwrap();
                 let obj_ref = obj.borrow().unwrap();
                 let state = obj_ref.inner();
@@ -1217,9 +2667,45 @@ match func {
             Ponga::List(l) => {
                 format!("'({})", l.iter().map(|p| self.ponga_to_string_no_id(p)).format(" "))
             }
+            Ponga::WeakBox(Some(val)) => {
+                format!("#<weak-box {}>", self.ponga_to_string_no_id(val))
+            }
+            Ponga::WeakBox(None) => format!("{}", ponga),
+            Ponga::WeakTable(_) => format!("{}", ponga),
+            Ponga::HashTable(_) => format!("{}", ponga),
+            Ponga::RecordType(_)
+            | Ponga::Promise(_)
+            | Ponga::Bytevector(_)
+            | Ponga::Port(_)
+            | Ponga::Environment(_)
+            | Ponga::Parameter(_)
+            | Ponga::Thread(_)
+            | Ponga::Mutex(_)
+            | Ponga::ConditionVariable(_)
+            | Ponga::Channel(_)
+            | Ponga::Coroutine(_)
+            | Ponga::Eof => format!("{}", ponga),
+            Ponga::Record(r) => {
+                let fields = r.fields.iter().map(|p| self.ponga_to_string_no_id(p));
+                self.record_to_string(r, fields)
+            }
+            Ponga::Values(vals) => {
+                format!("{}", vals.iter().map(|p| self.ponga_to_string_no_id(p)).format(" "))
+            }
         }
     }
 
+    fn record_to_string(&self, record: &Record, fields: impl Iterator<Item = String>) -> String {
+        let name = self.get_id_obj_ref(record.rtype)
+                       .ok()
+                       .and_then(|obj| match obj.borrow()?.inner() {
+                           Ponga::RecordType(t) => Some(t.name.clone()),
+                           _ => None,
+                       })
+                       .unwrap_or_default();
+        format!("#<record {}>", std::iter::once(name).chain(fields).format(" "))
+    }
+
     pub fn run_str(&mut self, s: &str) -> RunRes<()> {
         let parsed = match pongascript_parser(s) {
             Ok(val) => val,
@@ -1261,16 +2747,854 @@ match func {
         Ok(())
     }
 
+    pub fn make_parameter(&mut self, value: Ponga, converter: Option<Ponga>) -> Ponga {
+        self.gc.ponga_into_gc_ref(Ponga::Parameter(Parameter::new(value, converter)))
+    }
+
+    pub fn parameter_id(&self, param: &Ponga) -> Option<Id> {
+        if let Ponga::Ref(id) = param {
+            let obj = self.get_id_obj_ref(*id).ok()?.borrow()?;
+            if let Ponga::Parameter(_) = obj.inner() {
+                return Some(*id);
+            }
+        }
+        None
+    }
+
+    fn parameter(&self, param: &Ponga) -> RunRes<(Id, Parameter)> {
+        if let Some(id) = self.parameter_id(param) {
+            if let Ponga::Parameter(param) = self.get_id_obj_ref(id)?.borrow().unwrap().inner() {
+                return Ok((id, param.clone()));
+            }
+        }
+        Err(RuntimeErr::TypeError(format!(
+            "Expected a parameter, received {}", self.ponga_to_string(param)
+        )))
+    }
+
+    // The value parameterize gave the parameter in this thread, or else the
+    // one it was made with
+    pub fn parameter_value(&self, param: &Ponga) -> RunRes<Ponga> {
+        let (id, param) = self.parameter(param)?;
+        match self.parameters.values.get(&id) {
+            Some(val) => Ok(val.clone()),
+            None => Ok(*param.value),
+        }
+    }
+
+    // Sets the parameters to the values, which have already been converted,
+    // until unbind_parameters puts back their old values
+    fn bind_parameters(&mut self, bindings: Vec<(Ponga, Ponga)>) -> RunRes<()> {
+        let ids = bindings.iter()
+                          .map(|(param, _)| Ok(self.parameter(param)?.0))
+                          .collect::<RunRes<Vec<Id>>>()?;
+        for (id, (_, val)) in ids.into_iter().zip(bindings) {
+            let old = self.parameters.values.insert(id, val);
+            self.parameters.saves.push((id, old));
+        }
+        Ok(())
+    }
+
+    fn unbind_parameters(&mut self, n: usize) -> RunRes<()> {
+        for _ in 0..n {
+            match self.parameters.saves.pop().unwrap() {
+                (id, Some(val)) => self.parameters.values.insert(id, val),
+                (id, None) => self.parameters.values.remove(&id),
+            };
+        }
+        Ok(())
+    }
+
+    // Makes the builtin currently running return the result of calling the
+    // procedure with the parameters set to the values, which have already
+    // been converted
+    pub fn tail_call_with_parameters(&mut self, bindings: Vec<(Ponga, Ponga)>,
+                                     func: Ponga, args: Vec<Ponga>) -> RunRes<()> {
+        let n = bindings.len();
+        let call = self.call_instructions(func, args)?;
+        self.bind_parameters(bindings)?;
+        self.scheduled.push(Instruction::PopStack);
+        self.scheduled.extend(call);
+        self.scheduled.push(Instruction::UnbindParameters(n));
+        Ok(())
+    }
+
+    // A new environment with only the builtins bound
+    pub fn make_environment(&mut self) -> Ponga {
+        self.gc.ponga_into_gc_ref(Ponga::Environment(Environment::new(self.builtin_globals.clone())))
+    }
+
+    // An environment that only has what's imported into it
+    pub fn make_empty_environment(&mut self) -> Ponga {
+        self.gc.ponga_into_gc_ref(Ponga::Environment(Environment::empty()))
+    }
+
+    // An empty environment with the standard libraries imported
+    pub fn make_report_environment(&mut self) -> RunRes<Ponga> {
+        let env = self.make_empty_environment();
+        let imports = self.libraries.values()
+                                    .filter(|lib| lib.name.starts_with("(scheme "))
+                                    .flat_map(|lib| lib.exports.clone())
+                                    .collect();
+        self.add_imports(&env, imports)?;
+        Ok(env)
+    }
+
+    // Like make_environment, but kept alive until drop_environment, for when
+    // it's only held from Rust
+    pub fn new_environment(&mut self) -> Ponga {
+        let env = self.make_environment();
+        self.pin(&env);
+        env
+    }
+
+    pub fn drop_environment(&mut self, env: &Ponga) {
+        self.unpin(env);
+    }
+
+    // Keeps the value alive until it's unpinned as many times as it was
+    // pinned, for values only held from Rust, like what eval, call or
+    // make_record returned, across other evaluations
+    pub fn pin(&mut self, val: &Ponga) {
+        self.pinned.push(val.clone());
+    }
+
+    pub fn unpin(&mut self, val: &Ponga) {
+        if let Some(i) = self.pinned.iter().rposition(|pinned| pinned == val) {
+            self.pinned.remove(i);
+        }
+    }
+
+    pub fn current_environment(&self) -> Ponga {
+        Ponga::Ref(self.current_env)
+    }
+
+    fn environment_id(&self, env: &Ponga) -> RunRes<Id> {
+        if let Ponga::Ref(id) = env {
+            if let Some(obj) = self.get_id_obj_ref(*id)?.borrow() {
+                if let Ponga::Environment(_) = obj.inner() {
+                    return Ok(*id);
+                }
+            }
+        }
+        Err(RuntimeErr::TypeError(format!(
+            "Expected an environment, received {}", self.ponga_to_string(env)
+        )))
+    }
+
+    // Adds a binding to an environment that isn't necessarily the current one
+    pub fn bind_in_environment(&mut self, env: &Ponga, name: String, val: Ponga) -> RunRes<()> {
+        let id = self.environment_id(env)?;
+        if id == self.current_env {
+            self.bind_global(name, val);
+            return Ok(());
+        }
+        let mut obj = self.get_id_obj(id)?.borrow_mut().unwrap();
+        if let Ponga::Environment(env) = obj.inner() {
+            env.globals.insert(name, val);
+        }
+        Ok(())
+    }
+
+    // Brings in the names an import set gives, as references to the bindings
+    // they come from
+    pub fn import_into_environment(&mut self, env: &Ponga, set: Ponga) -> RunRes<()> {
+        let imports = self.import_set(set)?;
+        self.add_imports(env, imports)
+    }
+
+    // Builtins imported under their own names are there already, unless the
+    // environment only sees what it imports
+    fn add_imports(&mut self, env: &Ponga, imports: Vec<(String, String)>) -> RunRes<()> {
+        let id = self.environment_id(env)?;
+        let prev = self.switch_environment(id)?;
+        for (name, global) in imports.into_iter() {
+            if name != global || !self.builtins {
+                self.globals.remove(&name);
+                self.imports.insert(name, global);
+            }
+        }
+        self.switch_environment(prev)?;
+        Ok(())
+    }
+
+    // Stores the current globals back in their environment and takes out the
+    // globals of the new one, returning the environment it replaced
+    fn switch_environment(&mut self, id: Id) -> RunRes<Id> {
+        let prev = self.current_env;
+        if id == prev {
+            return Ok(prev);
+        }
+        let mut globals = std::mem::take(&mut self.globals);
+        let mut imports = std::mem::take(&mut self.imports);
+        let mut builtins = self.builtins;
+        for env in [prev, id] {
+            let mut obj = self.get_id_obj(env)?.borrow_mut().unwrap();
+            if let Ponga::Environment(env) = obj.inner() {
+                std::mem::swap(&mut env.globals, &mut globals);
+                std::mem::swap(&mut env.imports, &mut imports);
+                std::mem::swap(&mut env.builtins, &mut builtins);
+            }
+        }
+        self.globals = globals;
+        self.imports = imports;
+        self.builtins = builtins;
+        self.current_env = id;
+        Ok(prev)
+    }
+
+    // Makes the builtin currently running return the value of the code
+    // evaluated in the environment, which can't see the locals of whatever
+    // is calling it
+    pub fn tail_eval_in(&mut self, env: &Ponga, code: Ponga) -> RunRes<()> {
+        let id = self.environment_id(env)?;
+        let prev = self.switch_environment(id)?;
+        let locals = std::mem::take(&mut self.locals);
+        self.scheduled.push(Instruction::PopStack);
+        self.scheduled.push(Instruction::Eval(code));
+        self.scheduled.push(Instruction::LeaveEnvironment(prev, locals));
+        Ok(())
+    }
+
+    // Evaluates code in the environment, which can't see the locals of
+    // whatever is calling it
+    pub fn eval_in(&mut self, env: &Ponga, code: Ponga) -> RunRes<Ponga> {
+        let id = self.environment_id(env)?;
+        let prev = self.switch_environment(id)?;
+        self.parked.push(Parked {
+            data_stack: vec![Ponga::Ref(prev)],
+            locals: std::mem::take(&mut self.locals),
+            ..Parked::default()
+        });
+        let res = self.eval(code);
+        self.locals = self.parked.pop().unwrap().locals;
+        self.switch_environment(prev)?;
+        res
+    }
+
+    // Evaluates each form in the string in the environment, returning the
+    // value of the last
+    pub fn eval_str_in(&mut self, env: &Ponga, s: &str) -> RunRes<Ponga> {
+        let (rest, forms) = pongascript_parser(s)?;
+        if !rest.trim().is_empty() {
+            return Err(RuntimeErr::ParseError(format!("Unexpected tokens: {}", rest)));
+        }
+        let mut res = Ponga::Null;
+        for form in forms.into_iter() {
+            res = self.eval_in(env, form)?;
+        }
+        Ok(res)
+    }
+
+    pub fn add_library_path(&mut self, dir: impl Into<PathBuf>) {
+        self.library_path.push(dir.into());
+    }
+
+    // Relative paths are relative to the file being loaded, if there is one
+    fn resolve_path(&self, name: &str) -> PathBuf {
+        let path = PathBuf::from(name);
+        match self.loading.last().and_then(|file| file.parent()) {
+            Some(dir) if path.is_relative() => dir.join(path),
+            _ => path,
+        }
+    }
+
+    // Marks the file as being loaded until leave_file, failing if that would
+    // mean loading it inside itself
+    fn enter_file(&mut self, path: &Path) -> RunRes<PathBuf> {
+        let path = path.canonicalize().map_err(|e| RuntimeErr::Other(format!(
+            "Couldn't open {}: {}", path.display(), e
+        )))?;
+        if self.loading.contains(&path) {
+            let cycle = self.loading.iter()
+                                    .skip_while(|file| **file != path)
+                                    .chain(std::iter::once(&path))
+                                    .map(|file| file.display().to_string())
+                                    .join(" -> ");
+            return Err(RuntimeErr::Other(format!("Cyclic load of {}: {}", path.display(), cycle)));
+        }
+        self.loading.push(path.clone());
+        Ok(path)
+    }
+
+    fn leave_file(&mut self) {
+        self.loading.pop();
+    }
+
+    // Evaluates every form in the file, stopping at the first error
+    fn eval_file(&mut self, path: &Path) -> RunRes<()> {
+        let path = self.enter_file(path)?;
+        let res = parse_file(&path).and_then(|forms| self.expand_includes(forms, false)).and_then(|forms| {
+            forms.into_iter().try_for_each(|form| self.eval(form).map(|_| ()))
+        });
+        self.leave_file();
+        res
+    }
+
+    pub fn load_file(&mut self, name: &str) -> RunRes<()> {
+        let path = self.resolve_path(name);
+        self.eval_file(&path)
+    }
+
+    // Makes the builtin currently running evaluate every form in the file
+    // once it returns, stopping at the first error
+    pub fn tail_load_file(&mut self, name: &str) -> RunRes<()> {
+        let path = self.resolve_path(name);
+        let path = self.enter_file(&path)?;
+        let forms = match parse_file(&path).and_then(|forms| self.expand_includes(forms, false)) {
+            Ok(forms) => forms,
+            Err(e) => {
+                self.leave_file();
+                return Err(e);
+            }
+        };
+        for form in forms.into_iter() {
+            self.scheduled.push(Instruction::PopStack);
+            self.scheduled.push(Instruction::Eval(form));
+        }
+        self.scheduled.push(Instruction::PopStack);
+        self.scheduled.push(Instruction::Data(Ponga::Null));
+        self.scheduled.push(Instruction::LeaveFile);
+        Ok(())
+    }
+
+    // The forms in the files, with any includes among them already spliced
+    // in so that their paths are resolved against the right file
+    fn include_files(&mut self, names: Vec<Ponga>, fold: bool) -> RunRes<Vec<Ponga>> {
+        let mut res = vec![];
+        for name in names.into_iter() {
+            let name = match name {
+                Ponga::String(name) => name,
+                name => return Err(RuntimeErr::TypeError(format!(
+                    "include requires file names (not {:?})", name
+                ))),
+            };
+            let path = self.resolve_path(&name);
+            let path = self.enter_file(&path)?;
+            let forms = parse_file(&path).and_then(|forms| self.expand_includes(forms, fold));
+            self.leave_file();
+            res.extend(forms?);
+        }
+        Ok(res)
+    }
+
+    fn expand_includes(&mut self, forms: Vec<Ponga>, fold: bool) -> RunRes<Vec<Ponga>> {
+        let mut res = vec![];
+        for form in forms.into_iter() {
+            let form = if fold { fold_case(form) } else { form };
+            if is_form(&form, "include") || is_form(&form, "include-ci") {
+                res.extend(self.included_forms(form)?);
+            } else {
+                res.push(self.expand_nested_includes(form)?);
+            }
+        }
+        Ok(res)
+    }
+
+    // Includes inside other forms, like procedure bodies, are read once here
+    // rather than every time the form is evaluated
+    fn expand_nested_includes(&mut self, form: Ponga) -> RunRes<Ponga> {
+        let arr = match form {
+            Ponga::Sexpr(arr) => arr,
+            form => return Ok(form),
+        };
+        if matches!(arr.first(), Some(Ponga::Identifier(s)) if s == "quote" || s == "quasiquote") {
+            return Ok(Ponga::Sexpr(arr));
+        }
+        let mut res = vec![];
+        for form in arr.into_iter() {
+            if is_form(&form, "include") || is_form(&form, "include-ci") {
+                let forms = self.included_forms(form)?;
+                if forms.is_empty() {
+                    res.push(Ponga::Null);
+                } else {
+                    let mut begin = vec![Ponga::Identifier("begin".to_string())];
+                    begin.extend(forms);
+                    res.push(Ponga::Sexpr(begin));
+                }
+            } else {
+                res.push(self.expand_nested_includes(form)?);
+            }
+        }
+        Ok(Ponga::Sexpr(res))
+    }
+
+    fn included_forms(&mut self, form: Ponga) -> RunRes<Vec<Ponga>> {
+        let mut iter = form.get_array()?.into_iter();
+        let ci = iter.next() == Some(Ponga::Identifier("include-ci".to_string()));
+        self.include_files(iter.collect(), ci)
+    }
+
+    // Returns the library, loading it from the search path the first time
+    pub fn load_library(&mut self, name: &Ponga) -> RunRes<Library> {
+        let lib_name = library_name(name)?;
+        if let Some(lib) = self.libraries.get(&lib_name) {
+            return Ok(lib.clone());
+        }
+        let parts = library_name_parts(name)?;
+        for dir in self.library_path.clone().into_iter() {
+            for file in library_files(&parts).into_iter() {
+                let path = dir.join(file);
+                if !path.is_file() {
+                    continue;
+                }
+                self.eval_file(&path)?;
+                return self.libraries.get(&lib_name).cloned().ok_or_else(|| {
+                    RuntimeErr::Other(format!("{} doesn't define {}", path.display(), lib_name))
+                });
+            }
+        }
+        Err(RuntimeErr::Other(format!("Library {} not found", lib_name)))
+    }
+
+    // The names an import set brings in, along with the global names they
+    // refer to
+    pub fn import_set(&mut self, set: Ponga) -> RunRes<Vec<(String, String)>> {
+        let arr = match set {
+            Ponga::Sexpr(arr) if arr.len() >= 2 && matches!(arr[1], Ponga::Sexpr(_)) => arr,
+            set => {
+                let lib = self.load_library(&set)?;
+                return Ok(lib.exports.into_iter().sorted().collect());
+            }
+        };
+        let mut iter = arr.into_iter();
+        let kind = iter.next().unwrap().extract_name()?;
+        let inner = iter.next().unwrap();
+        if !["only", "except", "prefix", "rename"].contains(&kind.as_str()) {
+            let lib = self.load_library(&Ponga::Sexpr(vec![Ponga::Identifier(kind), inner]))?;
+            return Ok(lib.exports.into_iter().sorted().collect());
+        }
+        let mut names = self.import_set(inner)?;
+        let not_exported = |name: &str| RuntimeErr::Other(format!(
+            "{} isn't part of the import set", name
+        ));
+        match kind.as_str() {
+            "only" => {
+                let only = iter.map(|n| n.extract_name()).collect::<RunRes<Vec<_>>>()?;
+                if let Some(name) = only.iter().find(|n| !names.iter().any(|(m, _)| m == *n)) {
+                    return Err(not_exported(name));
+                }
+                names.retain(|(name, _)| only.contains(name));
+            }
+            "except" => {
+                let except = iter.map(|n| n.extract_name()).collect::<RunRes<Vec<_>>>()?;
+                names.retain(|(name, _)| !except.contains(name));
+            }
+            "prefix" => {
+                let prefix = match iter.next() {
+                    Some(prefix) if iter.len() == 0 => prefix.extract_name()?,
+                    _ => return Err(RuntimeErr::Other("prefix must have one prefix".to_string())),
+                };
+                for (name, _) in names.iter_mut() {
+                    name.insert_str(0, &prefix);
+                }
+            }
+            _ => {
+                for pair in iter {
+                    let (from, to) = match pair.get_array()?.into_iter().collect_tuple() {
+                        Some((from, to)) => (from.extract_name()?, to.extract_name()?),
+                        None => return Err(RuntimeErr::Other(
+                            "rename pairs must be (name new-name)".to_string()
+                        )),
+                    };
+                    match names.iter_mut().find(|(name, _)| *name == from) {
+                        Some((name, _)) => *name = to,
+                        None => return Err(not_exported(&from)),
+                    }
+                }
+            }
+        }
+        Ok(names)
+    }
+
+    // Evaluates the library body with its definitions given names of their
+    // own, and its imports renamed to what they refer to
+    fn define_library(&mut self, name: Ponga, decls: Vec<Ponga>) -> RunRes<()> {
+        let lib_name = library_name(&name)?;
+        let mut renames = HashMap::new();
+        let mut exports = vec![];
+        let mut body = vec![];
+        for decl in decls.into_iter() {
+            let mut iter = decl.get_array()?.into_iter();
+            let kind = iter.next().map(|k| k.extract_name()).transpose()?;
+            match kind.as_deref() {
+                Some("export") => {
+                    for spec in iter {
+                        match spec {
+                            Ponga::Sexpr(spec)
+                                if spec.len() == 3 && matches!(&spec[0], Ponga::Identifier(s) if s == "rename") => {
+                                let mut spec = spec.into_iter().skip(1);
+                                let from = spec.next().unwrap().extract_name()?;
+                                exports.push((from, spec.next().unwrap().extract_name()?));
+                            }
+                            spec => {
+                                let name = spec.extract_name()?;
+                                exports.push((name.clone(), name));
+                            }
+                        }
+                    }
+                }
+                Some("import") => {
+                    for set in iter {
+                        renames.extend(self.import_set(set)?);
+                    }
+                }
+                Some("begin") => body.extend(iter),
+                Some(kind @ ("include" | "include-ci")) => {
+                    let forms = self.include_files(iter.collect(), kind == "include-ci")?;
+                    body.extend(forms);
+                }
+                _ => return Err(RuntimeErr::Other(format!(
+                    "{} has an unknown library declaration", lib_name
+                ))),
+            }
+        }
+
+        let mut defined = vec![];
+        for form in body.iter() {
+            defined_names(form, &mut defined);
+        }
+        for name in defined.into_iter() {
+            let global = mangle(&lib_name, &name);
+            renames.insert(name, global);
+        }
+        for form in body.into_iter() {
+            self.eval(rename_identifiers(form, &renames))?;
+        }
+
+        let exports = exports.into_iter().map(|(from, to)| {
+            let global = renames.get(&from).cloned().unwrap_or(from);
+            (to, global)
+        }).collect();
+        self.libraries.insert(lib_name.clone(), Library::new(lib_name, exports));
+        Ok(())
+    }
+
+    pub fn make_thread(&mut self, thunk: Ponga, name: Ponga) -> RunRes<Ponga> {
+        let mut ins_stack = self.call_instructions(thunk, vec![])?;
+        ins_stack.reverse();
+        // A new thread starts out with the parameterize bindings of the one
+        // that made it
+        let thread = Thread::new(name, ins_stack, self.current_env, self.parameters.inherit());
+        Ok(self.gc.ponga_into_gc_ref(Ponga::Thread(thread)))
+    }
+
+    // Runs func on the gc object val refers to, which gives None if the
+    // object isn't the kind expected
+    fn with_object<T>(&self, val: &Ponga, kind: &str,
+                      func: impl FnOnce(&mut Ponga) -> Option<T>) -> RunRes<T> {
+        let res = match val {
+            Ponga::Ref(id) => func(self.get_id_obj_ref(*id)?.borrow_mut().unwrap().inner()),
+            _ => None,
+        };
+        res.ok_or_else(|| RuntimeErr::TypeError(format!(
+            "Expected a {}, received {}", kind, self.ponga_to_string(val)
+        )))
+    }
+
+    pub fn with_thread<T>(&self, thread: &Ponga,
+                          func: impl FnOnce(&mut Thread) -> T) -> RunRes<T> {
+        self.with_object(thread, "thread", |obj| match obj {
+            Ponga::Thread(thread) => Some(func(thread)),
+            _ => None,
+        })
+    }
+
+    pub fn with_mutex<T>(&self, mutex: &Ponga,
+                         func: impl FnOnce(&mut Mutex) -> T) -> RunRes<T> {
+        self.with_object(mutex, "mutex", |obj| match obj {
+            Ponga::Mutex(mutex) => Some(func(mutex)),
+            _ => None,
+        })
+    }
+
+    pub fn with_condition_variable<T>(&self, cv: &Ponga,
+                                      func: impl FnOnce(&mut ConditionVariable) -> T) -> RunRes<T> {
+        self.with_object(cv, "condition variable", |obj| match obj {
+            Ponga::ConditionVariable(cv) => Some(func(cv)),
+            _ => None,
+        })
+    }
+
+    pub fn with_channel<T>(&self, channel: &Ponga,
+                           func: impl FnOnce(&mut Channel) -> T) -> RunRes<T> {
+        self.with_object(channel, "channel", |obj| match obj {
+            Ponga::Channel(channel) => Some(func(channel)),
+            _ => None,
+        })
+    }
+
+    pub fn start_thread(&mut self, thread: &Ponga) -> RunRes<()> {
+        let started = self.with_thread(thread, |thread| match thread.state {
+            ThreadState::New => {
+                thread.state = ThreadState::Ready(None);
+                true
+            }
+            _ => false,
+        })?;
+        if !started {
+            return Err(RuntimeErr::Other(format!(
+                "Thread {} has already been started", self.ponga_to_string(thread)
+            )));
+        }
+        if let Ponga::Ref(id) = thread {
+            self.threads.push(*id);
+        }
+        Ok(())
+    }
+
+    // What the thread's thunk returned, or None if it hasn't finished
+    pub fn thread_result(&self, thread: &Ponga) -> RunRes<Option<RunRes<Ponga>>> {
+        self.with_thread(thread, |thread| match &thread.state {
+            ThreadState::Done(res) => Some(res.clone().map(|val| *val)),
+            _ => None,
+        })
+    }
+
+    // Whether the mutex can be locked, which it can be once its owner has
+    // finished even if it never unlocked it
+    pub fn mutex_available(&self, mutex: &Ponga) -> RunRes<bool> {
+        match self.with_mutex(mutex, |mutex| mutex.state.clone())? {
+            MutexState::Unlocked => Ok(true),
+            MutexState::Locked(Some(owner)) => {
+                self.with_thread(&Ponga::Ref(owner), |owner| owner.is_done())
+            }
+            MutexState::Locked(None) => Ok(false),
+        }
+    }
+
+    // Makes the current thread give way to the others once the builtin
+    // currently running returns
+    pub fn block(&mut self, wait: Wait, deadline: Option<SystemTime>) {
+        self.blocked = Some(Block { wait, deadline });
+    }
+
+    // Whether a thread blocked like this can go on, or at least try again
+    fn can_resume(&self, block: &Block) -> bool {
+        if timed_out(block.deadline) {
+            return true;
+        }
+        match block.wait {
+            Wait::Yield => true,
+            Wait::Sleep => false,
+            Wait::Join(id) => {
+                self.with_thread(&Ponga::Ref(id), |thread| thread.is_done()).unwrap_or(true)
+            }
+            Wait::Mutex(id) => self.mutex_available(&Ponga::Ref(id)).unwrap_or(true),
+            Wait::Condition(id, ticket) => {
+                self.with_condition_variable(&Ponga::Ref(id), |cv| !cv.is_waiting(ticket))
+                    .unwrap_or(true)
+            }
+            Wait::Channels(ref channels) => channels.iter().any(|(id, op)| {
+                self.with_channel(&Ponga::Ref(*id), |channel| channel.is_ready(*op))
+                    .unwrap_or(true)
+            }),
+            Wait::Message => self.mailbox.as_ref().is_none_or(|mailbox| {
+                mailbox.has_pending() || mailbox.is_closed()
+            }),
+        }
+    }
+
+    // Gives every other thread that can go on a turn. If none can and the
+    // current thread is blocked too, waits until the first deadline, or
+    // until then for a message if a thread is waiting for one.
+    fn run_other_threads(&mut self) -> RunRes<()> {
+        let block = self.blocked.take();
+        if let Some(mailbox) = self.mailbox.as_mut() {
+            mailbox.fetch();
+        }
+        let mut ran = false;
+        for id in self.threads.clone() {
+            let ready = match self.with_thread(&Ponga::Ref(id), |thread| thread.state.clone())? {
+                ThreadState::Ready(block) => block.is_none_or(|block| self.can_resume(&block)),
+                _ => false,
+            };
+            if ready {
+                self.run_thread_turn(id)?;
+                ran = true;
+            }
+        }
+        let mut finished = vec![];
+        for id in self.threads.iter() {
+            if self.with_thread(&Ponga::Ref(*id), |thread| thread.is_done())? {
+                finished.push(*id);
+            }
+        }
+        self.threads.retain(|id| !finished.contains(id));
+
+        let block = match block {
+            Some(block) if !ran && !self.can_resume(&block) => block,
+            _ => return Ok(()),
+        };
+        let mut deadlines = vec![];
+        let mut for_message = block.wait == Wait::Message;
+        for id in self.threads.iter() {
+            if let ThreadState::Ready(Some(block)) =
+                self.with_thread(&Ponga::Ref(*id), |thread| thread.state.clone())? {
+                deadlines.extend(block.deadline);
+                for_message |= block.wait == Wait::Message;
+            }
+        }
+        deadlines.extend(block.deadline);
+        let deadline = deadlines.into_iter().min();
+        if for_message && self.mailbox.as_mut().is_some_and(|mailbox| mailbox.wait(deadline)) {
+            return Ok(());
+        }
+        match deadline {
+            Some(deadline) => {
+                let now = SystemTime::now();
+                std::thread::sleep(deadline.duration_since(now).unwrap_or_default());
+                Ok(())
+            }
+            None => Err(RuntimeErr::Other(
+                "Deadlock: every thread is blocked".to_string()
+            )),
+        }
+    }
+
+    // Runs the thread with this id until its turn is over, keeping its stacks
+    // and locals for next time
+    fn run_thread_turn(&mut self, id: Id) -> RunRes<()> {
+        let thread = Ponga::Ref(id);
+        let (ins_stack, data_stack, locals, env, parameters) = self.with_thread(&thread, |thread| {
+            thread.state = ThreadState::Running;
+            (std::mem::take(&mut thread.ins_stack),
+             std::mem::take(&mut thread.data_stack),
+             std::mem::take(&mut thread.locals),
+             thread.env,
+             std::mem::take(&mut *thread.parameters))
+        })?;
+        let outer_env = self.switch_environment(env)?;
+        let outer = std::mem::replace(&mut self.current_thread, thread.clone());
+        self.parked.push(Parked {
+            data_stack: vec![Ponga::Ref(outer_env), outer],
+            locals: std::mem::replace(&mut self.locals, locals),
+            parameters: std::mem::replace(&mut self.parameters, parameters),
+            ..Parked::default()
+        });
+        let res = self.run_loop(ins_stack, data_stack, LoopMode::Thread);
+        let mut parked = self.parked.pop().unwrap();
+        self.current_thread = parked.data_stack.pop().unwrap();
+        let env = self.switch_environment(outer_env)?;
+        let locals = std::mem::replace(&mut self.locals, parked.locals);
+        let parameters = std::mem::replace(&mut self.parameters, parked.parameters);
+        let block = self.blocked.take();
+        self.with_thread(&thread, |thread| match res {
+            Ok(Exit::Suspended(ins_stack, data_stack)) => {
+                thread.ins_stack = ins_stack;
+                thread.data_stack = data_stack;
+                thread.locals = locals;
+                thread.env = env;
+                *thread.parameters = parameters;
+                thread.state = ThreadState::Ready(block);
+            }
+            Ok(Exit::Done(val)) => thread.state = ThreadState::Done(Ok(Box::new(val))),
+            Err(e) => thread.state = ThreadState::Done(Err(e)),
+        })
+    }
+
+    // A generator that runs proc, passing it yield
+    pub fn make_coroutine(&mut self, proc: Ponga) -> RunRes<Ponga> {
+        let yield_func = self.global_funcs["yield"].clone();
+        let mut ins_stack = self.call_instructions(proc, vec![yield_func])?;
+        ins_stack.reverse();
+        Ok(self.gc.ponga_into_gc_ref(Ponga::Coroutine(Coroutine::new(ins_stack, self.current_env))))
+    }
+
+    pub fn coroutine_id(&self, coroutine: &Ponga) -> Option<Id> {
+        if let Ponga::Ref(id) = coroutine {
+            let obj = self.get_id_obj_ref(*id).ok()?.borrow()?;
+            if let Ponga::Coroutine(_) = obj.inner() {
+                return Some(*id);
+            }
+        }
+        None
+    }
+
+    pub fn with_coroutine<T>(&self, coroutine: &Ponga,
+                             func: impl FnOnce(&mut Coroutine) -> T) -> RunRes<T> {
+        self.with_object(coroutine, "coroutine", |obj| match obj {
+            Ponga::Coroutine(coroutine) => Some(func(coroutine)),
+            _ => None,
+        })
+    }
+
+    // Parameters and generators are called like procedures
+    fn is_applicable_object(&self, val: &Ponga) -> bool {
+        self.parameter_id(val).is_some() || self.coroutine_id(val).is_some()
+    }
+
+    // Makes the coroutine running now give back the value once the builtin
+    // currently running returns
+    pub fn yield_value(&mut self, val: Ponga) {
+        self.yielded = Some(val);
+    }
+
+    // Runs the coroutine until it yields, giving the value yielded, or the
+    // eof object once its procedure has returned
+    pub fn resume_coroutine(&mut self, id: Id) -> RunRes<Ponga> {
+        let coroutine = Ponga::Ref(id);
+        match self.with_coroutine(&coroutine, |coroutine| coroutine.state)? {
+            CoroutineState::Suspended => (),
+            CoroutineState::Running => return Err(RuntimeErr::Other(
+                "A generator can't be called while it's running".to_string()
+            )),
+            CoroutineState::Done => return Ok(Ponga::Eof),
+        }
+        let (ins_stack, data_stack, locals, env) = self.with_coroutine(&coroutine, |coroutine| {
+            coroutine.state = CoroutineState::Running;
+            (std::mem::take(&mut coroutine.ins_stack),
+             std::mem::take(&mut coroutine.data_stack),
+             std::mem::take(&mut coroutine.locals),
+             coroutine.env)
+        })?;
+        let outer_env = self.switch_environment(env)?;
+        self.parked.push(Parked {
+            data_stack: vec![Ponga::Ref(outer_env), coroutine.clone()],
+            locals: std::mem::replace(&mut self.locals, locals),
+            ..Parked::default()
+        });
+        let res = self.run_loop(ins_stack, data_stack, LoopMode::Coroutine);
+        let parked = self.parked.pop().unwrap();
+        let env = self.switch_environment(outer_env)?;
+        let locals = std::mem::replace(&mut self.locals, parked.locals);
+        let yielded = self.yielded.take();
+        self.with_coroutine(&coroutine, |coroutine| match res {
+            Ok(Exit::Suspended(ins_stack, data_stack)) => {
+                coroutine.ins_stack = ins_stack;
+                coroutine.data_stack = data_stack;
+                coroutine.locals = locals;
+                coroutine.env = env;
+                coroutine.state = CoroutineState::Suspended;
+                Ok(yielded.unwrap())
+            }
+            Ok(Exit::Done(_)) => {
+                coroutine.state = CoroutineState::Done;
+                Ok(Ponga::Eof)
+            }
+            Err(e) => {
+                coroutine.state = CoroutineState::Done;
+                Err(e)
+            }
+        })?
+    }
+
     pub fn run_file(&mut self, filename: &str) -> RunRes<()> {
         let mut file = File::open(filename)?;
         let mut contents = String::new();
         file.read_to_string(&mut contents)?;
-        self.run_str(&contents)
+        self.enter_file(Path::new(filename))?;
+        let res = self.run_str(&contents);
+        self.leave_file();
+        res
     }
 }
 
 pub fn run_str(s: &str) -> RunRes<Vec<RunRes<Ponga>>> {
-    let mut runtime = Runtime::new();
+    run_str_in(Runtime::new(), s)
+}
+
+pub fn run_str_in(mut runtime: Runtime, s: &str) -> RunRes<Vec<RunRes<Ponga>>> {
     let parsed = pongascript_parser(s);
     let parsed = parsed?;
     if parsed.0.len() != 0 {
@@ -1279,8 +3603,7 @@ pub fn run_str(s: &str) -> RunRes<Vec<RunRes<Ponga>>> {
             parsed.0
         )));
     }
-    let evald = parsed
-        .1
+    let evald = runtime.expand_includes(parsed.1, false)?
         .into_iter()
         .map(|x| {
             let res = runtime.eval(x);
@@ -1304,12 +3627,253 @@ pub fn run_str(s: &str) -> RunRes<Vec<RunRes<Ponga>>> {
 }
 
 pub fn run_file(s: &str) -> RunRes<Vec<RunRes<Ponga>>> {
+    run_file_in(Runtime::new(), s)
+}
+
+pub fn run_file_in(mut runtime: Runtime, s: &str) -> RunRes<Vec<RunRes<Ponga>>> {
     let mut file = File::open(s)?;
     let mut contents = String::new();
     file.read_to_string(&mut contents)?;
-    run_str(&contents)
+    runtime.enter_file(Path::new(s))?;
+    run_str_in(runtime, &contents)
+}
+
+fn parse_file(path: &Path) -> RunRes<Vec<Ponga>> {
+    let contents = std::fs::read_to_string(path)?;
+    let (rest, forms) = pongascript_parser(&contents)?;
+    if !rest.trim().is_empty() {
+        return Err(RuntimeErr::ParseError(format!(
+            "Unexpected tokens in {}: {}", path.display(), rest
+        )));
+    }
+    Ok(forms)
+}
+
+// What include-ci reads, with identifiers and symbols in lower case
+fn fold_case(form: Ponga) -> Ponga {
+    match form {
+        Ponga::Identifier(s) => Ponga::Identifier(s.to_lowercase()),
+        Ponga::Symbol(s) => Ponga::Symbol(s.to_lowercase()),
+        Ponga::Sexpr(arr) => Ponga::Sexpr(arr.into_iter().map(fold_case).collect()),
+        Ponga::Array(arr) => Ponga::Array(arr.into_iter().map(fold_case).collect()),
+        Ponga::List(list) => Ponga::List(list.into_iter().map(fold_case).collect()),
+        form => form,
+    }
 }
 
 pub fn pop_or<T>(vec: &mut Vec<T>) -> RunRes<T> {
     vec.pop().ok_or(RuntimeErr::Other(format!("Expected value in stack")))
 }
+
+fn is_form(pong: &Ponga, name: &str) -> bool {
+    match pong {
+        Ponga::Sexpr(arr) => matches!(arr.first(), Some(Ponga::Identifier(s)) if s == name),
+        _ => false,
+    }
+}
+
+// Body forms with any begins spliced in, since definitions inside them
+// belong to the enclosing body
+fn splice_begins(forms: Vec<Ponga>, res: &mut Vec<Ponga>) {
+    for form in forms.into_iter() {
+        if is_form(&form, "begin") {
+            let inner = form.get_array().unwrap().into_iter().skip(1).collect();
+            splice_begins(inner, res);
+        } else {
+            res.push(form);
+        }
+    }
+}
+
+// The name and value bound by an internal (define ...)
+fn define_binding(form: Ponga) -> RunRes<(String, Ponga)> {
+    let mut iter = form.get_array()?.into_iter().skip(1);
+    match iter.next() {
+        Some(Ponga::Identifier(name)) if iter.len() == 1 => Ok((name, iter.next().unwrap())),
+        Some(Ponga::Sexpr(formals)) if !formals.is_empty() && iter.len() > 0 => {
+            let mut formals = formals.into_iter();
+            let name = formals.next().unwrap().extract_name()?;
+            let mut lambda = vec![Ponga::Identifier("lambda".to_string()),
+                                  Ponga::Sexpr(formals.collect())];
+            lambda.extend(iter);
+            Ok((name, Ponga::Sexpr(lambda)))
+        }
+        _ => Err(RuntimeErr::Other(
+            "define first argument must be an identifier or an S-Expr of identifiers".to_string()
+        )),
+    }
+}
+
+// A single expression evaluating the forms in order
+fn sequence(mut forms: Vec<Ponga>) -> Ponga {
+    match forms.len() {
+        0 => Ponga::Null,
+        1 => forms.pop().unwrap(),
+        _ => {
+            forms.insert(0, Ponga::Identifier("begin".to_string()));
+            Ponga::Sexpr(forms)
+        }
+    }
+}
+
+// Turns the forms of a lambda or let body into one expression. Internal
+// definitions become a letrec* around the rest of the body, as in R7RS.
+pub fn make_body(forms: Vec<Ponga>) -> RunRes<Ponga> {
+    let mut spliced = vec![];
+    splice_begins(forms, &mut spliced);
+    let mut bindings = vec![];
+    let mut rest = vec![];
+    for form in spliced.into_iter() {
+        if is_form(&form, "define") {
+            let (name, val) = define_binding(form)?;
+            bindings.push(Ponga::Sexpr(vec![Ponga::Identifier(name), val]));
+        } else {
+            rest.push(form);
+        }
+    }
+    if bindings.is_empty() {
+        return Ok(sequence(rest));
+    }
+    if rest.is_empty() {
+        rest.push(Ponga::Null);
+    }
+    let mut res = vec![Ponga::Identifier("letrec*".to_string()), Ponga::Sexpr(bindings)];
+    res.extend(rest);
+    Ok(Ponga::Sexpr(res))
+}
+
+// The (name value) pairs of a let style binding list
+fn parse_bindings(bindings: Ponga, form: &str) -> RunRes<Vec<(String, Ponga)>> {
+    if !bindings.is_sexpr() {
+        return Err(RuntimeErr::Other(format!(
+            "first argument to {} must be an s-expr of bindings", form
+        )));
+    }
+    let mut res = vec![];
+    for pair in bindings.get_array()?.into_iter() {
+        match pair {
+            Ponga::Sexpr(pair) if pair.len() == 2 => {
+                let mut pair = pair.into_iter();
+                let name = pair.next().unwrap().extract_name()?;
+                res.push((name, pair.next().unwrap()));
+            }
+            _ => return Err(RuntimeErr::Other(format!(
+                "{} requires pairs of S-Exprs", form
+            ))),
+        }
+    }
+    Ok(res)
+}
+
+// Formals are a list of identifiers, optionally ending in `. rest`, or a
+// single identifier that takes every value
+fn parse_formals(formals: Ponga) -> RunRes<(Vec<String>, Option<String>)> {
+    match formals {
+        Ponga::Identifier(rest) => Ok((vec![], Some(rest))),
+        Ponga::Sexpr(arr) => {
+            let mut names = vec![];
+            let mut iter = arr.into_iter();
+            while let Some(name) = iter.next() {
+                let name = name.extract_name()?;
+                if name == "." {
+                    let rest = iter.next().map(|r| r.extract_name()).transpose()?;
+                    if rest.is_none() || iter.next().is_some() {
+                        return Err(RuntimeErr::Other(
+                            "expected one identifier after . in formals".to_string()
+                        ));
+                    }
+                    return Ok((names, rest));
+                }
+                names.push(name);
+            }
+            Ok((names, None))
+        }
+        _ => Err(RuntimeErr::Other(format!(
+            "formals must be identifiers (not {:?})", formals
+        ))),
+    }
+}
+
+// (let name ((var init) ...) body ...) is
+// ((letrec ((name (lambda (var ...) body ...))) name) init ...)
+fn named_let(name: String, mut rest: Vec<Ponga>) -> RunRes<Ponga> {
+    if rest.len() < 2 {
+        return Err(RuntimeErr::Other(
+            "named let must have bindings and a body".to_string()
+        ));
+    }
+    let body: Vec<Ponga> = rest.split_off(1);
+    let bindings = parse_bindings(rest.pop().unwrap(), "let")?;
+    let (vars, inits): (Vec<String>, Vec<Ponga>) = bindings.into_iter().unzip();
+    let mut lambda = vec![
+        Ponga::Identifier("lambda".to_string()),
+        Ponga::Sexpr(vars.into_iter().map(Ponga::Identifier).collect()),
+    ];
+    lambda.extend(body);
+    let letrec = Ponga::Sexpr(vec![
+        Ponga::Identifier("letrec".to_string()),
+        Ponga::Sexpr(vec![Ponga::Sexpr(vec![Ponga::Identifier(name.clone()), Ponga::Sexpr(lambda)])]),
+        Ponga::Identifier(name),
+    ]);
+    let mut res = vec![letrec];
+    res.extend(inits);
+    Ok(Ponga::Sexpr(res))
+}
+
+// (select ((chan -> var) body ...) ((chan <- val) body ...) (timeout secs body ...))
+// is ($SELECT secs timeout-thunk op chan val handler ...), with a handler
+// taking the value received for each receive op and nothing for each send.
+// An else clause is a timeout of zero.
+fn select_call(clauses: Vec<Ponga>) -> RunRes<Ponga> {
+    let lambda = |formals: Vec<Ponga>, mut body: Vec<Ponga>, default: Ponga| {
+        if body.is_empty() {
+            body.push(default);
+        }
+        let mut res = vec![Ponga::Identifier("lambda".to_string()), Ponga::Sexpr(formals)];
+        res.extend(body);
+        Ponga::Sexpr(res)
+    };
+    let invalid = |clause: &Ponga| RuntimeErr::Other(format!("Invalid select clause {:?}", clause));
+    let mut timeout = None;
+    let mut ops = vec![];
+    for clause in clauses.into_iter() {
+        let mut parts = match &clause {
+            Ponga::Sexpr(parts) if !parts.is_empty() => parts.clone().into_iter(),
+            _ => return Err(invalid(&clause)),
+        };
+        match parts.next().unwrap() {
+            Ponga::Identifier(s) if s == "else" || s == "timeout" => {
+                let secs = match s.as_str() {
+                    "else" => Ponga::Number(crate::number::Number::Int(0)),
+                    _ => parts.next().ok_or_else(|| invalid(&clause))?,
+                };
+                if timeout.is_some() {
+                    return Err(RuntimeErr::Other(
+                        "select can only have one timeout or else clause".to_string()
+                    ));
+                }
+                timeout = Some((secs, lambda(vec![], parts.collect(), Ponga::Null)));
+            }
+            Ponga::Sexpr(op) if op.len() == 3 => {
+                let (channel, arrow, x) = op.into_iter().collect_tuple().unwrap();
+                match arrow {
+                    Ponga::Identifier(s) if s == "->" => {
+                        let handler = lambda(vec![x.clone()], parts.collect(), x);
+                        ops.extend([Ponga::Symbol("receive".to_string()), channel,
+                                    Ponga::Null, handler]);
+                    }
+                    Ponga::Identifier(s) if s == "<-" => {
+                        let handler = lambda(vec![], parts.collect(), Ponga::Null);
+                        ops.extend([Ponga::Symbol("send".to_string()), channel, x, handler]);
+                    }
+                    _ => return Err(invalid(&clause)),
+                }
+            }
+            _ => return Err(invalid(&clause)),
+        }
+    }
+    let (secs, thunk) = timeout.unwrap_or((Ponga::False, Ponga::False));
+    let mut res = vec![Ponga::Identifier("$SELECT".to_string()), secs, thunk];
+    res.extend(ops);
+    Ok(Ponga::Sexpr(res))
+}
@@ -0,0 +1,274 @@
+use crate::gc::*;
+use crate::runtime::Runtime;
+use crate::types::*;
+use itertools::Itertools;
+use std::collections::HashMap;
+
+// A snapshot of every live object in the gc heap.
+//
+// The JSON export has the form
+//
+//   {
+//     "roots": [{"name": "x", "ids": [3], "retained": 120}, ...],
+//     "objects": [{"id": 3, "type": "vector", "size": 72, "retained": 120,
+//                  "reachable": true, "edges": [4]}, ...]
+//   }
+//
+// where `size` is the approximate number of bytes used by the object itself,
+// `edges` are the ids of the objects it strongly refers to (weak boxes and
+// weak hash table values are not included) and `retained` is the number of
+// bytes that would be freed if the object (or root) went away, i.e. the size
+// of everything it dominates. Roots are the global and local bindings, and
+// objects that are not reachable from any root are marked as unreachable
+// (they are garbage or only held by an evaluation in progress).
+//
+// The DOT export draws the same graph, with roots as boxes.
+
+pub struct HeapObject {
+    pub id: Id,
+    pub kind: &'static str,
+    pub size: usize,
+    pub retained: usize,
+    pub reachable: bool,
+    pub edges: Vec<Id>,
+}
+
+pub struct HeapRoot {
+    pub name: String,
+    pub ids: Vec<Id>,
+    pub retained: usize,
+}
+
+pub struct HeapSnapshot {
+    pub roots: Vec<HeapRoot>,
+    pub objects: Vec<HeapObject>,
+}
+
+impl HeapSnapshot {
+    pub fn new(runtime: &Runtime) -> HeapSnapshot {
+        let mut roots = vec![];
+        for (name, val) in runtime.globals.iter().sorted_by(|a, b| a.0.cmp(b.0)) {
+            roots.push(HeapRoot { name: name.clone(), ids: direct_ids(val), retained: 0 });
+        }
+        for (name, vals) in runtime.locals.iter().sorted_by(|a, b| a.0.cmp(b.0)) {
+            for val in vals.iter() {
+                roots.push(HeapRoot {
+                    name: format!("local {}", name),
+                    ids: direct_ids(val),
+                    retained: 0,
+                });
+            }
+        }
+        for (name, val) in runtime.other_roots() {
+            roots.push(HeapRoot { name, ids: direct_ids(&val), retained: 0 });
+        }
+
+        let mut objects = vec![];
+        for obj in runtime.gc.ptrs.values() {
+            let inner = obj.borrow().unwrap();
+            let mut edges = vec![];
+            inner.trace(&mut edges);
+            let edges = edges.into_iter()
+                             .filter(|id| runtime.gc.ptrs.contains(*id))
+                             .unique()
+                             .collect();
+            objects.push(HeapObject {
+                id: obj.id,
+                kind: inner.type_name(),
+                size: inner.approx_size(),
+                retained: 0,
+                reachable: false,
+                edges,
+            });
+        }
+
+        let mut res = HeapSnapshot { roots, objects };
+        res.compute_retained();
+        res
+    }
+
+    // Builds the dominator tree of the graph (a synthetic node pointing at
+    // every root, then the roots, then the objects) using the algorithm from
+    // Cooper, Harvey and Kennedy, "A Simple, Fast Dominance Algorithm"
+    fn compute_retained(&mut self) {
+        let n_roots = self.roots.len();
+        let index: HashMap<Id, usize> = self.objects
+                                            .iter()
+                                            .enumerate()
+                                            .map(|(i, obj)| (obj.id, 1 + n_roots + i))
+                                            .collect();
+        let n = 1 + n_roots + self.objects.len();
+        let mut succs = vec![vec![]; n];
+        succs[0] = (1..=n_roots).collect();
+        for (i, root) in self.roots.iter().enumerate() {
+            succs[1 + i] = root.ids.iter().filter_map(|id| index.get(id).cloned()).collect();
+        }
+        for (i, obj) in self.objects.iter().enumerate() {
+            succs[1 + n_roots + i] = obj.edges.iter().map(|id| index[id]).collect();
+        }
+
+        // Postorder numbering with an explicit stack
+        let mut post = vec![usize::MAX; n];
+        let mut order = vec![];
+        let mut visited = vec![false; n];
+        let mut stack = vec![(0, 0)];
+        visited[0] = true;
+        while let Some((node, child)) = stack.pop() {
+            if child < succs[node].len() {
+                stack.push((node, child + 1));
+                let next = succs[node][child];
+                if !visited[next] {
+                    visited[next] = true;
+                    stack.push((next, 0));
+                }
+            } else {
+                post[node] = order.len();
+                order.push(node);
+            }
+        }
+
+        let mut preds = vec![vec![]; n];
+        for (node, s) in succs.iter().enumerate() {
+            if visited[node] {
+                for next in s.iter() {
+                    preds[*next].push(node);
+                }
+            }
+        }
+
+        let mut idom = vec![usize::MAX; n];
+        idom[0] = 0;
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for node in order.iter().rev().skip(1) {
+                let mut new_idom = usize::MAX;
+                for pred in preds[*node].iter() {
+                    if idom[*pred] == usize::MAX {
+                        continue;
+                    }
+                    new_idom = if new_idom == usize::MAX {
+                        *pred
+                    } else {
+                        intersect(&idom, &post, *pred, new_idom)
+                    };
+                }
+                if idom[*node] != new_idom {
+                    idom[*node] = new_idom;
+                    changed = true;
+                }
+            }
+        }
+
+        let mut retained = vec![0; n];
+        for (i, obj) in self.objects.iter().enumerate() {
+            retained[1 + n_roots + i] = obj.size;
+        }
+        // Children come before their dominators in postorder
+        for node in order.iter() {
+            if *node != 0 {
+                retained[idom[*node]] += retained[*node];
+            }
+        }
+
+        for (i, root) in self.roots.iter_mut().enumerate() {
+            root.retained = retained[1 + i];
+        }
+        for (i, obj) in self.objects.iter_mut().enumerate() {
+            let node = 1 + n_roots + i;
+            obj.reachable = visited[node];
+            obj.retained = if visited[node] { retained[node] } else { obj.size };
+        }
+    }
+
+    pub fn total_size(&self) -> usize {
+        self.objects.iter().map(|obj| obj.size).sum()
+    }
+
+    pub fn to_json(&self) -> String {
+        let roots = self.roots.iter().map(|root| {
+            format!("    {{\"name\": {}, \"ids\": [{}], \"retained\": {}}}",
+                    json_string(&root.name), root.ids.iter().format(", "), root.retained)
+        }).join(",\n");
+        let objects = self.objects.iter().map(|obj| {
+            format!("    {{\"id\": {}, \"type\": \"{}\", \"size\": {}, \"retained\": {}, \
+                     \"reachable\": {}, \"edges\": [{}]}}",
+                    obj.id, obj.kind, obj.size, obj.retained, obj.reachable,
+                    obj.edges.iter().format(", "))
+        }).join(",\n");
+        format!("{{\n  \"roots\": [\n{}\n  ],\n  \"objects\": [\n{}\n  ]\n}}\n", roots, objects)
+    }
+
+    pub fn to_dot(&self) -> String {
+        let mut res = String::from("digraph heap {\n");
+        for (i, root) in self.roots.iter().enumerate() {
+            if root.ids.is_empty() {
+                continue;
+            }
+            res.push_str(&format!("  root{} [shape=box, label={}];\n",
+                                  i, json_string(&root.name)));
+            for id in root.ids.iter() {
+                res.push_str(&format!("  root{} -> obj{};\n", i, id));
+            }
+        }
+        for obj in self.objects.iter() {
+            res.push_str(&format!("  obj{} [label=\"#{} {}\\n{} bytes ({} retained)\"];\n",
+                                  obj.id, obj.id, obj.kind, obj.size, obj.retained));
+            for edge in obj.edges.iter() {
+                res.push_str(&format!("  obj{} -> obj{};\n", obj.id, edge));
+            }
+        }
+        res.push_str("}\n");
+        res
+    }
+
+    // The roots keeping the most memory alive
+    pub fn summary(&self, limit: usize) -> String {
+        let unreachable = self.objects.iter().filter(|obj| !obj.reachable).count();
+        let mut res = format!("{} objects, {} bytes ({} unreachable objects)\n",
+                              self.objects.len(), self.total_size(), unreachable);
+        res.push_str("Largest roots by retained size:\n");
+        let roots = self.roots
+                        .iter()
+                        .filter(|root| root.retained > 0)
+                        .sorted_by(|a, b| b.retained.cmp(&a.retained))
+                        .take(limit);
+        for root in roots {
+            res.push_str(&format!("  {}: {} bytes\n", root.name, root.retained));
+        }
+        res
+    }
+}
+
+fn direct_ids(pong: &Ponga) -> Vec<Id> {
+    let mut ids = vec![];
+    pong.trace(&mut ids);
+    ids
+}
+
+fn intersect(idom: &[usize], post: &[usize], mut a: usize, mut b: usize) -> usize {
+    while a != b {
+        while post[a] < post[b] {
+            a = idom[a];
+        }
+        while post[b] < post[a] {
+            b = idom[b];
+        }
+    }
+    a
+}
+
+fn json_string(s: &str) -> String {
+    let mut res = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => res.push_str("\\\""),
+            '\\' => res.push_str("\\\\"),
+            '\n' => res.push_str("\\n"),
+            c if (c as u32) < 0x20 => res.push_str(&format!("\\u{:04x}", c as u32)),
+            c => res.push(c),
+        }
+    }
+    res.push('"');
+    res
+}
@@ -1,10 +1,106 @@
 use crate::runtime::*;
+use crate::gc::*;
+use crate::hash_table::*;
+use crate::instructions::Until;
+use crate::pool::*;
+use crate::port::*;
+use crate::promise::Promise;
+use crate::thread::*;
 use crate::types::*;
 use crate::number::*;
 use std::collections::LinkedList;
 use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-pub const FUNCS: &[(&str, fn(&mut Runtime, Vec<Ponga>) -> RunRes<Ponga>)] = &[
+// The standard libraries the builtins belong to, for import. Every builtin
+// is also exported by (pongascript).
+pub const LIBRARIES: &[(&str, &[&str])] = &[
+    ("(scheme base)", &[
+        "cons", "null?", "car", "cdr", "list", "length", "append", "reverse", "list-copy",
+        "list-ref", "list-tail", "member", "memq", "memv", "assoc", "assq", "assv",
+        "map", "for-each", "apply", "vector?", "vector", "make-vector", "vector-length",
+        "vector-ref", "vector-set!", "vector-fill!", "vector-copy", "vector-copy!",
+        "vector-map", "vector-for-each", "vector->list", "list->vector", "vector->string",
+        "string->vector", "bytevector?", "make-bytevector", "bytevector",
+        "bytevector-length", "bytevector-u8-ref", "bytevector-u8-set!", "bytevector-copy",
+        "bytevector-append", "utf8->string", "string->utf8", "+", "-", "*", "/", "=", "<", "<=", ">", ">=", "modulo",
+        "floor", "ceiling", "even?", "eq?", "eqv?", "equal?", "not", "and", "or",
+        "string?", "make-string", "string", "string-length", "string-ref", "string-set!",
+        "substring", "string-append", "string-copy", "string-fill!", "string->list",
+        "list->string", "string-for-each", "string=?", "string<?", "string>?",
+        "string<=?", "string>=?", "char?", "char->integer", "integer->char", "char=?",
+        "char<?", "char>?", "char<=?", "char>=?", "values", "call-with-values",
+        "make-parameter",
+        "port?", "input-port?", "output-port?", "textual-port?", "binary-port?",
+        "input-port-open?", "output-port-open?", "current-input-port",
+        "current-output-port", "current-error-port", "open-input-string",
+        "open-output-string", "get-output-string", "open-input-bytevector",
+        "open-output-bytevector", "get-output-bytevector", "close-port", "close-input-port",
+        "close-output-port", "read-char", "peek-char", "read-line", "read-string",
+        "read-bytevector", "char-ready?", "read-u8", "peek-u8", "u8-ready?", "write-char",
+        "write-string", "write-u8", "write-bytevector", "newline", "flush-output-port", "eof-object", "eof-object?",
+        "call-with-port",
+    ]),
+    ("(scheme char)", &[
+        "char-upcase", "char-downcase", "char-foldcase", "char-alphabetic?",
+        "char-numeric?", "char-whitespace?", "char-upper-case?", "char-lower-case?",
+        "digit-value", "char-ci=?", "char-ci<?", "char-ci>?", "char-ci<=?", "char-ci>=?",
+        "string-upcase", "string-downcase", "string-foldcase", "string-ci=?",
+        "string-ci<?", "string-ci>?", "string-ci<=?", "string-ci>=?",
+    ]),
+    ("(scheme eval)", &["eval", "environment"]),
+    ("(scheme file)", &[
+        "open-input-file", "open-binary-input-file", "open-output-file",
+        "open-binary-output-file", "call-with-input-file", "call-with-output-file",
+        "with-input-from-file", "with-output-to-file",
+    ]),
+    ("(scheme inexact)", &["sqrt"]),
+    ("(scheme lazy)", &["force", "make-promise", "promise?"]),
+    ("(scheme load)", &["load"]),
+    ("(scheme r5rs)", &["scheme-report-environment"]),
+    ("(scheme read)", &["read"]),
+    ("(scheme repl)", &["interaction-environment"]),
+    ("(scheme write)", &["display", "write"]),
+    ("(srfi 1)", &[
+        "iota", "last", "take", "drop", "take-while", "drop-while", "append-map",
+        "filter-map", "filter", "remove", "partition", "fold", "fold-right", "reduce",
+        "count", "find", "find-tail", "list-index", "any", "every", "delete",
+        "delete-duplicates",
+    ]),
+    ("(srfi 18)", &[
+        "current-thread", "thread?", "make-thread", "thread-name", "thread-specific",
+        "thread-specific-set!", "thread-start!", "thread-yield!", "thread-sleep!",
+        "thread-join!", "mutex?", "make-mutex", "mutex-name", "mutex-state", "mutex-lock!",
+        "mutex-unlock!", "condition-variable?", "make-condition-variable",
+        "condition-variable-name", "condition-variable-signal!",
+        "condition-variable-broadcast!",
+    ]),
+    ("(srfi 41)", &[
+        "stream-null", "stream?", "stream-null?", "stream-pair?", "stream-car",
+        "stream-cdr", "stream->list", "list->stream", "stream-take",
+    ]),
+    ("(srfi 69)", &[
+        "make-hash-table", "alist->hash-table", "hash-table?", "hash-table-ref",
+        "hash-table-ref/default", "hash-table-set!", "hash-table-delete!",
+        "hash-table-contains?", "hash-table-exists?", "hash-table-update!",
+        "hash-table-update!/default", "hash-table-count", "hash-table-size",
+        "hash-table-keys", "hash-table-values", "hash-table->alist", "hash-table-walk",
+        "hash-table-fold", "hash", "string-hash", "hash-by-identity",
+    ]),
+    ("(srfi 158)", &[
+        "generator", "list->generator", "make-coroutine-generator", "gmap", "gfilter",
+        "gtake", "generator->list", "generator-fold", "make-accumulator",
+        "count-accumulator", "list-accumulator", "reverse-list-accumulator",
+        "sum-accumulator", "product-accumulator",
+    ]),
+    ("(srfi 132)", &["list-sort", "vector-sort", "vector-sort!", "sorted?"]),
+    ("(pongascript pool)", &["worker-id", "worker-count", "send-message", "receive-message"]),
+];
+
+pub type Builtin = fn(&mut Runtime, Vec<Ponga>) -> RunRes<Ponga>;
+
+pub const FUNCS: &[(&str, Builtin)] = &[
     ("cons", cons),
     ("null?", null),
     ("car", car),
@@ -26,7 +122,6 @@ pub const FUNCS: &[(&str, fn(&mut Runtime, Vec<Ponga>) -> RunRes<Ponga>)] = &[
     (">=", ge),
     (">", gt),
     ("modulo", modulus),
-    ("cond", cond),
     ("display", disp),
     ("map", map_),
     ("foldl", foldl),
@@ -34,6 +129,27 @@ pub const FUNCS: &[(&str, fn(&mut Runtime, Vec<Ponga>) -> RunRes<Ponga>)] = &[
     ("vector-length", vector_len),
     ("vector-ref", vector_ref),
     ("vector-append!", vector_append),
+    ("make-vector", make_vector),
+    ("vector", vector),
+    ("vector-set!", vector_set),
+    ("vector-fill!", vector_fill),
+    ("vector-copy", vector_copy),
+    ("vector-copy!", vector_copy_to),
+    ("subvector", subvector),
+    ("vector-map", vector_map),
+    ("vector-for-each", vector_for_each),
+    ("vector->string", vector_to_string),
+    ("string->vector", string_to_vector),
+    ("bytevector?", bytevector_p),
+    ("make-bytevector", make_bytevector),
+    ("bytevector", bytevector),
+    ("bytevector-length", bytevector_length),
+    ("bytevector-u8-ref", bytevector_u8_ref),
+    ("bytevector-u8-set!", bytevector_u8_set),
+    ("bytevector-copy", bytevector_copy),
+    ("bytevector-append", bytevector_append),
+    ("utf8->string", utf8_to_string),
+    ("string->utf8", string_to_utf8),
     ("list->map", list_to_map),
     ("map-contains?", map_contains),
     ("map-ref", map_ref),
@@ -43,9 +159,238 @@ pub const FUNCS: &[(&str, fn(&mut Runtime, Vec<Ponga>) -> RunRes<Ponga>)] = &[
     ("sqrt", sqrt),
     ("string->list", string_to_list),
     ("list->string", list_to_string),
+    ("string?", string_query),
+    ("make-string", make_string),
+    ("string", string),
+    ("string-length", string_length),
+    ("string-ref", string_ref),
+    ("substring", substring),
+    ("string-append", string_append),
+    ("string-copy", string_copy),
+    ("string-set!", string_set),
+    ("string-fill!", string_fill),
+    ("string-upcase", string_upcase),
+    ("string-downcase", string_downcase),
+    ("string-foldcase", string_foldcase),
+    ("string=?", string_eq),
+    ("string<?", string_lt),
+    ("string>?", string_gt),
+    ("string<=?", string_le),
+    ("string>=?", string_ge),
+    ("string-ci=?", string_ci_eq),
+    ("string-ci<?", string_ci_lt),
+    ("string-ci>?", string_ci_gt),
+    ("string-ci<=?", string_ci_le),
+    ("string-ci>=?", string_ci_ge),
+    ("string-index", string_index),
+    ("string-contains", string_contains),
+    ("string-split", string_split),
+    ("string-join", string_join),
+    ("string-pad", string_pad),
+    ("string-pad-right", string_pad_right),
+    ("string-for-each", string_for_each),
+    ("char?", char_query),
+    ("char->integer", char_to_integer),
+    ("integer->char", integer_to_char),
+    ("char-upcase", char_upcase),
+    ("char-downcase", char_downcase),
+    ("char-foldcase", char_foldcase),
+    ("char-alphabetic?", char_alphabetic),
+    ("char-numeric?", char_numeric),
+    ("char-whitespace?", char_whitespace),
+    ("char-upper-case?", char_upper_case),
+    ("char-lower-case?", char_lower_case),
+    ("digit-value", digit_value),
+    ("char=?", char_eq),
+    ("char<?", char_lt),
+    ("char>?", char_gt),
+    ("char<=?", char_le),
+    ("char>=?", char_ge),
+    ("char-ci=?", char_ci_eq),
+    ("char-ci<?", char_ci_lt),
+    ("char-ci>?", char_ci_gt),
+    ("char-ci<=?", char_ci_le),
+    ("char-ci>=?", char_ci_ge),
     ("show", show),
     ("len", len),
     ("reverse", reverse),
+    ("list", list),
+    ("length", length),
+    ("append", append),
+    ("list-copy", list_copy),
+    ("list-ref", list_ref),
+    ("list-tail", list_tail),
+    ("last", last),
+    ("iota", iota),
+    ("take", take),
+    ("drop", drop_),
+    ("take-while", take_while),
+    ("drop-while", drop_while),
+    ("for-each", for_each),
+    ("apply", apply),
+    ("append-map", append_map),
+    ("filter-map", filter_map),
+    ("filter", filter),
+    ("remove", remove),
+    ("partition", partition),
+    ("fold", fold),
+    ("fold-right", fold_right),
+    ("reduce", reduce),
+    ("count", count),
+    ("find", find),
+    ("find-tail", find_tail),
+    ("list-index", list_index),
+    ("any", any),
+    ("every", every),
+    ("member", member),
+    ("memq", memq),
+    ("memv", memv),
+    ("assoc", assoc),
+    ("assq", assq),
+    ("assv", assv),
+    ("delete", delete),
+    ("delete-duplicates", delete_duplicates),
+    ("sort", sort),
+    ("sort!", sort_in_place),
+    ("list-sort", list_sort),
+    ("vector-sort", vector_sort),
+    ("vector-sort!", vector_sort_in_place),
+    ("sorted?", sorted),
+    ("merge", merge),
+    ("make-weak-box", make_weak_box),
+    ("weak-box?", weak_box_query),
+    ("weak-box-value", weak_box_value),
+    ("make-weak-hash-table", make_weak_hash_table),
+    ("weak-hash-table-set!", weak_hash_table_set),
+    ("weak-hash-table-ref", weak_hash_table_ref),
+    ("weak-hash-table-delete!", weak_hash_table_delete),
+    ("weak-hash-table-count", weak_hash_table_count),
+    ("weak-hash-table-keys", weak_hash_table_keys),
+    ("set-finalizer!", set_finalizer),
+    ("make-hash-table", make_hash_table),
+    ("alist->hash-table", alist_to_hash_table),
+    ("hash-table?", hash_table_query),
+    ("hash-table-ref", hash_table_ref),
+    ("hash-table-ref/default", hash_table_ref_default),
+    ("hash-table-set!", hash_table_set),
+    ("hash-table-delete!", hash_table_delete),
+    ("hash-table-contains?", hash_table_contains),
+    ("hash-table-exists?", hash_table_contains),
+    ("hash-table-update!", hash_table_update),
+    ("hash-table-update!/default", hash_table_update_default),
+    ("hash-table-count", hash_table_count),
+    ("hash-table-size", hash_table_count),
+    ("hash-table-keys", hash_table_keys),
+    ("hash-table-values", hash_table_values),
+    ("hash-table->alist", hash_table_to_alist),
+    ("hash-table-walk", hash_table_walk),
+    ("hash-table-fold", hash_table_fold),
+    ("hash", hash),
+    ("equal-hash", hash),
+    ("string-hash", string_hash),
+    ("hash-by-identity", hash_by_identity),
+    ("record?", record_p),
+    ("$MAKE-RECORD", make_record),
+    ("$RECORD?", record_of_type_p),
+    ("$RECORD-REF", record_ref),
+    ("$RECORD-SET!", record_set),
+    ("force", force),
+    ("make-promise", make_promise),
+    ("promise?", promise_p),
+    ("stream->list", stream_to_list),
+    ("list->stream", list_to_stream),
+    ("stream-take", stream_take),
+    ("values", values),
+    ("call-with-values", call_with_values),
+    ("make-parameter", make_parameter),
+    ("port?", port_p),
+    ("input-port?", input_port_p),
+    ("output-port?", output_port_p),
+    ("textual-port?", textual_port_p),
+    ("binary-port?", binary_port_p),
+    ("input-port-open?", input_port_open_p),
+    ("output-port-open?", output_port_open_p),
+    ("open-input-file", open_input_file),
+    ("open-binary-input-file", open_binary_input_file),
+    ("open-output-file", open_output_file),
+    ("open-binary-output-file", open_binary_output_file),
+    ("open-input-string", open_input_string),
+    ("open-output-string", open_output_string),
+    ("get-output-string", get_output_string),
+    ("open-input-bytevector", open_input_bytevector),
+    ("open-output-bytevector", open_output_bytevector),
+    ("get-output-bytevector", get_output_bytevector),
+    ("close-port", close_port),
+    ("close-input-port", close_port),
+    ("close-output-port", close_port),
+    ("read-char", read_char),
+    ("peek-char", peek_char),
+    ("read-line", read_line),
+    ("read-string", read_string),
+    ("read-bytevector", read_bytevector),
+    ("char-ready?", char_ready),
+    ("read-u8", read_u8),
+    ("peek-u8", peek_u8),
+    ("u8-ready?", char_ready),
+    ("write", write),
+    ("write-char", write_char),
+    ("write-string", write_string),
+    ("write-u8", write_u8),
+    ("write-bytevector", write_bytevector),
+    ("newline", newline),
+    ("flush-output-port", flush_output_port),
+    ("eof-object", eof_object),
+    ("eof-object?", eof_object_p),
+    ("call-with-port", call_with_port),
+    ("call-with-output-string", call_with_output_string),
+    ("call-with-input-file", call_with_input_file),
+    ("call-with-output-file", call_with_output_file),
+    ("with-output-to-string", with_output_to_string),
+    ("with-output-to-file", with_output_to_file),
+    ("with-input-from-file", with_input_from_file),
+    ("with-input-from-string", with_input_from_string),
+    ("read", read),
+    ("read-all", read_all),
+    ("load", load),
+    ("eval", eval),
+    ("environment", environment),
+    ("scheme-report-environment", scheme_report_environment),
+    ("interaction-environment", interaction_environment),
+    ("current-thread", current_thread),
+    ("thread?", thread_p),
+    ("make-thread", make_thread),
+    ("thread-name", thread_name),
+    ("thread-specific", thread_specific),
+    ("thread-specific-set!", thread_specific_set),
+    ("thread-start!", thread_start),
+    ("thread-yield!", thread_yield),
+    ("thread-sleep!", thread_sleep),
+    ("thread-join!", thread_join),
+    ("mutex?", mutex_p),
+    ("make-mutex", make_mutex),
+    ("mutex-name", mutex_name),
+    ("mutex-state", mutex_state),
+    ("mutex-lock!", mutex_lock),
+    ("mutex-unlock!", mutex_unlock),
+    ("condition-variable?", condition_variable_p),
+    ("make-condition-variable", make_condition_variable),
+    ("condition-variable-name", condition_variable_name),
+    ("condition-variable-signal!", condition_variable_signal),
+    ("condition-variable-broadcast!", condition_variable_broadcast),
+    ("make-channel", make_channel),
+    ("channel?", channel_p),
+    ("channel-send", channel_send),
+    ("channel-receive", channel_receive),
+    ("channel-close", channel_close),
+    ("channel-closed?", channel_closed_p),
+    ("$SELECT", select),
+    ("make-coroutine-generator", make_coroutine_generator),
+    ("yield", yield_value),
+    ("generator", generator),
+    ("worker-id", worker_id),
+    ("worker-count", worker_count),
+    ("send-message", send_message),
+    ("receive-message", receive_message),
 ];
 
 pub fn transform_args(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Vec<Ponga>> {
@@ -76,6 +421,15 @@ pub fn args_assert_gt(args: &Vec<Ponga>, len: usize, name: &str) -> RunRes<()> {
     Ok(())
 }
 
+pub fn args_assert_between(args: &[Ponga], min: usize, max: usize, name: &str) -> RunRes<()> {
+    if args.len() < min || args.len() > max {
+        return Err(RuntimeErr::TypeError(format!(
+            "{} requires {} to {} arguments", name, min, max
+        )));
+    }
+    Ok(())
+}
+
 pub fn bool_to_ponga(b: bool) -> Ponga {
     if b {
         Ponga::True
@@ -105,6 +459,7 @@ pub fn cons(runtime: &mut Runtime, mut args: Vec<Ponga>) -> RunRes<Ponga> {
                 runtime.gc.add_obj_with_id(taken_obj, id);
                 Ok(Ponga::Ref(id))
             } else {
+                runtime.gc.add_obj_with_id(taken_obj, id);
                 Ok(runtime
                     .gc
                     .ponga_into_gc_ref(Ponga::List([first, Ponga::Ref(id)].into_iter().collect())))
@@ -185,6 +540,12 @@ pub fn car(runtime: &mut Runtime, mut args: Vec<Ponga>) -> RunRes<Ponga> {
 }
 
 pub fn map_(runtime: &mut Runtime, mut args: Vec<Ponga>) -> RunRes<Ponga> {
+    if args.len() > 2 {
+        let (func, columns) = list_columns(runtime, args, "map")?;
+        runtime.tail_map(func, columns, Until::End)?;
+        runtime.then(Continuation::ListOf, vec![]);
+        return Ok(Ponga::Null);
+    }
     args_assert_len(&mut args, 2, "map")?;
     let mut args = transform_args(runtime, args)?;
     let iterable = args.pop().unwrap();
@@ -211,40 +572,27 @@ pub fn map_(runtime: &mut Runtime, mut args: Vec<Ponga>) -> RunRes<Ponga> {
             let r = obj.inner();
             match r {
                 Ponga::List(list) => {
-                    let cloned: LinkedList<Ponga> = list.clone();
-                    drop(list);
+                    let columns = list.iter().map(|i| vec![i.clone()]).collect();
                     drop(obj);
-                    let mut res = LinkedList::new();
-                    for i in cloned.into_iter() {
-                        let sexpr = Ponga::Sexpr(vec![func.clone(), i]);
-                        res.push_back(runtime.eval(sexpr)?);
-                    }
-                    let res_ref = runtime.gc.ponga_into_gc_ref(Ponga::List(res));
-                    Ok(res_ref)
+                    runtime.tail_map(func, columns, Until::End)?;
+                    runtime.then(Continuation::ListOf, vec![]);
+                    Ok(Ponga::Null)
                 }
                 Ponga::Array(arr) => {
-                    let cloned: Vec<Ponga> = arr.clone();
-                    drop(arr);
+                    let columns = arr.iter().map(|i| vec![i.clone()]).collect();
                     drop(obj);
-                    let mut res = Vec::new();
-                    for i in cloned.into_iter() {
-                        let sexpr = Ponga::Sexpr(vec![func.clone(), i]);
-                        res.push(runtime.eval(sexpr)?);
-                    }
-                    let res_ref = runtime.gc.ponga_into_gc_ref(Ponga::Array(res));
-                    Ok(res_ref)
+                    runtime.tail_map(func, columns, Until::End)?;
+                    runtime.then(Continuation::VectorOf, vec![]);
+                    Ok(Ponga::Null)
                 }
                 Ponga::Object(o) => {
-                    let cloned: HashMap<String, Ponga> = o.clone();
-                    drop(o);
+                    let (keys, columns): (Vec<Ponga>, Vec<Vec<Ponga>>) = o.iter().map(|(k, v)| {
+                        (Ponga::String(k.clone()), vec![v.clone()])
+                    }).unzip();
                     drop(obj);
-                    let mut res = HashMap::new();
-                    for (k, v) in cloned.into_iter() {
-                        let sexpr = Ponga::Sexpr(vec![func.clone(), v]);
-                        res.insert(k, runtime.eval(sexpr)?);
-                    }
-                    let res_ref = runtime.gc.ponga_into_gc_ref(Ponga::Object(res));
-                    Ok(res_ref)
+                    runtime.tail_map(func, columns, Until::End)?;
+                    runtime.then(Continuation::ObjectOf, vec![Ponga::Array(keys)]);
+                    Ok(Ponga::Null)
                 }
                 _ => Err(RuntimeErr::TypeError(format!("map requires an iterable"))),
             }
@@ -351,10 +699,7 @@ pub fn teq(runtime: &mut Runtime, mut args: Vec<Ponga>) -> RunRes<Ponga> {
     // println!("EQUAL {:?}", args);
     let snd = args.pop().unwrap();
     let fst = args.pop().unwrap();
-    // Ok for now
-    Ok(bool_to_ponga(
-        runtime.ponga_to_string(&fst) == runtime.ponga_to_string(&snd)
-    ))
+    Ok(bool_to_ponga(Equiv::Equal.equal(runtime, &fst, &snd)?))
 }
 
 pub fn peq(runtime: &mut Runtime, mut args: Vec<Ponga>) -> RunRes<Ponga> {
@@ -444,47 +789,19 @@ pub fn modulus(runtime: &mut Runtime, mut args: Vec<Ponga>) -> RunRes<Ponga> {
     Ok(Ponga::Number(fst.modulus(snd)))
 }
 
-pub fn cond(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
-    args_assert_gt(&args, 1, "cond")?;
-    for arg in args.into_iter() {
-        match arg {
-            Ponga::Sexpr(v) => {
-                if v.len() != 2 {
-                    return Err(RuntimeErr::TypeError(format!(
-                        "Args to cond must be S-Expr pairs"
-                    )));
-                }
-                let mut iter = v.into_iter();
-                let first = iter.next().unwrap();
-                match &first {
-                    Ponga::Identifier(s) => {
-                        if s == "else" {
-                            return runtime.eval(iter.next().unwrap());
-                        }
-                    }
-                    _ => (),
-                }
-                let cond = runtime.eval(first)?;
-                match cond {
-                    Ponga::True => return runtime.eval(iter.next().unwrap()),
-                    _ => continue,
-                }
-            }
-            _ => {
-                return Err(RuntimeErr::TypeError(format!(
-                    "cond requires a S-Expr args"
-                )))
-            }
-        }
-    }
-    Ok(Ponga::Null)
-}
-
+// Writes strings and chars as they are rather than as literals
 pub fn disp(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
-    args_assert_len(&args, 1, "display")?;
-    let mut args = transform_args(runtime, args)?;
-    let arg = runtime.eval(args.pop().unwrap())?;
-    println!("{}", runtime.ponga_to_string(&arg));
+    args_assert_between(&args, 1, 2, "display")?;
+    let mut args = transform_args(runtime, args)?.into_iter();
+    let val = args.next().unwrap();
+    let s = match (runtime.get_string(&val), &val) {
+        (Some(s), _) => s,
+        (None, Ponga::Char(c)) => c.to_string(),
+        (None, Ponga::Symbol(s)) => s.clone(),
+        (None, _) => runtime.ponga_to_string(&val),
+    };
+    let port = output_port_arg(runtime, args.next())?;
+    with_port(runtime, &port, "display", |p| p.write_str(&s))?;
     Ok(Ponga::Null)
 }
 
@@ -493,7 +810,7 @@ pub fn foldl(runtime: &mut Runtime, mut args: Vec<Ponga>) -> RunRes<Ponga> {
     let mut args = transform_args(runtime, args)?;
     // println!("ARGS: {:?}", args);
     let iterable = args.pop().unwrap();
-    let mut acc = args.pop().unwrap();
+    let acc = args.pop().unwrap();
     let func = args.pop().unwrap();
     if !func.is_func() {
         let mut fail = true;
@@ -518,34 +835,22 @@ pub fn foldl(runtime: &mut Runtime, mut args: Vec<Ponga>) -> RunRes<Ponga> {
             let r = obj.inner();
             match r {
                 Ponga::List(list) => {
-                    let cloned: LinkedList<Ponga> = list.clone();
-                    drop(list);
+                    let columns = list.iter().map(|i| vec![i.clone()]).collect();
                     drop(obj);
-                    for i in cloned.into_iter() {
-                        let sexpr = Ponga::Sexpr(vec![func.clone(), acc, i]);
-                        acc = runtime.eval(sexpr)?;
-                    }
-                    Ok(acc)
+                    runtime.tail_fold(func, acc, columns, false)?;
+                    Ok(Ponga::Null)
                 }
                 Ponga::Array(arr) => {
-                    let cloned: Vec<Ponga> = arr.clone();
-                    drop(arr);
+                    let columns = arr.iter().map(|i| vec![i.clone()]).collect();
                     drop(obj);
-                    for i in cloned.into_iter() {
-                        let sexpr = Ponga::Sexpr(vec![func.clone(), acc, i]);
-                        acc = runtime.eval(sexpr)?;
-                    }
-                    Ok(acc)
+                    runtime.tail_fold(func, acc, columns, false)?;
+                    Ok(Ponga::Null)
                 }
                 Ponga::Object(o) => {
-                    let cloned: HashMap<String, Ponga> = o.clone();
-                    drop(o);
+                    let columns = o.values().map(|v| vec![v.clone()]).collect();
                     drop(obj);
-                    for (_, v) in cloned.into_iter() {
-                        let sexpr = Ponga::Sexpr(vec![func.clone(), acc, v]);
-                        acc = runtime.eval(sexpr)?;
-                    }
-                    Ok(acc)
+                    runtime.tail_fold(func, acc, columns, false)?;
+                    Ok(Ponga::Null)
                 }
                 _ => Err(RuntimeErr::TypeError(format!("foldl requires an iterable"))),
             }
@@ -558,7 +863,7 @@ pub fn foldr(runtime: &mut Runtime, mut args: Vec<Ponga>) -> RunRes<Ponga> {
     args_assert_len(&mut args, 3, "foldl")?;
     let mut args = transform_args(runtime, args)?;
     let iterable = args.pop().unwrap();
-    let mut acc = args.pop().unwrap();
+    let acc = args.pop().unwrap();
     let func = args.pop().unwrap();
     if !func.is_func() {
         let mut fail = true;
@@ -582,34 +887,22 @@ pub fn foldr(runtime: &mut Runtime, mut args: Vec<Ponga>) -> RunRes<Ponga> {
             let r = obj.inner();
             match r {
                 Ponga::List(list) => {
-                    let cloned: LinkedList<Ponga> = list.clone();
-                    drop(list);
+                    let columns = list.iter().rev().map(|i| vec![i.clone()]).collect();
                     drop(obj);
-                    for i in cloned.into_iter().rev() {
-                        let sexpr = Ponga::Sexpr(vec![func.clone(), i, acc]);
-                        acc = runtime.eval(sexpr)?;
-                    }
-                    Ok(acc)
+                    runtime.tail_fold(func, acc, columns, true)?;
+                    Ok(Ponga::Null)
                 }
                 Ponga::Array(arr) => {
-                    let cloned: Vec<Ponga> = arr.clone();
-                    drop(arr);
+                    let columns = arr.iter().rev().map(|i| vec![i.clone()]).collect();
                     drop(obj);
-                    for i in cloned.into_iter().rev() {
-                        let sexpr = Ponga::Sexpr(vec![func.clone(), i, acc]);
-                        acc = runtime.eval(sexpr)?;
-                    }
-                    Ok(acc)
+                    runtime.tail_fold(func, acc, columns, true)?;
+                    Ok(Ponga::Null)
                 }
                 Ponga::Object(o) => {
-                    let cloned: HashMap<String, Ponga> = o.clone();
-                    drop(o);
+                    let columns = o.values().map(|v| vec![v.clone()]).collect();
                     drop(obj);
-                    for (_, v) in cloned.into_iter() {
-                        let sexpr = Ponga::Sexpr(vec![func.clone(), v, acc]);
-                        acc = runtime.eval(sexpr)?;
-                    }
-                    Ok(acc)
+                    runtime.tail_fold(func, acc, columns, true)?;
+                    Ok(Ponga::Null)
                 }
                 _ => Err(RuntimeErr::TypeError(format!("foldr requires an iterable"))),
             }
@@ -642,7 +935,7 @@ pub fn vector_ref(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
     if !n.is_number() {
         return Err(RuntimeErr::TypeError(format!("vector-ref requires a number")));
     }
-    let arg = runtime.eval(args.pop().unwrap())?;
+    let arg = args.pop().unwrap();
     match arg {
         Ponga::Ref(id) => {
             let obj = runtime.get_id_obj_ref(id)?.borrow().unwrap();
@@ -686,6 +979,193 @@ pub fn vector_append(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
     }
 }
 
+// Vectors live in the gc and are shared between every reference to them, so
+// all of these operate on the heap object in place
+fn with_vector<T>(runtime: &Runtime, vec: &Ponga, name: &str,
+                  func: impl FnOnce(&mut Vec<Ponga>) -> RunRes<T>) -> RunRes<T> {
+    match vec {
+        Ponga::Ref(id) => {
+            let mut obj = runtime.get_id_obj_ref(*id)?.borrow_mut().unwrap();
+            match obj.inner() {
+                Ponga::Array(v) => func(v),
+                _ => Err(RuntimeErr::TypeError(format!("{} requires a vector", name))),
+            }
+        }
+        _ => Err(RuntimeErr::TypeError(format!("{} requires a vector", name))),
+    }
+}
+
+fn index_arg(pong: &Ponga, name: &str) -> RunRes<usize> {
+    match pong {
+        Ponga::Number(Number::Int(n)) if *n >= 0 => Ok(*n as usize),
+        _ => Err(RuntimeErr::TypeError(format!(
+            "{} requires a non-negative integer index (not {:?})", name, pong
+        ))),
+    }
+}
+
+// The optional start and end arguments shared by the slicing procedures
+fn range_args(start: Option<Ponga>, end: Option<Ponga>, len: usize,
+              name: &str) -> RunRes<(usize, usize)> {
+    let start = match start {
+        Some(start) => index_arg(&start, name)?,
+        None => 0,
+    };
+    let end = match end {
+        Some(end) => index_arg(&end, name)?,
+        None => len,
+    };
+    if start > end || end > len {
+        return Err(RuntimeErr::TypeError(format!(
+            "{} range {} to {} out of bounds for length {}", name, start, end, len
+        )));
+    }
+    Ok((start, end))
+}
+
+pub fn make_vector(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    args_assert_between(&args, 1, 2, "make-vector")?;
+    let mut args = transform_args(runtime, args)?.into_iter();
+    let len = index_arg(&args.next().unwrap(), "make-vector")?;
+    let fill = args.next().unwrap_or(Ponga::False);
+    Ok(runtime.gc.ponga_into_gc_ref(Ponga::Array(vec![fill; len])))
+}
+
+pub fn vector(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    let args = transform_args(runtime, args)?;
+    Ok(runtime.gc.ponga_into_gc_ref(Ponga::Array(args)))
+}
+
+pub fn vector_set(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    args_assert_len(&args, 3, "vector-set!")?;
+    let mut args = transform_args(runtime, args)?;
+    let val = args.pop().unwrap();
+    let n = index_arg(&args.pop().unwrap(), "vector-set!")?;
+    let vec = args.pop().unwrap();
+    with_vector(runtime, &vec, "vector-set!", |v| {
+        match v.get_mut(n) {
+            Some(slot) => {
+                *slot = val;
+                Ok(())
+            }
+            None => Err(RuntimeErr::TypeError("vector-set! index out of bounds".to_string())),
+        }
+    })?;
+    Ok(Ponga::Null)
+}
+
+pub fn vector_fill(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    args_assert_between(&args, 2, 4, "vector-fill!")?;
+    let mut args = transform_args(runtime, args)?.into_iter();
+    let vec = args.next().unwrap();
+    let fill = args.next().unwrap();
+    let (start, end) = (args.next(), args.next());
+    with_vector(runtime, &vec, "vector-fill!", |v| {
+        let (start, end) = range_args(start, end, v.len(), "vector-fill!")?;
+        for slot in v[start..end].iter_mut() {
+            *slot = fill.clone();
+        }
+        Ok(())
+    })?;
+    Ok(Ponga::Null)
+}
+
+fn copy_range(runtime: &mut Runtime, args: Vec<Ponga>, name: &str) -> RunRes<Vec<Ponga>> {
+    let mut args = transform_args(runtime, args)?.into_iter();
+    let vec = args.next().unwrap();
+    let (start, end) = (args.next(), args.next());
+    with_vector(runtime, &vec, name, |v| {
+        let (start, end) = range_args(start, end, v.len(), name)?;
+        Ok(v[start..end].to_vec())
+    })
+}
+
+pub fn vector_copy(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    args_assert_between(&args, 1, 3, "vector-copy")?;
+    let copied = copy_range(runtime, args, "vector-copy")?;
+    Ok(runtime.gc.ponga_into_gc_ref(Ponga::Array(copied)))
+}
+
+pub fn subvector(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    args_assert_len(&args, 3, "subvector")?;
+    let copied = copy_range(runtime, args, "subvector")?;
+    Ok(runtime.gc.ponga_into_gc_ref(Ponga::Array(copied)))
+}
+
+// (vector-copy! to at from [start [end]]). The source is copied out first,
+// so overlapping copies within the same vector behave as if buffered.
+pub fn vector_copy_to(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    args_assert_between(&args, 3, 5, "vector-copy!")?;
+    let mut args = transform_args(runtime, args)?.into_iter();
+    let to = args.next().unwrap();
+    let at = index_arg(&args.next().unwrap(), "vector-copy!")?;
+    let from = args.next().unwrap();
+    let (start, end) = (args.next(), args.next());
+    let copied = with_vector(runtime, &from, "vector-copy!", |v| {
+        let (start, end) = range_args(start, end, v.len(), "vector-copy!")?;
+        Ok(v[start..end].to_vec())
+    })?;
+    with_vector(runtime, &to, "vector-copy!", |v| {
+        if at + copied.len() > v.len() {
+            return Err(RuntimeErr::TypeError("vector-copy! destination is too small".to_string()));
+        }
+        for (slot, val) in v[at..].iter_mut().zip(copied) {
+            *slot = val;
+        }
+        Ok(())
+    })?;
+    Ok(Ponga::Null)
+}
+
+// The elements of each vector argument, up to the length of the shortest one
+fn vector_columns(runtime: &mut Runtime, args: Vec<Ponga>,
+                  name: &str) -> RunRes<(Ponga, Vec<Vec<Ponga>>)> {
+    args_assert_gt(&args, 1, name)?;
+    let mut args = transform_args(runtime, args)?.into_iter();
+    let func = args.next().unwrap();
+    let mut vecs = vec![];
+    for vec in args {
+        vecs.push(with_vector(runtime, &vec, name, |v| Ok(v.clone()))?);
+    }
+    let len = vecs.iter().map(|v| v.len()).min().unwrap();
+    let columns = (0..len).map(|i| vecs.iter().map(|v| v[i].clone()).collect()).collect();
+    Ok((func, columns))
+}
+
+pub fn vector_map(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    let (func, columns) = vector_columns(runtime, args, "vector-map")?;
+    runtime.tail_map(func, columns, Until::End)?;
+    runtime.then(Continuation::VectorOf, vec![]);
+    Ok(Ponga::Null)
+}
+
+pub fn vector_for_each(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    let (func, columns) = vector_columns(runtime, args, "vector-for-each")?;
+    runtime.call_each(func, columns)?;
+    Ok(Ponga::Null)
+}
+
+pub fn vector_to_string(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    args_assert_between(&args, 1, 3, "vector->string")?;
+    let chars = copy_range(runtime, args, "vector->string")?;
+    let mut string = String::new();
+    for ch in chars.iter() {
+        string.push(ch.char_to_char()?);
+    }
+    Ok(Ponga::String(string))
+}
+
+pub fn string_to_vector(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    args_assert_between(&args, 1, 3, "string->vector")?;
+    let mut args = transform_args(runtime, args)?.into_iter();
+    let chars: Vec<Ponga> = chars_arg(runtime, &args.next().unwrap(), "string->vector")?
+        .into_iter()
+        .map(Ponga::Char)
+        .collect();
+    let (start, end) = range_args(args.next(), args.next(), chars.len(), "string->vector")?;
+    Ok(runtime.gc.ponga_into_gc_ref(Ponga::Array(chars[start..end].to_vec())))
+}
+
 pub fn floor(runtime: &mut Runtime, mut args: Vec<Ponga>) -> RunRes<Ponga> {
     args_assert_len(&mut args, 1, "floor")?;
     let mut args = transform_args(runtime, args)?;
@@ -842,8 +1322,8 @@ pub fn string_to_list(runtime: &mut Runtime, mut args: Vec<Ponga>) -> RunRes<Pon
     args_assert_len(&mut args, 1, "string->list")?;
     let mut args = transform_args(runtime, args)?;
     let s = args.pop().unwrap();
-    match s {
-        Ponga::String(s) => {
+    match runtime.get_string(&s) {
+        Some(s) => {
             let mut list = LinkedList::new();
             for c in s.chars() {
                 list.push_back(Ponga::Char(c));
@@ -872,57 +1352,3237 @@ pub fn list_to_string(runtime: &mut Runtime, mut args: Vec<Ponga>) -> RunRes<Pon
     }
 }
 
-pub fn show(runtime: &mut Runtime, mut args: Vec<Ponga>) -> RunRes<Ponga> {
-    args_assert_len(&mut args, 1, "show")?;
-    let s = args.pop().unwrap();
-    Ok(Ponga::String(runtime.ponga_to_string(&s)))
+// Strings are indexed by unicode scalar values, so most of these work on
+// the string as a vector of chars
+fn string_arg(runtime: &Runtime, pong: &Ponga, name: &str) -> RunRes<String> {
+    runtime.get_string(pong).ok_or_else(|| RuntimeErr::TypeError(format!(
+        "{} requires a string (not {})", name, runtime.ponga_to_string(pong)
+    )))
 }
 
-pub fn len(runtime: &mut Runtime, mut args: Vec<Ponga>) -> RunRes<Ponga> {
-    args_assert_len(&mut args, 1, "len")?;
-    let mut args = transform_args(runtime, args)?;
-    let iterable = args.pop().unwrap();
-    
-    match iterable {
+fn chars_arg(runtime: &Runtime, pong: &Ponga, name: &str) -> RunRes<Vec<char>> {
+    Ok(string_arg(runtime, pong, name)?.chars().collect())
+}
+
+fn char_arg(pong: &Ponga, name: &str) -> RunRes<char> {
+    match pong {
+        Ponga::Char(c) => Ok(*c),
+        _ => Err(RuntimeErr::TypeError(format!("{} requires a char (not {:?})", name, pong))),
+    }
+}
+
+fn with_mut_string<T>(runtime: &Runtime, pong: &Ponga, name: &str,
+                      func: impl FnOnce(&mut Vec<char>) -> RunRes<T>) -> RunRes<T> {
+    let err = || RuntimeErr::TypeError(format!(
+        "{} requires a mutable string, such as one made by string-copy", name
+    ));
+    match pong {
         Ponga::Ref(id) => {
-            let obj = runtime.get_id_obj_ref(id)?.borrow().unwrap();
-            let r = obj.inner();
-            match r {
-                Ponga::List(list) => Ok(Ponga::Number(Number::Int(list.len() as isize))),
-                Ponga::Array(arr) => Ok(Ponga::Number(Number::Int(arr.len() as isize))),
-                Ponga::Object(o) => Ok(Ponga::Number(Number::Int(o.len() as isize))),
-                _ => Ok(Ponga::Number(Number::Int(1))),
+            let mut obj = runtime.get_id_obj_ref(*id)?.borrow_mut().unwrap();
+            match obj.inner() {
+                Ponga::MutString(s) => {
+                    let mut chars = s.chars().collect();
+                    let res = func(&mut chars)?;
+                    *s = chars.into_iter().collect();
+                    Ok(res)
+                }
+                _ => Err(err()),
             }
         }
-        Ponga::String(s) => Ok(Ponga::Number(Number::Int(s.len() as isize))),
-        _ => Ok(Ponga::Number(Number::Int(1))),
+        _ => Err(err()),
     }
 }
 
-pub fn reverse(runtime: &mut Runtime, mut args: Vec<Ponga>) -> RunRes<Ponga> {
-    args_assert_len(&mut args, 1, "len")?;
+pub fn string_query(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    args_assert_len(&args, 1, "string?")?;
     let mut args = transform_args(runtime, args)?;
-    let iterable = args.pop().unwrap();
-    
-    match iterable {
-        Ponga::Ref(id) => {
-            let obj = runtime.get_id_obj_ref(id)?.borrow().unwrap();
-            let r = obj.inner();
-            match r {
-                Ponga::List(list) => {
-                    let res = Ponga::List(list.into_iter().rev().cloned().collect());
-                    drop(obj);
-                    Ok(runtime.gc.ponga_into_gc_ref(res))
-                }
-                Ponga::Array(arr) => {
-                    let res = Ponga::Array(arr.into_iter().rev().cloned().collect());
-                    drop(obj);
-                    Ok(runtime.gc.ponga_into_gc_ref(res))
-                }
-                _ => Ok(Ponga::Ref(id)),
+    let arg = args.pop().unwrap();
+    Ok(bool_to_ponga(runtime.get_string(&arg).is_some()))
+}
+
+pub fn make_string(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    args_assert_between(&args, 1, 2, "make-string")?;
+    let mut args = transform_args(runtime, args)?.into_iter();
+    let len = index_arg(&args.next().unwrap(), "make-string")?;
+    let fill = match args.next() {
+        Some(c) => char_arg(&c, "make-string")?,
+        None => ' ',
+    };
+    let s = std::iter::repeat_n(fill, len).collect();
+    Ok(runtime.gc.ponga_into_gc_ref(Ponga::MutString(s)))
+}
+
+pub fn string(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    let args = transform_args(runtime, args)?;
+    let mut s = String::new();
+    for arg in args.iter() {
+        s.push(char_arg(arg, "string")?);
+    }
+    Ok(runtime.gc.ponga_into_gc_ref(Ponga::MutString(s)))
+}
+
+pub fn string_length(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    args_assert_len(&args, 1, "string-length")?;
+    let mut args = transform_args(runtime, args)?;
+    let s = string_arg(runtime, &args.pop().unwrap(), "string-length")?;
+    Ok(Ponga::Number(Number::Int(s.chars().count() as isize)))
+}
+
+pub fn string_ref(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    args_assert_len(&args, 2, "string-ref")?;
+    let mut args = transform_args(runtime, args)?;
+    let n = index_arg(&args.pop().unwrap(), "string-ref")?;
+    let s = string_arg(runtime, &args.pop().unwrap(), "string-ref")?;
+    s.chars()
+     .nth(n)
+     .map(Ponga::Char)
+     .ok_or_else(|| RuntimeErr::TypeError("string-ref index out of bounds".to_string()))
+}
+
+// The chars of a string argument followed by optional start and end indices
+fn string_range(runtime: &mut Runtime, args: Vec<Ponga>, name: &str) -> RunRes<String> {
+    let mut args = transform_args(runtime, args)?.into_iter();
+    let chars = chars_arg(runtime, &args.next().unwrap(), name)?;
+    let (start, end) = range_args(args.next(), args.next(), chars.len(), name)?;
+    Ok(chars[start..end].iter().collect())
+}
+
+pub fn substring(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    args_assert_between(&args, 2, 3, "substring")?;
+    Ok(Ponga::String(string_range(runtime, args, "substring")?))
+}
+
+pub fn string_copy(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    args_assert_between(&args, 1, 3, "string-copy")?;
+    let s = string_range(runtime, args, "string-copy")?;
+    Ok(runtime.gc.ponga_into_gc_ref(Ponga::MutString(s)))
+}
+
+pub fn string_append(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    let args = transform_args(runtime, args)?;
+    let mut res = String::new();
+    for arg in args.iter() {
+        res.push_str(&string_arg(runtime, arg, "string-append")?);
+    }
+    Ok(Ponga::String(res))
+}
+
+pub fn string_set(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    args_assert_len(&args, 3, "string-set!")?;
+    let mut args = transform_args(runtime, args)?;
+    let c = char_arg(&args.pop().unwrap(), "string-set!")?;
+    let n = index_arg(&args.pop().unwrap(), "string-set!")?;
+    let s = args.pop().unwrap();
+    with_mut_string(runtime, &s, "string-set!", |chars| {
+        match chars.get_mut(n) {
+            Some(slot) => {
+                *slot = c;
+                Ok(())
             }
+            None => Err(RuntimeErr::TypeError("string-set! index out of bounds".to_string())),
         }
-        Ponga::String(s) => Ok(Ponga::String(s.chars().rev().collect())),
-        _ => Ok(Ponga::Number(Number::Int(1))),
+    })?;
+    Ok(Ponga::Null)
+}
+
+pub fn string_fill(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    args_assert_between(&args, 2, 4, "string-fill!")?;
+    let mut args = transform_args(runtime, args)?.into_iter();
+    let s = args.next().unwrap();
+    let c = char_arg(&args.next().unwrap(), "string-fill!")?;
+    let (start, end) = (args.next(), args.next());
+    with_mut_string(runtime, &s, "string-fill!", |chars| {
+        let (start, end) = range_args(start, end, chars.len(), "string-fill!")?;
+        for slot in chars[start..end].iter_mut() {
+            *slot = c;
+        }
+        Ok(())
+    })?;
+    Ok(Ponga::Null)
+}
+
+fn map_string(runtime: &mut Runtime, args: Vec<Ponga>, name: &str,
+              func: fn(&str) -> String) -> RunRes<Ponga> {
+    args_assert_len(&args, 1, name)?;
+    let mut args = transform_args(runtime, args)?;
+    let s = string_arg(runtime, &args.pop().unwrap(), name)?;
+    Ok(Ponga::String(func(&s)))
+}
+
+pub fn string_upcase(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    map_string(runtime, args, "string-upcase", str::to_uppercase)
+}
+
+pub fn string_downcase(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    map_string(runtime, args, "string-downcase", str::to_lowercase)
+}
+
+pub fn string_foldcase(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    map_string(runtime, args, "string-foldcase", foldcase_str)
+}
+
+fn foldcase_str(s: &str) -> String {
+    s.chars().flat_map(fold_chars).collect()
+}
+
+// Checks that each adjacent pair of strings is ordered by `cmp`
+fn compare_strings(runtime: &mut Runtime, args: Vec<Ponga>, name: &str, fold: bool,
+                   cmp: fn(&String, &String) -> bool) -> RunRes<Ponga> {
+    args_assert_gt(&args, 0, name)?;
+    let args = transform_args(runtime, args)?;
+    let mut strs = vec![];
+    for arg in args.iter() {
+        let s = string_arg(runtime, arg, name)?;
+        strs.push(if fold { foldcase_str(&s) } else { s });
+    }
+    Ok(bool_to_ponga(strs.windows(2).all(|w| cmp(&w[0], &w[1]))))
+}
+
+pub fn string_eq(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    compare_strings(runtime, args, "string=?", false, |a, b| a == b)
+}
+
+pub fn string_lt(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    compare_strings(runtime, args, "string<?", false, |a, b| a < b)
+}
+
+pub fn string_gt(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    compare_strings(runtime, args, "string>?", false, |a, b| a > b)
+}
+
+pub fn string_le(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    compare_strings(runtime, args, "string<=?", false, |a, b| a <= b)
+}
+
+pub fn string_ge(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    compare_strings(runtime, args, "string>=?", false, |a, b| a >= b)
+}
+
+pub fn string_ci_eq(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    compare_strings(runtime, args, "string-ci=?", true, |a, b| a == b)
+}
+
+pub fn string_ci_lt(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    compare_strings(runtime, args, "string-ci<?", true, |a, b| a < b)
+}
+
+pub fn string_ci_gt(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    compare_strings(runtime, args, "string-ci>?", true, |a, b| a > b)
+}
+
+pub fn string_ci_le(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    compare_strings(runtime, args, "string-ci<=?", true, |a, b| a <= b)
+}
+
+pub fn string_ci_ge(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    compare_strings(runtime, args, "string-ci>=?", true, |a, b| a >= b)
+}
+
+// (string-index s pred [start end]), where pred is a char or a procedure.
+// Returns the index of the first matching char, or #f.
+pub fn string_index(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    args_assert_between(&args, 2, 4, "string-index")?;
+    let mut args = transform_args(runtime, args)?.into_iter();
+    let chars = chars_arg(runtime, &args.next().unwrap(), "string-index")?;
+    let pred = args.next().unwrap();
+    let (start, end) = range_args(args.next(), args.next(), chars.len(), "string-index")?;
+    let p = match pred {
+        Ponga::Char(p) => p,
+        pred => {
+            let columns = chars[start..end].iter().map(|c| vec![Ponga::Char(*c)]).collect();
+            runtime.tail_map(pred, columns, Until::True)?;
+            let offset = Ponga::Number(Number::Int(start as isize));
+            runtime.then(Continuation::FoundIndex, vec![offset]);
+            return Ok(Ponga::Null);
+        }
+    };
+    match chars[start..end].iter().position(|c| *c == p) {
+        Some(i) => Ok(Ponga::Number(Number::Int((start + i) as isize))),
+        None => Ok(Ponga::False),
+    }
+}
+
+// The char index where the second string first occurs in the first, or #f
+pub fn string_contains(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    args_assert_len(&args, 2, "string-contains")?;
+    let mut args = transform_args(runtime, args)?;
+    let needle = chars_arg(runtime, &args.pop().unwrap(), "string-contains")?;
+    let haystack = chars_arg(runtime, &args.pop().unwrap(), "string-contains")?;
+    if needle.is_empty() {
+        return Ok(Ponga::Number(Number::Int(0)));
+    }
+    Ok(haystack.windows(needle.len())
+               .position(|w| w == needle.as_slice())
+               .map(|i| Ponga::Number(Number::Int(i as isize)))
+               .unwrap_or(Ponga::False))
+}
+
+// (string-split s [delimiter]) splits on a char or string delimiter, or on
+// runs of whitespace if none is given
+pub fn string_split(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    args_assert_between(&args, 1, 2, "string-split")?;
+    let mut args = transform_args(runtime, args)?.into_iter();
+    let s = string_arg(runtime, &args.next().unwrap(), "string-split")?;
+    let parts: Vec<String> = match args.next() {
+        Some(Ponga::Char(c)) => s.split(c).map(String::from).collect(),
+        Some(delim) => {
+            let delim = string_arg(runtime, &delim, "string-split")?;
+            if delim.is_empty() {
+                return Err(RuntimeErr::TypeError("string-split delimiter can't be empty".to_string()));
+            }
+            s.split(delim.as_str()).map(String::from).collect()
+        }
+        None => s.split_whitespace().map(String::from).collect(),
+    };
+    let list = parts.into_iter().map(Ponga::String).collect();
+    Ok(runtime.gc.ponga_into_gc_ref(Ponga::List(list)))
+}
+
+// (string-join list [delimiter [grammar]]), where grammar is one of 'infix
+// (the default), 'strict-infix, 'prefix or 'suffix
+pub fn string_join(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    args_assert_between(&args, 1, 3, "string-join")?;
+    let mut args = transform_args(runtime, args)?.into_iter();
+    let items = match args.next().unwrap() {
+        Ponga::Ref(id) => match runtime.get_id_obj_ref(id)?.borrow().unwrap().inner() {
+            Ponga::List(list) => list.iter().cloned().collect::<Vec<Ponga>>(),
+            _ => return Err(RuntimeErr::TypeError("string-join requires a list".to_string())),
+        },
+        Ponga::List(list) => list.into_iter().collect(),
+        Ponga::Null => vec![],
+        _ => return Err(RuntimeErr::TypeError("string-join requires a list".to_string())),
+    };
+    let delim = match args.next() {
+        Some(delim) => string_arg(runtime, &delim, "string-join")?,
+        None => " ".to_string(),
+    };
+    let mut strs = vec![];
+    for item in items.iter() {
+        strs.push(string_arg(runtime, item, "string-join")?);
+    }
+    let joined = strs.join(&delim);
+    match args.next() {
+        None => Ok(Ponga::String(joined)),
+        Some(Ponga::Symbol(grammar)) => match grammar.as_str() {
+            "infix" => Ok(Ponga::String(joined)),
+            "strict-infix" if strs.is_empty() => Err(RuntimeErr::TypeError(
+                "string-join with 'strict-infix requires a non-empty list".to_string()
+            )),
+            "strict-infix" => Ok(Ponga::String(joined)),
+            "prefix" if strs.is_empty() => Ok(Ponga::String(String::new())),
+            "prefix" => Ok(Ponga::String(format!("{}{}", delim, joined))),
+            "suffix" if strs.is_empty() => Ok(Ponga::String(String::new())),
+            "suffix" => Ok(Ponga::String(format!("{}{}", joined, delim))),
+            _ => Err(RuntimeErr::TypeError(format!("string-join: unknown grammar {}", grammar))),
+        },
+        Some(grammar) => Err(RuntimeErr::TypeError(format!(
+            "string-join grammar must be a symbol (not {:?})", grammar
+        ))),
+    }
+}
+
+pub fn string_for_each(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    args_assert_gt(&args, 1, "string-for-each")?;
+    let mut args = transform_args(runtime, args)?.into_iter();
+    let func = args.next().unwrap();
+    let mut strs = vec![];
+    for s in args {
+        strs.push(chars_arg(runtime, &s, "string-for-each")?);
+    }
+    let len = strs.iter().map(|s| s.len()).min().unwrap();
+    let columns = (0..len).map(|i| strs.iter().map(|s| Ponga::Char(s[i])).collect()).collect();
+    runtime.call_each(func, columns)?;
+    Ok(Ponga::Null)
+}
+
+fn pad_args(runtime: &mut Runtime, args: Vec<Ponga>,
+            name: &str) -> RunRes<(Vec<char>, usize, char)> {
+    args_assert_between(&args, 2, 3, name)?;
+    let mut args = transform_args(runtime, args)?.into_iter();
+    let chars = chars_arg(runtime, &args.next().unwrap(), name)?;
+    let len = index_arg(&args.next().unwrap(), name)?;
+    let fill = match args.next() {
+        Some(c) => char_arg(&c, name)?,
+        None => ' ',
+    };
+    Ok((chars, len, fill))
+}
+
+// Pads on the left to the given length, or keeps the rightmost chars if the
+// string is already longer
+pub fn string_pad(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    let (chars, len, fill) = pad_args(runtime, args, "string-pad")?;
+    let res = if chars.len() >= len {
+        chars[chars.len() - len..].iter().collect()
+    } else {
+        std::iter::repeat_n(fill, len - chars.len()).chain(chars).collect()
+    };
+    Ok(Ponga::String(res))
+}
+
+pub fn string_pad_right(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    let (chars, len, fill) = pad_args(runtime, args, "string-pad-right")?;
+    let res = chars.into_iter().chain(std::iter::repeat(fill)).take(len).collect();
+    Ok(Ponga::String(res))
+}
+
+// The zero of every run of ten decimal digits (general category Nd) in
+// Unicode 13
+const DIGIT_ZEROS: &[u32] = &[
+    0x30, 0x660, 0x6f0, 0x7c0, 0x966, 0x9e6, 0xa66, 0xae6, 0xb66, 0xbe6, 0xc66,
+    0xce6, 0xd66, 0xde6, 0xe50, 0xed0, 0xf20, 0x1040, 0x1090, 0x17e0, 0x1810,
+    0x1946, 0x19d0, 0x1a80, 0x1a90, 0x1b50, 0x1bb0, 0x1c40, 0x1c50, 0xa620,
+    0xa8d0, 0xa900, 0xa9d0, 0xa9f0, 0xaa50, 0xabf0, 0xff10, 0x104a0, 0x10d30,
+    0x11066, 0x110f0, 0x11136, 0x111d0, 0x112f0, 0x11450, 0x114d0, 0x11650,
+    0x116c0, 0x11730, 0x118e0, 0x11950, 0x11c50, 0x11d50, 0x11da0, 0x16a60,
+    0x16b50, 0x1d7ce, 0x1d7d8, 0x1d7e2, 0x1d7ec, 0x1d7f6, 0x1e140, 0x1e2f0,
+    0x1e950, 0x1fbf0,
+];
+
+fn char_digit_value(c: char) -> Option<u32> {
+    let c = c as u32;
+    DIGIT_ZEROS.iter()
+               .find(|zero| (**zero..**zero + 10).contains(&c))
+               .map(|zero| c - zero)
+}
+
+// Case mappings that would need more than one char leave the char as it is
+fn map_char<I: Iterator<Item = char>>(c: char, mapped: I) -> char {
+    let mapped: Vec<char> = mapped.collect();
+    match mapped.as_slice() {
+        [single] => *single,
+        _ => c,
+    }
+}
+
+// Full case folding, which std doesn't have. Lowercasing each char's
+// uppercase expands ones like ß to ss and merges variants like final sigma.
+// Cherokee folds to its uppercase letters, and dotless i doesn't fold.
+fn fold_chars(c: char) -> Vec<char> {
+    let shifted = |from: u32, to: u32| vec![char::from_u32(c as u32 - from + to).unwrap()];
+    match c {
+        'ı' | '\u{13a0}'..='\u{13f5}' => vec![c],
+        '\u{13f8}'..='\u{13fd}' => shifted(0x13f8, 0x13f0),
+        '\u{ab70}'..='\u{abbf}' => shifted(0xab70, 0x13a0),
+        _ => c.to_lowercase().flat_map(char::to_uppercase).flat_map(char::to_lowercase).collect(),
+    }
+}
+
+// Chars that fold to more than one, like ß, are only lowercased
+fn foldcase_char(c: char) -> char {
+    match fold_chars(c).as_slice() {
+        [single] => *single,
+        _ => map_char(c, c.to_lowercase()),
+    }
+}
+
+fn single_char_arg(runtime: &mut Runtime, args: Vec<Ponga>, name: &str) -> RunRes<char> {
+    args_assert_len(&args, 1, name)?;
+    let mut args = transform_args(runtime, args)?;
+    char_arg(&args.pop().unwrap(), name)
+}
+
+pub fn char_query(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    args_assert_len(&args, 1, "char?")?;
+    let mut args = transform_args(runtime, args)?;
+    Ok(bool_to_ponga(args.pop().unwrap().is_char()))
+}
+
+pub fn char_to_integer(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    let c = single_char_arg(runtime, args, "char->integer")?;
+    Ok(Ponga::Number(Number::Int(c as isize)))
+}
+
+pub fn integer_to_char(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    args_assert_len(&args, 1, "integer->char")?;
+    let mut args = transform_args(runtime, args)?;
+    let n = args.pop().unwrap();
+    match n {
+        Ponga::Number(Number::Int(i)) if i >= 0 => {
+            u32::try_from(i).ok()
+                            .and_then(char::from_u32)
+                            .map(Ponga::Char)
+                            .ok_or_else(|| RuntimeErr::TypeError(format!(
+                                "integer->char: {} is not a unicode scalar value", i
+                            )))
+        }
+        _ => Err(RuntimeErr::TypeError(format!(
+            "integer->char requires a non-negative integer (not {:?})", n
+        ))),
+    }
+}
+
+pub fn char_upcase(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    let c = single_char_arg(runtime, args, "char-upcase")?;
+    Ok(Ponga::Char(map_char(c, c.to_uppercase())))
+}
+
+pub fn char_downcase(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    let c = single_char_arg(runtime, args, "char-downcase")?;
+    Ok(Ponga::Char(map_char(c, c.to_lowercase())))
+}
+
+pub fn char_foldcase(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    let c = single_char_arg(runtime, args, "char-foldcase")?;
+    Ok(Ponga::Char(foldcase_char(c)))
+}
+
+pub fn char_alphabetic(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    let c = single_char_arg(runtime, args, "char-alphabetic?")?;
+    Ok(bool_to_ponga(c.is_alphabetic()))
+}
+
+// Only decimal digits count, so e.g. roman numerals and fractions do not
+pub fn char_numeric(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    let c = single_char_arg(runtime, args, "char-numeric?")?;
+    Ok(bool_to_ponga(char_digit_value(c).is_some()))
+}
+
+pub fn char_whitespace(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    let c = single_char_arg(runtime, args, "char-whitespace?")?;
+    Ok(bool_to_ponga(c.is_whitespace()))
+}
+
+pub fn char_upper_case(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    let c = single_char_arg(runtime, args, "char-upper-case?")?;
+    Ok(bool_to_ponga(c.is_uppercase()))
+}
+
+pub fn char_lower_case(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    let c = single_char_arg(runtime, args, "char-lower-case?")?;
+    Ok(bool_to_ponga(c.is_lowercase()))
+}
+
+pub fn digit_value(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    let c = single_char_arg(runtime, args, "digit-value")?;
+    Ok(char_digit_value(c).map(|d| Ponga::Number(Number::Int(d as isize)))
+                          .unwrap_or(Ponga::False))
+}
+
+// Checks that each adjacent pair of chars is ordered by `cmp`
+fn compare_chars(runtime: &mut Runtime, args: Vec<Ponga>, name: &str, fold: bool,
+                 cmp: fn(char, char) -> bool) -> RunRes<Ponga> {
+    args_assert_gt(&args, 0, name)?;
+    let args = transform_args(runtime, args)?;
+    let mut chars = vec![];
+    for arg in args.iter() {
+        let c = char_arg(arg, name)?;
+        chars.push(if fold { foldcase_char(c) } else { c });
+    }
+    Ok(bool_to_ponga(chars.windows(2).all(|w| cmp(w[0], w[1]))))
+}
+
+pub fn char_eq(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    compare_chars(runtime, args, "char=?", false, |a, b| a == b)
+}
+
+pub fn char_lt(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    compare_chars(runtime, args, "char<?", false, |a, b| a < b)
+}
+
+pub fn char_gt(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    compare_chars(runtime, args, "char>?", false, |a, b| a > b)
+}
+
+pub fn char_le(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    compare_chars(runtime, args, "char<=?", false, |a, b| a <= b)
+}
+
+pub fn char_ge(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    compare_chars(runtime, args, "char>=?", false, |a, b| a >= b)
+}
+
+pub fn char_ci_eq(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    compare_chars(runtime, args, "char-ci=?", true, |a, b| a == b)
+}
+
+pub fn char_ci_lt(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    compare_chars(runtime, args, "char-ci<?", true, |a, b| a < b)
+}
+
+pub fn char_ci_gt(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    compare_chars(runtime, args, "char-ci>?", true, |a, b| a > b)
+}
+
+pub fn char_ci_le(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    compare_chars(runtime, args, "char-ci<=?", true, |a, b| a <= b)
+}
+
+pub fn char_ci_ge(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    compare_chars(runtime, args, "char-ci>=?", true, |a, b| a >= b)
+}
+
+pub fn show(runtime: &mut Runtime, mut args: Vec<Ponga>) -> RunRes<Ponga> {
+    args_assert_len(&mut args, 1, "show")?;
+    let s = args.pop().unwrap();
+    Ok(Ponga::String(runtime.ponga_to_string(&s)))
+}
+
+pub fn len(runtime: &mut Runtime, mut args: Vec<Ponga>) -> RunRes<Ponga> {
+    args_assert_len(&mut args, 1, "len")?;
+    let mut args = transform_args(runtime, args)?;
+    let iterable = args.pop().unwrap();
+    
+    match iterable {
+        Ponga::Ref(id) => {
+            let obj = runtime.get_id_obj_ref(id)?.borrow().unwrap();
+            let r = obj.inner();
+            match r {
+                Ponga::List(list) => Ok(Ponga::Number(Number::Int(list.len() as isize))),
+                Ponga::Array(arr) => Ok(Ponga::Number(Number::Int(arr.len() as isize))),
+                Ponga::Object(o) => Ok(Ponga::Number(Number::Int(o.len() as isize))),
+                _ => Ok(Ponga::Number(Number::Int(1))),
+            }
+        }
+        Ponga::String(s) => Ok(Ponga::Number(Number::Int(s.len() as isize))),
+        _ => Ok(Ponga::Number(Number::Int(1))),
+    }
+}
+
+pub fn reverse(runtime: &mut Runtime, mut args: Vec<Ponga>) -> RunRes<Ponga> {
+    args_assert_len(&mut args, 1, "len")?;
+    let mut args = transform_args(runtime, args)?;
+    let iterable = args.pop().unwrap();
+    
+    match iterable {
+        Ponga::Ref(id) => {
+            let obj = runtime.get_id_obj_ref(id)?.borrow().unwrap();
+            let r = obj.inner();
+            match r {
+                Ponga::List(list) => {
+                    let res = Ponga::List(list.into_iter().rev().cloned().collect());
+                    drop(obj);
+                    Ok(runtime.gc.ponga_into_gc_ref(res))
+                }
+                Ponga::Array(arr) => {
+                    let res = Ponga::Array(arr.into_iter().rev().cloned().collect());
+                    drop(obj);
+                    Ok(runtime.gc.ponga_into_gc_ref(res))
+                }
+                _ => Ok(Ponga::Ref(id)),
+            }
+        }
+        Ponga::String(s) => Ok(Ponga::String(s.chars().rev().collect())),
+        _ => Ok(Ponga::Number(Number::Int(1))),
+    }
+}
+
+// Lists are passed to builtins as refs to gc lists, except for the empty
+// list which may also be '() itself
+fn list_arg(runtime: &Runtime, pong: &Ponga, name: &str) -> RunRes<Vec<Ponga>> {
+    match pong {
+        Ponga::Ref(id) => match runtime.get_id_obj_ref(*id)?.borrow().unwrap().inner() {
+            Ponga::List(list) => Ok(list.iter().cloned().collect()),
+            _ => Err(RuntimeErr::TypeError(format!(
+                "{} requires a list (not {})", name, runtime.ponga_to_string(pong)
+            ))),
+        },
+        Ponga::List(list) => Ok(list.iter().cloned().collect()),
+        Ponga::Null => Ok(vec![]),
+        _ => Err(RuntimeErr::TypeError(format!(
+            "{} requires a list (not {})", name, runtime.ponga_to_string(pong)
+        ))),
+    }
+}
+
+fn list_result(runtime: &mut Runtime, items: Vec<Ponga>) -> Ponga {
+    runtime.gc.ponga_into_gc_ref(Ponga::List(items.into_iter().collect()))
+}
+
+fn is_true(pong: &Ponga) -> bool {
+    *pong != Ponga::False
+}
+
+// The elements of each list argument after the procedure, up to the length
+// of the shortest list
+fn list_columns(runtime: &mut Runtime, args: Vec<Ponga>,
+                name: &str) -> RunRes<(Ponga, Vec<Vec<Ponga>>)> {
+    args_assert_gt(&args, 1, name)?;
+    let mut args = transform_args(runtime, args)?.into_iter();
+    let func = args.next().unwrap();
+    let mut lists = vec![];
+    for list in args {
+        lists.push(list_arg(runtime, &list, name)?);
+    }
+    let len = lists.iter().map(|l| l.len()).min().unwrap();
+    let columns = (0..len).map(|i| lists.iter().map(|l| l[i].clone()).collect()).collect();
+    Ok((func, columns))
+}
+
+// What a builtin does with the results of the procedures it scheduled, once
+// they've run in the caller's loop. Each is called like a builtin, on the
+// arguments it was given followed by the result.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Continuation {
+    // Mapped results as a list, a vector, or an object with the given keys
+    ListOf,
+    VectorOf,
+    ObjectOf,
+    // Predicate results for the given items, which stop at the first false
+    TakeWhile,
+    DropWhile,
+    // Predicate results for all the given items
+    Filter,
+    Remove,
+    Partition,
+    // Results of append-map, filter-map and count
+    AppendLists,
+    KeepTrue,
+    CountTrue,
+    // Predicate results for the given items, which stop at the first true
+    Find,
+    FindTail,
+    // Like Find, but for the index, counted from the given offset
+    FoundIndex,
+    // The last result if it's true, for any
+    LastIfTrue,
+    // The last result if every one is true
+    Every,
+    // Whether none of the results are true
+    NoneTrue,
+    // Whether the item was a duplicate of one already kept
+    DeleteDuplicates,
+    // Whether the right element of a merge is less than the left one
+    SortCompared,
+    // What to do with the sorted items
+    SortInPlace,
+    SpliceVector,
+    // The value for a key of a hash table, the old one for HashTableUpdate
+    HashTableSet,
+    HashTableUpdate,
+    // A forced stream, and a forced element of a stream
+    StreamNext(&'static str),
+    StreamItem(&'static str),
+    MakeStream,
+    // What a procedure given a port returned, or its converted value for
+    // MakeParameter
+    ClosePort,
+    OutputString,
+    MakeParameter,
+    // A builtin that blocked trying again once its thread has another turn,
+    // on the arguments it was given
+    ThreadSleep,
+    ThreadJoin,
+    MutexLock,
+    ConditionWait,
+    ChannelSend,
+    ChannelReceive,
+    Select,
+    ReceiveMessage,
+}
+
+impl Continuation {
+    pub fn call(self, runtime: &mut Runtime, mut args: Vec<Ponga>) -> RunRes<Ponga> {
+        if let Some(retry) = self.retry() {
+            return retry(runtime, args);
+        }
+        let res = args.pop().unwrap();
+        let mut args = args.into_iter();
+        let mut arg = || args.next().unwrap();
+        match self {
+            Continuation::ListOf => Ok(list_result(runtime, inline_vec(res))),
+            Continuation::VectorOf => Ok(runtime.gc.ponga_into_gc_ref(Ponga::Array(inline_vec(res)))),
+            Continuation::ObjectOf => {
+                let keys = inline_vec(arg()).into_iter().map(|key| match key {
+                    Ponga::String(key) => key,
+                    _ => unreachable!(),
+                });
+                let obj = keys.zip(inline_vec(res)).collect();
+                Ok(runtime.gc.ponga_into_gc_ref(Ponga::Object(obj)))
+            }
+            Continuation::TakeWhile | Continuation::DropWhile => {
+                let mut items = inline_vec(arg());
+                let results = inline_vec(res);
+                let n = results.iter().take_while(|res| is_true(res)).count();
+                let rest = items.split_off(n);
+                match self {
+                    Continuation::TakeWhile => Ok(list_result(runtime, items)),
+                    _ => Ok(list_result(runtime, rest)),
+                }
+            }
+            Continuation::Filter | Continuation::Remove | Continuation::Partition => {
+                let (mut yes, mut no) = (vec![], vec![]);
+                for (item, res) in inline_vec(arg()).into_iter().zip(inline_vec(res)) {
+                    if is_true(&res) {
+                        yes.push(item);
+                    } else {
+                        no.push(item);
+                    }
+                }
+                match self {
+                    Continuation::Filter => Ok(list_result(runtime, yes)),
+                    Continuation::Remove => Ok(list_result(runtime, no)),
+                    _ => {
+                        let (yes, no) = (list_result(runtime, yes), list_result(runtime, no));
+                        Ok(Ponga::Values(vec![yes, no]))
+                    }
+                }
+            }
+            Continuation::AppendLists => {
+                let mut items = vec![];
+                for list in inline_vec(res).iter() {
+                    items.extend(list_arg(runtime, list, "append-map")?);
+                }
+                Ok(list_result(runtime, items))
+            }
+            Continuation::KeepTrue => {
+                let items = inline_vec(res).into_iter().filter(is_true).collect();
+                Ok(list_result(runtime, items))
+            }
+            Continuation::CountTrue => {
+                let n = inline_vec(res).iter().filter(|res| is_true(res)).count();
+                Ok(Ponga::Number(Number::Int(n as isize)))
+            }
+            Continuation::Find | Continuation::FindTail | Continuation::FoundIndex => {
+                let items = arg();
+                let i = match found_index(res) {
+                    Some(i) => i,
+                    None => return Ok(Ponga::False),
+                };
+                match (self, items) {
+                    (Continuation::FoundIndex, Ponga::Number(offset)) => {
+                        Ok(Ponga::Number(offset.plus(Number::Int(i as isize))))
+                    }
+                    (Continuation::Find, items) => Ok(inline_vec(items).swap_remove(i)),
+                    (_, items) => {
+                        let tail = inline_vec(items).split_off(i);
+                        Ok(list_result(runtime, tail))
+                    }
+                }
+            }
+            Continuation::LastIfTrue => Ok(inline_vec(res).pop().unwrap_or(Ponga::False)),
+            Continuation::Every => {
+                let mut results = inline_vec(res);
+                match results.pop() {
+                    Some(last) if results.iter().all(is_true) => Ok(last),
+                    Some(_) => Ok(Ponga::False),
+                    None => Ok(Ponga::True),
+                }
+            }
+            Continuation::NoneTrue => Ok(bool_to_ponga(!inline_vec(res).iter().any(is_true))),
+            Continuation::DeleteDuplicates => {
+                let (equiv, rest, mut kept, item) = (arg(), inline_vec(arg()), inline_vec(arg()), arg());
+                if !inline_vec(res).iter().any(is_true) {
+                    kept.push(item);
+                }
+                delete_duplicates_step(runtime, equiv, rest, kept)
+            }
+            Continuation::SortCompared => {
+                let less = arg();
+                let (pending, done) = (runs_arg(arg()), runs_arg(arg()));
+                let (mut left, mut right, mut out) = (inline_vec(arg()), inline_vec(arg()), inline_vec(arg()));
+                if is_true(&res) {
+                    out.push(right.pop().unwrap());
+                } else {
+                    out.push(left.pop().unwrap());
+                }
+                sort_step(runtime, less, pending, done, left, right, out)
+            }
+            Continuation::SortInPlace => {
+                let seq = arg();
+                let sorted = inline_vec(res);
+                if let Ponga::Ref(id) = &seq {
+                    let mut obj = runtime.get_id_obj_ref(*id)?.borrow_mut().unwrap();
+                    match obj.inner() {
+                        Ponga::Array(arr) => *arr = sorted,
+                        Ponga::List(list) => *list = sorted.into_iter().collect(),
+                        _ => unreachable!(),
+                    }
+                }
+                Ok(seq)
+            }
+            Continuation::SpliceVector => {
+                let (vec, start, end) = (arg(), arg(), arg());
+                let (start, end) = (index_arg(&start, "vector-sort!")?, index_arg(&end, "vector-sort!")?);
+                let sorted = inline_vec(res);
+                with_vector(runtime, &vec, "vector-sort!", |v| {
+                    // The comparison procedure may have shrunk the vector
+                    if end > v.len() {
+                        return Err(RuntimeErr::Other("vector-sort!: vector changed while sorting".to_string()));
+                    }
+                    v.splice(start..end, sorted);
+                    Ok(())
+                })?;
+                Ok(Ponga::Null)
+            }
+            Continuation::HashTableSet => {
+                let (table, key) = (arg(), arg());
+                hash_table_put(runtime, &table, key, res, "hash-table-update!")?;
+                Ok(Ponga::Null)
+            }
+            Continuation::HashTableUpdate => {
+                let (table, key, func) = (arg(), arg(), arg());
+                runtime.tail_call(func, vec![res])?;
+                runtime.then(Continuation::HashTableSet, vec![table, key]);
+                Ok(Ponga::Null)
+            }
+            Continuation::StreamNext(name) => {
+                let (items, limit) = (inline_vec(arg()), arg());
+                match stream_pair(runtime, res, name)? {
+                    Some((first, rest)) => {
+                        runtime.tail_force(first);
+                        runtime.then(Continuation::StreamItem(name), vec![Ponga::Array(items), limit, rest]);
+                        Ok(Ponga::Null)
+                    }
+                    None => Ok(Ponga::Array(items)),
+                }
+            }
+            Continuation::StreamItem(name) => {
+                let (mut items, limit, rest) = (inline_vec(arg()), arg(), arg());
+                items.push(res);
+                stream_step(runtime, items, limit, rest, name)
+            }
+            Continuation::MakeStream => make_stream(runtime, inline_vec(res)),
+            Continuation::ClosePort => {
+                let port = arg();
+                with_port(runtime, &port, "close-port", |p| {
+                    p.close();
+                    Ok(())
+                })?;
+                Ok(res)
+            }
+            Continuation::OutputString => get_output_string(runtime, vec![arg()]),
+            Continuation::MakeParameter => Ok(runtime.make_parameter(res, Some(arg()))),
+            _ => unreachable!(),
+        }
+    }
+
+    fn retry(self) -> Option<Builtin> {
+        match self {
+            Continuation::ThreadSleep => Some(thread_sleep_until),
+            Continuation::ThreadJoin => Some(thread_join_until),
+            Continuation::MutexLock => Some(mutex_lock_until),
+            Continuation::ConditionWait => Some(condition_wait_until),
+            Continuation::ChannelSend => Some(channel_send),
+            Continuation::ChannelReceive => Some(channel_receive),
+            Continuation::Select => Some(select_until),
+            Continuation::ReceiveMessage => Some(receive_message_until),
+            _ => None,
+        }
+    }
+}
+
+// The items of a vector that was never put in the heap, which is how
+// results and state are passed between continuations
+fn inline_vec(pong: Ponga) -> Vec<Ponga> {
+    match pong {
+        Ponga::Array(arr) => arr,
+        _ => unreachable!(),
+    }
+}
+
+fn runs_arg(pong: Ponga) -> Vec<Vec<Ponga>> {
+    inline_vec(pong).into_iter().map(inline_vec).collect()
+}
+
+fn runs_val(runs: Vec<Vec<Ponga>>) -> Ponga {
+    Ponga::Array(runs.into_iter().map(Ponga::Array).collect())
+}
+
+// The index of the result a map that stops at the first true one stopped at
+fn found_index(res: Ponga) -> Option<usize> {
+    let results = inline_vec(res);
+    match results.last() {
+        Some(last) if is_true(last) => Some(results.len() - 1),
+        _ => None,
+    }
+}
+
+// A procedure and a single list, as taken by filter, find and friends
+fn pred_and_list(runtime: &mut Runtime, args: Vec<Ponga>,
+                 name: &str) -> RunRes<(Ponga, Vec<Ponga>)> {
+    args_assert_len(&args, 2, name)?;
+    let mut args = transform_args(runtime, args)?;
+    let list = list_arg(runtime, &args.pop().unwrap(), name)?;
+    Ok((args.pop().unwrap(), list))
+}
+
+pub fn list(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    let args = transform_args(runtime, args)?;
+    Ok(list_result(runtime, args))
+}
+
+pub fn length(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    args_assert_len(&args, 1, "length")?;
+    let mut args = transform_args(runtime, args)?;
+    let list = list_arg(runtime, &args.pop().unwrap(), "length")?;
+    Ok(Ponga::Number(Number::Int(list.len() as isize)))
+}
+
+pub fn append(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    let args = transform_args(runtime, args)?;
+    let mut res = vec![];
+    for arg in args.iter() {
+        res.extend(list_arg(runtime, arg, "append")?);
+    }
+    Ok(list_result(runtime, res))
+}
+
+pub fn list_copy(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    args_assert_len(&args, 1, "list-copy")?;
+    let mut args = transform_args(runtime, args)?;
+    let list = list_arg(runtime, &args.pop().unwrap(), "list-copy")?;
+    Ok(list_result(runtime, list))
+}
+
+fn list_and_index(runtime: &mut Runtime, args: Vec<Ponga>,
+                  name: &str) -> RunRes<(Vec<Ponga>, usize)> {
+    args_assert_len(&args, 2, name)?;
+    let mut args = transform_args(runtime, args)?;
+    let n = index_arg(&args.pop().unwrap(), name)?;
+    let list = list_arg(runtime, &args.pop().unwrap(), name)?;
+    if n > list.len() {
+        return Err(RuntimeErr::TypeError(format!(
+            "{}: index {} out of bounds for list of length {}", name, n, list.len()
+        )));
+    }
+    Ok((list, n))
+}
+
+pub fn list_ref(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    let (list, n) = list_and_index(runtime, args, "list-ref")?;
+    list.into_iter()
+        .nth(n)
+        .ok_or_else(|| RuntimeErr::TypeError("list-ref index out of bounds".to_string()))
+}
+
+pub fn list_tail(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    let (list, n) = list_and_index(runtime, args, "list-tail")?;
+    Ok(list_result(runtime, list[n..].to_vec()))
+}
+
+pub fn take(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    let (list, n) = list_and_index(runtime, args, "take")?;
+    Ok(list_result(runtime, list[..n].to_vec()))
+}
+
+pub fn drop_(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    let (list, n) = list_and_index(runtime, args, "drop")?;
+    Ok(list_result(runtime, list[n..].to_vec()))
+}
+
+pub fn last(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    args_assert_len(&args, 1, "last")?;
+    let mut args = transform_args(runtime, args)?;
+    let list = list_arg(runtime, &args.pop().unwrap(), "last")?;
+    list.last()
+        .cloned()
+        .ok_or_else(|| RuntimeErr::TypeError("last of empty list".to_string()))
+}
+
+// (iota count [start [step]])
+pub fn iota(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    args_assert_between(&args, 1, 3, "iota")?;
+    let mut args = transform_args(runtime, args)?.into_iter();
+    let count = index_arg(&args.next().unwrap(), "iota")?;
+    let start = args.next().unwrap_or(Ponga::Number(Number::Int(0)));
+    let step = args.next().unwrap_or(Ponga::Number(Number::Int(1)));
+    let (start, step) = match (start, step) {
+        (Ponga::Number(start), Ponga::Number(step)) => (start, step),
+        _ => return Err(RuntimeErr::TypeError("iota requires numbers".to_string())),
+    };
+    let res = (0..count).map(|i| Ponga::Number(start.plus(step.times(Number::Int(i as isize)))))
+                        .collect();
+    Ok(list_result(runtime, res))
+}
+
+// Calls the predicate on each item of the list until it returns false, then
+// passes the results on to the continuation along with the items
+fn split_while(runtime: &mut Runtime, args: Vec<Ponga>, k: Continuation,
+               name: &str) -> RunRes<Ponga> {
+    let (pred, list) = pred_and_list(runtime, args, name)?;
+    let columns = list.iter().map(|item| vec![item.clone()]).collect();
+    runtime.tail_map(pred, columns, Until::False)?;
+    runtime.then(k, vec![Ponga::Array(list)]);
+    Ok(Ponga::Null)
+}
+
+pub fn take_while(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    split_while(runtime, args, Continuation::TakeWhile, "take-while")
+}
+
+pub fn drop_while(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    split_while(runtime, args, Continuation::DropWhile, "drop-while")
+}
+
+// The calls run in the caller's loop after this returns
+pub fn for_each(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    let (func, columns) = list_columns(runtime, args, "for-each")?;
+    runtime.call_each(func, columns)?;
+    Ok(Ponga::Null)
+}
+
+// (apply proc arg ... list) calls proc with the args followed by the
+// elements of the list, in place of the call to apply itself
+pub fn apply(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    args_assert_gt(&args, 1, "apply")?;
+    let mut args = transform_args(runtime, args)?;
+    let list = list_arg(runtime, &args.pop().unwrap(), "apply")?;
+    let mut args = args.into_iter();
+    let func = args.next().unwrap();
+    let call_args = args.chain(list).collect();
+    runtime.tail_call(func, call_args)?;
+    Ok(Ponga::Null)
+}
+
+pub fn append_map(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    let (func, columns) = list_columns(runtime, args, "append-map")?;
+    runtime.tail_map(func, columns, Until::End)?;
+    runtime.then(Continuation::AppendLists, vec![]);
+    Ok(Ponga::Null)
+}
+
+pub fn filter_map(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    let (func, columns) = list_columns(runtime, args, "filter-map")?;
+    runtime.tail_map(func, columns, Until::End)?;
+    runtime.then(Continuation::KeepTrue, vec![]);
+    Ok(Ponga::Null)
+}
+
+// Calls the predicate on every item of the list, then passes the results on
+// to the continuation along with the items
+fn split_by(runtime: &mut Runtime, args: Vec<Ponga>, k: Continuation,
+            name: &str) -> RunRes<Ponga> {
+    let (pred, list) = pred_and_list(runtime, args, name)?;
+    let columns = list.iter().map(|item| vec![item.clone()]).collect();
+    runtime.tail_map(pred, columns, Until::End)?;
+    runtime.then(k, vec![Ponga::Array(list)]);
+    Ok(Ponga::Null)
+}
+
+pub fn filter(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    split_by(runtime, args, Continuation::Filter, "filter")
+}
+
+pub fn remove(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    split_by(runtime, args, Continuation::Remove, "remove")
+}
+
+// Returns the matching and non-matching elements as two values
+pub fn partition(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    split_by(runtime, args, Continuation::Partition, "partition")
+}
+
+// (fold kons knil list ...) calls (kons elem ... acc) from the left
+pub fn fold(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    args_assert_gt(&args, 2, "fold")?;
+    let mut args = args;
+    let knil = args.remove(1);
+    let acc = runtime.id_or_ref_peval(knil)?;
+    let (func, columns) = list_columns(runtime, args, "fold")?;
+    runtime.tail_fold(func, acc, columns, true)?;
+    Ok(Ponga::Null)
+}
+
+pub fn fold_right(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    args_assert_gt(&args, 2, "fold-right")?;
+    let mut args = args;
+    let knil = args.remove(1);
+    let acc = runtime.id_or_ref_peval(knil)?;
+    let (func, mut columns) = list_columns(runtime, args, "fold-right")?;
+    columns.reverse();
+    runtime.tail_fold(func, acc, columns, true)?;
+    Ok(Ponga::Null)
+}
+
+// (reduce f ridentity list) is (fold f (car list) (cdr list)), or
+// ridentity for the empty list
+pub fn reduce(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    args_assert_len(&args, 3, "reduce")?;
+    let mut args = transform_args(runtime, args)?;
+    let list = list_arg(runtime, &args.pop().unwrap(), "reduce")?;
+    let ridentity = args.pop().unwrap();
+    let func = args.pop().unwrap();
+    let mut list = list.into_iter();
+    let acc = match list.next() {
+        Some(first) => first,
+        None => return Ok(ridentity),
+    };
+    runtime.tail_fold(func, acc, list.map(|item| vec![item]).collect(), true)?;
+    Ok(Ponga::Null)
+}
+
+pub fn count(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    let (pred, columns) = list_columns(runtime, args, "count")?;
+    runtime.tail_map(pred, columns, Until::End)?;
+    runtime.then(Continuation::CountTrue, vec![]);
+    Ok(Ponga::Null)
+}
+
+// Calls the predicate on each item of the list until it returns true, then
+// passes the results on to the continuation along with the items
+fn find_by(runtime: &mut Runtime, args: Vec<Ponga>, k: Continuation,
+           name: &str) -> RunRes<Ponga> {
+    let (pred, list) = pred_and_list(runtime, args, name)?;
+    let columns = list.iter().map(|item| vec![item.clone()]).collect();
+    runtime.tail_map(pred, columns, Until::True)?;
+    runtime.then(k, vec![Ponga::Array(list)]);
+    Ok(Ponga::Null)
+}
+
+pub fn find(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    find_by(runtime, args, Continuation::Find, "find")
+}
+
+pub fn find_tail(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    find_by(runtime, args, Continuation::FindTail, "find-tail")
+}
+
+pub fn list_index(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    let (pred, columns) = list_columns(runtime, args, "list-index")?;
+    runtime.tail_map(pred, columns, Until::True)?;
+    runtime.then(Continuation::FoundIndex, vec![Ponga::Number(Number::Int(0))]);
+    Ok(Ponga::Null)
+}
+
+// The first true result of the predicate, or #f
+pub fn any(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    let (pred, columns) = list_columns(runtime, args, "any")?;
+    runtime.tail_map(pred, columns, Until::True)?;
+    runtime.then(Continuation::LastIfTrue, vec![]);
+    Ok(Ponga::Null)
+}
+
+// The last result of the predicate if all are true, or #f
+pub fn every(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    let (pred, columns) = list_columns(runtime, args, "every")?;
+    runtime.tail_map(pred, columns, Until::False)?;
+    runtime.then(Continuation::Every, vec![]);
+    Ok(Ponga::Null)
+}
+
+// Compares the item with each of the others using a procedure given by the
+// caller, then passes the results on to the continuation along with the
+// others. Stops at the first match unless `until` says otherwise.
+fn tail_compare(runtime: &mut Runtime, func: Ponga, item: &Ponga, others: Vec<Ponga>,
+                until: Until, k: Continuation) -> RunRes<Ponga> {
+    let columns = others.iter().map(|x| vec![item.clone(), x.clone()]).collect();
+    runtime.tail_map(func, columns, until)?;
+    runtime.then(k, vec![Ponga::Array(others)]);
+    Ok(Ponga::Null)
+}
+
+// An item and a list, followed by an optional equivalence procedure
+fn item_and_list(runtime: &mut Runtime, args: Vec<Ponga>, default: Equiv,
+                 name: &str) -> RunRes<(Ponga, Vec<Ponga>, Result<Equiv, Ponga>)> {
+    args_assert_between(&args, 2, 3, name)?;
+    let mut args = transform_args(runtime, args)?.into_iter();
+    let item = args.next().unwrap();
+    let list = list_arg(runtime, &args.next().unwrap(), name)?;
+    let equiv = match args.next() {
+        Some(func) => Err(func),
+        None => Ok(default),
+    };
+    Ok((item, list, equiv))
+}
+
+fn member_by(runtime: &mut Runtime, args: Vec<Ponga>, default: Equiv,
+             name: &str) -> RunRes<Ponga> {
+    let (item, list, equiv) = item_and_list(runtime, args, default, name)?;
+    let equiv = match equiv {
+        Ok(equiv) => equiv,
+        Err(func) => return tail_compare(runtime, func, &item, list, Until::True, Continuation::FindTail),
+    };
+    match equiv.position(runtime, &item, &list)? {
+        Some(i) => Ok(list_result(runtime, list[i..].to_vec())),
+        None => Ok(Ponga::False),
+    }
+}
+
+pub fn member(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    member_by(runtime, args, Equiv::Equal, "member")
+}
+
+pub fn memq(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    member_by(runtime, args, Equiv::Eq, "memq")
+}
+
+pub fn memv(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    member_by(runtime, args, Equiv::Eqv, "memv")
+}
+
+// Entries are lists whose first element is the key, such as those made by
+// (cons key value)
+fn assoc_by(runtime: &mut Runtime, args: Vec<Ponga>, default: Equiv,
+            name: &str) -> RunRes<Ponga> {
+    let (key, alist, equiv) = item_and_list(runtime, args, default, name)?;
+    let mut keys = vec![];
+    for entry in alist.iter() {
+        match list_arg(runtime, entry, name)?.into_iter().next() {
+            Some(first) => keys.push(first),
+            None => return Err(RuntimeErr::TypeError(format!("{} requires an association list", name))),
+        }
+    }
+    let equiv = match equiv {
+        Ok(equiv) => equiv,
+        Err(func) => {
+            let columns = keys.into_iter().map(|x| vec![key.clone(), x]).collect();
+            runtime.tail_map(func, columns, Until::True)?;
+            runtime.then(Continuation::Find, vec![Ponga::Array(alist)]);
+            return Ok(Ponga::Null);
+        }
+    };
+    match equiv.position(runtime, &key, &keys)? {
+        Some(i) => Ok(alist[i].clone()),
+        None => Ok(Ponga::False),
+    }
+}
+
+pub fn assoc(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    assoc_by(runtime, args, Equiv::Equal, "assoc")
+}
+
+pub fn assq(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    assoc_by(runtime, args, Equiv::Eq, "assq")
+}
+
+pub fn assv(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    assoc_by(runtime, args, Equiv::Eqv, "assv")
+}
+
+pub fn delete(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    let (item, list, equiv) = item_and_list(runtime, args, Equiv::Equal, "delete")?;
+    let equiv = match equiv {
+        Ok(equiv) => equiv,
+        Err(func) => return tail_compare(runtime, func, &item, list, Until::End, Continuation::Remove),
+    };
+    let mut res = vec![];
+    for x in list.into_iter() {
+        if !equiv.equal(runtime, &item, &x)? {
+            res.push(x);
+        }
+    }
+    Ok(list_result(runtime, res))
+}
+
+// Keeps the first occurrence of each element
+pub fn delete_duplicates(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    args_assert_between(&args, 1, 2, "delete-duplicates")?;
+    let mut args = transform_args(runtime, args)?.into_iter();
+    let list = list_arg(runtime, &args.next().unwrap(), "delete-duplicates")?;
+    let equiv = match args.next() {
+        Some(func) => {
+            let rest = list.into_iter().rev().collect();
+            let res = delete_duplicates_step(runtime, func, rest, vec![])?;
+            runtime.then(Continuation::ListOf, vec![]);
+            return Ok(res);
+        }
+        None => Equiv::Equal,
+    };
+    let mut res: Vec<Ponga> = vec![];
+    for x in list.into_iter() {
+        if equiv.position(runtime, &x, &res)?.is_none() {
+            res.push(x);
+        }
+    }
+    Ok(list_result(runtime, res))
+}
+
+// Compares the next of the remaining items, which are stored last first,
+// with the ones kept so far. Gives the kept items once none remain.
+fn delete_duplicates_step(runtime: &mut Runtime, equiv: Ponga, mut rest: Vec<Ponga>,
+                          mut kept: Vec<Ponga>) -> RunRes<Ponga> {
+    let x = match rest.pop() {
+        Some(x) => x,
+        None => return Ok(Ponga::Array(kept)),
+    };
+    if kept.is_empty() {
+        kept.push(x);
+        return delete_duplicates_step(runtime, equiv, rest, kept);
+    }
+    let columns = kept.iter().map(|y| vec![y.clone(), x.clone()]).collect();
+    runtime.tail_map(equiv.clone(), columns, Until::True)?;
+    runtime.then(Continuation::DeleteDuplicates, vec![equiv, Ponga::Array(rest), Ponga::Array(kept), x]);
+    Ok(Ponga::Null)
+}
+
+// One step of a bottom-up merge sort, which runs the comparison procedure
+// in the caller's loop. Each pass merges the pending runs in pairs into the
+// done ones, until only one run is left, which is given as an inline
+// vector. The pending runs and the two being merged are stored last first.
+// Stable: an element from the right run is only taken first if it is
+// strictly less than the one from the left.
+fn sort_step(runtime: &mut Runtime, less: Ponga, mut pending: Vec<Vec<Ponga>>,
+             mut done: Vec<Vec<Ponga>>, mut left: Vec<Ponga>, mut right: Vec<Ponga>,
+             mut out: Vec<Ponga>) -> RunRes<Ponga> {
+    loop {
+        if let (Some(l), Some(r)) = (left.last(), right.last()) {
+            runtime.tail_call(less.clone(), vec![r.clone(), l.clone()])?;
+            let state = vec![less, runs_val(pending), runs_val(done), Ponga::Array(left),
+                             Ponga::Array(right), Ponga::Array(out)];
+            runtime.then(Continuation::SortCompared, state);
+            return Ok(Ponga::Null);
+        }
+        if !(left.is_empty() && right.is_empty() && out.is_empty()) {
+            out.extend(left.drain(..).rev());
+            out.extend(right.drain(..).rev());
+            done.push(std::mem::take(&mut out));
+        }
+        if pending.len() >= 2 {
+            left = pending.pop().unwrap();
+            right = pending.pop().unwrap();
+            left.reverse();
+            right.reverse();
+            continue;
+        }
+        done.append(&mut pending);
+        if done.len() <= 1 {
+            return Ok(Ponga::Array(done.pop().unwrap_or_default()));
+        }
+        pending = std::mem::take(&mut done);
+        pending.reverse();
+    }
+}
+
+// Sorts the items with the comparison procedure, giving them as an inline
+// vector
+fn sort_items(runtime: &mut Runtime, items: Vec<Ponga>, less: Ponga) -> RunRes<Ponga> {
+    let pending = items.into_iter().rev().map(|item| vec![item]).collect();
+    sort_step(runtime, less, pending, vec![], vec![], vec![], vec![])
+}
+
+// The elements of a list or vector, and whether it was a vector
+fn sequence_arg(runtime: &Runtime, seq: &Ponga, name: &str) -> RunRes<(Vec<Ponga>, bool)> {
+    if let Ponga::Ref(id) = seq {
+        if let Ponga::Array(arr) = runtime.get_id_obj_ref(*id)?.borrow().unwrap().inner() {
+            return Ok((arr.clone(), true));
+        }
+    }
+    Ok((list_arg(runtime, seq, name)?, false))
+}
+
+// (sort seq less?) returns a new sorted list or vector
+pub fn sort(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    args_assert_len(&args, 2, "sort")?;
+    let mut args = transform_args(runtime, args)?;
+    let less = args.pop().unwrap();
+    let seq = args.pop().unwrap();
+    let (items, is_vector) = sequence_arg(runtime, &seq, "sort")?;
+    let res = sort_items(runtime, items, less)?;
+    match is_vector {
+        true => runtime.then(Continuation::VectorOf, vec![]),
+        false => runtime.then(Continuation::ListOf, vec![]),
+    }
+    Ok(res)
+}
+
+// Replaces the contents of a gc list or vector, so every reference to it
+// sees the sorted sequence
+pub fn sort_in_place(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    args_assert_len(&args, 2, "sort!")?;
+    let mut args = transform_args(runtime, args)?;
+    let less = args.pop().unwrap();
+    let seq = args.pop().unwrap();
+    let (items, _) = sequence_arg(runtime, &seq, "sort!")?;
+    let res = sort_items(runtime, items, less)?;
+    runtime.then(Continuation::SortInPlace, vec![seq]);
+    Ok(res)
+}
+
+// (list-sort < list)
+pub fn list_sort(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    args_assert_len(&args, 2, "list-sort")?;
+    let mut args = transform_args(runtime, args)?;
+    let list = list_arg(runtime, &args.pop().unwrap(), "list-sort")?;
+    let less = args.pop().unwrap();
+    let res = sort_items(runtime, list, less)?;
+    runtime.then(Continuation::ListOf, vec![]);
+    Ok(res)
+}
+
+// (vector-sort < vector [start end]) sorts a copy of the given range
+pub fn vector_sort(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    args_assert_between(&args, 2, 4, "vector-sort")?;
+    let mut args = transform_args(runtime, args)?.into_iter();
+    let less = args.next().unwrap();
+    let vec = args.next().unwrap();
+    let (start, end) = (args.next(), args.next());
+    let items = with_vector(runtime, &vec, "vector-sort", |v| {
+        let (start, end) = range_args(start, end, v.len(), "vector-sort")?;
+        Ok(v[start..end].to_vec())
+    })?;
+    let res = sort_items(runtime, items, less)?;
+    runtime.then(Continuation::VectorOf, vec![]);
+    Ok(res)
+}
+
+// (vector-sort! vector < [start end]) sorts the given range in place
+pub fn vector_sort_in_place(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    args_assert_between(&args, 2, 4, "vector-sort!")?;
+    let mut args = transform_args(runtime, args)?.into_iter();
+    let vec = args.next().unwrap();
+    let less = args.next().unwrap();
+    let (start, end) = (args.next(), args.next());
+    let (start, end, items) = with_vector(runtime, &vec, "vector-sort!", |v| {
+        let (start, end) = range_args(start, end, v.len(), "vector-sort!")?;
+        Ok((start, end, v[start..end].to_vec()))
+    })?;
+    let res = sort_items(runtime, items, less)?;
+    let (start, end) = (Ponga::Number(Number::Int(start as isize)), Ponga::Number(Number::Int(end as isize)));
+    runtime.then(Continuation::SpliceVector, vec![vec, start, end]);
+    Ok(res)
+}
+
+// (sorted? seq less?)
+pub fn sorted(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    args_assert_len(&args, 2, "sorted?")?;
+    let mut args = transform_args(runtime, args)?;
+    let less = args.pop().unwrap();
+    let (items, _) = sequence_arg(runtime, &args.pop().unwrap(), "sorted?")?;
+    let columns = items.windows(2).map(|pair| vec![pair[1].clone(), pair[0].clone()]).collect();
+    runtime.tail_map(less, columns, Until::True)?;
+    runtime.then(Continuation::NoneTrue, vec![]);
+    Ok(Ponga::Null)
+}
+
+// (merge list1 list2 less?) merges two sorted lists, preferring the first
+// on ties
+pub fn merge(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    args_assert_len(&args, 3, "merge")?;
+    let mut args = transform_args(runtime, args)?;
+    let less = args.pop().unwrap();
+    let mut right = list_arg(runtime, &args.pop().unwrap(), "merge")?;
+    let mut left = list_arg(runtime, &args.pop().unwrap(), "merge")?;
+    left.reverse();
+    right.reverse();
+    let res = sort_step(runtime, less, vec![], vec![], left, right, vec![])?;
+    runtime.then(Continuation::ListOf, vec![]);
+    Ok(res)
+}
+
+pub fn make_weak_box(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    args_assert_len(&args, 1, "make-weak-box")?;
+    let mut args = transform_args(runtime, args)?;
+    let val = args.pop().unwrap();
+    Ok(runtime.gc.ponga_into_gc_ref(Ponga::WeakBox(Some(Box::new(val)))))
+}
+
+pub fn weak_box_query(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    args_assert_len(&args, 1, "weak-box?")?;
+    let mut args = transform_args(runtime, args)?;
+    match args.pop().unwrap() {
+        Ponga::Ref(id) => {
+            let obj = runtime.get_id_obj_ref(id)?.borrow().unwrap();
+            Ok(bool_to_ponga(matches!(obj.inner(), Ponga::WeakBox(_))))
+        }
+        _ => Ok(Ponga::False),
+    }
+}
+
+pub fn weak_box_value(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    if args.len() != 1 && args.len() != 2 {
+        return Err(RuntimeErr::TypeError("weak-box-value requires 1 or 2 arguments".to_string()));
+    }
+    let mut args = transform_args(runtime, args)?.into_iter();
+    let weak_box = args.next().unwrap();
+    let default = args.next().unwrap_or(Ponga::False);
+    match weak_box {
+        Ponga::Ref(id) => {
+            let obj = runtime.get_id_obj_ref(id)?.borrow().unwrap();
+            match obj.inner() {
+                Ponga::WeakBox(Some(val)) => Ok(*val.clone()),
+                Ponga::WeakBox(None) => Ok(default),
+                _ => Err(RuntimeErr::TypeError("weak-box-value requires a weak box".to_string())),
+            }
+        }
+        _ => Err(RuntimeErr::TypeError("weak-box-value requires a weak box".to_string())),
+    }
+}
+
+pub fn make_weak_hash_table(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    args_assert_len(&args, 0, "make-weak-hash-table")?;
+    Ok(runtime.gc.ponga_into_gc_ref(Ponga::WeakTable(HashMap::new())))
+}
+
+// Keys are compared by identity, so only heap objects can be used
+fn weak_table_key(key: &Ponga, name: &str) -> RunRes<Id> {
+    match key {
+        Ponga::Ref(id) => Ok(*id),
+        _ => Err(RuntimeErr::TypeError(format!(
+            "{} keys must be heap objects (not {:?})", name, key
+        ))),
+    }
+}
+
+fn with_weak_table<T>(runtime: &mut Runtime, table: &Ponga, name: &str,
+                      func: impl FnOnce(&mut HashMap<Id, Ponga>) -> T) -> RunRes<T> {
+    match table {
+        Ponga::Ref(id) => {
+            let mut obj = runtime.get_id_obj(*id)?.borrow_mut().unwrap();
+            match obj.inner() {
+                Ponga::WeakTable(table) => Ok(func(table)),
+                _ => Err(RuntimeErr::TypeError(format!("{} requires a weak hash table", name))),
+            }
+        }
+        _ => Err(RuntimeErr::TypeError(format!("{} requires a weak hash table", name))),
+    }
+}
+
+pub fn weak_hash_table_set(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    args_assert_len(&args, 3, "weak-hash-table-set!")?;
+    let mut args = transform_args(runtime, args)?;
+    let val = args.pop().unwrap();
+    let key = weak_table_key(&args.pop().unwrap(), "weak-hash-table-set!")?;
+    let table = args.pop().unwrap();
+    with_weak_table(runtime, &table, "weak-hash-table-set!", |t| t.insert(key, val))?;
+    Ok(Ponga::Null)
+}
+
+pub fn weak_hash_table_ref(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    if args.len() != 2 && args.len() != 3 {
+        return Err(RuntimeErr::TypeError("weak-hash-table-ref requires 2 or 3 arguments".to_string()));
+    }
+    let mut args = transform_args(runtime, args)?.into_iter();
+    let table = args.next().unwrap();
+    let key = weak_table_key(&args.next().unwrap(), "weak-hash-table-ref")?;
+    let default = args.next().unwrap_or(Ponga::False);
+    let res = with_weak_table(runtime, &table, "weak-hash-table-ref", |t| t.get(&key).cloned())?;
+    Ok(res.unwrap_or(default))
+}
+
+pub fn weak_hash_table_delete(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    args_assert_len(&args, 2, "weak-hash-table-delete!")?;
+    let mut args = transform_args(runtime, args)?;
+    let key = weak_table_key(&args.pop().unwrap(), "weak-hash-table-delete!")?;
+    let table = args.pop().unwrap();
+    with_weak_table(runtime, &table, "weak-hash-table-delete!", |t| t.remove(&key))?;
+    Ok(Ponga::Null)
+}
+
+pub fn weak_hash_table_count(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    args_assert_len(&args, 1, "weak-hash-table-count")?;
+    let mut args = transform_args(runtime, args)?;
+    let table = args.pop().unwrap();
+    let n = with_weak_table(runtime, &table, "weak-hash-table-count", |t| t.len())?;
+    Ok(Ponga::Number(Number::Int(n as isize)))
+}
+
+pub fn weak_hash_table_keys(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    args_assert_len(&args, 1, "weak-hash-table-keys")?;
+    let mut args = transform_args(runtime, args)?;
+    let table = args.pop().unwrap();
+    let keys = with_weak_table(runtime, &table, "weak-hash-table-keys", |t| {
+        t.keys().map(|id| Ponga::Ref(*id)).collect::<LinkedList<Ponga>>()
+    })?;
+    Ok(runtime.gc.ponga_into_gc_ref(Ponga::List(keys)))
+}
+
+pub fn set_finalizer(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    args_assert_len(&args, 2, "set-finalizer!")?;
+    let mut args = transform_args(runtime, args)?;
+    let func = args.pop().unwrap();
+    let obj = args.pop().unwrap();
+    if !runtime.is_func(&func) {
+        return Err(RuntimeErr::TypeError("second argument to set-finalizer! must be a function".to_string()));
+    }
+    match obj {
+        Ponga::Ref(id) => {
+            runtime.gc.add_finalizer(id, Finalizer::Scheme(func));
+            Ok(obj)
+        }
+        _ => Err(RuntimeErr::TypeError(format!(
+            "set-finalizer! requires a heap object (not {:?})", obj
+        ))),
+    }
+}
+
+// The equivalence predicate is passed as the procedure itself, e.g.
+// (make-hash-table string=?)
+fn equiv_arg(runtime: &Runtime, pred: &Ponga, name: &str) -> RunRes<Equiv> {
+    let res = match pred {
+        Ponga::HFunc(id) => Equiv::from_name(FUNCS[*id].0),
+        _ => None,
+    };
+    res.ok_or_else(|| RuntimeErr::TypeError(format!(
+        "{} requires one of eq?, eqv?, equal? or string=? (not {})",
+        name, runtime.ponga_to_string(pred)
+    )))
+}
+
+pub fn make_hash_table(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    if args.len() > 2 {
+        return Err(RuntimeErr::TypeError("make-hash-table requires 0 to 2 arguments".to_string()));
+    }
+    let args = transform_args(runtime, args)?;
+    // A hash function may be given as the second argument, but the table
+    // always uses the one consistent with its equivalence predicate
+    let equiv = match args.first() {
+        Some(pred) => equiv_arg(runtime, pred, "make-hash-table")?,
+        None => Equiv::Equal,
+    };
+    Ok(runtime.gc.ponga_into_gc_ref(Ponga::HashTable(HashTable::new(equiv))))
+}
+
+// An alist entry is either a pair made by cons or a quoted two element list
+fn alist_entry(runtime: &Runtime, entry: &Ponga) -> RunRes<(Ponga, Ponga)> {
+    let items: Vec<Ponga> = match entry {
+        Ponga::Ref(id) => match runtime.get_id_obj_ref(*id)?.borrow().unwrap().inner() {
+            Ponga::List(list) => list.iter().cloned().collect(),
+            _ => vec![],
+        },
+        Ponga::List(list) => list.iter().cloned().collect(),
+        Ponga::Sexpr(arr) => arr.clone(),
+        _ => vec![],
+    };
+    match items.len() {
+        2 => {
+            let mut items = items.into_iter();
+            Ok((items.next().unwrap(), items.next().unwrap()))
+        }
+        _ => Err(RuntimeErr::TypeError(format!(
+            "alist->hash-table requires a list of pairs (not {})",
+            runtime.ponga_to_string(entry)
+        ))),
+    }
+}
+
+pub fn alist_to_hash_table(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    if args.is_empty() || args.len() > 3 {
+        return Err(RuntimeErr::TypeError("alist->hash-table requires 1 to 3 arguments".to_string()));
+    }
+    let args = transform_args(runtime, args)?;
+    let equiv = match args.get(1) {
+        Some(pred) => equiv_arg(runtime, pred, "alist->hash-table")?,
+        None => Equiv::Equal,
+    };
+    let entries = match &args[0] {
+        Ponga::Ref(id) => match runtime.get_id_obj_ref(*id)?.borrow().unwrap().inner() {
+            Ponga::List(list) => list.iter().cloned().collect::<Vec<Ponga>>(),
+            _ => return Err(RuntimeErr::TypeError("alist->hash-table requires a list".to_string())),
+        },
+        Ponga::List(list) => list.iter().cloned().collect(),
+        _ => return Err(RuntimeErr::TypeError("alist->hash-table requires a list".to_string())),
+    };
+    let mut table = HashTable::new(equiv);
+    // Earlier entries take precedence, as with assoc
+    for entry in entries.iter().rev() {
+        let (key, val) = alist_entry(runtime, entry)?;
+        table.insert(runtime, key, val)?;
+    }
+    Ok(runtime.gc.ponga_into_gc_ref(Ponga::HashTable(table)))
+}
+
+pub fn hash_table_query(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    args_assert_len(&args, 1, "hash-table?")?;
+    let mut args = transform_args(runtime, args)?;
+    match args.pop().unwrap() {
+        Ponga::Ref(id) => {
+            let obj = runtime.get_id_obj_ref(id)?.borrow().unwrap();
+            Ok(bool_to_ponga(matches!(obj.inner(), Ponga::HashTable(_))))
+        }
+        _ => Ok(Ponga::False),
+    }
+}
+
+fn with_hash_table<T>(runtime: &Runtime, table: &Ponga, name: &str,
+                      func: impl FnOnce(&mut HashTable) -> RunRes<T>) -> RunRes<T> {
+    match table {
+        Ponga::Ref(id) => {
+            let mut obj = runtime.get_id_obj_ref(*id)?.borrow_mut().ok_or_else(|| {
+                RuntimeErr::Other(format!("{} can't be used on a table that is in use", name))
+            })?;
+            match obj.inner() {
+                Ponga::HashTable(table) => func(table),
+                _ => Err(RuntimeErr::TypeError(format!("{} requires a hash table", name))),
+            }
+        }
+        _ => Err(RuntimeErr::TypeError(format!("{} requires a hash table", name))),
+    }
+}
+
+// Hashing and comparing a key may look inside other heap objects, the table
+// itself included, so the table is only borrowed between those steps
+fn hash_table_find(runtime: &Runtime, table: &Ponga, key: &Ponga, name: &str) -> RunRes<Position> {
+    let equiv = with_hash_table(runtime, table, name, |t| Ok(t.equiv))?;
+    let hash = equiv.hash(runtime, key)?;
+    let keys = with_hash_table(runtime, table, name, |t| Ok(t.keys_at(hash)))?;
+    Ok(Position { hash, index: equiv.position(runtime, key, &keys)? })
+}
+
+fn hash_table_get(runtime: &Runtime, table: &Ponga, key: &Ponga, name: &str) -> RunRes<Option<Ponga>> {
+    let pos = hash_table_find(runtime, table, key, name)?;
+    with_hash_table(runtime, table, name, |t| Ok(t.value_at(pos)))
+}
+
+fn hash_table_put(runtime: &Runtime, table: &Ponga, key: Ponga, val: Ponga, name: &str) -> RunRes<()> {
+    let pos = hash_table_find(runtime, table, &key, name)?;
+    with_hash_table(runtime, table, name, |t| {
+        t.insert_at(pos, key, val);
+        Ok(())
+    })
+}
+
+pub fn hash_table_ref(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    if args.len() < 2 || args.len() > 4 {
+        return Err(RuntimeErr::TypeError("hash-table-ref requires 2 to 4 arguments".to_string()));
+    }
+    let mut args = transform_args(runtime, args)?.into_iter();
+    let table = args.next().unwrap();
+    let key = args.next().unwrap();
+    let fail = args.next();
+    let succeed = args.next();
+    let res = hash_table_get(runtime, &table, &key, "hash-table-ref")?;
+    match (res, fail, succeed) {
+        (Some(val), _, Some(succeed)) => runtime.tail_call(succeed, vec![val])?,
+        (Some(val), _, None) => return Ok(val),
+        (None, Some(fail), _) => runtime.tail_call(fail, vec![])?,
+        (None, None, _) => return Err(RuntimeErr::Other(format!(
+            "hash-table-ref: no value for key {}", runtime.ponga_to_string(&key)
+        ))),
+    }
+    Ok(Ponga::Null)
+}
+
+pub fn hash_table_ref_default(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    args_assert_len(&args, 3, "hash-table-ref/default")?;
+    let mut args = transform_args(runtime, args)?;
+    let default = args.pop().unwrap();
+    let key = args.pop().unwrap();
+    let table = args.pop().unwrap();
+    let res = hash_table_get(runtime, &table, &key, "hash-table-ref/default")?;
+    Ok(res.unwrap_or(default))
+}
+
+pub fn hash_table_set(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    args_assert_len(&args, 3, "hash-table-set!")?;
+    let mut args = transform_args(runtime, args)?;
+    let val = args.pop().unwrap();
+    let key = args.pop().unwrap();
+    let table = args.pop().unwrap();
+    hash_table_put(runtime, &table, key, val, "hash-table-set!")?;
+    Ok(Ponga::Null)
+}
+
+pub fn hash_table_delete(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    args_assert_len(&args, 2, "hash-table-delete!")?;
+    let mut args = transform_args(runtime, args)?;
+    let key = args.pop().unwrap();
+    let table = args.pop().unwrap();
+    let pos = hash_table_find(runtime, &table, &key, "hash-table-delete!")?;
+    with_hash_table(runtime, &table, "hash-table-delete!", |t| Ok(t.remove_at(pos)))?;
+    Ok(Ponga::Null)
+}
+
+pub fn hash_table_contains(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    args_assert_len(&args, 2, "hash-table-contains?")?;
+    let mut args = transform_args(runtime, args)?;
+    let key = args.pop().unwrap();
+    let table = args.pop().unwrap();
+    let res = hash_table_get(runtime, &table, &key, "hash-table-contains?")?;
+    Ok(bool_to_ponga(res.is_some()))
+}
+
+pub fn hash_table_update(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    if args.len() != 3 && args.len() != 4 {
+        return Err(RuntimeErr::TypeError("hash-table-update! requires 3 or 4 arguments".to_string()));
+    }
+    let mut args = transform_args(runtime, args)?.into_iter();
+    let table = args.next().unwrap();
+    let key = args.next().unwrap();
+    let func = args.next().unwrap();
+    let fail = args.next();
+    let current = hash_table_get(runtime, &table, &key, "hash-table-update!")?;
+    match (current, fail) {
+        (Some(val), _) => runtime.tail_continue(Continuation::HashTableUpdate, vec![table, key, func, val]),
+        (None, Some(fail)) => {
+            runtime.tail_call(fail, vec![])?;
+            runtime.then(Continuation::HashTableUpdate, vec![table, key, func]);
+        }
+        (None, None) => return Err(RuntimeErr::Other(format!(
+            "hash-table-update!: no value for key {}", runtime.ponga_to_string(&key)
+        ))),
+    }
+    Ok(Ponga::Null)
+}
+
+pub fn hash_table_update_default(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    args_assert_len(&args, 4, "hash-table-update!/default")?;
+    let mut args = transform_args(runtime, args)?;
+    let default = args.pop().unwrap();
+    let func = args.pop().unwrap();
+    let key = args.pop().unwrap();
+    let table = args.pop().unwrap();
+    let current = hash_table_get(runtime, &table, &key, "hash-table-update!/default")?.unwrap_or(default);
+    runtime.tail_continue(Continuation::HashTableUpdate, vec![table, key, func, current]);
+    Ok(Ponga::Null)
+}
+
+pub fn hash_table_count(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    args_assert_len(&args, 1, "hash-table-count")?;
+    let mut args = transform_args(runtime, args)?;
+    let table = args.pop().unwrap();
+    let n = with_hash_table(runtime, &table, "hash-table-count", |t| Ok(t.len))?;
+    Ok(Ponga::Number(Number::Int(n as isize)))
+}
+
+fn hash_table_entries(runtime: &mut Runtime, args: Vec<Ponga>,
+                      name: &str) -> RunRes<Vec<(Ponga, Ponga)>> {
+    args_assert_len(&args, 1, name)?;
+    let mut args = transform_args(runtime, args)?;
+    let table = args.pop().unwrap();
+    with_hash_table(runtime, &table, name, |t| Ok(t.entries()))
+}
+
+pub fn hash_table_keys(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    let entries = hash_table_entries(runtime, args, "hash-table-keys")?;
+    let keys = entries.into_iter().map(|(k, _)| k).collect();
+    Ok(runtime.gc.ponga_into_gc_ref(Ponga::List(keys)))
+}
+
+pub fn hash_table_values(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    let entries = hash_table_entries(runtime, args, "hash-table-values")?;
+    let vals = entries.into_iter().map(|(_, v)| v).collect();
+    Ok(runtime.gc.ponga_into_gc_ref(Ponga::List(vals)))
+}
+
+pub fn hash_table_to_alist(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    let entries = hash_table_entries(runtime, args, "hash-table->alist")?;
+    let mut alist = LinkedList::new();
+    for (k, v) in entries.into_iter() {
+        let pair = Ponga::List(vec![k, v].into_iter().collect());
+        alist.push_back(runtime.gc.ponga_into_gc_ref(pair));
+    }
+    Ok(runtime.gc.ponga_into_gc_ref(Ponga::List(alist)))
+}
+
+pub fn hash_table_walk(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    args_assert_len(&args, 2, "hash-table-walk")?;
+    let mut args = transform_args(runtime, args)?;
+    let func = args.pop().unwrap();
+    let table = args.pop().unwrap();
+    let entries = with_hash_table(runtime, &table, "hash-table-walk", |t| Ok(t.entries()))?;
+    runtime.call_each(func, entries.into_iter().map(|(k, v)| vec![k, v]).collect())?;
+    Ok(Ponga::Null)
+}
+
+pub fn hash_table_fold(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    args_assert_len(&args, 3, "hash-table-fold")?;
+    let mut args = transform_args(runtime, args)?;
+    let acc = args.pop().unwrap();
+    let func = args.pop().unwrap();
+    let table = args.pop().unwrap();
+    let entries = with_hash_table(runtime, &table, "hash-table-fold", |t| Ok(t.entries()))?;
+    runtime.tail_fold(func, acc, entries.into_iter().map(|(k, v)| vec![k, v]).collect(), true)?;
+    Ok(Ponga::Null)
+}
+
+// Hashes are reduced modulo the optional bound, and always fit in an isize
+fn hash_result(hash: u64, bound: Option<Ponga>, name: &str) -> RunRes<Ponga> {
+    let bound = match bound {
+        Some(Ponga::Number(Number::Int(n))) if n > 0 => n as u64,
+        Some(b) => return Err(RuntimeErr::TypeError(format!(
+            "{} bound must be a positive integer (not {:?})", name, b
+        ))),
+        None => isize::MAX as u64,
+    };
+    Ok(Ponga::Number(Number::Int((hash % bound) as isize)))
+}
+
+fn hash_args(runtime: &mut Runtime, args: Vec<Ponga>, name: &str) -> RunRes<(Ponga, Option<Ponga>)> {
+    if args.len() != 1 && args.len() != 2 {
+        return Err(RuntimeErr::TypeError(format!("{} requires 1 or 2 arguments", name)));
+    }
+    let mut args = transform_args(runtime, args)?.into_iter();
+    Ok((args.next().unwrap(), args.next()))
+}
+
+pub fn hash(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    let (key, bound) = hash_args(runtime, args, "hash")?;
+    hash_result(equal_hash(runtime, &key)?, bound, "hash")
+}
+
+pub fn string_hash(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    let (key, bound) = hash_args(runtime, args, "string-hash")?;
+    let hash = Equiv::StringEq.hash(runtime, &key)?;
+    hash_result(hash, bound, "string-hash")
+}
+
+pub fn hash_by_identity(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    let (key, bound) = hash_args(runtime, args, "hash-by-identity")?;
+    hash_result(identity_hash(&key), bound, "hash-by-identity")
+}
+
+pub fn record_p(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    args_assert_len(&args, 1, "record?")?;
+    let args = transform_args(runtime, args)?;
+    Ok(bool_to_ponga(runtime.record_type_of(&args[0]).is_some()))
+}
+
+// Gives the fields of the record to func if it is of the type
+fn with_record<T>(runtime: &Runtime, rtype: &Ponga, record: &Ponga, name: &Ponga,
+                  func: impl FnOnce(&mut Vec<Ponga>) -> RunRes<T>) -> RunRes<T> {
+    let (type_id, t) = runtime.record_type(rtype)?;
+    if let Ponga::Ref(id) = record {
+        if let Some(mut obj) = runtime.get_id_obj_ref(*id)?.borrow_mut() {
+            match obj.inner() {
+                Ponga::Record(r) if r.rtype == type_id => return func(&mut r.fields),
+                _ => (),
+            }
+        }
+    }
+    Err(RuntimeErr::TypeError(format!(
+        "{} requires a record of type {}, received {}",
+        runtime.ponga_to_string(name).trim_start_matches('\''), t.name, runtime.ponga_to_string(record)
+    )))
+}
+
+// The builtins behind define-record-type. The constructor passes the index of
+// each field it sets followed by its value, leaving the others as #f.
+pub fn make_record(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    args_assert_gt(&args, 0, "$MAKE-RECORD")?;
+    let mut args = transform_args(runtime, args)?.into_iter();
+    let rtype = args.next().unwrap();
+    let (_, t) = runtime.record_type(&rtype)?;
+    let mut fields = vec![Ponga::False; t.fields.len()];
+    while let Some(i) = args.next() {
+        let i = index_arg(&i, "$MAKE-RECORD")?;
+        fields[i] = args.next().unwrap_or(Ponga::False);
+    }
+    runtime.make_record(&rtype, fields)
+}
+
+pub fn record_of_type_p(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    args_assert_len(&args, 2, "$RECORD?")?;
+    let args = transform_args(runtime, args)?;
+    let (type_id, _) = runtime.record_type(&args[0])?;
+    Ok(bool_to_ponga(runtime.record_type_of(&args[1]) == Some(Ponga::Ref(type_id))))
+}
+
+pub fn record_ref(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    args_assert_len(&args, 4, "$RECORD-REF")?;
+    let args = transform_args(runtime, args)?;
+    let i = index_arg(&args[1], "$RECORD-REF")?;
+    with_record(runtime, &args[0], &args[2], &args[3], |fields| Ok(fields[i].clone()))
+}
+
+pub fn record_set(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    args_assert_len(&args, 5, "$RECORD-SET!")?;
+    let mut args = transform_args(runtime, args)?;
+    let i = index_arg(&args[1], "$RECORD-SET!")?;
+    let val = std::mem::replace(&mut args[3], Ponga::Null);
+    with_record(runtime, &args[0], &args[2], &args[4], |fields| {
+        fields[i] = val;
+        Ok(())
+    })?;
+    Ok(Ponga::Null)
+}
+
+// The promise is forced after this returns, so it doesn't nest the evaluator
+pub fn force(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    args_assert_len(&args, 1, "force")?;
+    let mut args = transform_args(runtime, args)?;
+    runtime.tail_force(args.pop().unwrap());
+    Ok(Ponga::Null)
+}
+
+pub fn make_promise(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    args_assert_len(&args, 1, "make-promise")?;
+    let mut args = transform_args(runtime, args)?;
+    let val = args.pop().unwrap();
+    if runtime.promise_state(&val)?.is_some() {
+        return Ok(val);
+    }
+    Ok(runtime.make_promise(Promise::Done(Box::new(val))))
+}
+
+pub fn promise_p(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    args_assert_len(&args, 1, "promise?")?;
+    let args = transform_args(runtime, args)?;
+    Ok(bool_to_ponga(runtime.promise_state(&args[0])?.is_some()))
+}
+
+// The promises of the first element and the rest of a forced stream, or
+// None at the end. Stream pairs are the records made by stream-cons in
+// stdlib.scm.
+fn stream_pair(runtime: &mut Runtime, val: Ponga, name: &str) -> RunRes<Option<(Ponga, Ponga)>> {
+    if let Some(rtype) = runtime.record_type_of(&val) {
+        if runtime.record_type(&rtype)?.1.name == "stream-pair" {
+            let first = runtime.record_field(&val, "stream-kar")?;
+            let rest = runtime.record_field(&val, "stream-kdr")?;
+            return Ok(Some((first, rest)));
+        }
+    }
+    match list_arg(runtime, &val, name) {
+        Ok(l) if l.is_empty() => Ok(None),
+        _ => Err(RuntimeErr::TypeError(format!(
+            "{} requires a stream, received {}", name, runtime.ponga_to_string(&val)
+        ))),
+    }
+}
+
+// Forces the elements of a stream after the items taken so far, up to the
+// limit if it's a number, giving them all as an inline vector
+fn stream_step(runtime: &mut Runtime, items: Vec<Ponga>, limit: Ponga, stream: Ponga,
+               name: &'static str) -> RunRes<Ponga> {
+    if let Ponga::Number(Number::Int(n)) = limit {
+        if items.len() >= n as usize {
+            return Ok(Ponga::Array(items));
+        }
+    }
+    runtime.tail_force(stream);
+    runtime.then(Continuation::StreamNext(name), vec![Ponga::Array(items), limit]);
+    Ok(Ponga::Null)
+}
+
+// A finite stream whose promises are all already forced
+fn make_stream(runtime: &mut Runtime, items: Vec<Ponga>) -> RunRes<Ponga> {
+    let rtype = runtime.get_identifier_obj_ref("<stream-pair>")?.clone();
+    let mut stream = runtime.make_promise(Promise::Done(Box::new(Ponga::Null)));
+    for item in items.into_iter().rev() {
+        let first = runtime.make_promise(Promise::Done(Box::new(item)));
+        let pair = runtime.make_record(&rtype, vec![first, stream])?;
+        stream = runtime.make_promise(Promise::Done(Box::new(pair)));
+    }
+    Ok(stream)
+}
+
+pub fn stream_to_list(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    args_assert_between(&args, 1, 2, "stream->list")?;
+    let mut args = transform_args(runtime, args)?.into_iter();
+    let stream = args.next().unwrap();
+    let limit = match args.next() {
+        Some(n) => Ponga::Number(Number::Int(index_arg(&n, "stream->list")? as isize)),
+        None => Ponga::False,
+    };
+    let res = stream_step(runtime, vec![], limit, stream, "stream->list")?;
+    runtime.then(Continuation::ListOf, vec![]);
+    Ok(res)
+}
+
+pub fn list_to_stream(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    args_assert_len(&args, 1, "list->stream")?;
+    let args = transform_args(runtime, args)?;
+    let items = list_arg(runtime, &args[0], "list->stream")?;
+    make_stream(runtime, items)
+}
+
+// Forces the first n elements straight away rather than when the result is
+// forced
+pub fn stream_take(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    args_assert_len(&args, 2, "stream-take")?;
+    let mut args = transform_args(runtime, args)?.into_iter();
+    let n = index_arg(&args.next().unwrap(), "stream-take")?;
+    let limit = Ponga::Number(Number::Int(n as isize));
+    let res = stream_step(runtime, vec![], limit, args.next().unwrap(), "stream-take")?;
+    runtime.then(Continuation::MakeStream, vec![]);
+    Ok(res)
+}
+
+pub fn values(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    let mut args = transform_args(runtime, args)?;
+    if args.len() == 1 {
+        return Ok(args.pop().unwrap());
+    }
+    Ok(Ponga::Values(args))
+}
+
+// Doesn't nest the evaluator, so producer and consumer run in the caller's loop
+pub fn call_with_values(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    args_assert_len(&args, 2, "call-with-values")?;
+    let mut args = transform_args(runtime, args)?.into_iter();
+    let producer = args.next().unwrap();
+    runtime.call_with_values(producer, args.next().unwrap())?;
+    Ok(Ponga::Null)
+}
+
+// Bytevectors live in the gc like vectors, and are changed in place
+fn with_bytevector<T>(runtime: &Runtime, bytes: &Ponga, name: &str,
+                      func: impl FnOnce(&mut Vec<u8>) -> RunRes<T>) -> RunRes<T> {
+    if let Ponga::Ref(id) = bytes {
+        if let Some(mut obj) = runtime.get_id_obj_ref(*id)?.borrow_mut() {
+            if let Ponga::Bytevector(bytes) = obj.inner() {
+                return func(bytes);
+            }
+        }
+    }
+    Err(RuntimeErr::TypeError(format!(
+        "{} requires a bytevector (not {})", name, runtime.ponga_to_string(bytes)
+    )))
+}
+
+fn byte_arg(pong: &Ponga, name: &str) -> RunRes<u8> {
+    match pong {
+        Ponga::Number(Number::Int(n)) if (0..256).contains(n) => Ok(*n as u8),
+        _ => Err(RuntimeErr::TypeError(format!("{} requires a byte (not {:?})", name, pong))),
+    }
+}
+
+pub fn bytevector_p(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    args_assert_len(&args, 1, "bytevector?")?;
+    let args = transform_args(runtime, args)?;
+    let res = with_bytevector(runtime, &args[0], "bytevector?", |_| Ok(())).is_ok();
+    Ok(bool_to_ponga(res))
+}
+
+pub fn make_bytevector(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    args_assert_between(&args, 1, 2, "make-bytevector")?;
+    let mut args = transform_args(runtime, args)?.into_iter();
+    let len = index_arg(&args.next().unwrap(), "make-bytevector")?;
+    let fill = match args.next() {
+        Some(fill) => byte_arg(&fill, "make-bytevector")?,
+        None => 0,
+    };
+    Ok(runtime.gc.ponga_into_gc_ref(Ponga::Bytevector(vec![fill; len])))
+}
+
+pub fn bytevector(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    let args = transform_args(runtime, args)?;
+    let bytes = args.iter().map(|b| byte_arg(b, "bytevector")).collect::<RunRes<Vec<u8>>>()?;
+    Ok(runtime.gc.ponga_into_gc_ref(Ponga::Bytevector(bytes)))
+}
+
+pub fn bytevector_length(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    args_assert_len(&args, 1, "bytevector-length")?;
+    let args = transform_args(runtime, args)?;
+    let len = with_bytevector(runtime, &args[0], "bytevector-length", |b| Ok(b.len()))?;
+    Ok(Ponga::Number(Number::Int(len as isize)))
+}
+
+pub fn bytevector_u8_ref(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    args_assert_len(&args, 2, "bytevector-u8-ref")?;
+    let args = transform_args(runtime, args)?;
+    let k = index_arg(&args[1], "bytevector-u8-ref")?;
+    let byte = with_bytevector(runtime, &args[0], "bytevector-u8-ref", |b| {
+        b.get(k).copied().ok_or_else(|| RuntimeErr::TypeError(
+            "bytevector-u8-ref index out of bounds".to_string()
+        ))
+    })?;
+    Ok(Ponga::Number(Number::Int(byte as isize)))
+}
+
+pub fn bytevector_u8_set(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    args_assert_len(&args, 3, "bytevector-u8-set!")?;
+    let args = transform_args(runtime, args)?;
+    let k = index_arg(&args[1], "bytevector-u8-set!")?;
+    let byte = byte_arg(&args[2], "bytevector-u8-set!")?;
+    with_bytevector(runtime, &args[0], "bytevector-u8-set!", |b| {
+        match b.get_mut(k) {
+            Some(slot) => {
+                *slot = byte;
+                Ok(())
+            }
+            None => Err(RuntimeErr::TypeError("bytevector-u8-set! index out of bounds".to_string())),
+        }
+    })?;
+    Ok(Ponga::Null)
+}
+
+// The bytes of the first argument between the optional start and end
+fn byte_range(runtime: &mut Runtime, args: Vec<Ponga>, name: &str) -> RunRes<Vec<u8>> {
+    let mut args = transform_args(runtime, args)?.into_iter();
+    let bytes = args.next().unwrap();
+    let (start, end) = (args.next(), args.next());
+    with_bytevector(runtime, &bytes, name, |b| {
+        let (start, end) = range_args(start, end, b.len(), name)?;
+        Ok(b[start..end].to_vec())
+    })
+}
+
+pub fn bytevector_copy(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    args_assert_between(&args, 1, 3, "bytevector-copy")?;
+    let copied = byte_range(runtime, args, "bytevector-copy")?;
+    Ok(runtime.gc.ponga_into_gc_ref(Ponga::Bytevector(copied)))
+}
+
+pub fn bytevector_append(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    let args = transform_args(runtime, args)?;
+    let mut res = vec![];
+    for bytes in args.iter() {
+        with_bytevector(runtime, bytes, "bytevector-append", |b| {
+            res.extend_from_slice(b);
+            Ok(())
+        })?;
+    }
+    Ok(runtime.gc.ponga_into_gc_ref(Ponga::Bytevector(res)))
+}
+
+pub fn utf8_to_string(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    args_assert_between(&args, 1, 3, "utf8->string")?;
+    let bytes = byte_range(runtime, args, "utf8->string")?;
+    String::from_utf8(bytes).map(Ponga::String).map_err(|_| RuntimeErr::Other(
+        "utf8->string requires valid UTF-8".to_string()
+    ))
+}
+
+pub fn string_to_utf8(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    args_assert_len(&args, 1, "string->utf8")?;
+    let args = transform_args(runtime, args)?;
+    let s = string_arg(runtime, &args[0], "string->utf8")?;
+    Ok(runtime.gc.ponga_into_gc_ref(Ponga::Bytevector(s.into_bytes())))
+}
+
+// Gives the port to func, which does any checking of its direction
+fn with_port<T>(runtime: &Runtime, port: &Ponga, name: &str,
+                func: impl FnOnce(&mut Port) -> RunRes<T>) -> RunRes<T> {
+    if let Ponga::Ref(id) = port {
+        if let Some(mut obj) = runtime.get_id_obj_ref(*id)?.borrow_mut() {
+            if let Ponga::Port(port) = obj.inner() {
+                return func(port);
+            }
+        }
+    }
+    Err(RuntimeErr::TypeError(format!(
+        "{} requires a port (not {})", name, runtime.ponga_to_string(port)
+    )))
+}
+
+fn input_port_arg(runtime: &Runtime, port: Option<Ponga>) -> RunRes<Ponga> {
+    port.map_or_else(|| runtime.parameter_value(&runtime.current_input), Ok)
+}
+
+fn output_port_arg(runtime: &Runtime, port: Option<Ponga>) -> RunRes<Ponga> {
+    port.map_or_else(|| runtime.parameter_value(&runtime.current_output), Ok)
+}
+
+fn text_port(port: &Port, name: &str) -> RunRes<()> {
+    if port.binary {
+        return Err(RuntimeErr::TypeError(format!("{} requires a textual port", name)));
+    }
+    Ok(())
+}
+
+fn binary_port(port: &Port, name: &str) -> RunRes<()> {
+    if !port.binary {
+        return Err(RuntimeErr::TypeError(format!("{} requires a binary port", name)));
+    }
+    Ok(())
+}
+
+fn port_pred(runtime: &mut Runtime, args: Vec<Ponga>, name: &str,
+             pred: impl FnOnce(&Port) -> bool) -> RunRes<Ponga> {
+    args_assert_len(&args, 1, name)?;
+    let args = transform_args(runtime, args)?;
+    let res = with_port(runtime, &args[0], name, |p| Ok(pred(p))).unwrap_or(false);
+    Ok(bool_to_ponga(res))
+}
+
+pub fn port_p(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    port_pred(runtime, args, "port?", |_| true)
+}
+
+pub fn input_port_p(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    port_pred(runtime, args, "input-port?", |p| p.is_input())
+}
+
+pub fn output_port_p(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    port_pred(runtime, args, "output-port?", |p| !p.is_input())
+}
+
+pub fn textual_port_p(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    port_pred(runtime, args, "textual-port?", |p| !p.binary)
+}
+
+pub fn binary_port_p(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    port_pred(runtime, args, "binary-port?", |p| p.binary)
+}
+
+pub fn input_port_open_p(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    port_pred(runtime, args, "input-port-open?", |p| p.is_input() && p.open)
+}
+
+pub fn output_port_open_p(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    port_pred(runtime, args, "output-port-open?", |p| !p.is_input() && p.open)
+}
+
+fn open_file(runtime: &mut Runtime, args: Vec<Ponga>, input: bool, binary: bool,
+             name: &str) -> RunRes<Ponga> {
+    args_assert_len(&args, 1, name)?;
+    let args = transform_args(runtime, args)?;
+    let path = string_arg(runtime, &args[0], name)?;
+    let cant_open = |e: std::io::Error| RuntimeErr::Other(format!(
+        "{} couldn't open {}: {}", name, path, e
+    ));
+    let port = if input {
+        Port::file(std::fs::File::open(&path).map_err(cant_open)?, binary)
+    } else {
+        let file = std::fs::File::create(&path).map_err(cant_open)?;
+        Port::output(Sink::File(Arc::new(std::sync::Mutex::new(file))), binary)
+    };
+    Ok(runtime.gc.ponga_into_gc_ref(Ponga::Port(port)))
+}
+
+pub fn open_input_file(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    open_file(runtime, args, true, false, "open-input-file")
+}
+
+pub fn open_binary_input_file(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    open_file(runtime, args, true, true, "open-binary-input-file")
+}
+
+pub fn open_output_file(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    open_file(runtime, args, false, false, "open-output-file")
+}
+
+pub fn open_binary_output_file(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    open_file(runtime, args, false, true, "open-binary-output-file")
+}
+
+pub fn open_input_string(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    args_assert_len(&args, 1, "open-input-string")?;
+    let args = transform_args(runtime, args)?;
+    let s = string_arg(runtime, &args[0], "open-input-string")?;
+    Ok(runtime.gc.ponga_into_gc_ref(Ponga::Port(Port::input(s.into_bytes(), false))))
+}
+
+pub fn open_output_string(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    args_assert_len(&args, 0, "open-output-string")?;
+    Ok(runtime.gc.ponga_into_gc_ref(Ponga::Port(Port::output(Sink::Buffer(vec![]), false))))
+}
+
+pub fn get_output_string(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    args_assert_len(&args, 1, "get-output-string")?;
+    let args = transform_args(runtime, args)?;
+    let s = with_port(runtime, &args[0], "get-output-string", |p| {
+        p.output_string().ok_or_else(|| RuntimeErr::TypeError(
+            "get-output-string requires a port made by open-output-string".to_string()
+        ))
+    })?;
+    Ok(Ponga::String(s))
+}
+
+pub fn open_input_bytevector(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    args_assert_len(&args, 1, "open-input-bytevector")?;
+    let args = transform_args(runtime, args)?;
+    let bytes = with_bytevector(runtime, &args[0], "open-input-bytevector", |b| Ok(b.clone()))?;
+    Ok(runtime.gc.ponga_into_gc_ref(Ponga::Port(Port::input(bytes, true))))
+}
+
+pub fn open_output_bytevector(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    args_assert_len(&args, 0, "open-output-bytevector")?;
+    Ok(runtime.gc.ponga_into_gc_ref(Ponga::Port(Port::output(Sink::Buffer(vec![]), true))))
+}
+
+pub fn get_output_bytevector(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    args_assert_len(&args, 1, "get-output-bytevector")?;
+    let args = transform_args(runtime, args)?;
+    let bytes = with_port(runtime, &args[0], "get-output-bytevector", |p| {
+        binary_port(p, "get-output-bytevector")?;
+        p.output_bytes().ok_or_else(|| RuntimeErr::TypeError(
+            "get-output-bytevector requires a port made by open-output-bytevector".to_string()
+        ))
+    })?;
+    Ok(runtime.gc.ponga_into_gc_ref(Ponga::Bytevector(bytes)))
+}
+
+pub fn close_port(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    args_assert_len(&args, 1, "close-port")?;
+    let args = transform_args(runtime, args)?;
+    with_port(runtime, &args[0], "close-port", |p| {
+        p.close();
+        Ok(())
+    })?;
+    Ok(Ponga::Null)
+}
+
+// Calls func with the port given as the argument at index, or the current
+// input port
+fn read_with<T>(runtime: &mut Runtime, args: Vec<Ponga>, index: usize, name: &str,
+                func: impl FnOnce(&mut Port) -> RunRes<T>) -> RunRes<T> {
+    args_assert_between(&args, index, index + 1, name)?;
+    let args = transform_args(runtime, args)?;
+    let port = input_port_arg(runtime, args.get(index).cloned())?;
+    with_port(runtime, &port, name, func)
+}
+
+fn char_or_eof(c: Option<char>) -> Ponga {
+    c.map_or(Ponga::Eof, Ponga::Char)
+}
+
+fn byte_or_eof(b: Option<u8>) -> Ponga {
+    b.map_or(Ponga::Eof, |b| Ponga::Number(Number::Int(b as isize)))
+}
+
+pub fn read(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    let val = read_with(runtime, args, 0, "read", |p| {
+        text_port(p, "read")?;
+        p.read_datum()
+    })?;
+    Ok(val.map_or(Ponga::Eof, |val| runtime.datum(val)))
+}
+
+// Reads every datum left in the port into a list
+pub fn read_all(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    let vals = read_with(runtime, args, 0, "read-all", |p| {
+        text_port(p, "read-all")?;
+        std::iter::from_fn(|| p.read_datum().transpose()).collect::<RunRes<Vec<Ponga>>>()
+    })?;
+    let list = vals.into_iter().map(|val| runtime.datum(val)).collect();
+    Ok(runtime.gc.ponga_into_gc_ref(Ponga::List(list)))
+}
+
+pub fn read_char(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    read_with(runtime, args, 0, "read-char", |p| {
+        text_port(p, "read-char")?;
+        Ok(char_or_eof(p.read_char()?))
+    })
+}
+
+pub fn peek_char(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    read_with(runtime, args, 0, "peek-char", |p| {
+        text_port(p, "peek-char")?;
+        Ok(char_or_eof(p.peek_char()?))
+    })
+}
+
+pub fn read_line(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    read_with(runtime, args, 0, "read-line", |p| {
+        text_port(p, "read-line")?;
+        Ok(p.read_line()?.map_or(Ponga::Eof, Ponga::String))
+    })
+}
+
+// (read-string k [port])
+pub fn read_string(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    let k = match args.first() {
+        Some(k) => index_arg(&runtime.id_or_ref_peval(k.clone())?, "read-string")?,
+        None => return Err(RuntimeErr::TypeError("read-string requires 1 to 2 arguments".to_string())),
+    };
+    read_with(runtime, args, 1, "read-string", |p| {
+        text_port(p, "read-string")?;
+        Ok(p.read_string(k)?.map_or(Ponga::Eof, Ponga::String))
+    })
+}
+
+// (read-bytevector k [port])
+pub fn read_bytevector(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    let k = match args.first() {
+        Some(k) => index_arg(&runtime.id_or_ref_peval(k.clone())?, "read-bytevector")?,
+        None => return Err(RuntimeErr::TypeError(
+            "read-bytevector requires 1 to 2 arguments".to_string()
+        )),
+    };
+    let bytes = read_with(runtime, args, 1, "read-bytevector", |p| {
+        binary_port(p, "read-bytevector")?;
+        p.read_bytes(k)
+    })?;
+    Ok(bytes.map_or(Ponga::Eof, |b| runtime.gc.ponga_into_gc_ref(Ponga::Bytevector(b))))
+}
+
+pub fn char_ready(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    read_with(runtime, args, 0, "char-ready?", |p| Ok(bool_to_ponga(p.ready()?)))
+}
+
+pub fn read_u8(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    read_with(runtime, args, 0, "read-u8", |p| {
+        binary_port(p, "read-u8")?;
+        Ok(byte_or_eof(p.read_u8()?))
+    })
+}
+
+pub fn peek_u8(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    read_with(runtime, args, 0, "peek-u8", |p| {
+        binary_port(p, "peek-u8")?;
+        Ok(byte_or_eof(p.peek_u8()?))
+    })
+}
+
+// Writes what func makes of the first argument to the port given as the
+// second, or the current output port
+fn write_with(runtime: &mut Runtime, args: Vec<Ponga>, name: &str, binary: bool,
+              func: impl FnOnce(&Runtime, &Ponga) -> RunRes<Vec<u8>>) -> RunRes<Ponga> {
+    args_assert_between(&args, 1, 2, name)?;
+    let mut args = transform_args(runtime, args)?.into_iter();
+    let val = args.next().unwrap();
+    let bytes = func(runtime, &val)?;
+    let port = output_port_arg(runtime, args.next())?;
+    with_port(runtime, &port, name, |p| {
+        if binary {
+            binary_port(p, name)?;
+        } else {
+            text_port(p, name)?;
+        }
+        p.write_bytes(&bytes)
+    })?;
+    Ok(Ponga::Null)
+}
+
+// Writes values as literals that read would give back
+pub fn write(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    write_with(runtime, args, "write", false, |runtime, val| {
+        let s = match val {
+            Ponga::Symbol(s) => s.clone(),
+            _ => runtime.ponga_to_string(val),
+        };
+        Ok(s.into_bytes())
+    })
+}
+
+pub fn write_char(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    write_with(runtime, args, "write-char", false, |_, val| {
+        Ok(char_arg(val, "write-char")?.to_string().into_bytes())
+    })
+}
+
+pub fn write_string(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    write_with(runtime, args, "write-string", false, |runtime, val| {
+        Ok(string_arg(runtime, val, "write-string")?.into_bytes())
+    })
+}
+
+pub fn write_u8(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    write_with(runtime, args, "write-u8", true, |_, val| Ok(vec![byte_arg(val, "write-u8")?]))
+}
+
+// (write-bytevector bytevector [port [start [end]]])
+pub fn write_bytevector(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    args_assert_between(&args, 1, 4, "write-bytevector")?;
+    let mut args = transform_args(runtime, args)?.into_iter();
+    let bytes = args.next().unwrap();
+    let port = output_port_arg(runtime, args.next())?;
+    let (start, end) = (args.next(), args.next());
+    let bytes = with_bytevector(runtime, &bytes, "write-bytevector", |b| {
+        let (start, end) = range_args(start, end, b.len(), "write-bytevector")?;
+        Ok(b[start..end].to_vec())
+    })?;
+    with_port(runtime, &port, "write-bytevector", |p| {
+        binary_port(p, "write-bytevector")?;
+        p.write_bytes(&bytes)
+    })?;
+    Ok(Ponga::Null)
+}
+
+pub fn newline(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    args_assert_between(&args, 0, 1, "newline")?;
+    let mut args = transform_args(runtime, args)?;
+    let port = output_port_arg(runtime, args.pop())?;
+    with_port(runtime, &port, "newline", |p| p.write_str("\n"))?;
+    Ok(Ponga::Null)
+}
+
+// Writes aren't buffered, so this only checks the port
+pub fn flush_output_port(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    args_assert_between(&args, 0, 1, "flush-output-port")?;
+    let mut args = transform_args(runtime, args)?;
+    let port = output_port_arg(runtime, args.pop())?;
+    with_port(runtime, &port, "flush-output-port", |p| p.write_bytes(&[]))?;
+    Ok(Ponga::Null)
+}
+
+pub fn eof_object(_runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    args_assert_len(&args, 0, "eof-object")?;
+    Ok(Ponga::Eof)
+}
+
+pub fn eof_object_p(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    args_assert_len(&args, 1, "eof-object?")?;
+    let args = transform_args(runtime, args)?;
+    Ok(bool_to_ponga(args[0] == Ponga::Eof))
+}
+
+// Calls proc with the port, closing the port once it returns
+fn call_then_close(runtime: &mut Runtime, port: Ponga, proc: Ponga) -> RunRes<Ponga> {
+    runtime.tail_call(proc, vec![port.clone()])?;
+    runtime.then(Continuation::ClosePort, vec![port]);
+    Ok(Ponga::Null)
+}
+
+pub fn call_with_port(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    args_assert_len(&args, 2, "call-with-port")?;
+    let mut args = transform_args(runtime, args)?.into_iter();
+    let port = args.next().unwrap();
+    call_then_close(runtime, port, args.next().unwrap())
+}
+
+pub fn call_with_output_string(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    args_assert_len(&args, 1, "call-with-output-string")?;
+    let mut args = transform_args(runtime, args)?;
+    let port = open_output_string(runtime, vec![])?;
+    runtime.tail_call(args.pop().unwrap(), vec![port.clone()])?;
+    runtime.then(Continuation::OutputString, vec![port]);
+    Ok(Ponga::Null)
+}
+
+pub fn call_with_input_file(runtime: &mut Runtime, mut args: Vec<Ponga>) -> RunRes<Ponga> {
+    args_assert_len(&args, 2, "call-with-input-file")?;
+    let proc = runtime.id_or_ref_peval(args.pop().unwrap())?;
+    let port = open_input_file(runtime, args)?;
+    call_then_close(runtime, port, proc)
+}
+
+pub fn call_with_output_file(runtime: &mut Runtime, mut args: Vec<Ponga>) -> RunRes<Ponga> {
+    args_assert_len(&args, 2, "call-with-output-file")?;
+    let proc = runtime.id_or_ref_peval(args.pop().unwrap())?;
+    let port = open_output_file(runtime, args)?;
+    call_then_close(runtime, port, proc)
+}
+
+// Calls the thunk with one of the current port parameters set to the port
+fn with_current_port(runtime: &mut Runtime, current: fn(&Runtime) -> Ponga,
+                     port: Ponga, thunk: Ponga) -> RunRes<Ponga> {
+    let param = current(runtime);
+    runtime.tail_call_with_parameters(vec![(param, port)], thunk, vec![])?;
+    Ok(Ponga::Null)
+}
+
+pub fn with_output_to_string(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    args_assert_len(&args, 1, "with-output-to-string")?;
+    let mut args = transform_args(runtime, args)?;
+    let port = open_output_string(runtime, vec![])?;
+    with_current_port(runtime, |r| r.current_output.clone(), port.clone(), args.pop().unwrap())?;
+    runtime.then(Continuation::OutputString, vec![port]);
+    Ok(Ponga::Null)
+}
+
+pub fn with_output_to_file(runtime: &mut Runtime, mut args: Vec<Ponga>) -> RunRes<Ponga> {
+    args_assert_len(&args, 2, "with-output-to-file")?;
+    let thunk = runtime.id_or_ref_peval(args.pop().unwrap())?;
+    let port = open_output_file(runtime, args)?;
+    with_current_port(runtime, |r| r.current_output.clone(), port.clone(), thunk)?;
+    runtime.then(Continuation::ClosePort, vec![port]);
+    Ok(Ponga::Null)
+}
+
+pub fn with_input_from_file(runtime: &mut Runtime, mut args: Vec<Ponga>) -> RunRes<Ponga> {
+    args_assert_len(&args, 2, "with-input-from-file")?;
+    let thunk = runtime.id_or_ref_peval(args.pop().unwrap())?;
+    let port = open_input_file(runtime, args)?;
+    with_current_port(runtime, |r| r.current_input.clone(), port.clone(), thunk)?;
+    runtime.then(Continuation::ClosePort, vec![port]);
+    Ok(Ponga::Null)
+}
+
+pub fn with_input_from_string(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    args_assert_len(&args, 2, "with-input-from-string")?;
+    let mut args = transform_args(runtime, args)?;
+    let thunk = args.pop().unwrap();
+    let port = open_input_string(runtime, args)?;
+    with_current_port(runtime, |r| r.current_input.clone(), port, thunk)
+}
+
+// Evaluates the file into the global environment
+pub fn load(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    args_assert_len(&args, 1, "load")?;
+    let args = transform_args(runtime, args)?;
+    let name = string_arg(runtime, &args[0], "load")?;
+    runtime.tail_load_file(&name)?;
+    Ok(Ponga::Null)
+}
+
+// (eval expr [env]) evaluates the data in expr as code
+pub fn eval(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    args_assert_between(&args, 1, 2, "eval")?;
+    let mut args = transform_args(runtime, args)?.into_iter();
+    let code = runtime.code(args.next().unwrap())?;
+    let env = args.next().unwrap_or_else(|| runtime.current_environment());
+    runtime.tail_eval_in(&env, code)?;
+    Ok(Ponga::Null)
+}
+
+// An environment with only what the import sets bring in
+pub fn environment(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    let args = transform_args(runtime, args)?;
+    let env = runtime.make_empty_environment();
+    for set in args.into_iter() {
+        let set = runtime.code(set)?;
+        runtime.import_into_environment(&env, set)?;
+    }
+    Ok(env)
+}
+
+pub fn scheme_report_environment(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    args_assert_len(&args, 1, "scheme-report-environment")?;
+    runtime.make_report_environment()
+}
+
+pub fn interaction_environment(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    args_assert_len(&args, 0, "interaction-environment")?;
+    Ok(runtime.interaction_env.clone())
+}
+
+// (make-parameter value [converter])
+pub fn make_parameter(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    args_assert_between(&args, 1, 2, "make-parameter")?;
+    let mut args = transform_args(runtime, args)?.into_iter();
+    let value = args.next().unwrap();
+    let converter = args.next();
+    match converter {
+        Some(converter) => {
+            runtime.tail_call(converter.clone(), vec![value])?;
+            runtime.then(Continuation::MakeParameter, vec![converter]);
+            Ok(Ponga::Null)
+        }
+        None => Ok(runtime.make_parameter(value, None)),
+    }
+}
+
+// A timeout in seconds from now as the time it runs out, in seconds since
+// the epoch, so that builtins which block can be retried with it
+fn deadline_arg(arg: Option<&Ponga>, name: &str) -> RunRes<Ponga> {
+    let secs = match arg {
+        None | Some(Ponga::False) => return Ok(Ponga::False),
+        Some(Ponga::Number(n)) => n.to_f64().max(0.0),
+        Some(other) => return Err(RuntimeErr::TypeError(format!(
+            "{} requires a timeout in seconds (not {})", name, other
+        ))),
+    };
+    let deadline = SystemTime::now() + Duration::from_secs_f64(secs);
+    let since_epoch = deadline.duration_since(UNIX_EPOCH).unwrap_or_default();
+    Ok(Ponga::Number(Number::Float(since_epoch.as_secs_f64())))
+}
+
+fn deadline(pong: &Ponga) -> Option<SystemTime> {
+    match pong {
+        Ponga::Number(n) => Some(UNIX_EPOCH + Duration::from_secs_f64(n.to_f64())),
+        _ => None,
+    }
+}
+
+// The id of a gc object that has already been checked
+fn ref_id(pong: &Ponga) -> Id {
+    match pong {
+        Ponga::Ref(id) => *id,
+        _ => unreachable!(),
+    }
+}
+
+// Blocks the current thread, trying again with the args once the thread gets
+// another turn
+fn retry_later(runtime: &mut Runtime, wait: Wait, deadline: Option<SystemTime>,
+               k: Continuation, args: Vec<Ponga>) -> RunRes<Ponga> {
+    runtime.block(wait, deadline);
+    runtime.tail_continue(k, args);
+    Ok(Ponga::Null)
+}
+
+pub fn current_thread(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    args_assert_len(&args, 0, "current-thread")?;
+    Ok(runtime.current_thread.clone())
+}
+
+pub fn thread_p(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    args_assert_len(&args, 1, "thread?")?;
+    let args = transform_args(runtime, args)?;
+    Ok(bool_to_ponga(runtime.with_thread(&args[0], |_| ()).is_ok()))
+}
+
+// (make-thread thunk [name])
+pub fn make_thread(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    args_assert_between(&args, 1, 2, "make-thread")?;
+    let mut args = transform_args(runtime, args)?.into_iter();
+    let thunk = args.next().unwrap();
+    let name = args.next().unwrap_or_else(|| Ponga::Symbol("thread".to_string()));
+    runtime.make_thread(thunk, name)
+}
+
+pub fn thread_name(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    args_assert_len(&args, 1, "thread-name")?;
+    let args = transform_args(runtime, args)?;
+    runtime.with_thread(&args[0], |thread| *thread.name.clone())
+}
+
+pub fn thread_specific(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    args_assert_len(&args, 1, "thread-specific")?;
+    let args = transform_args(runtime, args)?;
+    runtime.with_thread(&args[0], |thread| *thread.specific.clone())
+}
+
+pub fn thread_specific_set(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    args_assert_len(&args, 2, "thread-specific-set!")?;
+    let mut args = transform_args(runtime, args)?;
+    let val = args.pop().unwrap();
+    runtime.with_thread(&args[0], |thread| *thread.specific = val)?;
+    Ok(Ponga::Null)
+}
+
+pub fn thread_start(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    args_assert_len(&args, 1, "thread-start!")?;
+    let args = transform_args(runtime, args)?;
+    runtime.start_thread(&args[0])?;
+    Ok(args[0].clone())
+}
+
+pub fn thread_yield(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    args_assert_len(&args, 0, "thread-yield!")?;
+    runtime.block(Wait::Yield, None);
+    Ok(Ponga::Null)
+}
+
+// (thread-sleep! seconds)
+pub fn thread_sleep(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    args_assert_len(&args, 1, "thread-sleep!")?;
+    let args = transform_args(runtime, args)?;
+    if !matches!(args[0], Ponga::Number(_)) {
+        return Err(RuntimeErr::TypeError(format!(
+            "thread-sleep! requires a number of seconds (not {})",
+            runtime.ponga_to_string(&args[0])
+        )));
+    }
+    let deadline = deadline_arg(args.first(), "thread-sleep!")?;
+    thread_sleep_until(runtime, vec![deadline])
+}
+
+fn thread_sleep_until(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    let until = deadline(&args[0]);
+    if timed_out(until) {
+        return Ok(Ponga::Null);
+    }
+    retry_later(runtime, Wait::Sleep, until, Continuation::ThreadSleep, args)
+}
+
+// (thread-join! thread [timeout [timeout-val]]) gives the result of the
+// thread's thunk, raising whatever error it finished with
+pub fn thread_join(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    args_assert_between(&args, 1, 3, "thread-join!")?;
+    let mut args = transform_args(runtime, args)?;
+    let deadline = deadline_arg(args.get(1), "thread-join!")?;
+    match args.get_mut(1) {
+        Some(timeout) => *timeout = deadline,
+        None => args.push(deadline),
+    }
+    thread_join_until(runtime, args)
+}
+
+fn thread_join_until(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    if let Some(res) = runtime.thread_result(&args[0])? {
+        return res;
+    }
+    let until = deadline(&args[1]);
+    if timed_out(until) {
+        return args.get(2).cloned().ok_or_else(|| {
+            RuntimeErr::Other("thread-join! timed out".to_string())
+        });
+    }
+    let wait = Wait::Join(ref_id(&args[0]));
+    retry_later(runtime, wait, until, Continuation::ThreadJoin, args)
+}
+
+pub fn make_mutex(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    args_assert_between(&args, 0, 1, "make-mutex")?;
+    let args = transform_args(runtime, args)?;
+    let name = args.into_iter().next().unwrap_or_else(|| Ponga::Symbol("mutex".to_string()));
+    Ok(runtime.gc.ponga_into_gc_ref(Ponga::Mutex(Mutex::new(name))))
+}
+
+pub fn mutex_p(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    args_assert_len(&args, 1, "mutex?")?;
+    let args = transform_args(runtime, args)?;
+    Ok(bool_to_ponga(runtime.with_mutex(&args[0], |_| ()).is_ok()))
+}
+
+pub fn mutex_name(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    args_assert_len(&args, 1, "mutex-name")?;
+    let args = transform_args(runtime, args)?;
+    runtime.with_mutex(&args[0], |mutex| *mutex.name.clone())
+}
+
+// The owning thread, or not-owned, abandoned or not-abandoned
+pub fn mutex_state(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    args_assert_len(&args, 1, "mutex-state")?;
+    let args = transform_args(runtime, args)?;
+    let symbol = |s: &str| Ponga::Symbol(s.to_string());
+    match runtime.with_mutex(&args[0], |mutex| mutex.state.clone())? {
+        MutexState::Unlocked => Ok(symbol("not-abandoned")),
+        MutexState::Locked(None) => Ok(symbol("not-owned")),
+        MutexState::Locked(Some(owner)) => {
+            if runtime.with_thread(&Ponga::Ref(owner), |owner| owner.is_done())? {
+                Ok(symbol("abandoned"))
+            } else {
+                Ok(Ponga::Ref(owner))
+            }
+        }
+    }
+}
+
+// (mutex-lock! mutex [timeout [thread]]) locks the mutex for the thread,
+// or for no thread if it's #f, giving #f if the timeout runs out first
+pub fn mutex_lock(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    args_assert_between(&args, 1, 3, "mutex-lock!")?;
+    let mut args = transform_args(runtime, args)?.into_iter();
+    let mutex = args.next().unwrap();
+    runtime.with_mutex(&mutex, |_| ())?;
+    let deadline = deadline_arg(args.next().as_ref(), "mutex-lock!")?;
+    let owner = args.next().unwrap_or_else(|| runtime.current_thread.clone());
+    if owner != Ponga::False {
+        runtime.with_thread(&owner, |_| ())?;
+    }
+    mutex_lock_until(runtime, vec![mutex, deadline, owner])
+}
+
+fn mutex_lock_until(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    if runtime.mutex_available(&args[0])? {
+        let owner = match &args[2] {
+            Ponga::Ref(id) => Some(*id),
+            _ => None,
+        };
+        runtime.with_mutex(&args[0], |mutex| mutex.state = MutexState::Locked(owner))?;
+        return Ok(Ponga::True);
+    }
+    let until = deadline(&args[1]);
+    if timed_out(until) {
+        return Ok(Ponga::False);
+    }
+    let wait = Wait::Mutex(ref_id(&args[0]));
+    retry_later(runtime, wait, until, Continuation::MutexLock, args)
+}
+
+// (mutex-unlock! mutex [condition-variable [timeout]]) unlocks the mutex,
+// then waits for the condition variable to be signaled if one is given,
+// giving #f if the timeout runs out first
+pub fn mutex_unlock(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    args_assert_between(&args, 1, 3, "mutex-unlock!")?;
+    let mut args = transform_args(runtime, args)?.into_iter();
+    let mutex = args.next().unwrap();
+    let cv = args.next();
+    let deadline = deadline_arg(args.next().as_ref(), "mutex-unlock!")?;
+    let ticket = match &cv {
+        Some(cv) => Some(runtime.with_condition_variable(cv, |cv| cv.add_waiter())?),
+        None => None,
+    };
+    runtime.with_mutex(&mutex, |mutex| mutex.state = MutexState::Unlocked)?;
+    match (cv, ticket) {
+        (Some(cv), Some(ticket)) => {
+            let ticket = Ponga::Number(Number::Int(ticket as isize));
+            condition_wait_until(runtime, vec![cv, ticket, deadline])
+        }
+        _ => Ok(Ponga::True),
+    }
+}
+
+fn condition_wait_until(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    let ticket = match &args[1] {
+        Ponga::Number(n) => n.to_isize() as usize,
+        _ => unreachable!(),
+    };
+    if !runtime.with_condition_variable(&args[0], |cv| cv.is_waiting(ticket))? {
+        return Ok(Ponga::True);
+    }
+    let until = deadline(&args[2]);
+    if timed_out(until) {
+        runtime.with_condition_variable(&args[0], |cv| cv.remove_waiter(ticket))?;
+        return Ok(Ponga::False);
+    }
+    let wait = Wait::Condition(ref_id(&args[0]), ticket);
+    retry_later(runtime, wait, until, Continuation::ConditionWait, args)
+}
+
+pub fn make_condition_variable(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    args_assert_between(&args, 0, 1, "make-condition-variable")?;
+    let args = transform_args(runtime, args)?;
+    let name = args.into_iter()
+                   .next()
+                   .unwrap_or_else(|| Ponga::Symbol("condition-variable".to_string()));
+    Ok(runtime.gc.ponga_into_gc_ref(Ponga::ConditionVariable(ConditionVariable::new(name))))
+}
+
+pub fn condition_variable_p(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    args_assert_len(&args, 1, "condition-variable?")?;
+    let args = transform_args(runtime, args)?;
+    Ok(bool_to_ponga(runtime.with_condition_variable(&args[0], |_| ()).is_ok()))
+}
+
+pub fn condition_variable_name(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    args_assert_len(&args, 1, "condition-variable-name")?;
+    let args = transform_args(runtime, args)?;
+    runtime.with_condition_variable(&args[0], |cv| *cv.name.clone())
+}
+
+pub fn condition_variable_signal(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    args_assert_len(&args, 1, "condition-variable-signal!")?;
+    let args = transform_args(runtime, args)?;
+    runtime.with_condition_variable(&args[0], |cv| cv.signal())?;
+    Ok(Ponga::Null)
+}
+
+pub fn condition_variable_broadcast(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    args_assert_len(&args, 1, "condition-variable-broadcast!")?;
+    let args = transform_args(runtime, args)?;
+    runtime.with_condition_variable(&args[0], |cv| cv.broadcast())?;
+    Ok(Ponga::Null)
+}
+
+// (make-channel [capacity]) makes an unbounded channel, or one that holds at
+// most capacity values that haven't been received
+pub fn make_channel(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    args_assert_between(&args, 0, 1, "make-channel")?;
+    let args = transform_args(runtime, args)?;
+    let capacity = match args.first() {
+        None => None,
+        Some(Ponga::Number(Number::Int(n))) if *n > 0 => Some(*n as usize),
+        Some(other) => return Err(RuntimeErr::TypeError(format!(
+            "make-channel requires a positive capacity (not {})", runtime.ponga_to_string(other)
+        ))),
+    };
+    Ok(runtime.gc.ponga_into_gc_ref(Ponga::Channel(Channel::new(capacity))))
+}
+
+pub fn channel_p(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    args_assert_len(&args, 1, "channel?")?;
+    let args = transform_args(runtime, args)?;
+    Ok(bool_to_ponga(runtime.with_channel(&args[0], |_| ()).is_ok()))
+}
+
+// Waits while the channel is full
+pub fn channel_send(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    args_assert_len(&args, 2, "channel-send")?;
+    let args = transform_args(runtime, args)?;
+    let val = args[1].clone();
+    if runtime.with_channel(&args[0], |channel| channel.try_send(val))?? {
+        return Ok(Ponga::Null);
+    }
+    let wait = Wait::Channels(vec![(ref_id(&args[0]), ChannelOp::Send)]);
+    retry_later(runtime, wait, None, Continuation::ChannelSend, args)
+}
+
+// Waits while the channel is empty, giving the eof object once it's closed
+pub fn channel_receive(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    args_assert_len(&args, 1, "channel-receive")?;
+    let args = transform_args(runtime, args)?;
+    if let Some(val) = runtime.with_channel(&args[0], |channel| channel.try_receive())? {
+        return Ok(val);
+    }
+    let wait = Wait::Channels(vec![(ref_id(&args[0]), ChannelOp::Receive)]);
+    retry_later(runtime, wait, None, Continuation::ChannelReceive, args)
+}
+
+pub fn channel_close(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    args_assert_len(&args, 1, "channel-close")?;
+    let args = transform_args(runtime, args)?;
+    runtime.with_channel(&args[0], |channel| channel.closed = true)?;
+    Ok(Ponga::Null)
+}
+
+pub fn channel_closed_p(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    args_assert_len(&args, 1, "channel-closed?")?;
+    let args = transform_args(runtime, args)?;
+    Ok(bool_to_ponga(runtime.with_channel(&args[0], |channel| channel.closed)?))
+}
+
+// What the select form expands to: ($SELECT timeout timeout-thunk op channel
+// val handler ...)
+pub fn select(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    if args.len() < 2 || !(args.len() - 2).is_multiple_of(4) {
+        return Err(RuntimeErr::Other(
+            "$SELECT requires a timeout, a thunk and groups of op, channel, value and handler".to_string()
+        ));
+    }
+    let mut args = transform_args(runtime, args)?;
+    args[0] = deadline_arg(args.first(), "select")?;
+    for op in args[2..].chunks(4) {
+        runtime.with_channel(&op[1], |_| ())?;
+    }
+    select_until(runtime, args)
+}
+
+// Calls the handler of the first op that can go on, or the timeout thunk
+// once the deadline has passed
+fn select_until(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    let mut channels = vec![];
+    for op in args[2..].chunks(4) {
+        let (kind, channel, val, handler) = (&op[0], &op[1], &op[2], &op[3]);
+        if *kind == Ponga::Symbol("receive".to_string()) {
+            if let Some(val) = runtime.with_channel(channel, |channel| channel.try_receive())? {
+                runtime.tail_call(handler.clone(), vec![val])?;
+                return Ok(Ponga::Null);
+            }
+            channels.push((ref_id(channel), ChannelOp::Receive));
+        } else {
+            let val = val.clone();
+            if runtime.with_channel(channel, |channel| channel.try_send(val))?? {
+                runtime.tail_call(handler.clone(), vec![])?;
+                return Ok(Ponga::Null);
+            }
+            channels.push((ref_id(channel), ChannelOp::Send));
+        }
+    }
+    let until = deadline(&args[0]);
+    if timed_out(until) {
+        runtime.tail_call(args[1].clone(), vec![])?;
+        return Ok(Ponga::Null);
+    }
+    retry_later(runtime, Wait::Channels(channels), until, Continuation::Select, args)
+}
+
+// (make-coroutine-generator proc) calls proc with yield the first time the
+// generator is called, and each call after that carries on from the last
+// yield. Once proc returns the generator gives the eof object.
+pub fn make_coroutine_generator(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    args_assert_len(&args, 1, "make-coroutine-generator")?;
+    let mut args = transform_args(runtime, args)?;
+    runtime.make_coroutine(args.pop().unwrap())
+}
+
+pub fn yield_value(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    args_assert_len(&args, 1, "yield")?;
+    let mut args = transform_args(runtime, args)?;
+    runtime.yield_value(args.pop().unwrap());
+    Ok(Ponga::Null)
+}
+
+// (generator item ...) gives each item in turn
+pub fn generator(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    let args = transform_args(runtime, args)?;
+    let list = runtime.gc.ponga_into_gc_ref(Ponga::List(args.into_iter().collect()));
+    let func = runtime.get_identifier_obj_ref("list->generator")?.clone();
+    runtime.tail_call(func, vec![list])?;
+    Ok(Ponga::Null)
+}
+
+fn mailbox<'a>(runtime: &'a mut Runtime, name: &str) -> RunRes<&'a mut Mailbox> {
+    runtime.mailbox.as_mut().ok_or_else(|| RuntimeErr::Other(format!(
+        "{} can only be used by a runtime in a pool", name
+    )))
+}
+
+// This runtime's place in its pool, or #f if it isn't in one
+pub fn worker_id(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    args_assert_len(&args, 0, "worker-id")?;
+    Ok(match &runtime.mailbox {
+        Some(mailbox) => Ponga::Number(Number::Int(mailbox.worker as isize)),
+        None => Ponga::False,
+    })
+}
+
+pub fn worker_count(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    args_assert_len(&args, 0, "worker-count")?;
+    let count = runtime.mailbox.as_ref().map_or(0, |mailbox| mailbox.worker_count());
+    Ok(Ponga::Number(Number::Int(count as isize)))
+}
+
+// (send-message worker val) sends a copy of val to the worker's runtime
+pub fn send_message(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    args_assert_len(&args, 2, "send-message")?;
+    let args = transform_args(runtime, args)?;
+    let worker = match &args[0] {
+        Ponga::Number(Number::Int(n)) if *n >= 0 => *n as usize,
+        other => return Err(RuntimeErr::TypeError(format!(
+            "send-message requires a worker number (not {})", runtime.ponga_to_string(other)
+        ))),
+    };
+    let msg = Message::new(runtime, &args[1])?;
+    mailbox(runtime, "send-message")?.send(worker, msg)?;
+    Ok(Ponga::Null)
+}
+
+// (receive-message [timeout [timeout-val]]) waits for the next message sent
+// to this runtime
+pub fn receive_message(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    args_assert_between(&args, 0, 2, "receive-message")?;
+    let mut args = transform_args(runtime, args)?;
+    mailbox(runtime, "receive-message")?;
+    let deadline = deadline_arg(args.first(), "receive-message")?;
+    match args.get_mut(0) {
+        Some(timeout) => *timeout = deadline,
+        None => args.push(deadline),
+    }
+    receive_message_until(runtime, args)
+}
+
+fn receive_message_until(runtime: &mut Runtime, args: Vec<Ponga>) -> RunRes<Ponga> {
+    let inbox = mailbox(runtime, "receive-message")?;
+    let (msg, closed) = (inbox.take(), inbox.is_closed());
+    if let Some(msg) = msg {
+        return Ok(msg.into_ponga(runtime));
+    }
+    if closed {
+        return Err(RuntimeErr::Other(
+            "receive-message can't wait as the pool is being dropped".to_string()
+        ));
+    }
+    let until = deadline(&args[0]);
+    if timed_out(until) {
+        return args.get(1).cloned().ok_or_else(|| {
+            RuntimeErr::Other("receive-message timed out".to_string())
+        });
     }
+    retry_later(runtime, Wait::Message, until, Continuation::ReceiveMessage, args)
 }
@@ -7,15 +7,6 @@
 (define (list->vector list)
         (foldl vector-append! #() list))
 
-(define (do func init lim)
-        (let ((go (lambda (var)
-                          (if (= var lim)
-                              (func var)
-                              (begin
-                                (func var)
-                                (go (+ var 1)))))))
-        (go init)))
-
 (defmacro (while WHILE_CONDI WHILE_EXPR) 
      (let ((WHILE_GO (open-lambda ()
           (if WHILE_CONDI
@@ -40,3 +31,79 @@
         (if (condi init)
             (apply-while condi iter (iter init))
             init))
+
+(define-record-type <stream-pair>
+        (make-stream-pair stream-kar stream-kdr)
+        stream-pair-cell?
+        (stream-kar stream-kar)
+        (stream-kdr stream-kdr))
+
+(define stream-null (make-promise '()))
+
+(define (stream? s)
+        (promise? s))
+
+(define (stream-null? s)
+        (and (promise? s) (equal? (force s) '())))
+
+(define (stream-pair? s)
+        (and (promise? s) (stream-pair-cell? (force s))))
+
+(define (stream-car s)
+        (force (stream-kar (force s))))
+
+(define (stream-cdr s)
+        (stream-kdr (force s)))
+
+(define (list->generator list->generator-items)
+        (lambda ()
+          (if (null? list->generator-items)
+              (eof-object)
+              (let ((next (car list->generator-items)))
+                (set! list->generator-items (cdr list->generator-items))
+                next))))
+
+(define (gmap gmap-proc gmap-gen)
+        (lambda ()
+          (let ((next (gmap-gen)))
+            (if (eof-object? next) next (gmap-proc next)))))
+
+(define (gfilter gfilter-pred gfilter-gen)
+        (lambda ()
+          (do ((next (gfilter-gen) (gfilter-gen)))
+              ((if (eof-object? next) #t (gfilter-pred next)) next))))
+
+(define (gtake gtake-gen gtake-k)
+        (lambda ()
+          (if (> gtake-k 0)
+              (begin (set! gtake-k (- gtake-k 1)) (gtake-gen))
+              (eof-object))))
+
+(define (generator-fold kons knil gen)
+        (do ((next (gen) (gen))
+             (acc knil (kons next acc)))
+            ((eof-object? next) acc)))
+
+(define (generator->list gen)
+        (reverse (generator-fold cons '() gen)))
+
+(define (make-accumulator accumulator-kons accumulator-state accumulator-finalizer)
+        (lambda (obj)
+          (if (eof-object? obj)
+              (accumulator-finalizer accumulator-state)
+              (set! accumulator-state (accumulator-kons obj accumulator-state)))))
+
+(define (count-accumulator)
+        (make-accumulator (lambda (obj count) (+ count 1)) 0 (lambda (count) count)))
+
+(define (list-accumulator)
+        (make-accumulator cons '() reverse))
+
+(define (reverse-list-accumulator)
+        (make-accumulator cons '() (lambda (list) list)))
+
+(define (sum-accumulator)
+        (make-accumulator + 0 (lambda (sum) sum)))
+
+(define (product-accumulator)
+        (make-accumulator * 1 (lambda (product) product)))
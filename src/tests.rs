@@ -1,6 +1,8 @@
 use crate::runtime::*;
 use crate::types::*;
 use crate::number::*;
+#[allow(unused_imports)]
+use crate::stdlib::LIBRARIES;
 use std::collections::HashMap;
 #[allow(unused_imports)]
 use crate::parser::*;
@@ -9,6 +11,9 @@ use crate::parser::*;
 // (can be called in main)
 pub fn test_basic_garbage_collection_manual_binding() {
     let mut runtime = Runtime::new();
+    // The stdlib's own definitions stay alive across collections
+    runtime.collect_garbage();
+    let base = runtime.gc.ptrs.len();
     let id1 = runtime.gc.add_obj(Ponga::Null);
     let id2 = runtime.gc.add_obj(Ponga::Array(vec![
         Ponga::Number(Number::Float(1.5)),
@@ -19,17 +24,17 @@ pub fn test_basic_garbage_collection_manual_binding() {
     runtime.bind_global("bye".to_string(), Ponga::Ref(id2));
     runtime.collect_garbage();
 
-    assert!(runtime.gc.ptrs.len() == 2);
+    assert!(runtime.gc.ptrs.len() == base + 2);
 
     runtime.unbind_global("hi");
     runtime.collect_garbage();
 
-    assert!(runtime.gc.ptrs.len() == 1);
+    assert!(runtime.gc.ptrs.len() == base + 1);
 
     runtime.unbind_global("bye");
     runtime.collect_garbage();
 
-    assert!(runtime.gc.ptrs.len() == 0);
+    assert!(runtime.gc.ptrs.len() == base);
 }
 
 #[test]
@@ -564,3 +569,1626 @@ curr
     let res = prog_res.pop().unwrap().unwrap();
     assert!(res == Ponga::Number(Number::Int(6857)));
 }
+
+#[test]
+pub fn test_weak_boxes_and_tables() {
+    let parsed = pongascript_parser("
+(define strong #(1 2 3))
+(define weak-strong (make-weak-box strong))
+(define weak-dead (make-weak-box #(4 5 6)))
+(define table (make-weak-hash-table))
+(weak-hash-table-set! table strong 1)
+(weak-hash-table-set! table #(7) 2)
+(let ((key #(8)))
+     (weak-hash-table-set! table key (cons key '())))
+(weak-hash-table-count table)
+    ")
+    .unwrap();
+    let mut runtime = Runtime::new();
+    let evald = parsed
+        .1
+        .into_iter()
+        .map(|x| runtime.eval(x))
+        .collect::<Vec<RunRes<Ponga>>>();
+    assert!(evald[7] == Ok(Ponga::Number(Number::Int(3))));
+
+    runtime.collect_garbage();
+
+    let parsed = pongascript_parser("
+(vector-length (weak-box-value weak-strong))
+(weak-box-value weak-dead 'gone)
+(weak-hash-table-count table)
+(weak-hash-table-ref table strong)
+    ")
+    .unwrap();
+    let evald = parsed
+        .1
+        .into_iter()
+        .map(|x| runtime.eval(x))
+        .collect::<Vec<RunRes<Ponga>>>();
+    assert!(evald[0] == Ok(Ponga::Number(Number::Int(3))));
+    assert!(evald[1] == Ok(Ponga::Symbol("gone".to_string())));
+    // The entry whose value refers back to its key must not keep itself alive
+    assert!(evald[2] == Ok(Ponga::Number(Number::Int(1))));
+    assert!(evald[3] == Ok(Ponga::Number(Number::Int(1))));
+}
+
+#[test]
+pub fn test_finalizers() {
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    let parsed = pongascript_parser("
+(define finalized 0)
+(define obj #(1 2))
+(set-finalizer! obj (lambda (x) (set! finalized (+ finalized (vector-length x)))))
+(define native #(1 2 3))
+    ")
+    .unwrap();
+    let mut runtime = Runtime::new();
+    for x in parsed.1.into_iter() {
+        runtime.eval(x).unwrap();
+    }
+
+    let swept = Arc::new(AtomicUsize::new(0));
+    let swept_clone = swept.clone();
+    let native = runtime.get_identifier_obj_ref("native").unwrap().clone();
+    runtime.set_native_finalizer(&native, Box::new(move |_| {
+        swept_clone.fetch_add(1, Ordering::SeqCst);
+    })).unwrap();
+
+    runtime.collect_garbage();
+    assert!(runtime.get_identifier_obj_ref("finalized") == Ok(&Ponga::Number(Number::Int(0))));
+    assert!(swept.load(Ordering::SeqCst) == 0);
+
+    runtime.unbind_global("obj");
+    runtime.unbind_global("native");
+    runtime.collect_garbage();
+    assert!(runtime.get_identifier_obj_ref("finalized") == Ok(&Ponga::Number(Number::Int(2))));
+    assert!(swept.load(Ordering::SeqCst) == 1);
+
+    // Finalizers only run once
+    runtime.collect_garbage();
+    assert!(runtime.get_identifier_obj_ref("finalized") == Ok(&Ponga::Number(Number::Int(2))));
+    assert!(runtime.take_finalizer_errors().is_empty());
+
+    // An error in one finalizer is kept for later, and the rest still run
+    let parsed = pongascript_parser("
+(set-finalizer! #(1) (lambda (x) (car x)))
+(set-finalizer! #(1 2 3) (lambda (x) (set! finalized (+ finalized (vector-length x)))))
+    ")
+    .unwrap();
+    for x in parsed.1.into_iter() {
+        runtime.eval(x).unwrap();
+    }
+    runtime.collect_garbage();
+    assert!(runtime.get_identifier_obj_ref("finalized") == Ok(&Ponga::Number(Number::Int(5))));
+    assert!(runtime.take_finalizer_errors().len() == 1);
+    assert!(runtime.take_finalizer_errors().is_empty());
+}
+
+#[test]
+pub fn test_heap_snapshot() {
+    let parsed = pongascript_parser("
+(define big #(#(1 2 3) #(4 5 6)))
+(define shared #(7))
+(define other shared)
+    ")
+    .unwrap();
+    let mut runtime = Runtime::new();
+    for x in parsed.1.into_iter() {
+        runtime.eval(x).unwrap();
+    }
+    runtime.collect_garbage();
+    let snapshot = runtime.heap_snapshot();
+    assert!(snapshot.objects.len() == runtime.gc.ptrs.len());
+    assert!(snapshot.objects.iter().all(|obj| obj.reachable));
+
+    let root = |name: &str| snapshot.roots.iter().find(|r| r.name == name).unwrap();
+    let big = root("big");
+    let big_obj = snapshot.objects.iter().find(|obj| obj.id == big.ids[0]).unwrap();
+    assert!(big_obj.kind == "vector");
+    assert!(big_obj.edges.len() == 2);
+    let big_size: usize = snapshot.objects
+                                  .iter()
+                                  .filter(|obj| obj.id == big_obj.id
+                                                || big_obj.edges.contains(&obj.id))
+                                  .map(|obj| obj.size)
+                                  .sum();
+    assert!(big.retained == big_size);
+    // Neither binding alone keeps the shared vector alive
+    assert!(root("shared").retained == 0);
+    assert!(root("other").retained == 0);
+
+    let json = snapshot.to_json();
+    assert!(json.contains(&format!("\"id\": {}, \"type\": \"vector\"", big_obj.id)));
+    assert!(snapshot.to_dot().starts_with("digraph heap {"));
+}
+
+#[test]
+pub fn test_hash_tables() {
+    let parsed = pongascript_parser("
+(define table (make-hash-table))
+(hash-table-set! table 1 'one)
+(hash-table-set! table 'two 2)
+(hash-table-set! table (cons 3 '()) \"three\")
+(hash-table-ref table (cons 3 '()))
+(hash-table-ref table 'missing (lambda () 'default))
+(hash-table-ref/default table 1 #f)
+(hash-table-update!/default table 'two (lambda (x) (+ x 1)) 0)
+(hash-table-ref table 'two (lambda () 0) (lambda (x) (* x 10)))
+(hash-table-delete! table 1)
+(hash-table-contains? table 1)
+(hash-table-fold table (lambda (k v acc) (+ acc 1)) 0)
+(define strings (make-hash-table string=?))
+(hash-table-set! strings \"a\" 1)
+(hash-table-ref/default strings \"a\" 0)
+(hash-table-set! strings 'a 1)
+(= (hash (cons 1 '())) (hash (cons 1 '())))
+(< (hash 'x 10) 10)
+(define nested (make-hash-table equal?))
+(hash-table-set! nested nested 'self)
+(hash-table-ref nested nested)
+(hash-table-set! nested (list 1 (vector 2 \"b\")) 'deep)
+(hash-table-ref/default nested (list 1 (vector 2 (string #\\b))) #f)
+(equal? (make-hash-table) (make-hash-table))
+    ")
+    .unwrap();
+    let mut runtime = Runtime::new();
+    let evald = parsed
+        .1
+        .into_iter()
+        .map(|x| runtime.eval(x))
+        .collect::<Vec<RunRes<Ponga>>>();
+    assert!(evald[4] == Ok(Ponga::String("three".to_string())));
+    assert!(evald[5] == Ok(Ponga::Symbol("default".to_string())));
+    assert!(evald[6] == Ok(Ponga::Symbol("one".to_string())));
+    assert!(evald[8] == Ok(Ponga::Number(Number::Int(30))));
+    assert!(evald[10] == Ok(Ponga::False));
+    assert!(evald[11] == Ok(Ponga::Number(Number::Int(2))));
+    assert!(evald[14] == Ok(Ponga::Number(Number::Int(1))));
+    // string=? tables only accept string keys
+    assert!(evald[15].is_err());
+    assert!(evald[16] == Ok(Ponga::True));
+    assert!(evald[17] == Ok(Ponga::True));
+    // A key may hold the table it is put in
+    assert!(evald[20] == Ok(Ponga::Symbol("self".to_string())));
+    // Keys are compared by structure, whichever kind of string they hold
+    assert!(evald[22] == Ok(Ponga::Symbol("deep".to_string())));
+    assert!(evald[23] == Ok(Ponga::False));
+}
+
+#[test]
+pub fn test_vector_library() {
+    let parsed = pongascript_parser("
+(define v (make-vector 3 0))
+(define alias v)
+(vector-set! alias 1 'x)
+(vector-ref v 1)
+(vector-fill! v 7 2)
+v
+(define w (vector 1 2 3 4 5))
+(vector-copy! w 1 w 0 3)
+w
+(subvector w 1 3)
+(vector-copy w 3)
+(vector-map + #(1 2 3) #(10 20))
+(define sum 0)
+(vector-for-each (lambda (x) (set! sum (+ sum x))) #(1 2 3))
+sum
+(vector->string (string->vector \"hello\" 1) 0 2)
+(vector-set! v 3 0)
+    ")
+    .unwrap();
+    let mut runtime = Runtime::new();
+    let evald = parsed
+        .1
+        .into_iter()
+        .map(|x| runtime.eval(x))
+        .collect::<Vec<RunRes<Ponga>>>();
+    let show = |runtime: &Runtime, res: &RunRes<Ponga>| runtime.ponga_to_string(res.as_ref().unwrap());
+    assert!(evald[3] == Ok(Ponga::Symbol("x".to_string())));
+    assert!(show(&runtime, &evald[5]) == "#(0 'x 7)");
+    assert!(show(&runtime, &evald[8]) == "#(1 1 2 3 5)");
+    assert!(show(&runtime, &evald[9]) == "#(1 2)");
+    assert!(show(&runtime, &evald[10]) == "#(3 5)");
+    assert!(show(&runtime, &evald[11]) == "#(11 22)");
+    assert!(evald[14] == Ok(Ponga::Number(Number::Int(6))));
+    assert!(evald[15] == Ok(Ponga::String("el".to_string())));
+    assert!(evald[16].is_err());
+}
+
+#[test]
+pub fn test_string_library() {
+    let parsed = pongascript_parser("
+(define s (string-copy \"héllo\"))
+(define alias s)
+(string-set! alias 0 #\\j)
+(substring s 0 5)
+(string-length s)
+(string-ref s 1)
+(substring s 1 3)
+(string-append \"a\" s \"b\")
+(string-fill! s #\\z 3)
+s
+(string-set! \"literal\" 0 #\\x)
+(string-upcase \"straße\")
+(string<? \"abc\" \"abd\" \"b\")
+(string-ci=? \"Hello\" s \"HELLO\")
+(string-ci=? \"JÉLZZ\" s)
+(string-index \"hello\" #\\l)
+(string-index \"hello\" (lambda (c) (eq? c #\\z)))
+(string-contains \"hello world\" \"o w\")
+(string-join (string-split \"a,b,,c\" #\\,) \"-\")
+(string-join (string-split \"  a  b \") \"/\" 'suffix)
+(string-pad \"42\" 5 #\\0)
+(string-pad \"12345\" 3)
+(string-pad-right \"ab\" 4)
+(equal? s \"jélzz\")
+(make-string 3 #\\x)
+(string-foldcase \"Straße ΣΑΣ\")
+(string-ci=? \"STRASSE\" \"straße\")
+    ")
+    .unwrap();
+    let mut runtime = Runtime::new();
+    let evald = parsed
+        .1
+        .into_iter()
+        .map(|x| runtime.eval(x))
+        .collect::<Vec<RunRes<Ponga>>>();
+    let show = |runtime: &Runtime, res: &RunRes<Ponga>| runtime.ponga_to_string(res.as_ref().unwrap());
+    let string = |s: &str| Ok(Ponga::String(s.to_string()));
+    assert!(evald[3] == string("jéllo"));
+    assert!(evald[4] == Ok(Ponga::Number(Number::Int(5))));
+    assert!(evald[5] == Ok(Ponga::Char('é')));
+    assert!(evald[6] == string("él"));
+    assert!(evald[7] == string("ajéllob"));
+    assert!(show(&runtime, &evald[9]) == "\"jélzz\"");
+    // Literals are immutable
+    assert!(evald[10].is_err());
+    assert!(evald[11] == string("STRASSE"));
+    assert!(evald[12] == Ok(Ponga::True));
+    assert!(evald[13] == Ok(Ponga::False));
+    assert!(evald[14] == Ok(Ponga::True));
+    assert!(evald[15] == Ok(Ponga::Number(Number::Int(2))));
+    assert!(evald[16] == Ok(Ponga::False));
+    assert!(evald[17] == Ok(Ponga::Number(Number::Int(4))));
+    assert!(evald[18] == string("a-b--c"));
+    assert!(evald[19] == string("a/b/"));
+    assert!(evald[20] == string("00042"));
+    assert!(evald[21] == string("345"));
+    assert!(evald[22] == string("ab  "));
+    assert!(evald[23] == Ok(Ponga::True));
+    assert!(show(&runtime, &evald[24]) == "\"xxx\"");
+    // Folding is full, so ß becomes ss and final sigma matches sigma
+    assert!(evald[25] == string("strasse σασ"));
+    assert!(evald[26] == Ok(Ponga::True));
+}
+
+#[test]
+pub fn test_char_procedures() {
+    let parsed = pongascript_parser("
+(char->integer #\\λ)
+(integer->char 955)
+(char-upcase #\\ä)
+(char-upcase #\\ß)
+(char-alphabetic? #\\λ)
+(char-numeric? #\\٣)
+(char-numeric? #\\½)
+(digit-value #\\٣)
+(digit-value #\\a)
+(char-whitespace? (integer->char 12288))
+(char-upper-case? #\\Λ)
+(char<? #\\a #\\b #\\c)
+(char<? #\\a #\\c #\\b)
+(char-ci=? #\\Λ #\\λ)
+(integer->char 55296)
+    ")
+    .unwrap();
+    let mut runtime = Runtime::new();
+    let evald = parsed
+        .1
+        .into_iter()
+        .map(|x| runtime.eval(x))
+        .collect::<Vec<RunRes<Ponga>>>();
+    assert!(evald[0] == Ok(Ponga::Number(Number::Int(955))));
+    assert!(evald[1] == Ok(Ponga::Char('λ')));
+    assert!(evald[2] == Ok(Ponga::Char('Ä')));
+    // ß upcases to two chars, so it is left alone
+    assert!(evald[3] == Ok(Ponga::Char('ß')));
+    assert!(evald[4] == Ok(Ponga::True));
+    assert!(evald[5] == Ok(Ponga::True));
+    assert!(evald[6] == Ok(Ponga::False));
+    assert!(evald[7] == Ok(Ponga::Number(Number::Int(3))));
+    assert!(evald[8] == Ok(Ponga::False));
+    assert!(evald[9] == Ok(Ponga::True));
+    assert!(evald[10] == Ok(Ponga::True));
+    assert!(evald[11] == Ok(Ponga::True));
+    assert!(evald[12] == Ok(Ponga::False));
+    assert!(evald[13] == Ok(Ponga::True));
+    // Surrogates are not chars
+    assert!(evald[14].is_err());
+}
+
+#[test]
+pub fn test_list_library() {
+    let parsed = pongascript_parser("
+(define xs (iota 6 1))
+xs
+(filter even? xs)
+(reduce + 0 xs)
+(append (list 1 2) '() (list 3))
+(list-ref xs 2)
+(list-tail xs 4)
+(take xs 2)
+(drop xs 5)
+(map + (list 1 2 3) (list 10 20))
+(define sum 0)
+(for-each (lambda (x y) (set! sum (+ sum (* x y)))) (list 1 2) (list 3 4))
+sum
+(assoc \"b\" (list (cons \"a\" 1) (cons \"b\" 2)))
+(assq 'c (list (cons 'a 1)))
+(member (list 2) (list (list 1) (list 2) (list 3)))
+(memq 'x (list 'a 'b))
+(any (lambda (x) (and (> x 4) x)) xs)
+(every (lambda (x) (> x 0)) xs)
+(find (lambda (x) (> x 3)) xs)
+(partition even? xs)
+(delete 3 xs)
+(delete-duplicates (list 1 2 1 3 2))
+(last xs)
+(append-map (lambda (x) (list x x)) (list 1 2))
+(fold cons '() (list 1 2 3))
+(fold-right cons '() (list 1 2 3))
+(count even? xs)
+(take-while (lambda (x) (< x 3)) xs)
+(delete-duplicates (list 1 2 4 5) (lambda (a b) (= (modulo a 2) (modulo b 2))))
+    ")
+    .unwrap();
+    let mut runtime = Runtime::new();
+    let evald = parsed
+        .1
+        .into_iter()
+        .map(|x| runtime.eval(x))
+        .collect::<Vec<RunRes<Ponga>>>();
+    let shown = evald.iter()
+                     .map(|res| runtime.ponga_to_string(res.as_ref().unwrap()))
+                     .collect::<Vec<String>>();
+    assert!(shown[1] == "'(1 2 3 4 5 6)");
+    assert!(shown[2] == "'(2 4 6)");
+    assert!(shown[3] == "21");
+    assert!(shown[4] == "'(1 2 3)");
+    assert!(shown[5] == "3");
+    assert!(shown[6] == "'(5 6)");
+    assert!(shown[7] == "'(1 2)");
+    assert!(shown[8] == "'(6)");
+    assert!(shown[9] == "'(11 22)");
+    assert!(shown[12] == "11");
+    assert!(shown[13] == "'(\"b\" 2)");
+    assert!(shown[14] == "#f");
+    assert!(shown[15] == "'('(2) '(3))");
+    assert!(shown[16] == "#f");
+    assert!(shown[17] == "5");
+    assert!(shown[18] == "#t");
+    assert!(shown[19] == "4");
+    assert!(shown[20] == "'(2 4 6) '(1 3 5)");
+    assert!(shown[21] == "'(1 2 4 5 6)");
+    assert!(shown[22] == "'(1 2 3)");
+    assert!(shown[23] == "6");
+    assert!(shown[24] == "'(1 1 2 2)");
+    assert!(shown[25] == "'(3 2 1)");
+    assert!(shown[26] == "'(1 2 3)");
+    assert!(shown[27] == "3");
+    assert!(shown[28] == "'(1 2)");
+    assert!(shown[29] == "'(1 2)");
+}
+
+#[test]
+pub fn test_sorting() {
+    let parsed = pongascript_parser("
+(sort (list 3 1 2) <)
+(define v (vector 5 3 4 1))
+(define alias v)
+(sort! v <)
+alias
+(list-sort (lambda (a b) (< (car a) (car b)))
+           (list (list 2 'a) (list 1 'b) (list 2 'c) (list 1 'd)))
+(vector-sort > #(1 3 2))
+(define w (vector 9 4 3 2 1))
+(vector-sort! w < 1 4)
+w
+(sorted? (list 1 2 2 3) <)
+(sorted? #(2 1) <)
+(merge (list 1 3 5) (list 2 4) <)
+(sort (list 1 'a) <)
+    ")
+    .unwrap();
+    let mut runtime = Runtime::new();
+    let evald = parsed
+        .1
+        .into_iter()
+        .map(|x| runtime.eval(x))
+        .collect::<Vec<RunRes<Ponga>>>();
+    let show = |runtime: &Runtime, res: &RunRes<Ponga>| runtime.ponga_to_string(res.as_ref().unwrap());
+    assert!(show(&runtime, &evald[0]) == "'(1 2 3)");
+    assert!(show(&runtime, &evald[4]) == "#(1 3 4 5)");
+    // Stable, so equal keys keep their order
+    assert!(show(&runtime, &evald[5]) == "'('(1 'b) '(1 'd) '(2 'a) '(2 'c))");
+    assert!(show(&runtime, &evald[6]) == "#(3 2 1)");
+    assert!(show(&runtime, &evald[9]) == "#(9 2 3 4 1)");
+    assert!(evald[10] == Ok(Ponga::True));
+    assert!(evald[11] == Ok(Ponga::False));
+    assert!(show(&runtime, &evald[12]) == "'(1 2 3 4 5)");
+    // Errors from the comparison procedure are passed through
+    assert!(evald[13].is_err());
+}
+
+#[test]
+pub fn test_apply_and_call() {
+    let parsed = pongascript_parser("
+(apply + 1 (list 2))
+(apply list 1 2 (list 3 4))
+(define (count-down n) (if (= n 0) 'done (apply count-down (list (- n 1)))))
+(count-down 20000)
+(define total 0)
+(for-each (lambda (x) (set! total (+ total x))) (iota 150000))
+total
+(define chars '())
+(string-for-each (lambda (a b) (set! chars (cons (list a b) chars))) \"abc\" \"xy\")
+chars
+(define (id x) x)
+(apply car (list 1))
+    ")
+    .unwrap();
+    let mut runtime = Runtime::new();
+    let evald = parsed
+        .1
+        .into_iter()
+        .map(|x| {
+            // Pinned so that a collection during a later form can't free it
+            let res = runtime.eval(x);
+            if let Ok(val) = &res {
+                runtime.pin(val);
+            }
+            res
+        })
+        .collect::<Vec<RunRes<Ponga>>>();
+    let show = |runtime: &Runtime, res: &RunRes<Ponga>| runtime.ponga_to_string(res.as_ref().unwrap());
+    assert!(evald[0] == Ok(Ponga::Number(Number::Int(3))));
+    assert!(show(&runtime, &evald[1]) == "'(1 2 3 4)");
+    // apply replaces its own call, so this doesn't nest the evaluator
+    assert!(evald[3] == Ok(Ponga::Symbol("done".to_string())));
+    assert!(evald[6] == Ok(Ponga::Number(Number::Int(11249925000))));
+    assert!(show(&runtime, &evald[9]) == "'('(#\\b #\\y) '(#\\a #\\x))");
+    assert!(evald[11].is_err());
+
+    // Arguments are passed as they are rather than evaluated again
+    let id = runtime.get_identifier_obj_ref("id").unwrap().clone();
+    let unbound = Ponga::Identifier("not-bound".to_string());
+    assert!(runtime.call(id, vec![unbound.clone()]) == Ok(unbound));
+    let plus = runtime.get_identifier_obj_ref("+").unwrap().clone();
+    let args = vec![Ponga::Number(Number::Int(1)), Ponga::Number(Number::Int(2))];
+    assert!(runtime.call(plus, args) == Ok(Ponga::Number(Number::Int(3))));
+    assert!(runtime.call(Ponga::Number(Number::Int(1)), vec![]).is_err());
+}
+
+#[test]
+pub fn test_higher_order_in_loop() {
+    let parsed = pongascript_parser("
+(define (deep n) (if (= n 0) 0 (car (map (lambda (x) (+ 1 (deep (- x 1)))) (list n)))))
+(deep 2000)
+(sort (list (list 1 'a) (list 0 'b) (list 1 'c) (list 0 'd)) (lambda (x y) (< (car x) (car y))))
+(fold (lambda (a b acc) (cons a (cons b acc))) '() (list 1 2) (list 3 4))
+(fold-right list 'end (list 1 2))
+(filter (lambda (x) (> x 6)) (iota 10))
+(define ht (make-hash-table))
+(hash-table-update! ht 'a (lambda (x) (+ x 1)) (lambda () 0))
+(hash-table-ref ht 'a)
+(define g (make-coroutine-generator (lambda (yield) (map (lambda (x) (yield x) (* x 10)) (list 1 2)))))
+(generator->list g)
+(define p (make-parameter 1))
+(parameterize ((p 2)) (map (lambda (x) (car x)) (list 1)))
+(p)
+(sort (list 3 1 2) (lambda (x y) (car x)))
+    ")
+    .unwrap();
+    let mut runtime = Runtime::new();
+    let evald = parsed
+        .1
+        .into_iter()
+        .map(|x| runtime.eval(x))
+        .collect::<Vec<RunRes<Ponga>>>();
+    let show = |runtime: &Runtime, res: &RunRes<Ponga>| runtime.ponga_to_string(res.as_ref().unwrap());
+    // The procedures run in the caller's loop, so deep recursion through
+    // them doesn't overflow the native stack
+    assert!(evald[1] == Ok(Ponga::Number(Number::Int(2000))));
+    // Sorting is stable
+    assert_eq!(show(&runtime, &evald[2]), "'('(0 'b) '(0 'd) '(1 'a) '(1 'c))");
+    assert_eq!(show(&runtime, &evald[3]), "'(2 4 1 3)");
+    assert_eq!(show(&runtime, &evald[4]), "'(1 '(2 'end))");
+    assert_eq!(show(&runtime, &evald[5]), "'(7 8 9)");
+    assert!(evald[8] == Ok(Ponga::Number(Number::Int(1))));
+    // A generator can yield from inside map
+    assert_eq!(show(&runtime, &evald[10]), "'(1 2)");
+    // An error inside map still undoes the parameterize around it
+    assert!(evald[12].is_err());
+    assert!(evald[13] == Ok(Ponga::Number(Number::Int(1))));
+    assert!(evald[14].is_err());
+}
+
+#[test]
+pub fn test_binding_forms() {
+    let parsed = pongascript_parser("
+(let loop ((i 0) (acc '())) (if (= i 3) acc (loop (+ i 1) (cons i acc))))
+(letrec ((ev? (lambda (n) (if (= n 0) #t (od? (- n 1)))))
+         (od? (lambda (n) (if (= n 0) #f (ev? (- n 1))))))
+  (ev? 10))
+(letrec* ((a 1) (b (+ a 1))) b)
+(let* ((x 1) (y (+ x 1))) (* x y))
+(define (sum-squares a b)
+  (define (square x) (* x x))
+  (define sa (square a))
+  (+ sa (square b)))
+(sum-squares 3 4)
+(let ((x 2))
+  (begin (define y 5))
+  (* x y))
+y
+square
+(let-values (((a b) (list 1 2))) a)
+(let-values (((a . rest) 7) (all 8)) (list a rest all))
+    ")
+    .unwrap();
+    let mut runtime = Runtime::new();
+    let evald = parsed
+        .1
+        .into_iter()
+        .map(|x| runtime.eval(x))
+        .collect::<Vec<RunRes<Ponga>>>();
+    let show = |runtime: &Runtime, res: &RunRes<Ponga>| runtime.ponga_to_string(res.as_ref().unwrap());
+    assert!(show(&runtime, &evald[0]) == "'(2 1 0)");
+    assert!(evald[1] == Ok(Ponga::True));
+    assert!(evald[2] == Ok(Ponga::Number(Number::Int(2))));
+    assert!(evald[3] == Ok(Ponga::Number(Number::Int(2))));
+    assert!(evald[5] == Ok(Ponga::Number(Number::Int(25))));
+    assert!(evald[6] == Ok(Ponga::Number(Number::Int(10))));
+    // Internal defines are scoped to their body
+    assert!(evald[7].is_err());
+    assert!(evald[8].is_err());
+    // A list is one value rather than several
+    assert!(evald[9].is_err());
+    assert!(show(&runtime, &evald[10]) == "'(7 '() '(8))");
+}
+
+#[test]
+pub fn test_let_scoping() {
+    let parsed = pongascript_parser("
+(define x 1)
+(let ((x 2) (y x)) y)
+(let* ((x 2) (y x)) y)
+(let ((a 1) (b 2)) (let ((a b) (b a)) (- a b)))
+(let-values (((a) (values 5)) ((b) (values a))) b)
+(let*-values (((a) (values 5)) ((b) (values a))) b)
+(let loop ((i 0)) (if (< i 5000) (loop (+ i 1)) i))
+(define (count-up n acc) (if (= n 0) acc (count-up (- n 1) (+ acc 1))))
+(count-up 5000 0)
+(define (f n acc) (if (= n 0) acc (let ((m (- n 1))) (f m (+ acc 1)))))
+(f 5000 0)
+(define (ev? n) (if (= n 0) #t (od? (- n 1))))
+(define (od? n) (if (= n 0) #f (ev? (- n 1))))
+(ev? 5001)
+    ")
+    .unwrap();
+    let mut runtime = Runtime::new();
+    // Low enough that a loop growing the stack would run out
+    runtime.max_stack_size = 1000;
+    let evald = parsed
+        .1
+        .into_iter()
+        .map(|x| runtime.eval(x))
+        .collect::<Vec<RunRes<Ponga>>>();
+    // let evaluates every init before binding any of them
+    assert!(evald[1] == Ok(Ponga::Number(Number::Int(1))));
+    assert!(evald[2] == Ok(Ponga::Number(Number::Int(2))));
+    assert!(evald[3] == Ok(Ponga::Number(Number::Int(1))));
+    assert!(evald[4].is_err());
+    assert!(evald[5] == Ok(Ponga::Number(Number::Int(5))));
+    // Loops through tail calls don't grow the stack
+    assert!(evald[6] == Ok(Ponga::Number(Number::Int(5000))));
+    assert!(evald[8] == Ok(Ponga::Number(Number::Int(5000))));
+    // Nor do ones that go through a let body, or between procedures
+    assert!(evald[10] == Ok(Ponga::Number(Number::Int(5000))));
+    assert!(evald[13] == Ok(Ponga::False));
+}
+
+#[test]
+pub fn test_conditional_forms() {
+    let parsed = pongascript_parser("
+(define (classify n) (cond ((< n 0) 'negative) ((= n 0) 'zero) (else 'positive)))
+(list (classify -1) (classify 0) (classify 5))
+(cond ((assv 2 (list (list 1 'one) (list 2 'two))) => (lambda (p) (car (cdr p)))) (else 'none))
+(cond (#f 1))
+(cond ((+ 1 2)))
+(define (kind x) (case x ((1 2 3) 'small) ((a b) 'letter) ((#\\x) 'char) (else => (lambda (v) v))))
+(list (kind 2) (kind 'b) (kind #\\x) (kind 9))
+(when (= 1 1) 'a 'b)
+(unless (= 1 1) 'a)
+(do ((vec (make-vector 5)) (i 0 (+ i 1))) ((= i 5) vec) (vector-set! vec i i))
+(let ((x '(1 3 5 7 9))) (do ((x x (cdr x)) (sum 0 (+ sum (car x)))) ((null? x) sum)))
+(define n 0)
+(do ((i 0 (+ i 1))) ((= i 120000)) (set! n (+ n 1)))
+n
+    ")
+    .unwrap();
+    let mut runtime = Runtime::new();
+    let evald = parsed
+        .1
+        .into_iter()
+        .map(|x| {
+            // Pinned so that a collection during a later form can't free it
+            let res = runtime.eval(x);
+            if let Ok(val) = &res {
+                runtime.pin(val);
+            }
+            res
+        })
+        .collect::<Vec<RunRes<Ponga>>>();
+    let show = |runtime: &Runtime, res: &RunRes<Ponga>| runtime.ponga_to_string(res.as_ref().unwrap());
+    assert!(show(&runtime, &evald[1]) == "'('negative 'zero 'positive)");
+    assert!(evald[2] == Ok(Ponga::Symbol("two".to_string())));
+    assert!(evald[3] == Ok(Ponga::Null));
+    assert!(evald[4] == Ok(Ponga::Number(Number::Int(3))));
+    assert!(show(&runtime, &evald[6]) == "'('small 'letter 'char 9)");
+    assert!(evald[7] == Ok(Ponga::Symbol("b".to_string())));
+    assert!(evald[8] == Ok(Ponga::Null));
+    assert!(show(&runtime, &evald[9]) == "#(0 1 2 3 4)");
+    assert!(evald[10] == Ok(Ponga::Number(Number::Int(25))));
+    // Iterating doesn't grow the instruction stack
+    assert!(evald[12].is_ok());
+    assert!(evald[13] == Ok(Ponga::Number(Number::Int(120000))));
+}
+
+#[test]
+pub fn test_records() {
+    let parsed = pongascript_parser("
+(define-record-type <point> (make-point x y) point? (x point-x set-point-x!) (y point-y))
+(define p (make-point 1 2))
+(list (point? p) (point? 5) (record? p) (point-x p) (point-y p))
+(set-point-x! p 10)
+(point-x p)
+p
+(define-record-type <other> (make-other x y) other? (x other-x) (y other-y))
+(other? p)
+(other-x p)
+(make-point 1)
+(define-record-type node (make-node value) node? (value node-value) (next node-next set-node-next!))
+(define n (make-node 'a))
+(node-next n)
+    ")
+    .unwrap();
+    let mut runtime = Runtime::new();
+    let evald = parsed
+        .1
+        .into_iter()
+        .map(|x| runtime.eval(x))
+        .collect::<Vec<RunRes<Ponga>>>();
+    let show = |runtime: &Runtime, res: &RunRes<Ponga>| runtime.ponga_to_string(res.as_ref().unwrap());
+    assert!(show(&runtime, &evald[2]) == "'(#t #f #t 1 2)");
+    assert!(evald[4] == Ok(Ponga::Number(Number::Int(10))));
+    assert!(show(&runtime, &evald[5]) == "#<record point 10 2>");
+    // Types with the same fields are still distinct
+    assert!(evald[7] == Ok(Ponga::False));
+    assert!(evald[8].is_err());
+    assert!(evald[9].is_err());
+    // Fields the constructor doesn't set start as #f
+    assert!(evald[12] == Ok(Ponga::False));
+
+    // Records survive collection through their references, and are usable
+    // from Rust
+    runtime.collect_garbage();
+    let p = runtime.get_identifier_obj_ref("p").unwrap().clone();
+    assert!(runtime.record_field(&p, "x") == Ok(Ponga::Number(Number::Int(10))));
+    assert!(runtime.record_field(&p, "z").is_err());
+    let rtype = runtime.record_type_of(&p).unwrap();
+    assert!(runtime.record_type(&rtype).unwrap().1.name == "point");
+    let pair_type = runtime.make_record_type("pair", &["left", "right"]);
+    let fields = vec![Ponga::Number(Number::Int(1)), Ponga::True];
+    let pair = runtime.make_record(&pair_type, fields).unwrap();
+    assert!(runtime.ponga_to_string(&pair) == "#<record pair 1 #t>");
+    assert!(runtime.record_field(&pair, "right") == Ok(Ponga::True));
+    assert!(runtime.make_record(&pair_type, vec![]).is_err());
+}
+
+#[test]
+pub fn test_promises_and_streams() {
+    let parsed = pongascript_parser("
+(define count 0)
+(define p (delay (begin (set! count (+ count 1)) count)))
+(list (promise? p) (promise? 1) (force p) (force p))
+count
+(force (make-promise 5))
+(force 7)
+(define (countdown n) (delay-force (if (= n 0) (make-promise 'done) (countdown (- n 1)))))
+(force (countdown 20000))
+(force (delay-force 1))
+(define (ints n) (stream-cons n (ints (+ n 1))))
+(define s (ints 0))
+(stream-car (stream-cdr (stream-cdr s)))
+(stream->list (stream-take 5 s))
+(stream->list (list->stream (list 1 2 3)))
+(stream->list s 3)
+(list (stream-null? stream-null) (stream-pair? s) (stream? 1))
+    ")
+    .unwrap();
+    let mut runtime = Runtime::new();
+    let evald = parsed
+        .1
+        .into_iter()
+        .map(|x| {
+            // Pinned so that a collection during a later form can't free it
+            let res = runtime.eval(x);
+            if let Ok(val) = &res {
+                runtime.pin(val);
+            }
+            res
+        })
+        .collect::<Vec<RunRes<Ponga>>>();
+    let show = |runtime: &Runtime, res: &RunRes<Ponga>| runtime.ponga_to_string(res.as_ref().unwrap());
+    // Forcing twice runs the body once
+    assert!(show(&runtime, &evald[2]) == "'(#t #f 1 1)");
+    assert!(evald[3] == Ok(Ponga::Number(Number::Int(1))));
+    assert!(evald[4] == Ok(Ponga::Number(Number::Int(5))));
+    assert!(evald[5] == Ok(Ponga::Number(Number::Int(7))));
+    // Chains of delay-force are forced iteratively
+    assert!(evald[7] == Ok(Ponga::Symbol("done".to_string())));
+    assert!(evald[8].is_err());
+    assert!(evald[11] == Ok(Ponga::Number(Number::Int(2))));
+    assert!(show(&runtime, &evald[12]) == "'(0 1 2 3 4)");
+    assert!(show(&runtime, &evald[13]) == "'(1 2 3)");
+    assert!(show(&runtime, &evald[14]) == "'(0 1 2)");
+    assert!(show(&runtime, &evald[15]) == "'(#t #t #f)");
+
+    // The memoised value survives collection
+    runtime.collect_garbage();
+    let p = runtime.get_identifier_obj_ref("p").unwrap().clone();
+    assert!(runtime.force(p) == Ok(Ponga::Number(Number::Int(1))));
+}
+
+#[test]
+pub fn test_multiple_values() {
+    let parsed = pongascript_parser("
+(call-with-values (lambda () (values 1 2)) +)
+(call-with-values (lambda () (values 1 2 3)) list)
+(call-with-values (lambda () 5) (lambda (x) (* x x)))
+(call-with-values (lambda () (values)) list)
+(receive (q . rest) (values 1 2 3) (list q rest))
+(receive all (values 1 2) all)
+(let-values (((a b) (values 1 2)) ((c) (values 3))) (list a b c))
+(define-values (x y) (values 10 20))
+(+ x y)
+(receive (evens odds) (partition even? (list 1 2 3 4)) evens)
+(let-values (((a b) (values 1 2 3))) a)
+(values 1)
+(values 1 2)
+    ")
+    .unwrap();
+    let mut runtime = Runtime::new();
+    let evald = parsed
+        .1
+        .into_iter()
+        .map(|x| runtime.eval(x))
+        .collect::<Vec<RunRes<Ponga>>>();
+    let show = |runtime: &Runtime, res: &RunRes<Ponga>| runtime.ponga_to_string(res.as_ref().unwrap());
+    assert!(evald[0] == Ok(Ponga::Number(Number::Int(3))));
+    assert!(show(&runtime, &evald[1]) == "'(1 2 3)");
+    assert!(evald[2] == Ok(Ponga::Number(Number::Int(25))));
+    assert!(show(&runtime, &evald[3]) == "'()");
+    assert!(show(&runtime, &evald[4]) == "'(1 '(2 3))");
+    assert!(show(&runtime, &evald[5]) == "'(1 2)");
+    assert!(show(&runtime, &evald[6]) == "'(1 2 3)");
+    assert!(evald[8] == Ok(Ponga::Number(Number::Int(30))));
+    assert!(show(&runtime, &evald[9]) == "'(2 4)");
+    assert!(evald[10].is_err());
+    assert!(evald[11] == Ok(Ponga::Number(Number::Int(1))));
+    assert!(show(&runtime, &evald[12]) == "1 2");
+}
+
+#[test]
+pub fn test_ports() {
+    let path = std::env::temp_dir().join("pongascript_test_ports.txt");
+    let src = format!("
+(define p (open-input-string \"ab\\ncd\"))
+(peek-char p)
+(read-char p)
+(read-line p)
+(read-string 5 p)
+(eof-object? (read-char p))
+(call-with-output-string (lambda (port) (write \"hi\" port) (write-char #\\! port)))
+(with-output-to-string (lambda () (display \"x = \") (display 1) (newline)))
+(with-output-to-file \"{0}\" (lambda () (write-string \"line 1\") (newline) (display 'sym)))
+(call-with-input-file \"{0}\" (lambda (port)
+  (let* ((a (read-line port)) (b (read-line port)) (c (read-line port))) (list a b c))))
+(define out (open-output-string))
+(close-port out)
+(display 1 out)
+(list (input-port? p) (output-port? p) (textual-port? out) (output-port-open? out))
+(read-u8 p)
+    ", path.display());
+    let parsed = pongascript_parser(&src).unwrap();
+    let mut runtime = Runtime::new();
+    let evald = parsed
+        .1
+        .into_iter()
+        .map(|x| runtime.eval(x))
+        .collect::<Vec<RunRes<Ponga>>>();
+    let _ = std::fs::remove_file(&path);
+    let show = |runtime: &Runtime, res: &RunRes<Ponga>| runtime.ponga_to_string(res.as_ref().unwrap());
+    assert!(evald[1] == Ok(Ponga::Char('a')));
+    assert!(evald[2] == Ok(Ponga::Char('a')));
+    assert!(evald[3] == Ok(Ponga::String("b".to_string())));
+    assert!(evald[4] == Ok(Ponga::String("cd".to_string())));
+    assert!(evald[5] == Ok(Ponga::True));
+    assert!(evald[6] == Ok(Ponga::String("\"hi\"!".to_string())));
+    assert!(evald[7] == Ok(Ponga::String("x = 1\n".to_string())));
+    assert!(show(&runtime, &evald[9]) == "'(\"line 1\" \"sym\" #<eof>)");
+    assert!(evald[12].is_err());
+    assert!(show(&runtime, &evald[13]) == "'(#t #f #t #f)");
+    assert!(evald[14].is_err());
+}
+
+#[test]
+pub fn test_bytevectors() {
+    let path = std::env::temp_dir().join("pongascript_test_bytevectors.bin");
+    let src = format!("
+(define b #u8(1 2 255))
+(bytevector-u8-set! b 0 9)
+(list b (bytevector? b) (bytevector? #(1)) (bytevector-length b) (bytevector-u8-ref b 2))
+(bytevector 1 256)
+(define p (open-input-bytevector (bytevector 1 2 3 4 5)))
+(let* ((a (read-bytevector 2 p)) (b (read-u8 p)) (c (read-bytevector 10 p)) (d (read-bytevector 1 p)))
+  (list a b c d))
+(define out (open-output-bytevector))
+(write-u8 7 out)
+(write-bytevector #u8(1 2 3 4) out 1 3)
+(equal? (get-output-bytevector out) #u8(7 2 3))
+(utf8->string (string->utf8 \"héllo\"))
+(bytevector-append #u8(1) (make-bytevector 2 6) (bytevector-copy #u8(1 2 3) 1))
+(read (open-input-string \"(a #u8(1 2) b)\"))
+(let ((port (open-binary-output-file \"{0}\")))
+  (write-bytevector (make-bytevector 20000 65) port)
+  (close-port port))
+(bytevector-length (read-bytevector 30000 (open-binary-input-file \"{0}\")))
+(read-bytevector 2 (open-input-string \"ab\"))
+    ", path.display());
+    let parsed = pongascript_parser(&src).unwrap();
+    let mut runtime = Runtime::new();
+    let evald = parsed
+        .1
+        .into_iter()
+        .map(|x| runtime.eval(x))
+        .collect::<Vec<RunRes<Ponga>>>();
+    let _ = std::fs::remove_file(&path);
+    let show = |runtime: &Runtime, res: &RunRes<Ponga>| runtime.ponga_to_string(res.as_ref().unwrap());
+    assert!(show(&runtime, &evald[2]) == "'(#u8(9 2 255) #t #f 3 255)");
+    assert!(evald[3].is_err());
+    assert!(show(&runtime, &evald[5]) == "'(#u8(1 2) 3 #u8(4 5) #<eof>)");
+    assert!(evald[9] == Ok(Ponga::True));
+    assert!(evald[10] == Ok(Ponga::String("h\u{e9}llo".to_string())));
+    assert!(show(&runtime, &evald[11]) == "#u8(1 6 6 2 3)");
+    assert!(show(&runtime, &evald[12]) == "'('a #u8(1 2) 'b)");
+    assert!(evald[14] == Ok(Ponga::Number(Number::Int(20000))));
+    // Textual ports don't give bytes
+    assert!(evald[15].is_err());
+}
+
+#[test]
+pub fn test_write_round_trip() {
+    let parsed = pongascript_parser(r#"
+(define (write-out val) (call-with-output-string (lambda (port) (write val port))))
+(define (round-trip val) (read (open-input-string (write-out val))))
+(write-out "a\"b\\c\nd")
+(write-out (list #\a #\space #\newline #\x7 #\x41))
+(define s (string #\" #\\ #\tab #\return #\x1))
+(equal? (round-trip s) s)
+(define cs (list #\space #\tab #\newline #\null #\x7f #\( #\x))
+(every (lambda (c) (equal? (round-trip c) c)) cs)
+    "#)
+    .unwrap();
+    let mut runtime = Runtime::new();
+    let evald = parsed
+        .1
+        .into_iter()
+        .map(|x| runtime.eval(x))
+        .collect::<Vec<RunRes<Ponga>>>();
+    assert!(evald[2] == Ok(Ponga::String(r#""a\"b\\c\nd""#.to_string())));
+    assert!(evald[3] == Ok(Ponga::String(r"'(#\a #\space #\newline #\alarm #\A)".to_string())));
+    assert!(evald[5] == Ok(Ponga::True));
+    assert!(evald[7] == Ok(Ponga::True));
+}
+
+#[test]
+pub fn test_read() {
+    let parsed = pongascript_parser("
+(define p (open-input-string \"(name \\\"pong\\\") 42 #(1 x)  sym\"))
+(read p)
+(read p)
+(vector-ref (read p) 1)
+(read p)
+(eof-object? (read p))
+(read-all (open-input-string \"1 (2 3) 'q\"))
+(with-input-from-string \"(+ 1 2) next\" (lambda () (let* ((a (read)) (b (read))) (list a b))))
+(equal? (car (read (open-input-string \"(* 6 7)\"))) '*)
+(read (open-input-string \"(1 2\"))
+(equal? (read (open-input-string \"\")) '())
+    ")
+    .unwrap();
+    let mut runtime = Runtime::new();
+    let evald = parsed
+        .1
+        .into_iter()
+        .map(|x| runtime.eval(x))
+        .collect::<Vec<RunRes<Ponga>>>();
+    let show = |runtime: &Runtime, res: &RunRes<Ponga>| runtime.ponga_to_string(res.as_ref().unwrap());
+    assert!(show(&runtime, &evald[1]) == "'('name \"pong\")");
+    assert!(evald[2] == Ok(Ponga::Number(Number::Int(42))));
+    assert!(evald[3] == Ok(Ponga::Symbol("x".to_string())));
+    assert!(evald[4] == Ok(Ponga::Symbol("sym".to_string())));
+    assert!(evald[5] == Ok(Ponga::True));
+    assert!(show(&runtime, &evald[6]) == "'(1 '(2 3) '('quote 'q))");
+    assert!(show(&runtime, &evald[7]) == "'('('+ 1 2) 'next)");
+    assert!(evald[8] == Ok(Ponga::True));
+    assert!(evald[9].is_err());
+    assert!(evald[10] == Ok(Ponga::False));
+}
+
+#[test]
+pub fn test_read_file_port() {
+    // Bigger than the chunks files are read in
+    let long: Vec<String> = (0..5000).map(|i| i.to_string()).collect();
+    let path = std::env::temp_dir().join("pongascript_test_read_file_port.scm");
+    std::fs::write(&path, format!(r#"  (a "b)" #\( #\space) '(x y) "s\"q" #\) ({})
+sym"#, long.join(" "))).unwrap();
+    let src = format!(r#"
+(define all (call-with-input-file "{}" read-all))
+(length all)
+(list (list-ref all 0) (list-ref all 1) (list-ref all 2) (list-ref all 3) (list-ref all 5))
+(length (list-ref all 4))
+(read (open-input-string "(1 \"2)\" #\\)"))
+    "#, path.display());
+    let parsed = pongascript_parser(&src).unwrap();
+    let mut runtime = Runtime::new();
+    let evald = parsed
+        .1
+        .into_iter()
+        .map(|x| runtime.eval(x))
+        .collect::<Vec<RunRes<Ponga>>>();
+    let _ = std::fs::remove_file(&path);
+    let show = |runtime: &Runtime, res: &RunRes<Ponga>| runtime.ponga_to_string(res.as_ref().unwrap());
+    assert!(evald[1] == Ok(Ponga::Number(Number::Int(6))));
+    assert_eq!(show(&runtime, &evald[2]),
+               r#"'('('a "b)" #\( #\space) '('quote '('x 'y)) "s\"q" #\) 'sym)"#);
+    assert!(evald[3] == Ok(Ponga::Number(Number::Int(5000))));
+    // The closing paren in the string and the char doesn't end the list
+    assert!(evald[4].is_err());
+}
+
+#[test]
+pub fn test_libraries() {
+    let dir = std::env::temp_dir().join("pongascript_test_libraries");
+    std::fs::create_dir_all(dir.join("shapes")).unwrap();
+    std::fs::write(dir.join("shapes/area.sld"), "
+(define-library (shapes area)
+  (export square-area (rename circle circle-area))
+  (import (scheme base))
+  (begin
+    (define pi 3)
+    (define (square x) (* x x))
+    (define (square-area side) (square side))
+    (define (circle r) (* pi (square r)))))
+    ").unwrap();
+    let parsed = pongascript_parser("
+(define-library (counter)
+  (export next! total)
+  (import (only (scheme base) +) (prefix (shapes area) a:))
+  (begin
+    (define total 0)
+    (define (next!) (set! total (+ total (a:square-area 2))) total)))
+(import (counter))
+(next!)
+(next!)
+(import (prefix (shapes area) s:) (rename (only (counter) next!) (next! bump!)))
+(s:circle-area 2)
+(bump!)
+(square 3)
+(define pi 4)
+(s:circle-area 1)
+(import (missing library))
+(import (only (counter) square))
+(import (except (srfi 1) iota))
+    ")
+    .unwrap();
+    let mut runtime = Runtime::new();
+    runtime.add_library_path(&dir);
+    let evald = parsed
+        .1
+        .into_iter()
+        .map(|x| runtime.eval(x))
+        .collect::<Vec<RunRes<Ponga>>>();
+    let _ = std::fs::remove_dir_all(&dir);
+    assert!(evald[2] == Ok(Ponga::Number(Number::Int(4))));
+    assert!(evald[3] == Ok(Ponga::Number(Number::Int(8))));
+    assert!(evald[5] == Ok(Ponga::Number(Number::Int(12))));
+    assert!(evald[6] == Ok(Ponga::Number(Number::Int(12))));
+    // Library definitions stay inside the library
+    assert!(evald[7].is_err());
+    assert!(evald[9] == Ok(Ponga::Number(Number::Int(3))));
+    assert!(evald[10].is_err());
+    assert!(evald[11].is_err());
+    assert!(evald[12] == Ok(Ponga::Null));
+
+    for (_, names) in LIBRARIES.iter() {
+        for name in names.iter() {
+            assert!(runtime.get_identifier_obj_ref(name).is_ok(), "{} isn't defined", name);
+        }
+    }
+}
+
+#[test]
+pub fn test_library_imports() {
+    let parsed = pongascript_parser("
+(define-library (tally)
+  (export count inc! get)
+  (import (scheme base))
+  (begin
+    (define count 0)
+    (define (inc!) (set! count (+ count 1)))
+    (define (get) count)))
+(define count 10)
+(import (tally))
+(inc!)
+(inc!)
+(+ (* count 10) (get))
+(set! count 5)
+(import (rename (tally) (count total)))
+(inc!)
+total
+(import (only (scheme base) car))
+(car (cdr '(1 2)))
+    ")
+    .unwrap();
+    let mut runtime = Runtime::new();
+    let evald = parsed
+        .1
+        .into_iter()
+        .map(|x| runtime.eval(x))
+        .collect::<Vec<RunRes<Ponga>>>();
+    // Imports see the library's binding, not a copy of its value
+    assert!(evald[5] == Ok(Ponga::Number(Number::Int(22))));
+    assert!(evald[6].is_err());
+    assert!(evald[9] == Ok(Ponga::Number(Number::Int(3))));
+    // Import sets don't hide builtins
+    assert!(evald[11] == Ok(Ponga::Number(Number::Int(2))));
+}
+
+#[test]
+pub fn test_load_and_include() {
+    let dir = std::env::temp_dir().join("pongascript_test_load");
+    std::fs::create_dir_all(dir.join("sub")).unwrap();
+    let files = [
+        ("helpers.scm", "(load \"sub/more.scm\") (define (helper x) (more x))"),
+        ("sub/more.scm", "(define (more x) (+ x 100))"),
+        ("defs.scm", "(define included 1) (include \"sub/inner.scm\")"),
+        ("sub/inner.scm", "(define inner (+ included 1))"),
+        ("upper.scm", "(DEFINE SHOUT 5)"),
+        ("a.scm", "(load \"b.scm\")"),
+        ("b.scm", "(load \"a.scm\")"),
+        ("lib.sld", "(define-library (inc) (export inc) (include \"sub/inc.scm\"))"),
+        ("sub/inc.scm", "(define (inc x) (+ x 1))"),
+        ("sub/proc.scm", "(define (from-body) (include \"body.scm\"))"),
+        ("sub/body.scm", "(+ 40 2)"),
+    ];
+    for (name, contents) in files.iter() {
+        std::fs::write(dir.join(name), contents).unwrap();
+    }
+    let path = |name: &str| dir.join(name).display().to_string();
+    let src = format!("
+(load \"{}\")
+(helper 1)
+(include \"{}\")
+(list included inner)
+(include-ci \"{}\")
+shout
+(load \"{}\")
+(load \"{}\")
+(import (inc))
+(inc 1)
+(load \"{}\")
+(load \"{}\")
+(from-body)
+    ", path("helpers.scm"), path("defs.scm"), path("upper.scm"), path("a.scm"),
+       path("lib.sld"), path("missing.scm"), path("sub/proc.scm"));
+    let parsed = pongascript_parser(&src).unwrap();
+    let mut runtime = Runtime::new();
+    let evald = parsed
+        .1
+        .into_iter()
+        .map(|x| runtime.eval(x))
+        .collect::<Vec<RunRes<Ponga>>>();
+    // Includes in a procedure body are read when the procedure is, relative
+    // to its file
+    std::fs::remove_file(dir.join("sub/body.scm")).unwrap();
+    let again = runtime.eval(pongascript_parser("(from-body)").unwrap().1.remove(0));
+    let _ = std::fs::remove_dir_all(&dir);
+    let show = |runtime: &Runtime, res: &RunRes<Ponga>| runtime.ponga_to_string(res.as_ref().unwrap());
+    assert!(evald[1] == Ok(Ponga::Number(Number::Int(101))));
+    assert!(show(&runtime, &evald[3]) == "'(1 2)");
+    assert!(evald[5] == Ok(Ponga::Number(Number::Int(5))));
+    match &evald[6] {
+        Err(RuntimeErr::Other(e)) => assert!(e.starts_with("Cyclic load")),
+        _ => panic!("Expected a cyclic load error"),
+    }
+    assert!(evald[9] == Ok(Ponga::Number(Number::Int(2))));
+    assert!(evald[10].is_err());
+    assert!(evald[12] == Ok(Ponga::Number(Number::Int(42))));
+    assert!(again == Ok(Ponga::Number(Number::Int(42))));
+}
+
+#[test]
+pub fn test_environments() {
+    let parsed = pongascript_parser("
+(eval (quote (+ 1 (* 2 3))))
+(eval (quote (* 2 (+ 1 2))) (scheme-report-environment 5))
+(define env (environment (quote (prefix (srfi 1) s:))))
+(eval (quote (define x 10)) env)
+(eval 'x env)
+x
+(eval (quote (s:iota 3)) env)
+(define y 5)
+(eval 'y (interaction-environment))
+(eval 'y env)
+(let ((z 1)) (eval 'z))
+(eval (read (open-input-string \"(if #t 'yes 'no)\")))
+(eval (quote (+ 1 2)) 5)
+(define sealed (environment (quote (only (scheme base) car list))))
+(eval (quote (define secret 1)) sealed)
+(eval (quote (define (get) secret)) sealed)
+(eval (quote (define (clobber!) (set! y 2))) sealed)
+(define get (eval (quote get) sealed))
+(define clobber! (eval (quote clobber!) sealed))
+(define secret 99)
+(get)
+(clobber!)
+y
+(eval (quote (car (list 1 2))) sealed)
+(eval (quote (cdr (list 1 2))) sealed)
+    ")
+    .unwrap();
+    let mut runtime = Runtime::new();
+    let evald = parsed
+        .1
+        .into_iter()
+        .map(|x| runtime.eval(x))
+        .collect::<Vec<RunRes<Ponga>>>();
+    let show = |runtime: &Runtime, res: &RunRes<Ponga>| runtime.ponga_to_string(res.as_ref().unwrap());
+    assert!(evald[0] == Ok(Ponga::Number(Number::Int(7))));
+    assert!(evald[1] == Ok(Ponga::Number(Number::Int(6))));
+    assert!(evald[4] == Ok(Ponga::Number(Number::Int(10))));
+    assert!(evald[5].is_err());
+    assert!(show(&runtime, &evald[6]) == "'(0 1 2)");
+    assert!(evald[8] == Ok(Ponga::Number(Number::Int(5))));
+    assert!(evald[9].is_err());
+    assert!(evald[10].is_err());
+    assert!(evald[11] == Ok(Ponga::Symbol("yes".to_string())));
+    assert!(evald[12].is_err());
+    // Procedures see the globals of the environment they were made in,
+    // wherever they're called from
+    assert!(evald[20] == Ok(Ponga::Number(Number::Int(1))));
+    assert!(evald[21].is_err());
+    assert!(evald[22] == Ok(Ponga::Number(Number::Int(5))));
+    // Only what the import set gives is visible
+    assert!(evald[23] == Ok(Ponga::Number(Number::Int(1))));
+    assert!(evald[24].is_err());
+
+    // Plugins evaluated in environments of their own can't see each other
+    let first = runtime.new_environment();
+    let second = runtime.new_environment();
+    runtime.eval_str_in(&first, "(define name \"first\") (define (greet) name)").unwrap();
+    runtime.eval_str_in(&second, "(define name \"second\")").unwrap();
+    runtime.collect_garbage();
+    assert!(runtime.eval_str_in(&first, "(greet)") == Ok(Ponga::String("first".to_string())));
+    assert!(runtime.eval_str_in(&second, "name") == Ok(Ponga::String("second".to_string())));
+    assert!(runtime.eval_str_in(&second, "(greet)").is_err());
+    assert!(runtime.get_identifier_obj_ref("name").is_err());
+    runtime.drop_environment(&first);
+    runtime.collect_garbage();
+    assert!(runtime.eval_str_in(&first, "name").is_err());
+}
+
+#[test]
+pub fn test_parameters() {
+    let parsed = pongascript_parser("
+(define p (make-parameter 10 (lambda (x) (* x 2))))
+(p)
+(parameterize ((p 3)) (p))
+(define (get-p) (p))
+(parameterize ((p 1)) (get-p))
+(parameterize ((p 5)) (undefined-function))
+(p)
+(with-output-to-string (lambda ()
+  (parameterize ((current-output-port (open-output-string))) (display \"hidden\"))
+  (display \"shown\")))
+(define out (open-output-string))
+(parameterize ((current-output-port out)) (display \"x\") (write 'y))
+(get-output-string out)
+(p 1)
+(parameterize ((5 1)) 1)
+(parameterize ((p 1) (p 2)) (p))
+    ")
+    .unwrap();
+    let mut runtime = Runtime::new();
+    let evald = parsed
+        .1
+        .into_iter()
+        .map(|x| runtime.eval(x))
+        .collect::<Vec<RunRes<Ponga>>>();
+    assert!(evald[1] == Ok(Ponga::Number(Number::Int(20))));
+    assert!(evald[2] == Ok(Ponga::Number(Number::Int(6))));
+    assert!(evald[4] == Ok(Ponga::Number(Number::Int(2))));
+    // Restored after an error in the body
+    assert!(evald[5].is_err());
+    assert!(evald[6] == Ok(Ponga::Number(Number::Int(20))));
+    assert!(evald[7] == Ok(Ponga::String("shown".to_string())));
+    assert!(evald[10] == Ok(Ponga::String("xy".to_string())));
+    assert!(evald[11].is_err());
+    assert!(evald[12].is_err());
+    assert!(evald[13] == Ok(Ponga::Number(Number::Int(4))));
+}
+
+#[test]
+pub fn test_threads() {
+    let parsed = pongascript_parser("
+(define log '())
+(define (worker name n)
+  (lambda ()
+    (do ((i 0 (+ i 1))) ((= i n) name)
+      (set! log (cons name log))
+      (thread-yield!))))
+(define a (thread-start! (make-thread (worker 'a 3))))
+(define b (thread-start! (make-thread (worker 'b 3))))
+(let* ((x (thread-join! a)) (y (thread-join! b))) (list x y (reverse log)))
+(define counter 0)
+(define stop #f)
+(define busy (thread-start! (make-thread (lambda ()
+  (do () (stop counter) (set! counter (+ counter 1)))))))
+(do () ((>= counter 100) (set! stop #t) 'preempted))
+(>= (thread-join! busy) 100)
+(define m (make-mutex 'lock))
+(define cv (make-condition-variable))
+(define queue '())
+(define consumer (thread-start! (make-thread (lambda ()
+  (mutex-lock! m)
+  (let loop ()
+    (if (null? queue)
+        (begin (mutex-unlock! m cv) (mutex-lock! m) (loop))
+        (let ((item (car queue))) (mutex-unlock! m) item)))))))
+(thread-sleep! 0.01)
+(mutex-state m)
+(mutex-lock! m)
+(eq? (mutex-state m) (current-thread))
+(set! queue (list 42))
+(condition-variable-signal! cv)
+(mutex-unlock! m)
+(thread-join! consumer)
+(define sleeper (thread-start! (make-thread (lambda () (thread-sleep! 0.05) 'woke))))
+(thread-join! sleeper 0.001 'timeout)
+(thread-join! sleeper)
+(thread-join! (thread-start! (make-thread (lambda () (car '())))))
+(thread-join! (thread-start! (make-thread (lambda () (thread-name (current-thread))) 'named)))
+(thread-name (current-thread))
+(thread-join! (make-thread (lambda () 1)))
+(mutex-lock! m 0.01)
+(mutex-lock! m 0.01)
+(list (thread? a) (thread? m) (mutex? m) (condition-variable? cv))
+    ")
+    .unwrap();
+    let mut runtime = Runtime::new();
+    let evald = parsed
+        .1
+        .into_iter()
+        .map(|x| runtime.eval(x))
+        .collect::<Vec<RunRes<Ponga>>>();
+    let show = |runtime: &Runtime, res: &RunRes<Ponga>| runtime.ponga_to_string(res.as_ref().unwrap());
+    // Each yield gives the other thread a turn
+    assert_eq!(show(&runtime, &evald[4]), "'('a 'b '('a 'b 'a 'b 'a 'b))");
+    // Neither loop yields, so they only take turns by being preempted
+    assert!(evald[8] == Ok(Ponga::Symbol("preempted".to_string())));
+    assert!(evald[9] == Ok(Ponga::True));
+    // The consumer has unlocked the mutex to wait on the condition variable
+    assert!(evald[15] == Ok(Ponga::Symbol("not-abandoned".to_string())));
+    assert!(evald[16] == Ok(Ponga::True));
+    assert!(evald[17] == Ok(Ponga::True));
+    assert!(evald[21] == Ok(Ponga::Number(Number::Int(42))));
+    assert!(evald[23] == Ok(Ponga::Symbol("timeout".to_string())));
+    assert!(evald[24] == Ok(Ponga::Symbol("woke".to_string())));
+    // An error in the thread is raised by joining it
+    assert!(evald[25].is_err());
+    assert!(evald[26] == Ok(Ponga::Symbol("named".to_string())));
+    assert!(evald[27] == Ok(Ponga::Symbol("primordial".to_string())));
+    // Never started, so nothing can wake main up
+    assert!(evald[28].is_err());
+    assert!(evald[29] == Ok(Ponga::True));
+    assert!(evald[30] == Ok(Ponga::False));
+    assert_eq!(show(&runtime, &evald[31]), "'(#t #f #t #t)");
+}
+
+#[test]
+pub fn test_blocking_in_callbacks() {
+    let parsed = pongascript_parser("
+(define m (make-mutex))
+(mutex-lock! m)
+(define t (thread-start! (make-thread (lambda ()
+  (map (lambda (x) (mutex-lock! m) (mutex-unlock! m) (* x 2)) (list 1 2 3))))))
+(thread-yield!)
+(mutex-unlock! m)
+(thread-join! t)
+($SELECT)
+($SELECT 1 (lambda () 1) 'receive)
+($THREAD-SLEEP-UNTIL)
+    ")
+    .unwrap();
+    let mut runtime = Runtime::new();
+    let evald = parsed
+        .1
+        .into_iter()
+        .map(|x| runtime.eval(x))
+        .collect::<Vec<RunRes<Ponga>>>();
+    let show = |runtime: &Runtime, res: &RunRes<Ponga>| runtime.ponga_to_string(res.as_ref().unwrap());
+    // The thread waits for the mutex inside map's callback without keeping
+    // the thread that holds it from running
+    assert_eq!(show(&runtime, &evald[5]), "'(2 4 6)");
+    assert!(evald[6].is_err());
+    assert!(evald[7].is_err());
+    assert!(evald[8].is_err());
+}
+
+#[test]
+pub fn test_thread_parameters() {
+    let parsed = pongascript_parser("
+(define p (make-parameter 0))
+(define seen '())
+(define outside (make-thread (lambda () (set! seen (cons (p) seen)))))
+(thread-start! outside)
+(parameterize ((p 42))
+  (thread-yield!)
+  (thread-join! (thread-start! (make-thread (lambda () (p))))))
+seen
+(p)
+(define made-inside (parameterize ((p 7)) (make-thread (lambda () (p)))))
+(thread-join! (thread-start! made-inside))
+    ")
+    .unwrap();
+    let mut runtime = Runtime::new();
+    let evald = parsed
+        .1
+        .into_iter()
+        .map(|x| runtime.eval(x))
+        .collect::<Vec<RunRes<Ponga>>>();
+    let show = |runtime: &Runtime, res: &RunRes<Ponga>| runtime.ponga_to_string(res.as_ref().unwrap());
+    // Threads get the parameterization in force where they're made, and
+    // don't see one made by another thread after that
+    assert!(evald[4] == Ok(Ponga::Number(Number::Int(42))));
+    assert_eq!(show(&runtime, &evald[5]), "'(0)");
+    assert!(evald[6] == Ok(Ponga::Number(Number::Int(0))));
+    assert!(evald[8] == Ok(Ponga::Number(Number::Int(7))));
+}
+
+#[test]
+pub fn test_channels() {
+    let parsed = pongascript_parser("
+(define ch (make-channel))
+(define producer (thread-start! (make-thread (lambda ()
+  (do ((i 0 (+ i 1))) ((= i 3)) (channel-send ch i))
+  (channel-close ch)))))
+(do ((acc '() (cons v acc)) (v (channel-receive ch) (channel-receive ch)))
+    ((eof-object? v) (reverse acc)))
+(define sent 0)
+(define bounded (make-channel 1))
+(define sender (thread-start! (make-thread (lambda ()
+  (do ((i 0 (+ i 1))) ((= i 3) 'done) (channel-send bounded i) (set! sent (+ sent 1)))))))
+(thread-yield!)
+sent
+(channel-receive bounded)
+(thread-yield!)
+sent
+(define c1 (make-channel))
+(define c2 (make-channel))
+(channel-send c2 'two)
+(select ((c1 -> v) (list 'one v)) ((c2 -> v) (list 'two v)))
+(select ((c1 -> v) v) (else 'nothing))
+(select ((c1 -> v) v) (timeout 0.01 'timed-out))
+(select ((c1 <- 5) 'sent))
+(channel-receive c1)
+(define late (make-channel))
+(thread-start! (make-thread (lambda () (thread-sleep! 0.01) (channel-send late 'late))))
+(select ((c2 -> v) v) ((late -> v) v) (timeout 1 'too-slow))
+(channel-close c1)
+(channel-receive c1)
+(channel-send c1 1)
+(select ((c1 -> v) (eof-object? v)))
+(make-channel 0)
+(channel-receive (make-channel))
+(list (channel? c1) (channel? 1) (channel-closed? c1) (channel-closed? c2))
+    ")
+    .unwrap();
+    let mut runtime = Runtime::new();
+    let evald = parsed
+        .1
+        .into_iter()
+        .map(|x| runtime.eval(x))
+        .collect::<Vec<RunRes<Ponga>>>();
+    let show = |runtime: &Runtime, res: &RunRes<Ponga>| runtime.ponga_to_string(res.as_ref().unwrap());
+    assert_eq!(show(&runtime, &evald[2]), "'(0 1 2)");
+    // The sender can only get one value ahead of the receiver
+    assert!(evald[7] == Ok(Ponga::Number(Number::Int(1))));
+    assert!(evald[8] == Ok(Ponga::Number(Number::Int(0))));
+    assert!(evald[10] == Ok(Ponga::Number(Number::Int(2))));
+    assert_eq!(show(&runtime, &evald[14]), "'('two 'two)");
+    assert!(evald[15] == Ok(Ponga::Symbol("nothing".to_string())));
+    assert!(evald[16] == Ok(Ponga::Symbol("timed-out".to_string())));
+    assert!(evald[17] == Ok(Ponga::Symbol("sent".to_string())));
+    assert!(evald[18] == Ok(Ponga::Number(Number::Int(5))));
+    assert!(evald[21] == Ok(Ponga::Symbol("late".to_string())));
+    // Closed channels give the eof object once they're empty
+    assert!(evald[23] == Ok(Ponga::Eof));
+    assert!(evald[24].is_err());
+    assert!(evald[25] == Ok(Ponga::True));
+    assert!(evald[26].is_err());
+    // Nothing will ever send on it
+    assert!(evald[27].is_err());
+    assert_eq!(show(&runtime, &evald[28]), "'(#t #f #t #f)");
+}
+
+#[test]
+pub fn test_generators() {
+    let parsed = pongascript_parser("
+(define counter (make-coroutine-generator (lambda (yield)
+  (do ((i 0 (+ i 1))) ((= i 3) 'finished) (yield i)))))
+(counter)
+(generator->list counter)
+(counter)
+(define (walk tree yield)
+  (if (vector? tree)
+      (for-each (lambda (t) (walk t yield)) (vector->list tree))
+      (yield tree)))
+(generator->list (make-coroutine-generator (lambda (yield) (walk #(1 #(2 #(3)) 4) yield))))
+(generator->list (gmap (lambda (x) (* x x)) (gfilter even? (gtake (generator 1 2 3 4 5 6 7) 5))))
+(generator-fold + 0 (list->generator (list 1 2 3)))
+(define bad (make-coroutine-generator (lambda (yield) (if (yield 1) 1 2))))
+(bad)
+(bad)
+(yield 1)
+(counter 1)
+(define acc (list-accumulator))
+(acc 1)
+(acc 2)
+(acc (eof-object))
+(define count (count-accumulator))
+(count 'a)
+(count (eof-object))
+(define product (product-accumulator))
+(product 3)
+(product 4)
+(product (eof-object))
+(define table (make-hash-table))
+(hash-table-set! table 'k 5)
+(generator->list (make-coroutine-generator (lambda (yield)
+  (map (lambda (x) (yield (* x 10))) (list 1 2))
+  (hash-table-walk table (lambda (k v) (yield v))))))
+    ")
+    .unwrap();
+    let mut runtime = Runtime::new();
+    let evald = parsed
+        .1
+        .into_iter()
+        .map(|x| runtime.eval(x))
+        .collect::<Vec<RunRes<Ponga>>>();
+    let show = |runtime: &Runtime, res: &RunRes<Ponga>| runtime.ponga_to_string(res.as_ref().unwrap());
+    assert!(evald[1] == Ok(Ponga::Number(Number::Int(0))));
+    // Carries on from where the first call left off
+    assert_eq!(show(&runtime, &evald[2]), "'(1 2)");
+    assert!(evald[3] == Ok(Ponga::Eof));
+    // Yields from inside recursive calls
+    assert_eq!(show(&runtime, &evald[5]), "'(1 2 3 4)");
+    assert_eq!(show(&runtime, &evald[6]), "'(4 16)");
+    assert!(evald[7] == Ok(Ponga::Number(Number::Int(6))));
+    // Yields from the test of an if, and finishes once it's resumed
+    assert!(evald[9] == Ok(Ponga::Number(Number::Int(1))));
+    assert!(evald[10] == Ok(Ponga::Eof));
+    assert!(evald[11].is_err());
+    assert!(evald[12].is_err());
+    assert_eq!(show(&runtime, &evald[16]), "'(1 2)");
+    assert!(evald[19] == Ok(Ponga::Number(Number::Int(1))));
+    assert!(evald[23] == Ok(Ponga::Number(Number::Int(12))));
+    // Yields from procedures that builtins call
+    assert_eq!(show(&runtime, &evald[26]), "'(10 20 5)");
+}
+
+#[test]
+pub fn test_pool() {
+    use crate::pool::*;
+
+    let mut host = Runtime::new();
+    let env = host.current_environment();
+    assert!(host.eval_str_in(&env, "(worker-id)") == Ok(Ponga::False));
+    assert!(host.eval_str_in(&env, "(send-message 0 1)").is_err());
+
+    // A runtime that has already been used can be moved onto a worker
+    let mut prepared = Runtime::new();
+    let prepared_env = prepared.current_environment();
+    prepared.eval_str_in(&prepared_env, "(define (square x) (* x x))").unwrap();
+    let pool = Pool::from_runtimes(vec![prepared, Runtime::new()]);
+    assert!(pool.len() == 2);
+
+    // Worker 0 squares what it's sent and passes it on to worker 1, where a
+    // green thread is waiting for it
+    let squares = pool.eval(0, "
+(do ((i 0 (+ i 1))) ((= i 3) (worker-id))
+  (send-message 1 (square (receive-message))))
+    ").unwrap();
+    let collected = pool.eval(1, "
+(define t (thread-start! (make-thread (lambda ()
+  (let* ((a (receive-message)) (b (receive-message)) (c (receive-message)))
+    (list a b c))))))
+(list (thread-join! t) (worker-id) (worker-count))
+    ").unwrap();
+    for i in 1..=3 {
+        let msg = Message::new(&host, &Ponga::Number(Number::Int(i))).unwrap();
+        pool.send(0, msg).unwrap();
+    }
+    assert!(squares.join().unwrap().unwrap().value() == &Ponga::Number(Number::Int(0)));
+    let res = collected.join().unwrap().unwrap().into_ponga(&mut host);
+    assert_eq!(host.ponga_to_string(&res), "'('(1 4 9) 1 2)");
+
+    // Values are copied, not shared
+    let copied = pool.eval(0, "
+(define v (vector 1 (list 'a \"b\" #\\c)))
+(send-message 0 v)
+(define w (receive-message))
+(vector-set! w 0 2)
+(list v w (eq? v w))
+    ").unwrap();
+    let res = copied.join().unwrap().unwrap().into_ponga(&mut host);
+    assert_eq!(host.ponga_to_string(&res), "'(#(1 '('a \"b\" #\\c)) #(2 '('a \"b\" #\\c)) #f)");
+
+    assert!(pool.eval(0, "(send-message 1 (lambda (x) x))").unwrap().join().unwrap().is_err());
+    assert!(pool.eval(0, "(send-message 2 1)").unwrap().join().unwrap().is_err());
+    let timeout = pool.eval(1, "(receive-message 0.01 'none)").unwrap();
+    assert!(timeout.join().unwrap().unwrap().value() == &Ponga::Symbol("none".to_string()));
+    assert!(pool.eval(1, "(receive-message 0)").unwrap().join().unwrap().is_err());
+
+    // Dropping the pool stops a worker waiting for a message that won't come,
+    // in the primordial thread or another one
+    let (done, finished) = std::sync::mpsc::channel();
+    std::thread::spawn(move || {
+        let pool = Pool::new(2);
+        let parked = pool.eval(0, "(receive-message)").unwrap();
+        let in_thread = pool.eval(1, "(thread-join! (thread-start! (make-thread receive-message)))").unwrap();
+        std::thread::sleep(std::time::Duration::from_millis(10));
+        drop(pool);
+        let _ = done.send((parked.join().unwrap().is_err(), in_thread.join().unwrap().is_err()));
+    });
+    let res = finished.recv_timeout(std::time::Duration::from_secs(10));
+    assert!(res == Ok((true, true)), "Dropping the pool hung");
+
+    // Runtimes made by the pool run in parallel
+    let pool = Pool::new(4);
+    let tasks: Vec<_> = (0..4).map(|i| {
+        pool.execute(i, move |runtime| {
+            let env = runtime.current_environment();
+            let src = format!("(do ((i 0 (+ i 1)) (sum 0 (+ sum i))) ((= i {}) sum))", i * 100);
+            runtime.eval_str_in(&env, &src)
+        }).unwrap()
+    }).collect();
+    let sums: Vec<_> = tasks.into_iter().map(|task| task.join().unwrap().unwrap()).collect();
+    assert!(sums == [0, 4950, 19900, 44850].map(|n| Ponga::Number(Number::Int(n))));
+}
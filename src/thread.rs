@@ -0,0 +1,193 @@
+use crate::instructions::Instruction;
+use crate::runtime::PriorityNamespace;
+use crate::types::*;
+use crate::parameter::Parameterization;
+use std::collections::VecDeque;
+use std::time::SystemTime;
+
+// A green thread made by make-thread. Threads share the gc heap and the
+// globals, but each has its own stacks, locals and parameterize bindings,
+// which are kept here while it isn't the one running, along with the
+// environment it's evaluating in.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Thread {
+    pub name: Box<Ponga>,
+    pub specific: Box<Ponga>,
+    pub state: ThreadState,
+    pub ins_stack: Vec<Instruction>,
+    pub data_stack: Vec<Ponga>,
+    pub locals: PriorityNamespace,
+    pub env: Id,
+    pub parameters: Box<Parameterization>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ThreadState {
+    New,
+    // Started and waiting for a turn, once whatever it's blocked on allows
+    Ready(Option<Block>),
+    // Running now, or further down the Rust stack underneath the one that is
+    Running,
+    Done(Result<Box<Ponga>, RuntimeErr>),
+}
+
+// Why a thread can't go on, and when it stops waiting regardless
+#[derive(Debug, Clone, PartialEq)]
+pub struct Block {
+    pub wait: Wait,
+    pub deadline: Option<SystemTime>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Wait {
+    Yield,
+    Sleep,
+    // For the thread with this id to finish
+    Join(Id),
+    // For the mutex with this id to be unlocked
+    Mutex(Id),
+    // For the ticket to be taken off the condition variable's waiting list
+    Condition(Id, usize),
+    // For any of the channels to be ready for what's waiting on it
+    Channels(Vec<(Id, ChannelOp)>),
+    // For a message from another runtime in the pool
+    Message,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ChannelOp {
+    Send,
+    Receive,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Mutex {
+    pub name: Box<Ponga>,
+    pub state: MutexState,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum MutexState {
+    Unlocked,
+    // Locked by the thread with this id, or by no thread in particular
+    Locked(Option<Id>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConditionVariable {
+    pub name: Box<Ponga>,
+    // Tickets of the waiting threads, first come first served
+    pub waiting: Vec<usize>,
+    pub next_ticket: usize,
+}
+
+// A queue of values sent between threads by channel-send and select
+#[derive(Debug, Clone, PartialEq)]
+pub struct Channel {
+    pub buffer: VecDeque<Ponga>,
+    // How many values can be waiting to be received, if there's a limit
+    pub capacity: Option<usize>,
+    pub closed: bool,
+}
+
+impl Thread {
+    pub fn new(name: Ponga, ins_stack: Vec<Instruction>, env: Id,
+               parameters: Parameterization) -> Thread {
+        Thread {
+            name: Box::new(name),
+            specific: Box::new(Ponga::Null),
+            state: ThreadState::New,
+            ins_stack,
+            data_stack: Vec::new(),
+            locals: PriorityNamespace::new(),
+            env,
+            parameters: Box::new(parameters),
+        }
+    }
+
+    pub fn is_done(&self) -> bool {
+        matches!(self.state, ThreadState::Done(_))
+    }
+}
+
+impl Mutex {
+    pub fn new(name: Ponga) -> Mutex {
+        Mutex { name: Box::new(name), state: MutexState::Unlocked }
+    }
+}
+
+impl ConditionVariable {
+    pub fn new(name: Ponga) -> ConditionVariable {
+        ConditionVariable { name: Box::new(name), waiting: Vec::new(), next_ticket: 0 }
+    }
+
+    pub fn add_waiter(&mut self) -> usize {
+        let ticket = self.next_ticket;
+        self.next_ticket += 1;
+        self.waiting.push(ticket);
+        ticket
+    }
+
+    pub fn is_waiting(&self, ticket: usize) -> bool {
+        self.waiting.contains(&ticket)
+    }
+
+    pub fn remove_waiter(&mut self, ticket: usize) {
+        self.waiting.retain(|t| *t != ticket);
+    }
+
+    pub fn signal(&mut self) {
+        if !self.waiting.is_empty() {
+            self.waiting.remove(0);
+        }
+    }
+
+    pub fn broadcast(&mut self) {
+        self.waiting.clear();
+    }
+}
+
+impl Channel {
+    pub fn new(capacity: Option<usize>) -> Channel {
+        Channel { buffer: VecDeque::new(), capacity, closed: false }
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.capacity.is_some_and(|capacity| self.buffer.len() >= capacity)
+    }
+
+    // Whether the operation can go on without waiting, which it always can
+    // once the channel is closed
+    pub fn is_ready(&self, op: ChannelOp) -> bool {
+        self.closed || match op {
+            ChannelOp::Send => !self.is_full(),
+            ChannelOp::Receive => !self.buffer.is_empty(),
+        }
+    }
+
+    // The next value, or the eof object once the channel is closed and
+    // empty. None if the receiver has to wait.
+    pub fn try_receive(&mut self) -> Option<Ponga> {
+        match self.buffer.pop_front() {
+            Some(val) => Some(val),
+            None if self.closed => Some(Ponga::Eof),
+            None => None,
+        }
+    }
+
+    // Whether the value was sent, which it isn't while the channel is full
+    pub fn try_send(&mut self, val: Ponga) -> RunRes<bool> {
+        if self.closed {
+            return Err(RuntimeErr::Other("Can't send on a closed channel".to_string()));
+        }
+        if self.is_full() {
+            return Ok(false);
+        }
+        self.buffer.push_back(val);
+        Ok(true)
+    }
+}
+
+pub fn timed_out(deadline: Option<SystemTime>) -> bool {
+    deadline.is_some_and(|deadline| SystemTime::now() >= deadline)
+}
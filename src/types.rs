@@ -1,9 +1,18 @@
+use crate::coroutine::Coroutine;
 use crate::number::*;
+use crate::environment::Environment;
+use crate::hash_table::HashTable;
+use crate::parser::CHAR_NAMES;
+use crate::parameter::Parameter;
+use crate::port::*;
+use crate::promise::Promise;
+use crate::record::*;
 use crate::runtime::Runtime;
+use crate::thread::*;
 use std::collections::HashMap;
 use std::collections::LinkedList;
 
-pub type Id = usize;
+pub type Id = u64;
 
 pub type FuncId = usize;
 
@@ -12,6 +21,9 @@ pub enum Ponga {
     Null,
     Number(Number),
     String(String),
+    // Strings made by make-string, string and string-copy, which live in the
+    // gc so that string-set! is seen through every reference
+    MutString(String),
     Char(char),
     Symbol(String),
     Identifier(String),
@@ -19,18 +31,41 @@ pub enum Ponga {
     Object(HashMap<String, Ponga>),
     Array(Vec<Ponga>),
     Sexpr(Vec<Ponga>),
-    CFunc(Vec<String>, Id, Id), // args, sexpr_id, state_id
+    CFunc(Vec<String>, Id, Id, Id), // args, sexpr_id, state_id, env_id
     MFunc(Vec<String>, Id),
     HFunc(FuncId),
     True,
     False,
     Ref(Id),
+    WeakBox(Option<Box<Ponga>>),
+    WeakTable(HashMap<Id, Ponga>), // key id, value
+    HashTable(HashTable),
+    RecordType(RecordType),
+    Record(Record),
+    Promise(Promise),
+    Parameter(Parameter),
+    // The results of values when there isn't exactly one. These are only
+    // meant to be on the data stack, so they aren't put in the gc.
+    Values(Vec<Ponga>),
+    // Bytes for binary ports, which live in the gc like vectors
+    Bytevector(Vec<u8>),
+    Port(Port),
+    // The global bindings of an environment that isn't the current one.
+    // The current environment's are in the runtime's globals instead.
+    Environment(Environment),
+    // What reading from a port returns once there is nothing left
+    Eof,
+    Thread(Thread),
+    Mutex(Mutex),
+    ConditionVariable(ConditionVariable),
+    Channel(Channel),
+    Coroutine(Coroutine),
 }
 
 impl Ponga {
     pub fn is_func(&self) -> bool {
         match self {
-            Ponga::CFunc(_, _, _)
+            Ponga::CFunc(_, _, _, _)
             | Ponga::HFunc(_)
             | Ponga::MFunc(_, _) => true,
             _ => false,
@@ -52,10 +87,12 @@ impl Ponga {
             | Ponga::Char(_)
             | Ponga::Symbol(_)
             | Ponga::Ref(_)
-            | Ponga::CFunc(_, _, _)
+            | Ponga::CFunc(_, _, _, _)
             | Ponga::MFunc(_, _)
             | Ponga::HFunc(_)
             | Ponga::Sexpr(_)
+            | Ponga::Values(_)
+            | Ponga::Eof
             | Ponga::True
             | Ponga::False => true,
             _ => false,
@@ -106,7 +143,7 @@ impl Ponga {
     
     pub fn is_string(&self) -> bool {
         match self {
-            Ponga::String(_) => true,
+            Ponga::String(_) | Ponga::MutString(_) => true,
             _ => false,
         }
     }
@@ -214,6 +251,124 @@ impl Ponga {
         }
     }
     
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Ponga::Null => "null",
+            Ponga::Number(_) => "number",
+            Ponga::String(_) | Ponga::MutString(_) => "string",
+            Ponga::Char(_) => "char",
+            Ponga::Symbol(_) => "symbol",
+            Ponga::Identifier(_) => "identifier",
+            Ponga::List(_) => "list",
+            Ponga::Object(_) => "map",
+            Ponga::Array(_) => "vector",
+            Ponga::Sexpr(_) => "sexpr",
+            Ponga::CFunc(_, _, _, _) => "procedure",
+            Ponga::MFunc(_, _) => "macro",
+            Ponga::HFunc(_) => "builtin",
+            Ponga::True | Ponga::False => "boolean",
+            Ponga::Ref(_) => "ref",
+            Ponga::WeakBox(_) => "weak-box",
+            Ponga::WeakTable(_) => "weak-hash-table",
+            Ponga::HashTable(_) => "hash-table",
+            Ponga::RecordType(_) => "record-type",
+            Ponga::Environment(_) => "environment",
+            Ponga::Parameter(_) => "parameter",
+            Ponga::Record(_) => "record",
+            Ponga::Promise(_) => "promise",
+            Ponga::Values(_) => "values",
+            Ponga::Bytevector(_) => "bytevector",
+            Ponga::Port(_) => "port",
+            Ponga::Eof => "eof-object",
+            Ponga::Thread(_) => "thread",
+            Ponga::Mutex(_) => "mutex",
+            Ponga::ConditionVariable(_) => "condition-variable",
+            Ponga::Channel(_) => "channel",
+            Ponga::Coroutine(_) => "coroutine",
+        }
+    }
+
+    // Rough number of bytes used by this value, not counting other gc objects
+    // it refers to
+    pub fn approx_size(&self) -> usize {
+        std::mem::size_of::<Ponga>() + self.heap_size()
+    }
+
+    fn heap_size(&self) -> usize {
+        let pong_size = std::mem::size_of::<Ponga>();
+        match self {
+            Ponga::String(s)
+            | Ponga::MutString(s)
+            | Ponga::Symbol(s)
+            | Ponga::Identifier(s) => s.capacity(),
+            Ponga::List(l) => {
+                l.iter()
+                 .map(|p| pong_size + 2 * std::mem::size_of::<usize>() + p.heap_size())
+                 .sum()
+            }
+            Ponga::Array(arr) | Ponga::Sexpr(arr) | Ponga::Values(arr) => {
+                arr.capacity() * pong_size + arr.iter().map(|p| p.heap_size()).sum::<usize>()
+            }
+            Ponga::Object(o) | Ponga::Environment(Environment { globals: o, .. }) => {
+                o.iter()
+                 .map(|(k, v)| std::mem::size_of::<String>() + k.capacity()
+                               + pong_size + v.heap_size())
+                 .sum()
+            }
+            Ponga::CFunc(args, _, _, _) | Ponga::MFunc(args, _) => {
+                args.iter().map(|s| std::mem::size_of::<String>() + s.capacity()).sum()
+            }
+            Ponga::WeakBox(Some(val)) => val.approx_size(),
+            Ponga::WeakTable(t) => {
+                t.values()
+                 .map(|v| std::mem::size_of::<Id>() + pong_size + v.heap_size())
+                 .sum()
+            }
+            Ponga::HashTable(t) => {
+                t.entries()
+                 .iter()
+                 .map(|(k, v)| 2 * pong_size + k.heap_size() + v.heap_size())
+                 .sum()
+            }
+            Ponga::RecordType(t) => {
+                t.name.capacity()
+                    + t.fields.iter().map(|s| std::mem::size_of::<String>() + s.capacity()).sum::<usize>()
+            }
+            Ponga::Record(r) => {
+                r.fields.capacity() * pong_size + r.fields.iter().map(|p| p.heap_size()).sum::<usize>()
+            }
+            Ponga::Promise(Promise::Done(val) | Promise::Delayed(val, _)) => val.approx_size(),
+            Ponga::Parameter(p) => p.value.approx_size(),
+            Ponga::Thread(t) => {
+                t.ins_stack.capacity() * std::mem::size_of::<crate::instructions::Instruction>()
+                    + t.data_stack.capacity() * pong_size
+                    + t.data_stack.iter().map(|p| p.heap_size()).sum::<usize>()
+            }
+            Ponga::Coroutine(c) => {
+                c.ins_stack.capacity() * std::mem::size_of::<crate::instructions::Instruction>()
+                    + c.data_stack.capacity() * pong_size
+                    + c.data_stack.iter().map(|p| p.heap_size()).sum::<usize>()
+            }
+            Ponga::Channel(c) => {
+                c.buffer.iter().map(|p| pong_size + p.heap_size()).sum()
+            }
+            Ponga::Bytevector(bytes) => bytes.capacity(),
+            Ponga::Port(Port { direction: Direction::Input(source), .. }) => source.data.capacity(),
+            Ponga::Port(Port { direction: Direction::Output(Sink::Buffer(buf)), .. }) => buf.capacity(),
+            _ => 0,
+        }
+    }
+
+    // Ids that a weak reference to this value would be watching
+    pub fn weak_ids(&self) -> Vec<Id> {
+        match self {
+            Ponga::Ref(id) => vec![*id],
+            Ponga::CFunc(_, id, state_id, _) => vec![*id, *state_id],
+            Ponga::MFunc(_, id) => vec![*id],
+            _ => vec![],
+        }
+    }
+
     pub fn char_to_char(&self) -> RunRes<char> {
         match self {
             Ponga::Char(c) => Ok(*c),
@@ -306,25 +461,76 @@ impl std::fmt::Display for Number {
     }
 }
 
+// The contents of a string literal, escaped so that the parser reads them
+// back as they were
+fn escape_string(s: &str) -> String {
+    let mut res = String::new();
+    for c in s.chars() {
+        match c {
+            '"' => res.push_str("\\\""),
+            '\\' => res.push_str("\\\\"),
+            '\n' => res.push_str("\\n"),
+            '\r' => res.push_str("\\r"),
+            '\t' => res.push_str("\\t"),
+            c if c.is_control() => res.push_str(&format!("\\u{{{:x}}}", c as u32)),
+            c => res.push(c),
+        }
+    }
+    res
+}
+
+// What goes after #\ to write the character
+fn char_literal(c: char) -> String {
+    match CHAR_NAMES.iter().find(|(_, named)| *named == c) {
+        Some((name, _)) => name.to_string(),
+        None if c.is_control() => format!("x{:x}", c as u32),
+        None => c.to_string(),
+    }
+}
+
 impl std::fmt::Display for Ponga {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> Result<(), std::fmt::Error> {
         match self {
             Ponga::Number(n) => write!(f, "{}", n),
-            Ponga::String(s) => write!(f, "\"{}\"", s),
+            Ponga::String(s) | Ponga::MutString(s) => write!(f, "\"{}\"", escape_string(s)),
             Ponga::False => write!(f, "#f"),
             Ponga::True => write!(f, "#t"),
-            Ponga::Char(c) => write!(f, "#\\{}", c),
+            Ponga::Char(c) => write!(f, "#\\{}", char_literal(*c)),
             Ponga::Null => write!(f, "'()"),
             Ponga::Symbol(s) => write!(f, "{}", s),
             Ponga::Array(arr) => write!(f, "{:?}", arr),
             Ponga::List(l) => write!(f, "{:?}", l),
             Ponga::HFunc(id) => write!(f, "Internal function with id {}", id),
-            Ponga::CFunc(args, _, state) => write!(f, "Compound function with args {:?} and state {:?}", args, state),
+            Ponga::CFunc(args, _, state, _) => write!(f, "Compound function with args {:?} and state {:?}", args, state),
             Ponga::MFunc(args, _) => write!(f, "Macro with args {:?}", args),
             Ponga::Sexpr(a) => write!(f, "S-expression {:?}", a),
             Ponga::Identifier(s) => write!(f, "Identifier {}", s),
             Ponga::Ref(id) => write!(f, "Ref {}", id),
             Ponga::Object(o) => write!(f, "{:?}", o),
+            Ponga::WeakBox(_) => write!(f, "#<weak-box>"),
+            Ponga::WeakTable(t) => write!(f, "#<weak-hash-table {}>", t.len()),
+            Ponga::HashTable(t) => write!(f, "#<hash-table {} {}>", t.equiv.name(), t.len),
+            Ponga::RecordType(t) => write!(f, "#<record-type {}>", t.name),
+            Ponga::Record(r) => write!(f, "#<record {:?}>", r.fields),
+            Ponga::Promise(_) => write!(f, "#<promise>"),
+            Ponga::Values(vals) => {
+                let vals: Vec<String> = vals.iter().map(|v| v.to_string()).collect();
+                write!(f, "{}", vals.join(" "))
+            }
+            Ponga::Bytevector(bytes) => {
+                let bytes: Vec<String> = bytes.iter().map(|b| b.to_string()).collect();
+                write!(f, "#u8({})", bytes.join(" "))
+            }
+            Ponga::Port(p) if p.is_input() => write!(f, "#<input-port>"),
+            Ponga::Port(_) => write!(f, "#<output-port>"),
+            Ponga::Environment(_) => write!(f, "#<environment>"),
+            Ponga::Parameter(_) => write!(f, "#<parameter>"),
+            Ponga::Eof => write!(f, "#<eof>"),
+            Ponga::Thread(t) => write!(f, "#<thread {}>", t.name),
+            Ponga::Mutex(m) => write!(f, "#<mutex {}>", m.name),
+            Ponga::ConditionVariable(c) => write!(f, "#<condition-variable {}>", c.name),
+            Ponga::Channel(_) => write!(f, "#<channel>"),
+            Ponga::Coroutine(_) => write!(f, "#<coroutine>"),
         }
     }
 }